@@ -0,0 +1,218 @@
+use crate::i8086::cpu::CPU;
+use super::loader::*;
+use super::psp::*;
+
+// The MZ header at the start of an .EXE file; sizes are in 512 byte pages
+// and 16 byte paragraphs.
+#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug)]
+pub struct MzHeader {
+  pub last_page_bytes: u16,
+  pub pages: u16,
+  pub relocations: u16,
+  pub header_paragraphs: u16,
+  pub min_alloc: u16,
+  pub max_alloc: u16,
+  pub ss: u16,
+  pub sp: u16,
+  pub checksum: u16,
+  pub ip: u16,
+  pub cs: u16,
+  pub relocation_offset: u16,
+  pub overlay: u16,
+}
+
+pub const MZ_HEADER_SIZE: usize = 0x1c;
+
+pub fn is_exe(image: &[u8]) -> bool {
+  image.starts_with(b"MZ") || image.starts_with(b"ZM")
+}
+
+fn read_u16(image: &[u8], offset: usize) -> Option<u16> {
+  let bytes = image.get(offset..offset + 2)?;
+  Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+impl MzHeader {
+  pub fn parse(image: &[u8]) -> Result<MzHeader, LoadError> {
+    if !is_exe(image) || image.len() < MZ_HEADER_SIZE {
+      return Err(LoadError::InvalidExe);
+    }
+    let field = |index: usize| read_u16(image, index * 2).unwrap();
+    Ok(MzHeader {
+      last_page_bytes: field(1),
+      pages: field(2),
+      relocations: field(3),
+      header_paragraphs: field(4),
+      min_alloc: field(5),
+      max_alloc: field(6),
+      ss: field(7),
+      sp: field(8),
+      checksum: field(9),
+      ip: field(10),
+      cs: field(11),
+      relocation_offset: field(12),
+      overlay: field(13),
+    })
+  }
+
+  pub fn header_size(&self) -> usize {
+    self.header_paragraphs as usize * 16
+  }
+
+  // The size of the file according to the header; a last page of 0 bytes
+  // means that it's full.
+  pub fn file_size(&self) -> usize {
+    let size = self.pages as usize * 512;
+    match self.last_page_bytes {
+      0 => size,
+      bytes => size.saturating_sub(512 - (bytes as usize & 511)),
+    }
+  }
+}
+
+// Loads an .EXE image right above its PSP, which follows the environment
+// at `segment`. The program gets the memory its header asks for, up to
+// `max_alloc` paragraphs past the load module, as long as `min_alloc` of
+// them are available below `memory_top`. With both of them 0, the program
+// gets all of the memory, and the load module goes at the top of it (it's
+// "loaded high"). Returns the segment of the PSP.
+pub fn load_exe(
+  cpu: &mut CPU,
+  image: &[u8],
+  segment: u16,
+  memory_top: u16,
+  psp_builder: &PspBuilder,
+) -> Result<u16, LoadError> {
+  let header = MzHeader::parse(image)?;
+  let start = header.header_size();
+  // A file shorter than its header says is loaded as far as it goes.
+  let end = header.file_size().min(image.len());
+  if start > end {
+    return Err(LoadError::InvalidExe);
+  }
+  let module = &image[start..end];
+  let table = header.relocation_offset as usize;
+  let relocations: Option<Vec<(u16, u16)>> = (0..header.relocations)
+    .map(|i| {
+      let entry = table + i as usize * 4;
+      Some((read_u16(image, entry)?, read_u16(image, entry + 2)?))
+    })
+    .collect();
+  let relocations = relocations.ok_or(LoadError::InvalidExe)?;
+  let psp = psp_builder.write_environment(cpu, segment, memory_top)?;
+  let module_paragraphs = module.len().div_ceil(16);
+  let psp_paragraphs = PSP_SIZE / 16;
+  let available = memory_top.saturating_sub(psp) as usize;
+  let needed = psp_paragraphs + module_paragraphs;
+  if needed > 0x10000 {
+    return Err(LoadError::TooLarge);
+  }
+  if available < needed + header.min_alloc as usize {
+    return Err(LoadError::OutOfMemory);
+  }
+  let high = header.min_alloc == 0 && header.max_alloc == 0;
+  let allocated = if high {
+    available
+  } else {
+    (needed + header.max_alloc as usize).min(available)
+  };
+  let memory_end = psp.wrapping_add(allocated as u16);
+  psp_builder.write(cpu, psp, segment, memory_end)?;
+  let load_segment = if high {
+    memory_end.wrapping_sub(module_paragraphs as u16)
+  } else {
+    psp.wrapping_add(psp_paragraphs as u16)
+  };
+  for (i, value) in module.iter().enumerate() {
+    let address = cpu.get_linear_addr(load_segment, 0) + i;
+    cpu.memory.write_u8(address, *value);
+  }
+  // Segment fixups are relative to where the module is loaded
+  for (offset, segment) in relocations {
+    let segment = load_segment.wrapping_add(segment);
+    let address = cpu.get_linear_addr(segment, offset);
+    let value = cpu.memory.read_u16(address);
+    cpu.memory.write_u16(address, value.wrapping_add(load_segment));
+  }
+  let register = &mut cpu.register;
+  register.cs = load_segment.wrapping_add(header.cs);
+  register.ip = header.ip;
+  register.ss = load_segment.wrapping_add(header.ss);
+  register.sp = header.sp;
+  register.ds = psp;
+  register.es = psp;
+  Ok(psp)
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::mem::linear::LinearMemory;
+  use super::*;
+  use super::super::process;
+
+  fn exe(min_alloc: u16, max_alloc: u16) -> Vec<u8> {
+    let mut image = vec![
+      b'M', b'Z',
+      49, 0, // 49 bytes in the last page
+      1, 0, // 1 page
+      1, 0, // 1 relocation
+      2, 0, // 2 paragraphs of header
+    ];
+    for value in [min_alloc, max_alloc, 2, 0x100, 0, 0, 0, 0x1c, 0].iter() {
+      image.extend(&value.to_le_bytes());
+    }
+    // The relocation at 0000:0001
+    image.extend(&[1, 0, 0, 0]);
+    image.extend(&[
+      0xb8, 0x01, 0x00, // mov ax, data
+      0x8e, 0xd8, // mov ds, ax
+      0xa0, 0x00, 0x00, // mov al, [0]
+      0xb4, 0x4c, // mov ah, 0x4c
+      0xcd, 0x21, // int 0x21
+      0, 0, 0, 0,
+      0x2a, // data
+    ]);
+    image
+  }
+
+  #[test]
+  fn test_load_exe() {
+    let image = exe(0x10, 0xffff);
+    let header = MzHeader::parse(&image).unwrap();
+    assert_eq!(header.file_size(), image.len());
+    assert_eq!((header.ss, header.sp), (2, 0x100));
+    let memory = LinearMemory::new(1024 * 1024 / 4);
+    let mut cpu = CPU::new(Box::new(memory), Box::new(LinearMemory::new(0)));
+    // A paragraph of environment, then the PSP
+    let builder = PspBuilder::new("C:\\TEST.EXE");
+    let load = |cpu: &mut CPU, image: &[u8], memory_top: u16| {
+      load(cpu, image, 0x1000, memory_top, &builder)
+    };
+    assert_eq!(load(&mut cpu, &image, 0xa000), Ok(0x1001));
+    assert_eq!((cpu.register.cs, cpu.register.ip), (0x1011, 0));
+    assert_eq!((cpu.register.ss, cpu.register.sp), (0x1013, 0x100));
+    assert_eq!((cpu.register.ds, cpu.register.es), (0x1001, 0x1001));
+    assert_eq!(cpu.memory.read_u16(0x10111), 0x1012);
+    assert_eq!(cpu.memory.read_u16(0x10012), 0xa000);
+    let exit = process::run(&mut cpu, Some(100), None).unwrap();
+    assert_eq!(exit, process::Exit::Terminated(0x2a));
+    // 0x10 paragraphs of PSP, 2 of the module and then min_alloc
+    assert_eq!(load(&mut cpu, &image, 0x1023), Ok(0x1001));
+    assert_eq!(cpu.memory.read_u16(0x10012), 0x1023);
+    assert_eq!(load(&mut cpu, &exe(0x10, 0x20), 0xa000), Ok(0x1001));
+    assert_eq!(cpu.memory.read_u16(0x10012), 0x1033);
+    assert_eq!(load(&mut cpu, &exe(0x11, 0xffff), 0x1023),
+      Err(LoadError::OutOfMemory));
+    assert_eq!(load(&mut cpu, &image[..0x1e], 0xa000),
+      Err(LoadError::InvalidExe));
+    // Loaded high, below the top of memory
+    assert_eq!(load(&mut cpu, &exe(0, 0), 0xa000), Ok(0x1001));
+    assert_eq!(cpu.memory.read_u16(0x10012), 0xa000);
+    assert_eq!((cpu.register.cs, cpu.register.ip), (0x9ffe, 0));
+    assert_eq!((cpu.register.ss, cpu.register.sp), (0xa000, 0x100));
+    assert_eq!(cpu.memory.read_u16(0x9ffe1), 0x9fff);
+    let exit = process::run(&mut cpu, Some(100), None).unwrap();
+    assert_eq!(exit, process::Exit::Terminated(0x2a));
+  }
+}
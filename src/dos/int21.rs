@@ -0,0 +1,1049 @@
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::TryRecvError;
+use std::thread;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use crate::i8086::cpu::CPU;
+use crate::i8086::flags::*;
+use crate::i8086::interrupt::InterruptHandler;
+use super::process::Terminate;
+use super::psp::parse_fcb_name;
+
+// INT 20h and the INT 21h services that command line tools use, with one
+// guest drive mapped onto a host directory. Run programs with it through
+// `process::run_with`; other interrupts go to the guest.
+
+// DOS error codes, returned in AX with CF set
+pub const ERROR_INVALID_FUNCTION: u16 = 0x01;
+pub const ERROR_FILE_NOT_FOUND: u16 = 0x02;
+pub const ERROR_PATH_NOT_FOUND: u16 = 0x03;
+pub const ERROR_TOO_MANY_OPEN_FILES: u16 = 0x04;
+pub const ERROR_ACCESS_DENIED: u16 = 0x05;
+pub const ERROR_INVALID_HANDLE: u16 = 0x06;
+pub const ERROR_INSUFFICIENT_MEMORY: u16 = 0x08;
+pub const ERROR_INVALID_ACCESS: u16 = 0x0c;
+pub const ERROR_INVALID_DRIVE: u16 = 0x0f;
+pub const ERROR_CURRENT_DIRECTORY: u16 = 0x10;
+pub const ERROR_NO_MORE_FILES: u16 = 0x12;
+
+// File attributes
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+
+// Handles 0 to 4 are stdin, stdout, stderr, AUX and PRN.
+const STD_HANDLES: usize = 5;
+const MAX_HANDLES: usize = 20;
+
+// What IOCTL reports for the console, and for AUX and PRN.
+const DEVICE_CONSOLE: u16 = 0x80d3;
+const DEVICE_OTHER: u16 = 0x80c0;
+
+const MAX_PATH: usize = 128;
+
+type DosResult<T> = Result<T, u16>;
+
+fn error_code(err: &io::Error, not_found: u16) -> u16 {
+  match err.kind() {
+    io::ErrorKind::NotFound => not_found,
+    _ => ERROR_ACCESS_DENIED,
+  }
+}
+
+// A date and time in UTC, broken down the way DOS reports it.
+#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug)]
+pub struct DateTime {
+  pub year: u16,
+  pub month: u8,
+  pub day: u8,
+  // 0 for Sunday
+  pub weekday: u8,
+  pub hour: u8,
+  pub minute: u8,
+  pub second: u8,
+  pub hundredths: u8,
+}
+
+impl DateTime {
+  pub fn from_system_time(time: SystemTime) -> Self {
+    let duration = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = duration.as_secs();
+    let days = (seconds / 86400) as i64;
+    // Days to a civil date, after Howard Hinnant's `civil_from_days`
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    DateTime {
+      year: year as u16,
+      month: month as u8,
+      day: day as u8,
+      // 1970-01-01 was a Thursday
+      weekday: ((days + 4) % 7) as u8,
+      hour: (seconds / 3600 % 24) as u8,
+      minute: (seconds / 60 % 60) as u8,
+      second: (seconds % 60) as u8,
+      hundredths: (duration.subsec_millis() / 10) as u8,
+    }
+  }
+
+  // The packed forms found in directory entries
+  pub fn dos_date(&self) -> u16 {
+    (self.year.saturating_sub(1980) << 9) |
+      ((self.month as u16) << 5) | self.day as u16
+  }
+
+  pub fn dos_time(&self) -> u16 {
+    ((self.hour as u16) << 11) | ((self.minute as u16) << 5) |
+      (self.second as u16 / 2)
+  }
+}
+
+// A directory entry as found by 4Eh and 4Fh.
+struct FoundFile {
+  name: String,
+  attributes: u8,
+  modified: DateTime,
+  size: u32,
+}
+
+// The 8.3 name DOS would see for a host file name, if it has one.
+fn dos_name(name: &str) -> Option<String> {
+  let valid = |part: &str, length: usize| {
+    part.len() <= length && part.bytes().all(|value| {
+      value.is_ascii_alphanumeric() || b"!#$%&'()-@^_`{}~".contains(&value)
+    })
+  };
+  let (base, extension) = match name.find('.') {
+    Some(dot) => (&name[..dot], &name[dot + 1..]),
+    None => (name, ""),
+  };
+  if base.is_empty() || !valid(base, 8) || !valid(extension, 3) {
+    return None;
+  }
+  Some(name.to_ascii_uppercase())
+}
+
+// Whether a name matches a pattern such as `*.TXT` or `FILE?.C`.
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+  let pattern = parse_fcb_name(pattern);
+  let name = parse_fcb_name(name);
+  pattern[1..].iter().zip(name[1..].iter())
+    .all(|(p, n)| *p == b'?' || p == n)
+}
+
+fn attributes(metadata: &fs::Metadata) -> u8 {
+  let mut attributes = if metadata.is_dir() {
+    ATTR_DIRECTORY
+  } else {
+    ATTR_ARCHIVE
+  };
+  if metadata.permissions().readonly() {
+    attributes |= ATTR_READ_ONLY;
+  }
+  attributes
+}
+
+// A string ending with `end`, without it.
+fn read_string(cpu: &CPU, seg: u16, offset: u16, end: u8) -> Vec<u8> {
+  let mut bytes = vec![];
+  loop {
+    let offset = offset.wrapping_add(bytes.len() as u16);
+    let address = cpu.get_linear_addr(seg, offset);
+    let value = cpu.memory.read_u8(address);
+    if value == end || bytes.len() >= 0xffff {
+      return bytes;
+    }
+    bytes.push(value);
+  }
+}
+
+fn read_path(cpu: &CPU, seg: u16, offset: u16) -> DosResult<String> {
+  let bytes = read_string(cpu, seg, offset, 0);
+  if bytes.len() >= MAX_PATH {
+    return Err(ERROR_PATH_NOT_FOUND);
+  }
+  Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn set_al(cpu: &mut CPU, value: u8) -> () {
+  cpu.register.ax = (cpu.register.ax & 0xff00) | value as u16;
+}
+
+// Console input, read by a thread of its own once it's first needed, so
+// that it can be polled without blocking.
+pub struct ConsoleInput {
+  reader: Option<Box<dyn Read + Send>>,
+  receiver: Option<Receiver<u8>>,
+}
+
+impl ConsoleInput {
+  pub fn new<R: Read + Send + 'static>(reader: R) -> Self {
+    ConsoleInput { reader: Some(Box::new(reader)), receiver: None }
+  }
+
+  fn receiver(&mut self) -> &Receiver<u8> {
+    let reader = &mut self.reader;
+    self.receiver.get_or_insert_with(|| {
+      let mut reader = reader.take().unwrap();
+      let (sender, receiver) = mpsc::channel();
+      thread::spawn(move || {
+        let mut byte = [0];
+        while let Ok(1) = reader.read(&mut byte) {
+          if sender.send(byte[0]).is_err() {
+            break;
+          }
+        }
+      });
+      receiver
+    })
+  }
+
+  // The next byte, or None at the end of the input.
+  pub fn read(&mut self) -> Option<u8> {
+    self.receiver().recv().ok()
+  }
+
+  // The next byte if there is one already, and whether the input has
+  // ended otherwise.
+  pub fn try_read(&mut self) -> Result<u8, bool> {
+    match self.receiver().try_recv() {
+      Ok(value) => Ok(value),
+      Err(TryRecvError::Empty) => Err(false),
+      Err(TryRecvError::Disconnected) => Err(true),
+    }
+  }
+}
+
+pub struct Dos {
+  // The guest drive, 0 for A:, and the host directory it maps to
+  pub drive: u8,
+  pub root: PathBuf,
+  // The current directory on the drive, as upper case names
+  pub current_dir: Vec<String>,
+  pub input: ConsoleInput,
+  pub output: Box<dyn Write>,
+  pub error: Box<dyn Write>,
+  // The disk transfer area used by 4Eh and 4Fh
+  pub dta: (u16, u16),
+  // The first segment past conventional memory, for 4Ah
+  pub memory_top: u16,
+  // A fixed time for 2Ah and 2Ch, for reproducible runs
+  pub clock: Option<SystemTime>,
+  // Set by INT 20h, 00h or 4Ch, which also stop the CPU
+  pub exit_code: Option<u8>,
+  files: Vec<Option<File>>,
+  found: Vec<FoundFile>,
+}
+
+impl Dos {
+  // Maps C: onto the host directory, with the console on the host's.
+  pub fn new(root: PathBuf) -> Self {
+    Dos {
+      drive: 2,
+      root,
+      current_dir: vec![],
+      input: ConsoleInput::new(io::stdin()),
+      output: Box::new(io::stdout()),
+      error: Box::new(io::stderr()),
+      dta: (0, 0x80),
+      memory_top: 0xa000,
+      clock: None,
+      exit_code: None,
+      files: vec![],
+      found: vec![],
+    }
+  }
+
+  fn now(&self) -> DateTime {
+    DateTime::from_system_time(self.clock.unwrap_or_else(SystemTime::now))
+  }
+
+  // The names, from the root of the drive, that a DOS path refers to.
+  fn components(&self, path: &str) -> DosResult<Vec<String>> {
+    let bytes = path.as_bytes();
+    let mut path = path;
+    if bytes.len() >= 2 && bytes[1] == b':' {
+      let drive = bytes[0].to_ascii_uppercase().wrapping_sub(b'A');
+      if drive != self.drive {
+        return Err(ERROR_PATH_NOT_FOUND);
+      }
+      path = &path[2..];
+    }
+    let mut components = if path.starts_with(&['\\', '/'][..]) {
+      vec![]
+    } else {
+      self.current_dir.clone()
+    };
+    for name in path.split(&['\\', '/'][..]) {
+      match name {
+        "" | "." => (),
+        ".." => {
+          components.pop();
+        },
+        _ => components.push(name.to_ascii_uppercase()),
+      }
+    }
+    Ok(components)
+  }
+
+  // The host path for the names; the host may well be case sensitive, so
+  // each one matches an existing entry regardless of case.
+  fn host_path(&self, components: &[String]) -> PathBuf {
+    let mut path = self.root.clone();
+    for name in components {
+      let exact = path.join(name);
+      let existing = match exact.exists() {
+        true => None,
+        false => fs::read_dir(&path).ok().and_then(|entries| {
+          entries.filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name())
+            .find(|entry| {
+              entry.to_str().is_some_and(|entry| {
+                entry.eq_ignore_ascii_case(name)
+              })
+            })
+        }),
+      };
+      path = match existing {
+        Some(entry) => path.join(entry),
+        None => exact,
+      };
+    }
+    path
+  }
+
+  fn resolve(&self, path: &str) -> DosResult<PathBuf> {
+    Ok(self.host_path(&self.components(path)?))
+  }
+
+  fn write_console(&mut self, handle: usize, bytes: &[u8]) -> () {
+    // The console has nowhere to report errors to
+    let output = match handle {
+      2 => &mut self.error,
+      _ => &mut self.output,
+    };
+    output.write_all(bytes).and_then(|_| output.flush()).ok();
+  }
+
+  fn read_console_byte(&mut self) -> Option<u8> {
+    self.output.flush().ok();
+    self.input.read()
+  }
+
+  // A key for 01h, 06h, 07h and 08h: Enter is CR, and the end of input
+  // reads as ^Z, like redirected input does.
+  fn read_key(&mut self) -> Option<u8> {
+    loop {
+      match self.read_console_byte() {
+        Some(b'\r') => continue,
+        Some(b'\n') => return Some(b'\r'),
+        key => return key,
+      }
+    }
+  }
+
+  // A key for 06h, if one is ready; there's none at the end of the input.
+  fn poll_key(&mut self) -> Option<u8> {
+    self.output.flush().ok();
+    loop {
+      match self.input.try_read() {
+        Ok(b'\r') => continue,
+        Ok(b'\n') => return Some(b'\r'),
+        Ok(key) => return Some(key),
+        Err(_) => return None,
+      }
+    }
+  }
+
+  // A line of input, without its end.
+  fn read_console_line(&mut self) -> Option<Vec<u8>> {
+    let mut line = vec![];
+    loop {
+      match self.read_console_byte() {
+        Some(b'\n') => return Some(line),
+        Some(b'\r') => (),
+        Some(value) => line.push(value),
+        None if line.is_empty() => return None,
+        None => return Some(line),
+      }
+    }
+  }
+
+  fn terminate(&mut self, cpu: &mut CPU, code: u8) -> () {
+    self.output.flush().ok();
+    self.exit_code = Some(code);
+    cpu.running = false;
+  }
+
+  fn file(&mut self, handle: u16) -> DosResult<&mut File> {
+    let index = (handle as usize).checked_sub(STD_HANDLES)
+      .ok_or(ERROR_INVALID_HANDLE)?;
+    match self.files.get_mut(index) {
+      Some(Some(file)) => Ok(file),
+      _ => Err(ERROR_INVALID_HANDLE),
+    }
+  }
+
+  fn add_file(&mut self, file: File) -> DosResult<u16> {
+    let index = match self.files.iter().position(|file| file.is_none()) {
+      Some(index) => index,
+      None if self.files.len() < MAX_HANDLES - STD_HANDLES => {
+        self.files.push(None);
+        self.files.len() - 1
+      },
+      None => return Err(ERROR_TOO_MANY_OPEN_FILES),
+    };
+    self.files[index] = Some(file);
+    Ok((STD_HANDLES + index) as u16)
+  }
+
+  fn open(&mut self, cpu: &mut CPU, create: bool) -> DosResult<()> {
+    let register = &cpu.register;
+    let path = self.resolve(&read_path(cpu, register.ds, register.dx)?)?;
+    let mut options = OpenOptions::new();
+    if create {
+      options.read(true).write(true).create(true).truncate(true);
+    } else {
+      match register.ax & 0x07 {
+        0 => options.read(true),
+        1 => options.write(true),
+        2 => options.read(true).write(true),
+        _ => return Err(ERROR_INVALID_ACCESS),
+      };
+    }
+    if path.is_dir() {
+      return Err(ERROR_ACCESS_DENIED);
+    }
+    let file = options.open(&path)
+      .map_err(|err| error_code(&err, ERROR_FILE_NOT_FOUND))?;
+    cpu.register.ax = self.add_file(file)?;
+    Ok(())
+  }
+
+  fn close(&mut self, cpu: &mut CPU) -> DosResult<()> {
+    let handle = cpu.register.bx as usize;
+    if handle < STD_HANDLES {
+      return Ok(());
+    }
+    match self.files.get_mut(handle - STD_HANDLES) {
+      Some(file @ Some(_)) => {
+        *file = None;
+        Ok(())
+      },
+      _ => Err(ERROR_INVALID_HANDLE),
+    }
+  }
+
+  fn read(&mut self, cpu: &mut CPU) -> DosResult<()> {
+    let register = &cpu.register;
+    let (seg, offset) = (register.ds, register.dx);
+    let length = register.cx as usize;
+    let bytes = match register.bx {
+      // The console gives a line at a time, ending with CR LF
+      0 => match self.read_console_line() {
+        Some(mut line) => {
+          line.extend(b"\r\n");
+          line.truncate(length);
+          line
+        },
+        None => vec![],
+      },
+      1..=4 => vec![],
+      handle => {
+        let file = self.file(handle)?;
+        let mut bytes = vec![0; length];
+        let mut read = 0;
+        while read < length {
+          match file.read(&mut bytes[read..]) {
+            Ok(0) => break,
+            Ok(count) => read += count,
+            Err(err) => return Err(error_code(&err, ERROR_ACCESS_DENIED)),
+          }
+        }
+        bytes.truncate(read);
+        bytes
+      },
+    };
+    cpu.write_bytes(seg, offset, &bytes);
+    cpu.register.ax = bytes.len() as u16;
+    Ok(())
+  }
+
+  fn write(&mut self, cpu: &mut CPU) -> DosResult<()> {
+    let register = &cpu.register;
+    let bytes = cpu.read_bytes(register.ds, register.dx, register.cx as usize);
+    match register.bx {
+      0..=2 => self.write_console(register.bx as usize, &bytes),
+      3 | 4 => (),
+      handle => {
+        let file = self.file(handle)?;
+        let result = if bytes.is_empty() {
+          // Writing nothing truncates the file where it is
+          file.stream_position().and_then(|position| file.set_len(position))
+        } else {
+          file.write_all(&bytes)
+        };
+        result.map_err(|err| error_code(&err, ERROR_ACCESS_DENIED))?;
+      },
+    }
+    cpu.register.ax = bytes.len() as u16;
+    Ok(())
+  }
+
+  fn seek(&mut self, cpu: &mut CPU) -> DosResult<()> {
+    let register = &cpu.register;
+    let offset = ((register.cx as u32) << 16) | register.dx as u32;
+    let position = match register.ax & 0xff {
+      0 => SeekFrom::Start(offset as u64),
+      1 => SeekFrom::Current(offset as i32 as i64),
+      2 => SeekFrom::End(offset as i32 as i64),
+      _ => return Err(ERROR_INVALID_FUNCTION),
+    };
+    let position = match register.bx as usize {
+      handle if handle < STD_HANDLES => 0,
+      handle => self.file(handle as u16)?.seek(position)
+        .map_err(|err| error_code(&err, ERROR_ACCESS_DENIED))?,
+    };
+    cpu.register.dx = (position >> 16) as u16;
+    cpu.register.ax = position as u16;
+    Ok(())
+  }
+
+  fn file_attributes(&mut self, cpu: &mut CPU) -> DosResult<()> {
+    let register = &cpu.register;
+    let path = self.resolve(&read_path(cpu, register.ds, register.dx)?)?;
+    let metadata = fs::metadata(&path)
+      .map_err(|err| error_code(&err, ERROR_FILE_NOT_FOUND))?;
+    match register.ax & 0xff {
+      0 => cpu.register.cx = attributes(&metadata) as u16,
+      // Only the read-only attribute means something to the host
+      1 => {
+        let mut permissions = metadata.permissions();
+        permissions.set_readonly(register.cx as u8 & ATTR_READ_ONLY != 0);
+        fs::set_permissions(&path, permissions)
+          .map_err(|err| error_code(&err, ERROR_FILE_NOT_FOUND))?;
+      },
+      _ => return Err(ERROR_INVALID_FUNCTION),
+    }
+    Ok(())
+  }
+
+  fn ioctl(&mut self, cpu: &mut CPU) -> DosResult<()> {
+    if cpu.register.ax & 0xff != 0 {
+      return Err(ERROR_INVALID_FUNCTION);
+    }
+    cpu.register.dx = match cpu.register.bx {
+      0..=2 => DEVICE_CONSOLE,
+      3 | 4 => DEVICE_OTHER,
+      handle => {
+        self.file(handle)?;
+        self.drive as u16
+      },
+    };
+    Ok(())
+  }
+
+  fn directory(&mut self, cpu: &mut CPU, function: u8) -> DosResult<()> {
+    let register = &cpu.register;
+    let path = read_path(cpu, register.ds, register.dx)?;
+    let components = self.components(&path)?;
+    let path = self.host_path(&components);
+    let result = match function {
+      0x39 => fs::create_dir(&path),
+      0x3a => {
+        if components == self.current_dir {
+          return Err(ERROR_CURRENT_DIRECTORY);
+        }
+        fs::remove_dir(&path)
+      },
+      _ => {
+        if !path.is_dir() {
+          return Err(ERROR_PATH_NOT_FOUND);
+        }
+        self.current_dir = components;
+        Ok(())
+      },
+    };
+    result.map_err(|err| error_code(&err, ERROR_PATH_NOT_FOUND))
+  }
+
+  fn current_directory(&mut self, cpu: &mut CPU) -> DosResult<()> {
+    let drive = (cpu.register.dx & 0xff) as u8;
+    if drive != 0 && drive != self.drive + 1 {
+      return Err(ERROR_INVALID_DRIVE);
+    }
+    let mut path = self.current_dir.join("\\").into_bytes();
+    path.push(0);
+    cpu.write_bytes(cpu.register.ds, cpu.register.si, &path);
+    cpu.register.ax = 0x0100;
+    Ok(())
+  }
+
+  fn delete(&mut self, cpu: &mut CPU) -> DosResult<()> {
+    let register = &cpu.register;
+    let path = self.resolve(&read_path(cpu, register.ds, register.dx)?)?;
+    fs::remove_file(&path)
+      .map_err(|err| error_code(&err, ERROR_FILE_NOT_FOUND))
+  }
+
+  fn rename(&mut self, cpu: &mut CPU) -> DosResult<()> {
+    let register = &cpu.register;
+    let from = self.resolve(&read_path(cpu, register.ds, register.dx)?)?;
+    let to = self.resolve(&read_path(cpu, register.es, register.di)?)?;
+    if to.exists() {
+      return Err(ERROR_ACCESS_DENIED);
+    }
+    fs::rename(&from, &to)
+      .map_err(|err| error_code(&err, ERROR_FILE_NOT_FOUND))
+  }
+
+  fn find_first(&mut self, cpu: &mut CPU) -> DosResult<()> {
+    let register = &cpu.register;
+    let spec = read_path(cpu, register.ds, register.dx)?;
+    let search_attributes = register.cx as u8;
+    let split = spec.rfind(&['\\', '/', ':'][..]).map_or(0, |i| i + 1);
+    let (directory, pattern) = spec.split_at(split);
+    let path = self.resolve(directory)?;
+    let entries = fs::read_dir(&path)
+      .map_err(|err| error_code(&err, ERROR_PATH_NOT_FOUND))?;
+    let mut found: Vec<FoundFile> = entries
+      .filter_map(|entry| {
+        let entry = entry.ok()?;
+        let name = dos_name(entry.file_name().to_str()?)?;
+        let metadata = entry.metadata().ok()?;
+        let attributes = attributes(&metadata);
+        if attributes & ATTR_DIRECTORY & !search_attributes != 0 ||
+          !matches_pattern(pattern, &name)
+        {
+          return None;
+        }
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        Some(FoundFile {
+          name,
+          attributes,
+          modified: DateTime::from_system_time(modified),
+          size: metadata.len().min(u32::MAX as u64) as u32,
+        })
+      })
+      .collect();
+    if found.is_empty() {
+      return Err(ERROR_FILE_NOT_FOUND);
+    }
+    found.sort_by(|a, b| b.name.cmp(&a.name));
+    self.found = found;
+    self.find_next(cpu)
+  }
+
+  // Fills the DTA with the next file found.
+  fn find_next(&mut self, cpu: &mut CPU) -> DosResult<()> {
+    let file = self.found.pop().ok_or(ERROR_NO_MORE_FILES)?;
+    let mut entry = vec![0; 0x2b];
+    entry[0x15] = file.attributes;
+    entry[0x16..0x18].copy_from_slice(&file.modified.dos_time().to_le_bytes());
+    entry[0x18..0x1a].copy_from_slice(&file.modified.dos_date().to_le_bytes());
+    entry[0x1a..0x1e].copy_from_slice(&file.size.to_le_bytes());
+    entry[0x1e..0x1e + file.name.len()].copy_from_slice(file.name.as_bytes());
+    let (seg, offset) = self.dta;
+    cpu.write_bytes(seg, offset, &entry);
+    Ok(())
+  }
+
+  fn resize_memory(&mut self, cpu: &mut CPU) -> DosResult<()> {
+    let available = self.memory_top.saturating_sub(cpu.register.es);
+    if cpu.register.bx > available {
+      cpu.register.bx = available;
+      return Err(ERROR_INSUFFICIENT_MEMORY);
+    }
+    Ok(())
+  }
+
+  fn int21(&mut self, cpu: &mut CPU) -> () {
+    let register = &cpu.register;
+    let (ah, al) = ((register.ax >> 8) as u8, register.ax as u8);
+    let dl = register.dx as u8;
+    let result = match ah {
+      0x00 => {
+        self.terminate(cpu, 0);
+        Ok(())
+      },
+      0x01 | 0x07 | 0x08 => {
+        let key = self.read_key().unwrap_or(0x1a);
+        if ah == 0x01 {
+          self.write_console(1, &[key]);
+        }
+        set_al(cpu, key);
+        Ok(())
+      },
+      0x02 => {
+        self.write_console(1, &[dl]);
+        Ok(())
+      },
+      // Input that doesn't wait; ZF tells whether there was a key
+      0x06 if dl == 0xff => {
+        match self.poll_key() {
+          Some(key) => {
+            set_al(cpu, key);
+            cpu.blit_flags(ZF, 0);
+          },
+          None => {
+            set_al(cpu, 0);
+            cpu.blit_flags(0, ZF);
+          },
+        }
+        Ok(())
+      },
+      0x06 => {
+        self.write_console(1, &[dl]);
+        Ok(())
+      },
+      0x09 => {
+        let text = read_string(cpu, register.ds, register.dx, b'$');
+        self.write_console(1, &text);
+        Ok(())
+      },
+      0x0a => {
+        // The buffer holds its size, then gets the length and the line
+        let (seg, offset) = (register.ds, register.dx);
+        let size = cpu.read_bytes(seg, offset, 1)[0] as usize;
+        let mut line = self.read_console_line().unwrap_or_default();
+        line.truncate(size.saturating_sub(1));
+        self.write_console(1, &line);
+        self.write_console(1, b"\r");
+        let mut bytes = vec![line.len() as u8];
+        bytes.extend(&line);
+        bytes.push(b'\r');
+        if size > 0 {
+          cpu.write_bytes(seg, offset.wrapping_add(1), &bytes);
+        }
+        Ok(())
+      },
+      // There's only the one drive, which keeps its current directory
+      0x0e => {
+        set_al(cpu, self.drive + 1);
+        Ok(())
+      },
+      0x19 => {
+        set_al(cpu, self.drive);
+        Ok(())
+      },
+      0x1a => {
+        self.dta = (register.ds, register.dx);
+        Ok(())
+      },
+      0x25 => {
+        let vector = [register.dx.to_le_bytes(), register.ds.to_le_bytes()];
+        cpu.write_bytes(0, al as u16 * 4, &vector.concat());
+        Ok(())
+      },
+      0x2a => {
+        let now = self.now();
+        cpu.register.cx = now.year;
+        cpu.register.dx = ((now.month as u16) << 8) | now.day as u16;
+        set_al(cpu, now.weekday);
+        Ok(())
+      },
+      0x2c => {
+        let now = self.now();
+        cpu.register.cx = ((now.hour as u16) << 8) | now.minute as u16;
+        cpu.register.dx = ((now.second as u16) << 8) | now.hundredths as u16;
+        Ok(())
+      },
+      0x2f => {
+        cpu.register.es = self.dta.0;
+        cpu.register.bx = self.dta.1;
+        Ok(())
+      },
+      // DOS 5.0
+      0x30 => {
+        cpu.register.ax = 0x0005;
+        cpu.register.bx = 0;
+        cpu.register.cx = 0;
+        Ok(())
+      },
+      // Ctrl-Break checking is always off
+      0x33 => {
+        cpu.register.dx &= 0xff00;
+        Ok(())
+      },
+      0x35 => {
+        let address = al as usize * 4;
+        cpu.register.bx = cpu.memory.read_u16(address);
+        cpu.register.es = cpu.memory.read_u16(address + 2);
+        Ok(())
+      },
+      0x39..=0x3b => self.directory(cpu, ah),
+      0x3c => self.open(cpu, true),
+      0x3d => self.open(cpu, false),
+      0x3e => self.close(cpu),
+      0x3f => self.read(cpu),
+      0x40 => self.write(cpu),
+      0x41 => self.delete(cpu),
+      0x42 => self.seek(cpu),
+      0x43 => self.file_attributes(cpu),
+      0x44 => self.ioctl(cpu),
+      0x47 => self.current_directory(cpu),
+      0x4a => self.resize_memory(cpu),
+      0x4c => {
+        self.terminate(cpu, al);
+        Ok(())
+      },
+      0x4e => self.find_first(cpu),
+      0x4f => self.find_next(cpu),
+      0x56 => self.rename(cpu),
+      _ => Err(ERROR_INVALID_FUNCTION),
+    };
+    match result {
+      Ok(()) => cpu.blit_flags(CF, 0),
+      Err(code) => {
+        cpu.register.ax = code;
+        cpu.blit_flags(0, CF);
+      },
+    }
+  }
+}
+
+impl InterruptHandler for Dos {
+  fn interrupt(&mut self, cpu: &mut CPU, vector: u8) -> bool {
+    match vector {
+      0x20 => self.terminate(cpu, 0),
+      0x21 => self.int21(cpu),
+      _ => return false,
+    }
+    true
+  }
+}
+
+impl Terminate for Dos {
+  fn exit_code(&self) -> Option<u8> {
+    self.exit_code
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::cell::RefCell;
+  use std::env;
+  use std::process;
+  use std::rc::Rc;
+  use crate::mem::linear::LinearMemory;
+  use super::*;
+
+  struct Shared(Rc<RefCell<Vec<u8>>>);
+
+  impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  // Calls INT 21h with AX, and returns CF.
+  fn call(cpu: &mut CPU, ax: u16) -> bool {
+    cpu.register.ax = ax;
+    cpu.interrupt(0x21);
+    cpu.get_flags() & CF != 0
+  }
+
+  fn set_string(cpu: &mut CPU, offset: u16, text: &[u8]) -> () {
+    cpu.write_bytes(0x2000, offset, text);
+    cpu.register.ds = 0x2000;
+    cpu.register.dx = offset;
+  }
+
+  // Input that arrives whenever the test sends it.
+  struct Channel(Receiver<u8>);
+
+  impl Read for Channel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      match self.0.recv() {
+        Ok(value) => {
+          buf[0] = value;
+          Ok(1)
+        },
+        Err(_) => Ok(0),
+      }
+    }
+  }
+
+  #[test]
+  fn test_poll_key() {
+    let memory = LinearMemory::new(1024 * 1024 / 4);
+    let mut cpu = CPU::new(Box::new(memory), Box::new(LinearMemory::new(0)));
+    let (sender, receiver) = mpsc::channel();
+    let mut dos = Dos::new(env::temp_dir());
+    dos.input = ConsoleInput::new(Channel(receiver));
+    dos.output = Box::new(io::sink());
+    cpu.set_interrupt_handler(Box::new(Rc::new(RefCell::new(dos))));
+
+    // Nothing typed yet: ZF is set rather than waiting
+    cpu.register.dx = 0xff;
+    call(&mut cpu, 0x0600);
+    assert_eq!(cpu.register.ax & 0xff, 0);
+    assert_ne!(cpu.get_flags() & ZF, 0);
+
+    sender.send(b'x').unwrap();
+    for _ in 0..1000 {
+      call(&mut cpu, 0x0600);
+      if cpu.get_flags() & ZF == 0 {
+        break;
+      }
+      thread::sleep(std::time::Duration::from_millis(1));
+    }
+    assert_eq!(cpu.get_flags() & ZF, 0);
+    assert_eq!(cpu.register.ax & 0xff, b'x' as u16);
+
+    // At the end of the input there's never a key
+    drop(sender);
+    call(&mut cpu, 0x0600);
+    assert_ne!(cpu.get_flags() & ZF, 0);
+  }
+
+  #[test]
+  fn test_dos() {
+    let root = env::temp_dir()
+      .join(format!("rust-8086-int21-{}", process::id()));
+    fs::create_dir_all(&root).unwrap();
+    let memory = LinearMemory::new(1024 * 1024 / 4);
+    let mut cpu = CPU::new(Box::new(memory), Box::new(LinearMemory::new(0)));
+    let output = Rc::new(RefCell::new(vec![]));
+    let mut dos = Dos::new(root.clone());
+    dos.input = ConsoleInput::new(&b"yes\nk"[..]);
+    dos.output = Box::new(Shared(output.clone()));
+    dos.dta = (0x3000, 0);
+    // 2001-09-09 01:46:40, a Sunday
+    dos.clock = Some(UNIX_EPOCH + std::time::Duration::from_secs(1000000000));
+    let dos = Rc::new(RefCell::new(dos));
+    cpu.set_interrupt_handler(Box::new(dos.clone()));
+
+    // Console
+    set_string(&mut cpu, 0, b"Hi$");
+    assert!(!call(&mut cpu, 0x0900));
+    cpu.register.dx = b'!' as u16;
+    assert!(!call(&mut cpu, 0x0200));
+    set_string(&mut cpu, 0, &[8]);
+    assert!(!call(&mut cpu, 0x0a00));
+    assert_eq!(cpu.read_bytes(0x2000, 0, 6), b"\x08\x03yes\r");
+    assert!(!call(&mut cpu, 0x0800));
+    assert_eq!(cpu.register.ax, 0x086b);
+    assert!(!call(&mut cpu, 0x0800));
+    assert_eq!(cpu.register.ax, 0x081a);
+    assert_eq!(&output.borrow()[..], b"Hi!yes\r");
+
+    // Directories and files
+    set_string(&mut cpu, 0, b"c:\\Sub\0");
+    assert!(!call(&mut cpu, 0x3900));
+    set_string(&mut cpu, 0, b"sub\0");
+    assert!(!call(&mut cpu, 0x3b00));
+    // Selecting the current drive stays in the same directory
+    cpu.register.dx = 2;
+    assert!(!call(&mut cpu, 0x0e00));
+    assert_eq!(cpu.register.ax & 0xff, 3);
+    cpu.register.dx = 0;
+    cpu.register.ds = 0x2000;
+    cpu.register.si = 0x40;
+    assert!(!call(&mut cpu, 0x4700));
+    assert_eq!(cpu.read_bytes(0x2000, 0x40, 4), b"SUB\0");
+    set_string(&mut cpu, 0, b"A.TXT\0");
+    assert!(!call(&mut cpu, 0x3c00));
+    let handle = cpu.register.ax;
+    assert_eq!(handle, 5);
+    cpu.register.bx = handle;
+    cpu.register.cx = 5;
+    set_string(&mut cpu, 0x10, b"hello");
+    assert!(!call(&mut cpu, 0x4000));
+    assert_eq!(cpu.register.ax, 5);
+    assert!(!call(&mut cpu, 0x3e00));
+    assert!(call(&mut cpu, 0x3e00));
+    assert_eq!(cpu.register.ax, ERROR_INVALID_HANDLE);
+    assert_eq!(fs::read(root.join("SUB").join("A.TXT")).unwrap(), b"hello");
+    set_string(&mut cpu, 0, b"\\sub\\..\\SUB\\a.txt\0");
+    assert!(!call(&mut cpu, 0x3d00));
+    cpu.register.bx = cpu.register.ax;
+    cpu.register.cx = 0;
+    cpu.register.dx = 0;
+    assert!(!call(&mut cpu, 0x4202));
+    assert_eq!((cpu.register.dx, cpu.register.ax), (0, 5));
+    cpu.register.cx = 0;
+    cpu.register.dx = 1;
+    assert!(!call(&mut cpu, 0x4200));
+    cpu.register.cx = 0x10;
+    cpu.register.dx = 0x20;
+    assert!(!call(&mut cpu, 0x3f00));
+    assert_eq!(cpu.register.ax, 4);
+    assert_eq!(cpu.read_bytes(0x2000, 0x20, 4), b"ello");
+    cpu.register.si = 0x40;
+    cpu.register.dx = 0;
+    assert!(!call(&mut cpu, 0x4700));
+    assert_eq!(cpu.read_bytes(0x2000, 0x40, 4), b"SUB\0");
+
+    // The root is as far up as it goes
+    set_string(&mut cpu, 0, b"..\\..\\..\\NOPE.TXT\0");
+    assert!(call(&mut cpu, 0x3d00));
+    assert_eq!(cpu.register.ax, ERROR_FILE_NOT_FOUND);
+    assert!(!root.parent().unwrap().join("NOPE.TXT").exists());
+    set_string(&mut cpu, 0, b"D:\\A.TXT\0");
+    assert!(call(&mut cpu, 0x3d00));
+    assert_eq!(cpu.register.ax, ERROR_PATH_NOT_FOUND);
+
+    // Finding files
+    set_string(&mut cpu, 0, b"B.TXT\0");
+    assert!(!call(&mut cpu, 0x3c00));
+    set_string(&mut cpu, 0, b"*.txt\0");
+    cpu.register.cx = 0;
+    assert!(!call(&mut cpu, 0x4e00));
+    assert_eq!(cpu.read_bytes(0x3000, 0x1a, 10), b"\x05\0\0\0A.TXT\0");
+    assert!(!call(&mut cpu, 0x4f00));
+    assert_eq!(cpu.read_bytes(0x3000, 0x1e, 6), b"B.TXT\0");
+    assert!(call(&mut cpu, 0x4f00));
+    assert_eq!(cpu.register.ax, ERROR_NO_MORE_FILES);
+    set_string(&mut cpu, 0, b"B.TXT\0");
+    cpu.register.es = 0x2000;
+    cpu.register.di = 0x10;
+    cpu.write_bytes(0x2000, 0x10, b"C.TXT\0");
+    assert!(!call(&mut cpu, 0x5600));
+    set_string(&mut cpu, 0, b"C.TXT\0");
+    assert!(!call(&mut cpu, 0x4100));
+    assert!(call(&mut cpu, 0x4100));
+
+    // Date and time
+    assert!(!call(&mut cpu, 0x2a00));
+    assert_eq!((cpu.register.cx, cpu.register.dx), (2001, 0x0909));
+    assert_eq!(cpu.register.ax & 0xff, 0);
+    assert!(!call(&mut cpu, 0x2c00));
+    assert_eq!((cpu.register.cx, cpu.register.dx), (0x012e, 0x2800));
+
+    // Other interrupts are left to the guest
+    assert!(call(&mut cpu, 0x9900));
+    assert_eq!(cpu.register.ax, ERROR_INVALID_FUNCTION);
+    cpu.register.cs = 0x1000;
+    cpu.register.ip = 0;
+    cpu.register.ss = 0x1000;
+    cpu.register.sp = 0x100;
+    cpu.interrupt(0x10);
+    assert_eq!((cpu.register.cs, cpu.register.ip), (0, 0));
+    assert!(!call(&mut cpu, 0x4c2a));
+    assert_eq!(dos.borrow().exit_code, Some(0x2a));
+    assert!(!cpu.running);
+    fs::remove_dir_all(&root).unwrap();
+  }
+}
@@ -0,0 +1,83 @@
+use std::error;
+use std::fmt;
+use crate::i8086::cpu::CPU;
+use super::exe;
+use super::psp::*;
+
+#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug)]
+pub enum LoadError {
+  // The image doesn't fit in a segment, or in memory.
+  TooLarge,
+  OutOfMemory,
+  CommandTailTooLong,
+  EnvironmentTooLarge,
+  // The MZ header, or its relocation table, is cut short.
+  InvalidExe,
+}
+
+impl fmt::Display for LoadError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(match self {
+      LoadError::TooLarge => "program too big to fit in memory",
+      LoadError::OutOfMemory => "insufficient memory",
+      LoadError::CommandTailTooLong => "command line too long",
+      LoadError::EnvironmentTooLarge => "environment too large",
+      LoadError::InvalidExe => "invalid EXE file",
+    })
+  }
+}
+
+impl error::Error for LoadError {}
+
+// Loads a .COM image, with its environment at `segment` and then its PSP,
+// where `memory_top` is the first segment past conventional memory. The
+// image goes at PSP:0100, all segment registers point to the PSP, and the
+// stack starts at the end of the segment with a zero word on it, so that a
+// near RET terminates the program. Returns the segment of the PSP.
+pub fn load_com(
+  cpu: &mut CPU,
+  image: &[u8],
+  segment: u16,
+  memory_top: u16,
+  psp_builder: &PspBuilder,
+) -> Result<u16, LoadError> {
+  // The stack needs a word at least
+  if image.len() > 0x10000 - PSP_SIZE - 2 {
+    return Err(LoadError::TooLarge);
+  }
+  let psp = psp_builder.write_environment(cpu, segment, memory_top)?;
+  let available = (memory_top.saturating_sub(psp) as usize) << 4;
+  if available < PSP_SIZE + image.len() + 2 {
+    return Err(LoadError::OutOfMemory);
+  }
+  // A .COM program gets all the memory there is
+  psp_builder.write(cpu, psp, segment, memory_top)?;
+  cpu.write_bytes(psp, PSP_SIZE as u16, image);
+  let sp = (available.min(0x10000) - 2) as u16;
+  cpu.write_bytes(psp, sp, &[0, 0]);
+  let register = &mut cpu.register;
+  register.cs = psp;
+  register.ds = psp;
+  register.es = psp;
+  register.ss = psp;
+  register.ip = PSP_SIZE as u16;
+  register.sp = sp;
+  Ok(psp)
+}
+
+// Loads either kind of program; like DOS, an MZ signature makes it an .EXE
+// whatever the name is.
+pub fn load(
+  cpu: &mut CPU,
+  image: &[u8],
+  segment: u16,
+  memory_top: u16,
+  psp_builder: &PspBuilder,
+) -> Result<u16, LoadError> {
+  if exe::is_exe(image) {
+    exe::load_exe(cpu, image, segment, memory_top, psp_builder)
+  } else {
+    load_com(cpu, image, segment, memory_top, psp_builder)
+  }
+}
@@ -0,0 +1,6 @@
+// Loading and running DOS programs, with an optional INT 21h emulation.
+pub mod exe;
+pub mod int21;
+pub mod loader;
+pub mod process;
+pub mod psp;
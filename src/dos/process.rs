@@ -0,0 +1,216 @@
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::rc::Rc;
+use crate::i8086::cpu::CPU;
+use crate::i8086::decode::DecodedInstruction;
+use crate::i8086::exception::Exception;
+use crate::i8086::interrupt::InterruptHandler;
+use crate::i8086::op::DecodeError;
+
+#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug)]
+pub enum Exit {
+  // INT 20h, or INT 21h with AH=00h or 4Ch; the exit code.
+  Terminated(u8),
+  // Halted with nothing to wake it up.
+  Halted,
+  LimitReached,
+  // An exception the program has no handler for.
+  Exception(Exception),
+  // An interrupt with no vector, and no emulation.
+  UnhandledInterrupt { vector: u8, ax: u16 },
+}
+
+impl Exit {
+  // The exit code to hand to the shell.
+  pub fn code(&self) -> i32 {
+    match self {
+      Exit::Terminated(code) => *code as i32,
+      Exit::Halted => 0,
+      _ => 1,
+    }
+  }
+}
+
+impl fmt::Display for Exit {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Exit::Terminated(code) => write!(f, "terminated with code {}", code),
+      Exit::Halted => f.write_str("halted"),
+      Exit::LimitReached => f.write_str("instruction limit reached"),
+      Exit::Exception(Exception::DivideError) =>
+        f.write_str("divide overflow"),
+      Exit::Exception(exception) =>
+        write!(f, "unhandled exception: {:?}", exception),
+      Exit::UnhandledInterrupt { vector, ax } =>
+        write!(f, "unhandled interrupt {:02X}h (AX={:04X})", vector, ax),
+    }
+  }
+}
+
+const EXCEPTIONS: [Exception; 5] = [
+  Exception::DivideError,
+  Exception::SingleStep,
+  Exception::Breakpoint,
+  Exception::Overflow,
+  Exception::InvalidOpcode,
+];
+
+fn has_vector(cpu: &CPU, vector: u8) -> bool {
+  let address = vector as usize * 4;
+  cpu.memory.read_u16(address) != 0 || cpu.memory.read_u16(address + 2) != 0
+}
+
+// The interrupt handler a program runs with, which ends it: on INT 20h, or
+// INT 21h with AH=00h or 4Ch, it keeps the exit code and stops the CPU.
+pub trait Terminate: InterruptHandler {
+  fn exit_code(&self) -> Option<u8>;
+}
+
+// Just enough of DOS for a program to end.
+#[derive(Default)]
+pub struct Termination {
+  pub exit_code: Option<u8>,
+}
+
+impl InterruptHandler for Termination {
+  fn interrupt(&mut self, cpu: &mut CPU, vector: u8) -> bool {
+    let ax = cpu.register.ax;
+    self.exit_code = match (vector, (ax >> 8) as u8) {
+      (0x20, _) | (0x21, 0x00) => Some(0),
+      (0x21, 0x4c) => Some(ax as u8),
+      _ => return false,
+    };
+    cpu.running = false;
+    true
+  }
+}
+
+impl Terminate for Termination {
+  fn exit_code(&self) -> Option<u8> {
+    self.exit_code
+  }
+}
+
+// Gives interrupts to the program's handler, then to the program itself;
+// one that neither takes ends the program.
+struct Supervisor<H> {
+  handler: Rc<RefCell<H>>,
+  unhandled: Rc<Cell<Option<Exit>>>,
+}
+
+impl<H: Terminate> InterruptHandler for Supervisor<H> {
+  fn interrupt(&mut self, cpu: &mut CPU, vector: u8) -> bool {
+    if self.handler.borrow_mut().interrupt(cpu, vector) {
+      return true;
+    }
+    if has_vector(cpu, vector) {
+      return false;
+    }
+    let ax = cpu.register.ax;
+    self.unhandled.set(Some(Exit::UnhandledInterrupt { vector, ax }));
+    cpu.running = false;
+    true
+  }
+}
+
+// Written once the instruction is decoded, with the registers as they were
+// before it.
+fn write_trace(
+  cpu: &CPU,
+  decoded: &Result<DecodedInstruction, DecodeError>,
+  output: &mut dyn Write,
+) -> io::Result<()> {
+  let register = &cpu.register;
+  let (bytes, text) = match decoded {
+    Ok(decoded) => (decoded.bytes.clone(), decoded.to_string()),
+    Err(err) => (err.bytes.clone(), "(bad)".to_string()),
+  };
+  let hex: String = bytes.iter().map(|value| format!("{:02X}", value))
+    .collect();
+  writeln!(output,
+    "{:04X}:{:04X} {:<14}{:<32}AX={:04X} BX={:04X} CX={:04X} DX={:04X} \
+    SP={:04X} BP={:04X} SI={:04X} DI={:04X} DS={:04X} ES={:04X} SS={:04X} \
+    FL={:04X}",
+    register.cs, cpu.instruction_ip, hex, text,
+    register.ax, register.bx, register.cx, register.dx,
+    register.sp, register.bp, register.si, register.di,
+    register.ds, register.es, register.ss, cpu.get_flags())
+}
+
+// Runs a loaded program until it terminates, or `limit` instructions have
+// run. Each instruction is written to `trace` before it runs.
+pub fn run(
+  cpu: &mut CPU,
+  limit: Option<u64>,
+  trace: Option<&mut dyn Write>,
+) -> io::Result<Exit> {
+  let handler = Rc::new(RefCell::new(Termination::default()));
+  run_with(cpu, handler, limit, trace)
+}
+
+// Like `run`, with `handler` taking the program's interrupts; it's
+// installed for the run, and the exit code comes from it.
+pub fn run_with<H: Terminate + 'static>(
+  cpu: &mut CPU,
+  handler: Rc<RefCell<H>>,
+  limit: Option<u64>,
+  trace: Option<&mut dyn Write>,
+) -> io::Result<Exit> {
+  let unhandled = Rc::new(Cell::new(None));
+  let previous = cpu.interrupt_handler.take();
+  cpu.set_interrupt_handler(Box::new(Supervisor {
+    handler: handler.clone(),
+    unhandled: unhandled.clone(),
+  }));
+  let result = run_supervised(cpu, limit, trace);
+  cpu.interrupt_handler = previous;
+  let exit = result?;
+  if exit != Exit::Halted {
+    return Ok(exit);
+  }
+  if let Some(exit) = unhandled.get() {
+    return Ok(exit);
+  }
+  let exit_code = handler.borrow().exit_code();
+  Ok(exit_code.map_or(Exit::Halted, Exit::Terminated))
+}
+
+fn run_supervised(
+  cpu: &mut CPU,
+  limit: Option<u64>,
+  mut trace: Option<&mut dyn Write>,
+) -> io::Result<Exit> {
+  // Exceptions go to the program's handler if it has installed one, and
+  // end the program otherwise.
+  cpu.intercepts = EXCEPTIONS.to_vec();
+  // The last program to end stopped the CPU
+  cpu.running = true;
+  let mut count = 0;
+  loop {
+    if cpu.is_stopped() {
+      return Ok(Exit::Halted);
+    }
+    if limit.is_some_and(|limit| count >= limit) {
+      return Ok(Exit::LimitReached);
+    }
+    let mut traced = Ok(());
+    let result = cpu.step_with(|cpu, decoded| {
+      if let Some(output) = trace.as_mut() {
+        traced = write_trace(cpu, decoded, *output);
+      }
+      true
+    });
+    traced?;
+    if let Err(exception) = result {
+      if !has_vector(cpu, exception.vector()) {
+        return Ok(Exit::Exception(exception));
+      }
+      cpu.interrupt(exception.vector());
+    }
+    count += 1;
+  }
+}
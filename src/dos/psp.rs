@@ -0,0 +1,284 @@
+use crate::i8086::cpu::CPU;
+use super::loader::LoadError;
+
+// Offsets in the program segment prefix
+pub const PSP_SIZE: usize = 0x100;
+pub const PSP_MEMORY_END: u16 = 0x02;
+pub const PSP_CPM_CALL: u16 = 0x05;
+pub const PSP_TERMINATE: u16 = 0x0a;
+pub const PSP_PARENT: u16 = 0x16;
+pub const PSP_HANDLES: u16 = 0x18;
+pub const PSP_ENVIRONMENT: u16 = 0x2c;
+pub const PSP_HANDLE_COUNT: u16 = 0x32;
+pub const PSP_HANDLE_TABLE: u16 = 0x34;
+pub const PSP_DOS_CALL: u16 = 0x50;
+pub const PSP_FCB1: u16 = 0x5c;
+pub const PSP_FCB2: u16 = 0x6c;
+pub const PSP_COMMAND_TAIL: u16 = 0x80;
+
+// The command tail holds up to 126 characters, and the final CR.
+pub const MAX_COMMAND_TAIL: usize = 126;
+pub const MAX_ENVIRONMENT: usize = 0x8000;
+
+// Handles 0 to 4 are open, on the console (stdin, stdout and stderr), AUX
+// and PRN.
+const HANDLE_COUNT: usize = 20;
+const DEFAULT_HANDLES: [u8; 5] = [1, 1, 1, 0, 2];
+
+// Quotes an argument with blanks or quotes in it the way C runtimes split
+// the command line again: backslashes only need escaping before a quote.
+fn quote_arg(arg: &str) -> String {
+  if !arg.is_empty() && !arg.contains(&[' ', '\t', '"'][..]) {
+    return arg.to_string();
+  }
+  let mut quoted = String::from("\"");
+  let mut backslashes = 0;
+  for c in arg.chars() {
+    match c {
+      '\\' => backslashes += 1,
+      '"' => {
+        quoted.push_str(&"\\".repeat(backslashes * 2 + 1));
+        backslashes = 0;
+      },
+      _ => {
+        quoted.push_str(&"\\".repeat(backslashes));
+        backslashes = 0;
+      },
+    }
+    if c != '\\' {
+      quoted.push(c);
+    }
+  }
+  quoted.push_str(&"\\".repeat(backslashes * 2));
+  quoted.push('"');
+  quoted
+}
+
+fn write_u16(cpu: &mut CPU, seg: u16, offset: u16, value: u16) -> () {
+  cpu.write_bytes(seg, offset, &value.to_le_bytes());
+}
+
+// The drive, name and extension of an unopened FCB, as DOS parses them from
+// the first two arguments: upper case, padded with spaces, and `*` filled
+// with `?`.
+pub fn parse_fcb_name(arg: &str) -> [u8; 12] {
+  let mut fcb = [b' '; 12];
+  fcb[0] = 0;
+  let bytes = arg.as_bytes();
+  let mut name = bytes;
+  if bytes.len() >= 2 && bytes[1] == b':' && bytes[0].is_ascii_alphabetic() {
+    fcb[0] = bytes[0].to_ascii_uppercase() - b'A' + 1;
+    name = &bytes[2..];
+  }
+  let end = name.iter()
+    .position(|value| b" \t/;,=+<>|\"[]".contains(value))
+    .unwrap_or(name.len());
+  let name = &name[..end];
+  let (base, extension) = match name.iter().position(|value| *value == b'.') {
+    Some(dot) => (&name[..dot], &name[dot + 1..]),
+    None => (name, &name[name.len()..]),
+  };
+  fill_fcb_field(&mut fcb[1..9], base);
+  fill_fcb_field(&mut fcb[9..12], extension);
+  fcb
+}
+
+fn fill_fcb_field(field: &mut [u8], text: &[u8]) -> () {
+  for (i, value) in text.iter().take(field.len()).enumerate() {
+    if *value == b'*' {
+      field[i..].iter_mut().for_each(|byte| *byte = b'?');
+      break;
+    }
+    field[i] = value.to_ascii_uppercase();
+  }
+}
+
+// Builds what DOS sets up for a program: its environment block, then its
+// PSP with the command tail and FCBs made from the arguments.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct PspBuilder {
+  // The full DOS path, as found at the end of the environment; C runtimes
+  // take argv[0] from there.
+  pub program: String,
+  pub args: Vec<String>,
+  // `NAME=value` strings
+  pub environment: Vec<String>,
+}
+
+impl PspBuilder {
+  pub fn new(program: &str) -> Self {
+    PspBuilder {
+      program: program.to_string(),
+      args: vec![],
+      environment: vec![],
+    }
+  }
+
+  pub fn arg(mut self, arg: &str) -> Self {
+    self.args.push(arg.to_string());
+    self
+  }
+
+  pub fn args<I, S>(mut self, args: I) -> Self
+    where I: IntoIterator<Item = S>, S: AsRef<str>
+  {
+    self.args.extend(args.into_iter().map(|arg| arg.as_ref().to_string()));
+    self
+  }
+
+  pub fn env(mut self, name: &str, value: &str) -> Self {
+    self.environment.push(format!("{}={}", name, value));
+    self
+  }
+
+  // The command tail DOS would pass for the arguments: a leading space,
+  // then the arguments separated by spaces, quoted where needed.
+  pub fn command_tail(&self) -> Result<String, LoadError> {
+    let tail: String = self.args.iter()
+      .map(|arg| format!(" {}", quote_arg(arg)))
+      .collect();
+    if tail.len() > MAX_COMMAND_TAIL {
+      return Err(LoadError::CommandTailTooLong);
+    }
+    Ok(tail)
+  }
+
+  // The variables, each terminated by a NUL and followed by another one,
+  // then a count of 1 and the program's path. Variables that would make it
+  // larger than DOS allows are left out, as a big host environment
+  // shouldn't keep programs from starting.
+  pub fn environment_block(&self) -> Result<Vec<u8>, LoadError> {
+    let mut block = vec![];
+    // The terminating NUL, the count and the path
+    let trailer = 1 + 2 + self.program.len() + 1;
+    for variable in self.environment.iter() {
+      if block.len() + variable.len() + 1 + trailer > MAX_ENVIRONMENT {
+        continue;
+      }
+      block.extend(variable.bytes());
+      block.push(0);
+    }
+    if block.is_empty() {
+      block.push(0);
+    }
+    block.push(0);
+    block.extend(&1u16.to_le_bytes());
+    block.extend(self.program.bytes());
+    block.push(0);
+    if block.len() > MAX_ENVIRONMENT {
+      return Err(LoadError::EnvironmentTooLarge);
+    }
+    Ok(block)
+  }
+
+  // Writes the environment at `segment`, and returns the segment right
+  // after it, where the PSP goes.
+  pub fn write_environment(
+    &self,
+    cpu: &mut CPU,
+    segment: u16,
+    memory_top: u16,
+  ) -> Result<u16, LoadError> {
+    let block = self.environment_block()?;
+    let paragraphs = block.len().div_ceil(16);
+    let psp = segment as usize + paragraphs;
+    if psp + PSP_SIZE / 16 > memory_top as usize {
+      return Err(LoadError::OutOfMemory);
+    }
+    cpu.write_bytes(segment, 0, &block);
+    Ok(psp as u16)
+  }
+
+  // Writes the PSP of a program owning memory up to `memory_end`.
+  pub fn write(
+    &self,
+    cpu: &mut CPU,
+    psp: u16,
+    environment: u16,
+    memory_end: u16,
+  ) -> Result<(), LoadError> {
+    let tail = self.command_tail()?;
+    cpu.write_bytes(psp, 0, &[0; PSP_SIZE]);
+    // INT 20h, where a near RET from a .COM program ends up
+    cpu.write_bytes(psp, 0, &[0xcd, 0x20]);
+    write_u16(cpu, psp, PSP_MEMORY_END, memory_end);
+    // The CP/M style entry point; what's read from it is the word at 6,
+    // the size of the segment.
+    cpu.write_bytes(psp, PSP_CPM_CALL, &[0x9a, 0xf0, 0xfe, 0x1d, 0xf0]);
+    // The INT 22h to 24h vectors, restored when the program exits
+    for i in 0..6 {
+      let value = cpu.memory.read_u16(0x22 * 4 + i * 2);
+      write_u16(cpu, psp, PSP_TERMINATE + i as u16 * 2, value);
+    }
+    // The program is its own parent, as COMMAND.COM is
+    write_u16(cpu, psp, PSP_PARENT, psp);
+    let mut handles = [0xff; HANDLE_COUNT];
+    handles[..DEFAULT_HANDLES.len()].copy_from_slice(&DEFAULT_HANDLES);
+    cpu.write_bytes(psp, PSP_HANDLES, &handles);
+    write_u16(cpu, psp, PSP_ENVIRONMENT, environment);
+    write_u16(cpu, psp, PSP_HANDLE_COUNT, HANDLE_COUNT as u16);
+    write_u16(cpu, psp, PSP_HANDLE_TABLE, PSP_HANDLES);
+    write_u16(cpu, psp, PSP_HANDLE_TABLE + 2, psp);
+    // INT 21h and RETF, for programs calling DOS with a far call
+    cpu.write_bytes(psp, PSP_DOS_CALL, &[0xcd, 0x21, 0xcb]);
+    for (i, offset) in [PSP_FCB1, PSP_FCB2].iter().enumerate() {
+      let arg = self.args.get(i).map(|arg| arg.as_str()).unwrap_or("");
+      cpu.write_bytes(psp, *offset, &parse_fcb_name(arg));
+    }
+    let mut bytes = vec![tail.len() as u8];
+    bytes.extend(tail.bytes());
+    bytes.push(0x0d);
+    cpu.write_bytes(psp, PSP_COMMAND_TAIL, &bytes);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::mem::linear::LinearMemory;
+  use super::*;
+
+  #[test]
+  fn test_fcb_name() {
+    assert_eq!(&parse_fcb_name("a:readme.txt"), b"\x01README  TXT");
+    assert_eq!(&parse_fcb_name("*.c"), b"\x00????????C  ");
+    assert_eq!(&parse_fcb_name("verylongname.text"), b"\x00VERYLONGTEX");
+    assert_eq!(&parse_fcb_name("/x"), b"\x00           ");
+  }
+
+  #[test]
+  fn test_psp() {
+    let memory = LinearMemory::new(0x20000 / 4);
+    let mut cpu = CPU::new(Box::new(memory), Box::new(LinearMemory::new(0)));
+    let builder = PspBuilder::new("C:\\HELLO.COM")
+      .env("PATH", "C:\\")
+      .args(["b:in.dat", "out"]);
+    let block = builder.environment_block().unwrap();
+    assert_eq!(&block[..], &b"PATH=C:\\\0\0\x01\0C:\\HELLO.COM\0"[..]);
+    let large = builder.clone().env("HUGE", &"x".repeat(MAX_ENVIRONMENT))
+      .env("TEMP", "C:\\");
+    assert_eq!(&large.environment_block().unwrap()[..],
+      &b"PATH=C:\\\0TEMP=C:\\\0\0\x01\0C:\\HELLO.COM\0"[..]);
+    let psp = builder.write_environment(&mut cpu, 0x1000, 0x2000).unwrap();
+    assert_eq!(psp, 0x1002);
+    builder.write(&mut cpu, psp, 0x1000, 0x2000).unwrap();
+    let read = |offset: usize, length: usize| -> Vec<u8> {
+      (0..length).map(|i| cpu.memory.read_u8(0x10020 + offset + i)).collect()
+    };
+    assert_eq!(read(0x00, 4), [0xcd, 0x20, 0x00, 0x20]);
+    assert_eq!(read(0x16, 7), [0x02, 0x10, 1, 1, 1, 0, 2]);
+    assert_eq!(read(0x2c, 2), [0x00, 0x10]);
+    assert_eq!(read(0x50, 3), [0xcd, 0x21, 0xcb]);
+    assert_eq!(&read(0x5c, 12)[..], &b"\x02IN      DAT"[..]);
+    assert_eq!(&read(0x6c, 12)[..], &b"\x00OUT        "[..]);
+    assert_eq!(&read(0x80, 15)[..], &b"\x0d b:in.dat out\x0d"[..]);
+    let quoted = PspBuilder::new("C:\\ECHO.COM")
+      .args(["a b", "", "x\"y", "c:\\dir\\", "tab\t"]);
+    assert_eq!(quoted.command_tail().unwrap(),
+      " \"a b\" \"\" \"x\\\"y\" c:\\dir\\ \"tab\t\"");
+    let builder = builder.arg(&"x".repeat(120));
+    assert_eq!(builder.write(&mut cpu, psp, 0x1000, 0x2000),
+      Err(LoadError::CommandTailTooLong));
+  }
+}
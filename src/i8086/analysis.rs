@@ -0,0 +1,376 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use super::cpu::CpuModel;
+use super::decode::*;
+use super::op::*;
+
+// Recursive-descent analysis of a flat image (.COM or .BIN): code is only
+// what can be reached from the entry points by following jumps, calls and
+// fall-through, and everything else is treated as data.
+
+#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug)]
+pub enum EdgeKind {
+  Fallthrough,
+  Jump,
+  Branch, // taken conditional jump
+  Call,
+}
+
+#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug)]
+pub struct Edge {
+  pub kind: EdgeKind,
+  pub target: u16,
+}
+
+#[derive(PartialEq, Clone)]
+#[derive(Debug)]
+pub struct BasicBlock {
+  pub start: u16,
+  pub end: u16, // IP right after the last instruction
+  pub successors: Vec<Edge>,
+}
+
+pub struct Analysis {
+  pub origin: u16,
+  pub image: Vec<u8>,
+  pub entries: Vec<u16>,
+  pub instructions: BTreeMap<u16, DecodedInstruction>,
+  pub blocks: BTreeMap<u16, BasicBlock>,
+  pub subroutines: BTreeSet<u16>,
+  jump_targets: BTreeSet<u16>,
+}
+
+// Where the control goes after an instruction. Jumps that can't be followed
+// statically (indirect or far) have no successors.
+fn successors(decoded: &DecodedInstruction) -> Vec<Edge> {
+  let next = decoded.next_ip();
+  let fallthrough = Edge { kind: EdgeKind::Fallthrough, target: next };
+  let relative = |offset: i16| next.wrapping_add(offset as u16);
+  match decoded.op() {
+    Op::Jmp(OpCallType::WithinDirect(offset)) =>
+      vec![Edge { kind: EdgeKind::Jump, target: relative(*offset) }],
+    Op::JmpShort(offset) =>
+      vec![Edge { kind: EdgeKind::Jump, target: relative(*offset as i16) }],
+    Op::Jmp(_) => vec![],
+    Op::CondJmp { offset, .. } => vec![
+      Edge { kind: EdgeKind::Branch, target: relative(*offset as i16) },
+      fallthrough,
+    ],
+    Op::Call(OpCallType::WithinDirect(offset)) => vec![
+      Edge { kind: EdgeKind::Call, target: relative(*offset) },
+      fallthrough,
+    ],
+    Op::RetWithin | Op::RetWithinImm(_) | Op::RetInter |
+    Op::RetInterImm(_) | Op::Nullary(OpNullaryOp::Iret) => vec![],
+    _ => vec![fallthrough],
+  }
+}
+
+// Whether the instruction ends a basic block; calls return, so they don't.
+fn is_terminator(edges: &[Edge]) -> bool {
+  edges.iter().all(|edge| edge.kind != EdgeKind::Fallthrough) ||
+    edges.iter().any(|edge| edge.kind == EdgeKind::Jump ||
+      edge.kind == EdgeKind::Branch)
+}
+
+pub fn analyze(
+  image: &[u8],
+  origin: u16,
+  entries: &[u16],
+  model: CpuModel,
+) -> Analysis {
+  let mut instructions = BTreeMap::new();
+  let mut subroutines = BTreeSet::new();
+  let mut jump_targets = BTreeSet::new();
+  let mut pending: Vec<u16> = entries.to_vec();
+  while let Some(ip) = pending.pop() {
+    if instructions.contains_key(&ip) {
+      continue;
+    }
+    let offset = ip.wrapping_sub(origin) as usize;
+    if offset >= image.len() {
+      continue;
+    }
+    // Invalid or truncated code ends the path; the bytes stay data.
+    let decoded = match decode(&image[offset..], 0, ip, model) {
+      Ok(decoded) => decoded,
+      Err(_) => continue,
+    };
+    for edge in successors(&decoded) {
+      match edge.kind {
+        EdgeKind::Call => {
+          subroutines.insert(edge.target);
+        },
+        EdgeKind::Jump | EdgeKind::Branch => {
+          jump_targets.insert(edge.target);
+        },
+        EdgeKind::Fallthrough => (),
+      }
+      pending.push(edge.target);
+    }
+    instructions.insert(ip, decoded);
+  }
+  let mut analysis = Analysis {
+    origin,
+    image: image.to_vec(),
+    entries: entries.to_vec(),
+    instructions,
+    blocks: BTreeMap::new(),
+    subroutines,
+    jump_targets,
+  };
+  analysis.split_blocks();
+  analysis
+}
+
+impl Analysis {
+  fn split_blocks(&mut self) -> () {
+    let mut leaders: BTreeSet<u16> = self.entries.iter().cloned().collect();
+    leaders.extend(self.subroutines.iter());
+    leaders.extend(self.jump_targets.iter());
+    for decoded in self.instructions.values() {
+      let edges = successors(decoded);
+      if is_terminator(&edges) {
+        leaders.insert(decoded.next_ip());
+      }
+    }
+    for &start in leaders.iter() {
+      let mut ip = start;
+      let mut block_edges = vec![];
+      while let Some(decoded) = self.instructions.get(&ip) {
+        let edges = successors(decoded);
+        ip = decoded.next_ip();
+        if is_terminator(&edges) {
+          block_edges.extend(edges);
+          break;
+        }
+        block_edges.extend(edges.iter()
+          .filter(|edge| edge.kind == EdgeKind::Call));
+        if leaders.contains(&ip) && self.instructions.contains_key(&ip) {
+          block_edges.push(Edge { kind: EdgeKind::Fallthrough, target: ip });
+          break;
+        }
+      }
+      if ip != start {
+        self.blocks.insert(start, BasicBlock {
+          start,
+          end: ip,
+          successors: block_edges,
+        });
+      }
+    }
+  }
+
+  pub fn is_code(&self, ip: u16) -> bool {
+    self.instruction_at(ip).is_some()
+  }
+
+  // The instruction covering the address, if any.
+  pub fn instruction_at(&self, ip: u16) -> Option<&DecodedInstruction> {
+    self.instructions.range(..=ip).next_back()
+      .map(|(_, decoded)| decoded)
+      .filter(|decoded| ip.wrapping_sub(decoded.ip) < decoded.length() as u16)
+  }
+
+  // Ranges of bytes that aren't reachable code, as [start, end).
+  pub fn data(&self) -> Vec<(u16, u16)> {
+    let mut ranges: Vec<(u16, u16)> = vec![];
+    for i in 0..self.image.len() {
+      let ip = self.origin.wrapping_add(i as u16);
+      if self.is_code(ip) {
+        continue;
+      }
+      match ranges.last_mut() {
+        Some((_, end)) if *end == ip => *end = ip.wrapping_add(1),
+        _ => ranges.push((ip, ip.wrapping_add(1))),
+      }
+    }
+    ranges
+  }
+
+  pub fn label(&self, ip: u16) -> Option<String> {
+    if self.entries.first() == Some(&ip) {
+      Some("start".to_string())
+    } else if self.entries.contains(&ip) {
+      Some(format!("entry_{:04x}", ip))
+    } else if self.subroutines.contains(&ip) {
+      Some(format!("sub_{:04x}", ip))
+    } else if self.jump_targets.contains(&ip) {
+      Some(format!("loc_{:04x}", ip))
+    } else {
+      None
+    }
+  }
+
+  fn comment(&self, decoded: &DecodedInstruction) -> Option<String> {
+    let targets: Vec<String> = successors(decoded).iter()
+      .filter(|edge| edge.kind != EdgeKind::Fallthrough)
+      .filter_map(|edge| self.label(edge.target))
+      .collect();
+    if !targets.is_empty() {
+      return Some(targets.join(", "));
+    }
+    match decoded.op() {
+      Op::Jmp(_) | Op::Call(_) => Some("not followed".to_string()),
+      _ => None,
+    }
+  }
+
+  // The whole image with addresses and bytes, code disassembled and data
+  // as `db`.
+  pub fn listing(&self) -> String {
+    let mut output = String::new();
+    let mut i = 0;
+    while i < self.image.len() {
+      let ip = self.origin.wrapping_add(i as u16);
+      if let Some(label) = self.label(ip).filter(|_| self.is_code(ip)) {
+        writeln!(output, "{}:", label).unwrap();
+      }
+      let (bytes, text, comment) = match self.instructions.get(&ip) {
+        Some(decoded) => {
+          (decoded.bytes.clone(), decoded.to_string(), self.comment(decoded))
+        },
+        None => {
+          let length = (i..self.image.len())
+            .take(8)
+            .take_while(|&j| {
+              let ip = self.origin.wrapping_add(j as u16);
+              j == i || !self.is_code(ip)
+            })
+            .count();
+          let bytes = self.image[i..i + length].to_vec();
+          let values: Vec<String> =
+            bytes.iter().map(|value| format!("{:#04x}", value)).collect();
+          let text: String = bytes.iter()
+            .map(|&value| match value {
+              0x20..=0x7e => value as char,
+              _ => '.',
+            })
+            .collect();
+          (bytes, format!("db {}", values.join(", ")), Some(text))
+        },
+      };
+      let hex: Vec<String> =
+        bytes.iter().map(|value| format!("{:02x}", value)).collect();
+      let line = format!("  {:04x}  {:<18}{}", ip, hex.join(" "), text);
+      match comment {
+        Some(comment) => writeln!(output, "{:<52} ; {}", line, comment),
+        None => writeln!(output, "{}", line),
+      }.unwrap();
+      i += bytes.len();
+    }
+    output
+  }
+
+  // The control flow graph in Graphviz's DOT language.
+  pub fn dot(&self) -> String {
+    let mut output = String::new();
+    output.push_str("digraph cfg {\n");
+    output.push_str("  node [shape=box, fontname=\"monospace\"];\n");
+    for block in self.blocks.values() {
+      let mut text = String::new();
+      if let Some(label) = self.label(block.start) {
+        write!(text, "{}:\\l", label).unwrap();
+      }
+      for (ip, decoded) in self.instructions.range(block.start..block.end) {
+        let line = format!("{:04x}  {}", ip, decoded);
+        write!(text, "{}\\l", line.replace('\\', "\\\\").replace('"', "\\\""))
+          .unwrap();
+      }
+      writeln!(output, "  b_{:04x} [label=\"{}\"];", block.start, text)
+        .unwrap();
+    }
+    for block in self.blocks.values() {
+      for edge in block.successors.iter() {
+        if !self.blocks.contains_key(&edge.target) {
+          continue;
+        }
+        let style = match edge.kind {
+          EdgeKind::Fallthrough => "",
+          EdgeKind::Jump => "",
+          EdgeKind::Branch => " [label=\"taken\"]",
+          EdgeKind::Call => " [style=dashed]",
+        };
+        writeln!(output, "  b_{:04x} -> b_{:04x}{};",
+          block.start, edge.target, style).unwrap();
+      }
+    }
+    output.push_str("}\n");
+    output
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::asm::assemble;
+
+  #[test]
+  fn test_analyze() {
+    let image = assemble("
+      org 0x100
+        mov cx, 3
+      .loop:
+        call print
+        loop .loop
+        jmp [table]
+      print:
+        cmp al, 0
+        je .done
+        int 0x10
+      .done:
+        ret
+      table: dw print
+      msg: db 'Hi', 0
+    ").unwrap().bytes;
+    let analysis = analyze(&image, 0x100, &[0x100], CpuModel::I8086);
+    let blocks: Vec<(u16, u16, Vec<Edge>)> = analysis.blocks.values()
+      .map(|block| (block.start, block.end, block.successors.clone()))
+      .collect();
+    let edge = |kind, target| Edge { kind, target };
+    assert_eq!(blocks, vec![
+      (0x100, 0x103, vec![edge(EdgeKind::Fallthrough, 0x103)]),
+      (0x103, 0x108, vec![
+        edge(EdgeKind::Call, 0x10c),
+        edge(EdgeKind::Branch, 0x103),
+        edge(EdgeKind::Fallthrough, 0x108),
+      ]),
+      (0x108, 0x10c, vec![]),
+      (0x10c, 0x110, vec![
+        edge(EdgeKind::Branch, 0x112),
+        edge(EdgeKind::Fallthrough, 0x110),
+      ]),
+      (0x110, 0x112, vec![edge(EdgeKind::Fallthrough, 0x112)]),
+      (0x112, 0x113, vec![]),
+    ]);
+    assert_eq!(analysis.data(), vec![(0x113, 0x118)]);
+    assert!(analysis.is_code(0x10d));
+    assert!(!analysis.is_code(0x115));
+    assert_eq!(analysis.listing(), [
+      "start:",
+      "  0100  b9 03 00          mov cx, 0x3",
+      "loc_0103:",
+      "  0103  e8 06 00          call 0x010c                ; sub_010c",
+      "  0106  e2 fb             loop 0x0103                ; loc_0103",
+      "  0108  ff 26 13 01       jmp word [0x113]           ; not followed",
+      "sub_010c:",
+      "  010c  3c 00             cmp al, 0x0",
+      "  010e  74 02             je 0x0112                  ; loc_0112",
+      "  0110  cd 10             int 0x10",
+      "loc_0112:",
+      "  0112  c3                ret",
+      "  0113  0c 01 48 69 00    db 0x0c, 0x01, 0x48, 0x69, 0x00 ; ..Hi.",
+      "",
+    ].join("\n"));
+    let dot = analysis.dot();
+    assert!(dot.starts_with("digraph cfg {\n"));
+    assert!(dot.contains(
+      "  b_0112 [label=\"loc_0112:\\l0112  ret\\l\"];\n"));
+    assert!(dot.contains("  b_0103 -> b_010c [style=dashed];\n"));
+    assert!(dot.contains("  b_010c -> b_0112 [label=\"taken\"];\n"));
+    assert!(dot.contains("  b_0110 -> b_0112;\n"));
+  }
+}
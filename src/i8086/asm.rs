@@ -0,0 +1,1491 @@
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use super::op::*;
+use super::operand::*;
+use super::register::*;
+use super::encode::*;
+
+// A two-pass assembler for a NASM-like subset: labels (including local
+// `.labels`), `org`, `equ`, `db`/`dw`/`times`/`align`, segment overrides
+// and `short`/`near`/`far` jumps. Macros and sections aren't supported.
+//
+// The first pass decides the size of every instruction. Whenever it depends
+// on a symbol that isn't defined yet, the long form is chosen, so that the
+// second pass can fill in the values without moving anything.
+
+#[derive(PartialEq, Clone)]
+#[derive(Debug)]
+pub struct AsmError {
+  pub line: usize,
+  pub message: String,
+}
+
+impl fmt::Display for AsmError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "line {}: {}", self.line, self.message)
+  }
+}
+
+impl error::Error for AsmError {}
+
+#[derive(Debug)]
+pub struct Assembly {
+  pub origin: u16,
+  pub bytes: Vec<u8>,
+  pub symbols: HashMap<String, i64>,
+}
+
+pub fn assemble(source: &str) -> Result<Assembly, AsmError> {
+  let mut assembler = Assembler {
+    origin: 0,
+    bytes: vec![],
+    symbols: HashMap::new(),
+    choices: HashMap::new(),
+    final_pass: false,
+    scope: String::new(),
+  };
+  let lines: Vec<&str> = source.lines().collect();
+  for &final_pass in [false, true].iter() {
+    assembler.final_pass = final_pass;
+    assembler.origin = 0;
+    assembler.bytes = vec![];
+    assembler.scope = String::new();
+    for (i, line) in lines.iter().enumerate() {
+      assembler.line(i, line)
+        .map_err(|message| AsmError { line: i + 1, message })?;
+    }
+  }
+  Ok(Assembly {
+    origin: assembler.origin,
+    bytes: assembler.bytes,
+    symbols: assembler.symbols,
+  })
+}
+
+#[derive(PartialEq, Clone)]
+#[derive(Debug)]
+enum Token {
+  Ident(String),
+  Number(i64),
+  Str(Vec<u8>),
+  Punct(&'static str),
+}
+
+const PUNCTS: [&str; 18] = [
+  "<<", ">>", "//", ",", ":", "[", "]", "+", "-", "*", "/", "%", "(", ")",
+  "~", "|", "&", "^",
+];
+
+fn is_ident_start(c: char) -> bool {
+  c.is_ascii_alphabetic() || "_.?@$".contains(c)
+}
+
+fn is_ident_char(c: char) -> bool {
+  c.is_ascii_alphanumeric() || "_.?@$#".contains(c)
+}
+
+fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+  let chars: Vec<char> = line.chars().collect();
+  let mut tokens = vec![];
+  let mut i = 0;
+  while i < chars.len() {
+    let c = chars[i];
+    if c == ';' {
+      break;
+    } else if c.is_whitespace() {
+      i += 1;
+    } else if c == '\'' || c == '"' || c == '`' {
+      let end = chars[i + 1..].iter().position(|&v| v == c)
+        .ok_or("unterminated string")?;
+      let text: String = chars[i + 1..i + 1 + end].iter().collect();
+      tokens.push(Token::Str(text.into_bytes()));
+      i += end + 2;
+    } else if c.is_ascii_digit() {
+      let start = i;
+      while i < chars.len() && (chars[i].is_ascii_alphanumeric() ||
+        chars[i] == '_')
+      {
+        i += 1;
+      }
+      let text: String = chars[start..i].iter().collect();
+      tokens.push(Token::Number(parse_number(&text)?));
+    } else if is_ident_start(c) {
+      let start = i;
+      while i < chars.len() && is_ident_char(chars[i]) {
+        i += 1;
+      }
+      let text: String = chars[start..i].iter().collect();
+      // `$name` escapes a name that would otherwise be a keyword
+      let text = match text.as_str() {
+        "$" | "$$" => text,
+        _ => text.trim_start_matches('$').to_string(),
+      };
+      tokens.push(Token::Ident(text));
+    } else {
+      let rest: String = chars[i..].iter().take(2).collect();
+      let punct = PUNCTS.iter().find(|p| rest.starts_with(*p))
+        .ok_or(format!("unexpected character '{}'", c))?;
+      tokens.push(Token::Punct(punct));
+      i += punct.len();
+    }
+  }
+  Ok(tokens)
+}
+
+fn parse_number(text: &str) -> Result<i64, String> {
+  let text = text.to_lowercase().replace('_', "");
+  let (digits, radix) = if text.starts_with("0x") || text.starts_with("0h") {
+    (&text[2..], 16)
+  } else if text.ends_with('h') {
+    (&text[..text.len() - 1], 16)
+  } else if text.starts_with("0b") || text.starts_with("0y") {
+    (&text[2..], 2)
+  } else if text.starts_with("0o") || text.starts_with("0q") {
+    (&text[2..], 8)
+  } else if let Some(digits) = text.strip_prefix("0d") {
+    (digits, 10)
+  } else if text.ends_with('b') || text.ends_with('y') {
+    (&text[..text.len() - 1], 2)
+  } else if text.ends_with('o') || text.ends_with('q') {
+    (&text[..text.len() - 1], 8)
+  } else if text.ends_with('d') {
+    (&text[..text.len() - 1], 10)
+  } else {
+    (&text[..], 10)
+  };
+  i64::from_str_radix(digits, radix)
+    .map_err(|_| format!("invalid number '{}'", text))
+}
+
+// A value is None while it depends on a symbol that isn't defined yet.
+type Value = Option<i64>;
+
+struct Context<'a> {
+  symbols: &'a HashMap<String, i64>,
+  scope: &'a str,
+  here: i64,
+  start: i64,
+  final_pass: bool,
+}
+
+impl<'a> Context<'a> {
+  fn lookup(&self, name: &str) -> Result<Value, String> {
+    let value = match name {
+      "$" => Some(self.here),
+      "$$" => Some(self.start),
+      _ => self.symbols.get(&qualify(self.scope, name)).cloned(),
+    };
+    match value {
+      None if self.final_pass => Err(format!("undefined symbol '{}'", name)),
+      _ => Ok(value),
+    }
+  }
+}
+
+// Local labels belong to the last non-local label.
+fn qualify(scope: &str, name: &str) -> String {
+  if name.starts_with('.') {
+    format!("{}{}", scope, name)
+  } else {
+    name.to_string()
+  }
+}
+
+struct ExprParser<'a, 'b> {
+  tokens: &'a [Token],
+  pos: usize,
+  ctx: &'a Context<'b>,
+}
+
+fn binary_value(
+  a: Value,
+  b: Value,
+  f: &dyn Fn(i64, i64) -> Result<i64, String>,
+) -> Result<Value, String> {
+  match (a, b) {
+    (Some(a), Some(b)) => Ok(Some(f(a, b)?)),
+    _ => Ok(None),
+  }
+}
+
+impl<'a, 'b> ExprParser<'a, 'b> {
+  fn peek(&self) -> Option<&Token> {
+    self.tokens.get(self.pos)
+  }
+  fn eat(&mut self, punct: &str) -> bool {
+    if self.peek() == Some(&Token::Punct(PUNCTS.iter()
+      .find(|p| **p == punct).unwrap()))
+    {
+      self.pos += 1;
+      true
+    } else {
+      false
+    }
+  }
+  // Operators from the lowest precedence, as in NASM.
+  fn parse(&mut self, level: usize) -> Result<Value, String> {
+    const LEVELS: [&[&str]; 5] = [
+      &["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"],
+    ];
+    if level == LEVELS.len() {
+      return self.parse_mul();
+    }
+    let mut value = self.parse(level + 1)?;
+    'outer: loop {
+      for op in LEVELS[level].iter() {
+        if self.eat(op) {
+          let rhs = self.parse(level + 1)?;
+          value = binary_value(value, rhs, &|a, b| Ok(match *op {
+            "|" => a | b,
+            "^" => a ^ b,
+            "&" => a & b,
+            "<<" => a.wrapping_shl(b as u32),
+            ">>" => a.wrapping_shr(b as u32),
+            "+" => a.wrapping_add(b),
+            _ => a.wrapping_sub(b),
+          }))?;
+          continue 'outer;
+        }
+      }
+      return Ok(value);
+    }
+  }
+  fn parse_mul(&mut self) -> Result<Value, String> {
+    let mut value = self.parse_unary()?;
+    loop {
+      let op = if self.eat("*") {
+        "*"
+      } else if self.eat("//") {
+        "//"
+      } else if self.eat("/") {
+        "/"
+      } else if self.eat("%") {
+        "%"
+      } else {
+        return Ok(value);
+      };
+      let rhs = self.parse_unary()?;
+      value = binary_value(value, rhs, &|a, b| {
+        if op != "*" && b == 0 {
+          return Err("division by zero".to_string());
+        }
+        Ok(match op {
+          "*" => a.wrapping_mul(b),
+          "/" => ((a as u64) / (b as u64)) as i64,
+          "//" => a / b,
+          _ => a % b,
+        })
+      })?;
+    }
+  }
+  fn parse_unary(&mut self) -> Result<Value, String> {
+    if self.eat("-") {
+      return Ok(self.parse_unary()?.map(|v| v.wrapping_neg()));
+    }
+    if self.eat("+") {
+      return self.parse_unary();
+    }
+    if self.eat("~") {
+      return Ok(self.parse_unary()?.map(|v| !v));
+    }
+    if self.eat("(") {
+      let value = self.parse(0)?;
+      if !self.eat(")") {
+        return Err("expected ')'".to_string());
+      }
+      return Ok(value);
+    }
+    let token = self.peek().cloned().ok_or("expected an expression")?;
+    self.pos += 1;
+    match token {
+      Token::Number(value) => Ok(Some(value)),
+      Token::Str(bytes) => {
+        // Character constants are packed little endian
+        Ok(Some(bytes.iter().rev()
+          .fold(0i64, |acc, &byte| (acc << 8) | byte as i64)))
+      },
+      Token::Ident(name) => {
+        if register_name(&name).is_some() {
+          return Err(format!("register '{}' in an expression", name));
+        }
+        self.ctx.lookup(&name)
+      },
+      Token::Punct(punct) => Err(format!("unexpected '{}'", punct)),
+    }
+  }
+}
+
+fn eval(tokens: &[Token], ctx: &Context) -> Result<Value, String> {
+  let mut parser = ExprParser { tokens, pos: 0, ctx };
+  let value = parser.parse(0)?;
+  if parser.pos != tokens.len() {
+    return Err("garbage after expression".to_string());
+  }
+  Ok(value)
+}
+
+#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug)]
+enum AsmRegister {
+  Byte(RegisterByteType),
+  Word(RegisterWordType),
+  Segment(RegisterWordType),
+}
+
+fn register_name(name: &str) -> Option<AsmRegister> {
+  Some(match name.to_lowercase().as_str() {
+    "al" => AsmRegister::Byte(RegisterByteType::Al),
+    "cl" => AsmRegister::Byte(RegisterByteType::Cl),
+    "dl" => AsmRegister::Byte(RegisterByteType::Dl),
+    "bl" => AsmRegister::Byte(RegisterByteType::Bl),
+    "ah" => AsmRegister::Byte(RegisterByteType::Ah),
+    "ch" => AsmRegister::Byte(RegisterByteType::Ch),
+    "dh" => AsmRegister::Byte(RegisterByteType::Dh),
+    "bh" => AsmRegister::Byte(RegisterByteType::Bh),
+    "ax" => AsmRegister::Word(RegisterWordType::Ax),
+    "cx" => AsmRegister::Word(RegisterWordType::Cx),
+    "dx" => AsmRegister::Word(RegisterWordType::Dx),
+    "bx" => AsmRegister::Word(RegisterWordType::Bx),
+    "sp" => AsmRegister::Word(RegisterWordType::Sp),
+    "bp" => AsmRegister::Word(RegisterWordType::Bp),
+    "si" => AsmRegister::Word(RegisterWordType::Si),
+    "di" => AsmRegister::Word(RegisterWordType::Di),
+    "es" => AsmRegister::Segment(RegisterWordType::Es),
+    "cs" => AsmRegister::Segment(RegisterWordType::Cs),
+    "ss" => AsmRegister::Segment(RegisterWordType::Ss),
+    "ds" => AsmRegister::Segment(RegisterWordType::Ds),
+    _ => return None,
+  })
+}
+
+#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug)]
+enum Size {
+  Byte,
+  Word,
+}
+
+#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug)]
+enum Distance {
+  Short,
+  Near,
+  Far,
+}
+
+#[derive(PartialEq, Clone)]
+#[derive(Debug)]
+enum Arg {
+  Reg(AsmRegister),
+  Mem {
+    segment: Option<RegisterWordType>,
+    base: Option<AddressType>,
+    disp: Value,
+  },
+  Imm(Value),
+  Far(Value, Value),
+}
+
+#[derive(PartialEq, Clone)]
+#[derive(Debug)]
+struct AsmOperand {
+  arg: Arg,
+  size: Option<Size>,
+  distance: Option<Distance>,
+}
+
+fn split_top_level<'a>(
+  tokens: &'a [Token],
+  separator: &str,
+) -> Vec<&'a [Token]> {
+  let mut parts = vec![];
+  let mut depth = 0;
+  let mut start = 0;
+  for (i, token) in tokens.iter().enumerate() {
+    match token {
+      Token::Punct("[") | Token::Punct("(") => depth += 1,
+      Token::Punct("]") | Token::Punct(")") => depth -= 1,
+      Token::Punct(punct) if *punct == separator && depth == 0 => {
+        parts.push(&tokens[start..i]);
+        start = i + 1;
+      },
+      _ => (),
+    }
+  }
+  parts.push(&tokens[start..]);
+  parts
+}
+
+fn parse_operand(
+  tokens: &[Token],
+  ctx: &Context,
+) -> Result<AsmOperand, String> {
+  let mut size = None;
+  let mut distance = None;
+  let mut pos = 0;
+  while let Some(Token::Ident(name)) = tokens.get(pos) {
+    match name.to_lowercase().as_str() {
+      "byte" => size = Some(Size::Byte),
+      "word" => size = Some(Size::Word),
+      "short" => distance = Some(Distance::Short),
+      "near" => distance = Some(Distance::Near),
+      "far" => distance = Some(Distance::Far),
+      "strict" => (),
+      _ => break,
+    }
+    pos += 1;
+  }
+  let tokens = &tokens[pos..];
+  let arg = match tokens {
+    [] => return Err("expected an operand".to_string()),
+    [Token::Ident(name)] if register_name(name).is_some() =>
+      Arg::Reg(register_name(name).unwrap()),
+    [Token::Punct("["), inner @ .., Token::Punct("]")] =>
+      parse_memory(inner, ctx)?,
+    _ => {
+      let parts = split_top_level(tokens, ":");
+      match parts.as_slice() {
+        [value] => Arg::Imm(eval(value, ctx)?),
+        [segment, offset] =>
+          Arg::Far(eval(segment, ctx)?, eval(offset, ctx)?),
+        _ => return Err("invalid operand".to_string()),
+      }
+    },
+  };
+  Ok(AsmOperand { arg, size, distance })
+}
+
+fn parse_memory(tokens: &[Token], ctx: &Context) -> Result<Arg, String> {
+  let (segment, tokens) = match tokens {
+    [Token::Ident(name), Token::Punct(":"), rest @ ..] => {
+      match register_name(name) {
+        Some(AsmRegister::Segment(reg)) => (Some(reg), rest),
+        _ => return Err(format!("invalid segment '{}'", name)),
+      }
+    },
+    _ => (None, tokens),
+  };
+  // Split into terms; registers are picked out, and everything else makes
+  // up the displacement.
+  let mut registers = vec![];
+  let mut disp = vec![Token::Number(0)];
+  let mut depth = 0;
+  let mut start = 0;
+  let mut sign = "+";
+  for i in 0..=tokens.len() {
+    let token = tokens.get(i);
+    match token {
+      Some(Token::Punct("(")) => depth += 1,
+      Some(Token::Punct(")")) => depth -= 1,
+      _ => (),
+    }
+    let is_split = match token {
+      None => true,
+      Some(Token::Punct(p)) => depth == 0 && (*p == "+" || *p == "-") &&
+        i > start,
+      _ => false,
+    };
+    if !is_split {
+      if i == start {
+        // A leading sign
+        if let Some(Token::Punct(p)) = token {
+          if *p == "-" || *p == "+" {
+            sign = p;
+            start = i + 1;
+          }
+        }
+      }
+      continue;
+    }
+    let term = &tokens[start..i];
+    match term {
+      [Token::Ident(name)] if register_name(name).is_some() => {
+        if sign != "+" {
+          return Err("registers can't be subtracted".to_string());
+        }
+        registers.push(name.to_lowercase());
+      },
+      _ => {
+        disp.push(Token::Punct(if sign == "+" { "+" } else { "-" }));
+        disp.push(Token::Punct("("));
+        disp.extend(term.iter().cloned());
+        disp.push(Token::Punct(")"));
+      },
+    }
+    if let Some(Token::Punct(p)) = token {
+      sign = p;
+    }
+    start = i + 1;
+  }
+  registers.sort();
+  let base = match registers.iter().map(|v| v.as_str())
+    .collect::<Vec<_>>().as_slice()
+  {
+    [] => None,
+    ["bx", "si"] => Some(AddressType::BxSi),
+    ["bx", "di"] => Some(AddressType::BxDi),
+    ["bp", "si"] => Some(AddressType::BpSi),
+    ["bp", "di"] => Some(AddressType::BpDi),
+    ["si"] => Some(AddressType::Si),
+    ["di"] => Some(AddressType::Di),
+    ["bp"] => Some(AddressType::Bp),
+    ["bx"] => Some(AddressType::Bx),
+    _ => return Err("invalid effective address".to_string()),
+  };
+  Ok(Arg::Mem { segment, base, disp: eval(&disp, ctx)? })
+}
+
+// Sizes decided on the first pass.
+#[derive(PartialEq, Copy, Clone, Default)]
+#[derive(Debug)]
+struct Choice {
+  long_disp: bool,
+  near: bool,
+  imm_word: bool,
+}
+
+struct Assembler {
+  origin: u16,
+  bytes: Vec<u8>,
+  symbols: HashMap<String, i64>,
+  choices: HashMap<usize, Choice>,
+  final_pass: bool,
+  scope: String,
+}
+
+fn check_range(value: i64, min: i64, max: i64) -> Result<i64, String> {
+  if value < min || value > max {
+    return Err(format!("value {:#x} out of range", value));
+  }
+  Ok(value)
+}
+
+fn fits_i8(value: i64) -> bool {
+  let value = value as u16 as i16;
+  (-0x80..=0x7f).contains(&value)
+}
+
+fn cond_jmp_op(mnemonic: &str) -> Option<OpCondJmpOp> {
+  Some(match mnemonic {
+    "jo" => OpCondJmpOp::Jo,
+    "jno" => OpCondJmpOp::Jno,
+    "jb" | "jc" | "jnae" => OpCondJmpOp::Jb,
+    "jnb" | "jnc" | "jae" => OpCondJmpOp::Jnb,
+    "je" | "jz" => OpCondJmpOp::Je,
+    "jne" | "jnz" => OpCondJmpOp::Jne,
+    "jbe" | "jna" => OpCondJmpOp::Jbe,
+    "ja" | "jnbe" => OpCondJmpOp::Ja,
+    "js" => OpCondJmpOp::Js,
+    "jns" => OpCondJmpOp::Jns,
+    "jp" | "jpe" => OpCondJmpOp::Jp,
+    "jnp" | "jpo" => OpCondJmpOp::Jnp,
+    "jl" | "jnge" => OpCondJmpOp::Jl,
+    "jge" | "jnl" => OpCondJmpOp::Jge,
+    "jle" | "jng" => OpCondJmpOp::Jle,
+    "jg" | "jnle" => OpCondJmpOp::Jg,
+    "jcxz" => OpCondJmpOp::Jcxz,
+    "loop" => OpCondJmpOp::Loop,
+    "loope" | "loopz" => OpCondJmpOp::Loope,
+    "loopne" | "loopnz" => OpCondJmpOp::Loopne,
+    _ => return None,
+  })
+}
+
+fn nullary_op(mnemonic: &str) -> Option<OpNullaryOp> {
+  Some(match mnemonic {
+    "xlat" | "xlatb" => OpNullaryOp::Xlat,
+    "lahf" => OpNullaryOp::Lahf,
+    "sahf" => OpNullaryOp::Sahf,
+    "pushf" => OpNullaryOp::Pushf,
+    "popf" => OpNullaryOp::Popf,
+    "aaa" => OpNullaryOp::Aaa,
+    "daa" => OpNullaryOp::Daa,
+    "aas" => OpNullaryOp::Aas,
+    "das" => OpNullaryOp::Das,
+    "cbw" => OpNullaryOp::Cbw,
+    "cwd" => OpNullaryOp::Cwd,
+    "into" => OpNullaryOp::Into,
+    "iret" => OpNullaryOp::Iret,
+    "clc" => OpNullaryOp::Clc,
+    "cmc" => OpNullaryOp::Cmc,
+    "stc" => OpNullaryOp::Stc,
+    "cld" => OpNullaryOp::Cld,
+    "std" => OpNullaryOp::Std,
+    "cli" => OpNullaryOp::Cli,
+    "sti" => OpNullaryOp::Sti,
+    "hlt" => OpNullaryOp::Hlt,
+    "wait" | "fwait" => OpNullaryOp::Wait,
+    "salc" => OpNullaryOp::Salc,
+    _ => return None,
+  })
+}
+
+fn binary_op(mnemonic: &str) -> Option<OpBinaryOp> {
+  Some(match mnemonic {
+    "add" => OpBinaryOp::Add,
+    "or" => OpBinaryOp::Or,
+    "adc" => OpBinaryOp::Adc,
+    "sbb" => OpBinaryOp::Sbb,
+    "and" => OpBinaryOp::And,
+    "sub" => OpBinaryOp::Sub,
+    "xor" => OpBinaryOp::Xor,
+    "cmp" => OpBinaryOp::Cmp,
+    "xchg" => OpBinaryOp::Xchg,
+    "test" => OpBinaryOp::Test,
+    "mov" => OpBinaryOp::Mov,
+    _ => return None,
+  })
+}
+
+fn unary_op(mnemonic: &str) -> Option<OpUnaryOp> {
+  Some(match mnemonic {
+    "push" => OpUnaryOp::Push,
+    "pop" => OpUnaryOp::Pop,
+    "inc" => OpUnaryOp::Inc,
+    "dec" => OpUnaryOp::Dec,
+    "not" => OpUnaryOp::Not,
+    "neg" => OpUnaryOp::Neg,
+    "mul" => OpUnaryOp::Mul,
+    "imul" => OpUnaryOp::Imul,
+    "div" => OpUnaryOp::Div,
+    "idiv" => OpUnaryOp::Idiv,
+    _ => return None,
+  })
+}
+
+fn shift_op(mnemonic: &str) -> Option<OpShiftOp> {
+  Some(match mnemonic {
+    "rol" => OpShiftOp::Rol,
+    "ror" => OpShiftOp::Ror,
+    "rcl" => OpShiftOp::Rcl,
+    "rcr" => OpShiftOp::Rcr,
+    "shl" => OpShiftOp::Shl,
+    "shr" => OpShiftOp::Shr,
+    // As NASM does; the undocumented /6 is left to `Op`
+    "sal" => OpShiftOp::Shl,
+    "sar" => OpShiftOp::Sar,
+    _ => return None,
+  })
+}
+
+fn string_op(mnemonic: &str) -> Option<Op> {
+  let (name, size) = match mnemonic.chars().last()? {
+    'b' => (&mnemonic[..mnemonic.len() - 1], OpSize::Byte),
+    'w' => (&mnemonic[..mnemonic.len() - 1], OpSize::Word),
+    _ => return None,
+  };
+  Some(match name {
+    "movs" => Op::Movs(size),
+    "cmps" => Op::Cmps(size),
+    "scas" => Op::Scas(size),
+    "lods" => Op::Lods(size),
+    "stos" => Op::Stos(size),
+    _ => return None,
+  })
+}
+
+// Byte and word registers, to convert operands into `Operand<T>`.
+trait AsmOperandType: RegisterType + Sized {
+  fn from_register(reg: AsmRegister) -> Option<Self>;
+  fn imm(value: i64, byte: bool) -> Result<Operand<Self>, String>;
+}
+
+impl AsmOperandType for RegisterByteType {
+  fn from_register(reg: AsmRegister) -> Option<Self> {
+    match reg {
+      AsmRegister::Byte(reg) => Some(reg),
+      _ => None,
+    }
+  }
+  fn imm(value: i64, _: bool) -> Result<OperandByte, String> {
+    Ok(Operand::ImmByte(check_range(value, -0x80, 0xff)? as u8))
+  }
+}
+
+impl AsmOperandType for RegisterWordType {
+  fn from_register(reg: AsmRegister) -> Option<Self> {
+    match reg {
+      AsmRegister::Word(reg) | AsmRegister::Segment(reg) => Some(reg),
+      _ => None,
+    }
+  }
+  fn imm(value: i64, byte: bool) -> Result<OperandWord, String> {
+    let value = check_range(value, -0x8000, 0xffff)?;
+    if byte {
+      if !fits_i8(value) {
+        return Err(format!("signed byte value {:#x} out of range", value));
+      }
+      Ok(Operand::ImmByte(value as u8))
+    } else {
+      Ok(Operand::ImmWord(value as u16))
+    }
+  }
+}
+
+fn register_size(reg: &AsmRegister) -> Size {
+  match reg {
+    AsmRegister::Byte(_) => Size::Byte,
+    _ => Size::Word,
+  }
+}
+
+// The operand size of an instruction, from its registers or size keywords.
+fn operand_size(operands: &[&AsmOperand]) -> Result<Size, String> {
+  let mut size = None;
+  for operand in operands {
+    let this = match (&operand.arg, operand.size) {
+      (Arg::Reg(reg), _) => Some(register_size(reg)),
+      (_, size) => size,
+    };
+    match (size, this) {
+      (Some(a), Some(b)) if a != b =>
+        return Err("mismatch in operand sizes".to_string()),
+      (None, _) => size = this,
+      _ => (),
+    }
+  }
+  size.ok_or("operation size not specified".to_string())
+}
+
+struct Encoding {
+  prefixes: OpPrefixes,
+  long_disp: bool,
+}
+
+impl Encoding {
+  fn segment(
+    &mut self,
+    segment: Option<RegisterWordType>,
+  ) -> Result<(), String> {
+    match (self.prefixes.segment, segment) {
+      (Some(a), Some(b)) if a != b =>
+        Err("conflicting segment overrides".to_string()),
+      (_, Some(b)) => {
+        self.prefixes.segment = Some(b);
+        Ok(())
+      },
+      _ => Ok(()),
+    }
+  }
+  fn operand<T: AsmOperandType>(
+    &mut self,
+    operand: &AsmOperand,
+    imm_byte: bool,
+  ) -> Result<Operand<T>, String> {
+    Ok(match &operand.arg {
+      Arg::Reg(reg) => Operand::Register(T::from_register(*reg)
+        .ok_or("invalid combination of operands")?),
+      Arg::Mem { segment, base, disp } => {
+        self.segment(*segment)?;
+        let value = check_range(disp.unwrap_or(0), -0x8000, 0xffff)?;
+        match base {
+          Some(base) => {
+            if disp.is_none() {
+              self.long_disp = true;
+            }
+            Operand::Address(*base, value as u16 as i16)
+          },
+          None => Operand::Direct(value as u16),
+        }
+      },
+      Arg::Imm(value) => T::imm(value.unwrap_or(0), imm_byte)?,
+      Arg::Far(_, _) =>
+        return Err("invalid combination of operands".to_string()),
+    })
+  }
+}
+
+fn is_memory(operand: &AsmOperand) -> bool {
+  matches!(operand.arg, Arg::Mem { .. })
+}
+
+fn is_register(operand: &AsmOperand) -> bool {
+  matches!(operand.arg, Arg::Reg(_))
+}
+
+impl Assembler {
+  fn context(&self) -> Context<'_> {
+    self.context_at(self.here())
+  }
+
+  fn context_at(&self, here: i64) -> Context<'_> {
+    Context {
+      symbols: &self.symbols,
+      scope: &self.scope,
+      here,
+      start: self.origin as i64,
+      final_pass: self.final_pass,
+    }
+  }
+
+  fn here(&self) -> i64 {
+    self.origin as i64 + self.bytes.len() as i64
+  }
+
+  fn define(&mut self, name: &str, value: i64) -> Result<(), String> {
+    let name = qualify(&self.scope, name);
+    match self.symbols.get(&name) {
+      Some(_) if !self.final_pass =>
+        Err(format!("symbol '{}' redefined", name)),
+      Some(old) if *old != value =>
+        Err(format!("symbol '{}' changed between passes", name)),
+      _ => {
+        self.symbols.insert(name, value);
+        Ok(())
+      },
+    }
+  }
+
+  fn line(&mut self, index: usize, line: &str) -> Result<(), String> {
+    let tokens = tokenize(line)?;
+    let mut tokens = tokens.as_slice();
+    // Labels; the colon may be left out before data and `equ`.
+    if let [Token::Ident(name), rest @ ..] = tokens {
+      let next = match rest.first() {
+        Some(Token::Ident(next)) => next.to_lowercase(),
+        _ => String::new(),
+      };
+      let is_label = rest.first() == Some(&Token::Punct(":")) ||
+        (!is_keyword(name) &&
+          ["equ", "db", "dw", "resb", "resw", "times"]
+            .contains(&next.as_str()));
+      if is_label {
+        if next == "equ" {
+          let value = eval(&rest[1..], &self.context())?;
+          if let Some(value) = value {
+            self.define(name, value)?;
+          }
+          return Ok(());
+        }
+        if !name.starts_with('.') {
+          self.scope = name.clone();
+        }
+        let here = self.here();
+        self.define(name, here)?;
+        tokens = match rest.first() {
+          Some(Token::Punct(":")) => &rest[1..],
+          _ => rest,
+        };
+      }
+    }
+    self.statement(index, tokens)
+  }
+
+  fn statement(
+    &mut self,
+    index: usize,
+    tokens: &[Token],
+  ) -> Result<(), String> {
+    let (name, rest) = match tokens {
+      [] => return Ok(()),
+      [Token::Ident(name), rest @ ..] => (name.to_lowercase(), rest),
+      [Token::Punct("["), ..] => {
+        // [bits 16], [cpu 8086] and similar
+        return Ok(());
+      },
+      _ => return Err("expected an instruction".to_string()),
+    };
+    match name.as_str() {
+      "cpu" | "bits" | "use16" | "section" | "segment" | "global" |
+      "extern" => Ok(()),
+      "org" => {
+        if !self.bytes.is_empty() {
+          return Err("org must come before any code".to_string());
+        }
+        let value = self.critical(rest)?;
+        self.origin = check_range(value, 0, 0xffff)? as u16;
+        Ok(())
+      },
+      "times" => {
+        // The count ends where the repeated statement starts
+        let split = (1..=rest.len()).find(|&i| match &rest[i - 1..] {
+          [Token::Ident(name), ..] if i > 1 => is_keyword(name),
+          _ => false,
+        }).ok_or("expected a statement after times")?;
+        let count = self.critical(&rest[..split - 1])?;
+        if count < 0 {
+          return Err("negative times count".to_string());
+        }
+        for _ in 0..count {
+          self.statement(index, &rest[split - 1..])?;
+        }
+        Ok(())
+      },
+      "db" | "dw" => self.data(&name, rest),
+      "resb" | "resw" => {
+        let count = self.critical(rest)?;
+        let size = if name == "resb" { 1 } else { 2 };
+        self.bytes.extend(vec![0; (count * size) as usize]);
+        Ok(())
+      },
+      "align" => {
+        let align = self.critical(rest)?;
+        if align <= 0 {
+          return Err("invalid alignment".to_string());
+        }
+        while self.here() % align != 0 {
+          self.bytes.push(0x90);
+        }
+        Ok(())
+      },
+      _ => {
+        let bytes = self.instruction(index, tokens)?;
+        self.bytes.extend(bytes);
+        Ok(())
+      },
+    }
+  }
+
+  // Evaluates an expression that has to be known on the first pass.
+  fn critical(&self, tokens: &[Token]) -> Result<i64, String> {
+    eval(tokens, &self.context())?
+      .ok_or("expression must not use forward references".to_string())
+  }
+
+  fn data(&mut self, name: &str, tokens: &[Token]) -> Result<(), String> {
+    // `$` is the start of the line for every item
+    let here = self.here();
+    for item in split_top_level(tokens, ",") {
+      match item {
+        [Token::Str(bytes)] => {
+          self.bytes.extend(bytes.iter());
+          if name == "dw" && bytes.len() % 2 == 1 {
+            self.bytes.push(0);
+          }
+        },
+        _ => {
+          let value = eval(item, &self.context_at(here))?.unwrap_or(0);
+          if name == "db" {
+            self.bytes.push(check_range(value, -0x80, 0xff)? as u8);
+          } else {
+            let value = check_range(value, -0x8000, 0xffff)? as u16;
+            self.bytes.push((value & 0xff) as u8);
+            self.bytes.push((value >> 8) as u8);
+          }
+        },
+      }
+    }
+    Ok(())
+  }
+
+  fn instruction(
+    &mut self,
+    index: usize,
+    tokens: &[Token],
+  ) -> Result<Vec<u8>, String> {
+    let mut prefixes = OpPrefixes::default();
+    let mut pos = 0;
+    let mnemonic = loop {
+      let name = match tokens.get(pos) {
+        Some(Token::Ident(name)) => name.to_lowercase(),
+        _ => return Err("expected an instruction".to_string()),
+      };
+      pos += 1;
+      if pos == tokens.len() && !matches!(name.as_str(), "lock" | "rep" |
+        "repe" | "repz" | "repne" | "repnz")
+      {
+        break name;
+      }
+      match name.as_str() {
+        "lock" => prefixes.lock = true,
+        "rep" | "repe" | "repz" => prefixes.repeat = Some(OpRepeatType::Rep),
+        "repne" | "repnz" => prefixes.repeat = Some(OpRepeatType::Repnz),
+        "es" | "cs" | "ss" | "ds" => {
+          if let Some(AsmRegister::Segment(reg)) = register_name(&name) {
+            prefixes.segment = Some(reg);
+          }
+        },
+        _ => break name,
+      }
+    };
+    let operands = if pos == tokens.len() {
+      vec![]
+    } else {
+      let ctx = self.context();
+      split_top_level(&tokens[pos..], ",").into_iter()
+        .map(|part| parse_operand(part, &ctx))
+        .collect::<Result<Vec<_>, _>>()?
+    };
+    let choice = if self.final_pass {
+      self.choices.get(&index).cloned().unwrap_or_default()
+    } else {
+      Choice::default()
+    };
+    let mut encoding = Encoding { prefixes, long_disp: false };
+    let here = self.here();
+    let result = build(&mnemonic, &operands, &mut encoding, choice, here,
+      self.final_pass)?;
+    let Built(op, mut choice) = result;
+    let instruction = Instruction { prefixes: encoding.prefixes, op };
+    let mut bytes = encode_instruction(&instruction)
+      .map_err(|err| err.to_string())?;
+    choice.long_disp = choice.long_disp || encoding.long_disp;
+    if choice.long_disp {
+      widen_displacement(&mut bytes, prefix_count(&encoding.prefixes) + 1);
+    }
+    if !self.final_pass {
+      self.choices.insert(index, choice);
+    }
+    Ok(bytes)
+  }
+}
+
+fn is_keyword(name: &str) -> bool {
+  let name = name.to_lowercase();
+  register_name(&name).is_some() || nullary_op(&name).is_some() ||
+    cond_jmp_op(&name).is_some() || binary_op(&name).is_some() ||
+    unary_op(&name).is_some() || shift_op(&name).is_some() ||
+    string_op(&name).is_some() ||
+    ["lock", "rep", "repe", "repz", "repne", "repnz", "times", "org", "db",
+      "dw", "resb", "resw", "align", "nop", "jmp", "call", "ret", "retn",
+      "retf", "int", "int3", "lea", "lds", "les", "in", "out", "aam", "aad"]
+      .contains(&name.as_str())
+}
+
+fn prefix_count(prefixes: &OpPrefixes) -> usize {
+  prefixes.lock as usize + prefixes.repeat.is_some() as usize +
+    prefixes.segment.is_some() as usize
+}
+
+// Forces a 16-bit displacement in the ModR/M byte at the index, keeping the
+// size chosen on the first pass.
+fn widen_displacement(bytes: &mut Vec<u8>, index: usize) -> () {
+  let mod_rm = bytes[index];
+  match mod_rm >> 6 {
+    0 => {
+      bytes[index] = mod_rm | 0x80;
+      bytes.splice(index + 1..index + 1, [0, 0].iter().cloned());
+    },
+    1 => {
+      let disp = bytes[index + 1] as i8 as i16 as u16;
+      bytes[index] = (mod_rm & 0x3f) | 0x80;
+      bytes.splice(index + 1..index + 2,
+        [(disp & 0xff) as u8, (disp >> 8) as u8].iter().cloned());
+    },
+    _ => (),
+  }
+}
+
+// The op for a line, and the choices made for it.
+struct Built(Op, Choice);
+
+fn expect_count(operands: &[AsmOperand], count: usize) -> Result<(), String> {
+  if operands.len() != count {
+    return Err(format!("expected {} operands", count));
+  }
+  Ok(())
+}
+
+fn imm_value(operand: &AsmOperand) -> Result<Value, String> {
+  match operand.arg {
+    Arg::Imm(value) => Ok(value),
+    _ => Err("expected an immediate".to_string()),
+  }
+}
+
+fn build(
+  mnemonic: &str,
+  operands: &[AsmOperand],
+  encoding: &mut Encoding,
+  choice: Choice,
+  here: i64,
+  final_pass: bool,
+) -> Result<Built, String> {
+  let mut next_choice = choice;
+  let prefix_len = prefix_count(&encoding.prefixes) as i64;
+  // Offset of a relative branch target from the end of the instruction
+  let relative = |target: Value, length: i64| -> Value {
+    target.map(|target| {
+      (target - (here + prefix_len + length)) as u16 as i16 as i64
+    })
+  };
+  if let Some(op) = nullary_op(mnemonic) {
+    expect_count(operands, 0)?;
+    return Ok(Built(Op::Nullary(op), choice));
+  }
+  if let Some(op) = string_op(mnemonic) {
+    expect_count(operands, 0)?;
+    return Ok(Built(op, choice));
+  }
+  if let Some(op) = cond_jmp_op(mnemonic) {
+    expect_count(operands, 1)?;
+    let offset = relative(imm_value(&operands[0])?, 2).unwrap_or(0);
+    if final_pass && !fits_i8(offset) {
+      return Err("short jump is out of range".to_string());
+    }
+    return Ok(Built(Op::CondJmp { op, offset: offset as i8 }, choice));
+  }
+  let op = match mnemonic {
+    "nop" => {
+      expect_count(operands, 0)?;
+      Op::BinaryWord {
+        op: OpBinaryOp::Xchg,
+        src: Operand::Register(RegisterWordType::Ax),
+        dest: Operand::Register(RegisterWordType::Ax),
+      }
+    },
+    "jmp" | "call" => {
+      expect_count(operands, 1)?;
+      let operand = &operands[0];
+      let is_call = mnemonic == "call";
+      let call_type = match (&operand.arg, operand.distance) {
+        (Arg::Far(segment, offset), _) => OpCallType::InterDirect(
+          check_range(offset.unwrap_or(0), -0x8000, 0xffff)? as u16,
+          check_range(segment.unwrap_or(0), -0x8000, 0xffff)? as u16),
+        (Arg::Mem { .. }, Some(Distance::Far)) =>
+          OpCallType::InterIndirect(encoding.operand(operand, false)?),
+        (Arg::Imm(_), Some(Distance::Far)) =>
+          return Err("far jumps need a segment:offset".to_string()),
+        (Arg::Imm(target), distance) => {
+          if is_call {
+            let offset = relative(*target, 3).unwrap_or(0);
+            OpCallType::WithinDirect(offset as i16)
+          } else {
+            // A short jump if it's known to fit on the first pass
+            let near = if final_pass {
+              choice.near
+            } else {
+              match distance {
+                Some(Distance::Short) => false,
+                Some(Distance::Near) => true,
+                _ => !relative(*target, 2).map(fits_i8).unwrap_or(false),
+              }
+            };
+            next_choice.near = near;
+            if near {
+              let offset = relative(*target, 3).unwrap_or(0);
+              let op = Op::Jmp(OpCallType::WithinDirect(offset as i16));
+              return Ok(Built(op, next_choice));
+            }
+            let offset = relative(*target, 2).unwrap_or(0);
+            if final_pass && !fits_i8(offset) {
+              return Err("short jump is out of range".to_string());
+            }
+            return Ok(Built(Op::JmpShort(offset as i8), next_choice));
+          }
+        },
+        _ => OpCallType::WithinIndirect(encoding.operand(operand, false)?),
+      };
+      if is_call { Op::Call(call_type) } else { Op::Jmp(call_type) }
+    },
+    "ret" | "retn" | "retf" => {
+      let far = mnemonic == "retf";
+      match operands {
+        [] => if far { Op::RetInter } else { Op::RetWithin },
+        [operand] => {
+          let value = check_range(imm_value(operand)?.unwrap_or(0),
+            -0x8000, 0xffff)? as u16;
+          if far { Op::RetInterImm(value) } else { Op::RetWithinImm(value) }
+        },
+        _ => return Err("expected 0 or 1 operands".to_string()),
+      }
+    },
+    "int3" => {
+      expect_count(operands, 0)?;
+      Op::Int(3)
+    },
+    "int" | "aam" | "aad" => {
+      let value = match operands {
+        [] if mnemonic != "int" => 10,
+        [operand] => imm_value(operand)?.unwrap_or(0),
+        _ => return Err("expected 1 operand".to_string()),
+      };
+      let value = check_range(value, 0, 0xff)? as u8;
+      match mnemonic {
+        "int" => Op::Int(value),
+        "aam" => Op::Aam(value),
+        _ => Op::Aad(value),
+      }
+    },
+    "in" | "out" => {
+      expect_count(operands, 2)?;
+      let (acc, port) = if mnemonic == "in" {
+        (&operands[0], &operands[1])
+      } else {
+        (&operands[1], &operands[0])
+      };
+      let size = match acc.arg {
+        Arg::Reg(AsmRegister::Byte(RegisterByteType::Al)) => OpSize::Byte,
+        Arg::Reg(AsmRegister::Word(RegisterWordType::Ax)) => OpSize::Word,
+        _ => return Err("expected al or ax".to_string()),
+      };
+      match (&port.arg, mnemonic) {
+        (Arg::Reg(AsmRegister::Word(RegisterWordType::Dx)), "in") =>
+          Op::InFixed(size),
+        (Arg::Reg(AsmRegister::Word(RegisterWordType::Dx)), _) =>
+          Op::OutFixed(size),
+        (Arg::Imm(value), _) => {
+          let value = check_range(value.unwrap_or(0), 0, 0xff)? as u8;
+          if mnemonic == "in" {
+            Op::InVariable(size, value)
+          } else {
+            Op::OutVariable(size, value)
+          }
+        },
+        _ => return Err("expected dx or an immediate port".to_string()),
+      }
+    },
+    "lea" | "lds" | "les" => {
+      expect_count(operands, 2)?;
+      let reg = match operands[0].arg {
+        Arg::Reg(AsmRegister::Word(reg)) => reg,
+        _ => return Err("expected a 16-bit register".to_string()),
+      };
+      if !is_memory(&operands[1]) {
+        return Err("expected a memory operand".to_string());
+      }
+      let operand = encoding.operand(&operands[1], false)?;
+      match mnemonic {
+        "lea" => Op::Lea(reg, operand),
+        "lds" => Op::Lds(reg, operand),
+        _ => Op::Les(reg, operand),
+      }
+    },
+    _ => {
+      if let Some(op) = binary_op(mnemonic) {
+        build_binary(op, operands, encoding, choice, &mut next_choice,
+          final_pass)?
+      } else if let Some(op) = unary_op(mnemonic) {
+        expect_count(operands, 1)?;
+        match operand_size(&[&operands[0]])? {
+          Size::Byte => Op::UnaryByte {
+            op, dest: encoding.operand(&operands[0], false)?,
+          },
+          Size::Word => Op::UnaryWord {
+            op, dest: encoding.operand(&operands[0], false)?,
+          },
+        }
+      } else if let Some(op) = shift_op(mnemonic) {
+        expect_count(operands, 2)?;
+        let shift_type = match &operands[1].arg {
+          Arg::Reg(AsmRegister::Byte(RegisterByteType::Cl)) => OpShiftType::Cl,
+          Arg::Imm(Some(1)) | Arg::Imm(None) => OpShiftType::One,
+          _ => return Err("8086 can only shift by 1 or cl".to_string()),
+        };
+        match operand_size(&[&operands[0]])? {
+          Size::Byte => Op::ShiftByte {
+            op, shift_type, dest: encoding.operand(&operands[0], false)?,
+          },
+          Size::Word => Op::ShiftWord {
+            op, shift_type, dest: encoding.operand(&operands[0], false)?,
+          },
+        }
+      } else {
+        return Err(format!("unknown instruction '{}'", mnemonic));
+      }
+    },
+  };
+  Ok(Built(op, next_choice))
+}
+
+fn build_binary(
+  op: OpBinaryOp,
+  operands: &[AsmOperand],
+  encoding: &mut Encoding,
+  choice: Choice,
+  next_choice: &mut Choice,
+  final_pass: bool,
+) -> Result<Op, String> {
+  expect_count(operands, 2)?;
+  let (mut dest, mut src) = (&operands[0], &operands[1]);
+  if (op == OpBinaryOp::Xchg || op == OpBinaryOp::Test) &&
+    is_memory(src) && is_register(dest)
+  {
+    // The register always goes to the reg field
+    std::mem::swap(&mut dest, &mut src);
+  }
+  // Immediates may be sized on their own, as in `add word [bx], byte 1`
+  let size = match operand_size(&[dest]) {
+    Ok(size) if matches!(src.arg, Arg::Imm(_)) => size,
+    _ => operand_size(&[dest, src])?,
+  };
+  // Arithmetic on words can use a sign extended byte immediate
+  let imm_byte = match (&src.arg, size, op) {
+    (Arg::Imm(value), Size::Word, OpBinaryOp::Add) |
+    (Arg::Imm(value), Size::Word, OpBinaryOp::Or) |
+    (Arg::Imm(value), Size::Word, OpBinaryOp::Adc) |
+    (Arg::Imm(value), Size::Word, OpBinaryOp::Sbb) |
+    (Arg::Imm(value), Size::Word, OpBinaryOp::And) |
+    (Arg::Imm(value), Size::Word, OpBinaryOp::Sub) |
+    (Arg::Imm(value), Size::Word, OpBinaryOp::Xor) |
+    (Arg::Imm(value), Size::Word, OpBinaryOp::Cmp) => {
+      let imm_word = if final_pass {
+        choice.imm_word
+      } else {
+        match src.size {
+          Some(Size::Byte) => false,
+          Some(Size::Word) => true,
+          None => !value.map(fits_i8).unwrap_or(false),
+        }
+      };
+      next_choice.imm_word = imm_word;
+      !imm_word
+    },
+    _ => false,
+  };
+  // Only the destination's size counts for the immediate
+  let src_size = src.size;
+  let src = AsmOperand {
+    size: if imm_byte { None } else { src_size },
+    ..src.clone()
+  };
+  Ok(match size {
+    Size::Byte => Op::BinaryByte {
+      op,
+      src: encoding.operand(&src, false)?,
+      dest: encoding.operand(dest, false)?,
+    },
+    Size::Word => Op::BinaryWord {
+      op,
+      src: encoding.operand(&src, imm_byte)?,
+      dest: encoding.operand(dest, false)?,
+    },
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::cpu::CpuModel;
+  use super::super::decode::*;
+
+  fn bytes(source: &str) -> Vec<u8> {
+    assemble(source).unwrap_or_else(|err| panic!("{}", err)).bytes
+  }
+
+  #[test]
+  fn test_assemble() {
+    assert_eq!(bytes("
+      org 0x100
+      start:
+        mov ax, 0x8086        ; b8 86 80
+        mov word [bx+si+0x10], 0x8086
+        add bx, -1
+        cmp byte [es:di], 'A'
+        jmp short start
+        jmp far 0xf000:0x0000
+        rep movsb
+      .loop:
+        loop .loop
+        call start
+        int 0x21
+        sal ax, 1
+    "), vec![
+      0xb8, 0x86, 0x80,
+      0xc7, 0x40, 0x10, 0x86, 0x80,
+      0x83, 0xc3, 0xff,
+      0x26, 0x80, 0x3d, 0x41,
+      0xeb, 0xef,
+      0xea, 0x00, 0x00, 0x00, 0xf0,
+      0xf3, 0xa4,
+      0xe2, 0xfe,
+      0xe8, 0xe3, 0xff,
+      0xcd, 0x21,
+      0xd1, 0xe0,
+    ]);
+  }
+
+  #[test]
+  fn test_assemble_forward() {
+    // Forward references get the long forms, decided on the first pass
+    let assembly = assemble("
+      org 0x7c00
+        jmp main
+        mov al, [bx+value]
+        add ax, value
+      value equ 4
+      main:
+        jmp main
+      msg: db 'hi', 0
+        dw msg, $ - msg
+        times 4 - ($ - msg) % 4 db 0x90
+        align 2
+    ").unwrap();
+    assert_eq!(assembly.bytes, vec![
+      0xe9, 0x07, 0x00,
+      0x8a, 0x87, 0x04, 0x00,
+      0x05, 0x04, 0x00,
+      0xeb, 0xfe,
+      0x68, 0x69, 0x00,
+      0x0c, 0x7c, 0x03, 0x00,
+      0x90,
+    ]);
+    assert_eq!(assembly.symbols.get("main"), Some(&0x7c0a));
+  }
+
+  #[test]
+  fn test_assemble_error() {
+    let cases = [
+      ("mov [bx], 1", 1, "operation size not specified"),
+      ("\nmov al, bx", 2, "mismatch in operand sizes"),
+      ("jz far_away\ntimes 200 nop\nfar_away:", 1,
+        "short jump is out of range"),
+      ("mov ax, undefined", 1, "undefined symbol 'undefined'"),
+      ("foo ax", 1, "unknown instruction 'foo'"),
+      ("mov [bx+bp], ax", 1, "invalid effective address"),
+    ];
+    for (source, line, message) in cases.iter() {
+      assert_eq!(assemble(source).unwrap_err(), AsmError {
+        line: *line,
+        message: message.to_string(),
+      });
+    }
+  }
+
+  #[test]
+  fn test_assemble_disasm() {
+    // Disassembly of any instruction assembles back to the same instruction
+    let tails: [[u8; 4]; 2] = [[0x12, 0x34, 0x56, 0x78], [0x80, 0xff, 0, 0]];
+    for first in 0..=0xffu16 {
+      for second in 0..=0xffu16 {
+        for tail in tails.iter() {
+          let mut input = vec![first as u8, second as u8];
+          input.extend(tail.iter());
+          let decoded = match decode(&input, 0, 0x100, CpuModel::I8086) {
+            Ok(decoded) => decoded,
+            Err(_) => continue,
+          };
+          let source = format!("org 0x100\n{}", decoded);
+          let output = assemble(&source)
+            .unwrap_or_else(|err| panic!("{}: {}", source, err)).bytes;
+          let reassembled = decode(&output, 0, 0x100, CpuModel::I8086)
+            .unwrap_or_else(|err| panic!("{}: {}", source, err));
+          assert_eq!(reassembled.length(), output.len(), "{}", source);
+          assert_eq!(
+            canonical(&reassembled),
+            canonical(&decoded),
+            "{}", source);
+        }
+      }
+    }
+  }
+
+  // Word immediates that fit in a byte are assembled to the short form,
+  // as nasm does, and jumps may change their length.
+  fn canonical(decoded: &DecodedInstruction) -> Instruction {
+    let op = match decoded.instruction.op.clone() {
+      Op::BinaryWord { op, src: Operand::ImmWord(value), dest }
+        if fits_i8(value as i64) && op != OpBinaryOp::Mov &&
+          op != OpBinaryOp::Test =>
+      {
+        Op::BinaryWord { op, src: Operand::ImmByte(value as u8), dest }
+      },
+      Op::Jmp(OpCallType::WithinDirect(offset)) => {
+        let target = decoded.next_ip().wrapping_add(offset as u16);
+        Op::Jmp(OpCallType::WithinDirect(target as i16))
+      },
+      Op::JmpShort(offset) => {
+        let target = decoded.next_ip().wrapping_add(offset as i16 as u16);
+        Op::Jmp(OpCallType::WithinDirect(target as i16))
+      },
+      // `sal` is read back as the /4 form
+      Op::ShiftByte { op: OpShiftOp::Sal, shift_type, dest } =>
+        Op::ShiftByte { op: OpShiftOp::Shl, shift_type, dest },
+      Op::ShiftWord { op: OpShiftOp::Sal, shift_type, dest } =>
+        Op::ShiftWord { op: OpShiftOp::Shl, shift_type, dest },
+      op => op,
+    };
+    Instruction { op, ..decoded.instruction.clone() }
+  }
+}
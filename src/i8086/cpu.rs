@@ -1,16 +1,56 @@
+use std::cell::Cell;
+use std::rc::Rc;
 use crate::mem::Memory;
 use crate::mem::MemoryValue;
 use super::register::Register;
 use super::register::RegisterWordType;
-use super::op::Op;
-use super::op::parse_op;
+use super::op::DecodeError;
+use super::op::OpRepeatType;
+use super::decode::DecodedInstruction;
+use super::exception::Exception;
+use super::interrupt::InterruptController;
+use super::interrupt::InterruptHandler;
+use super::debugger::Debugger;
+use super::flags::IF;
+
+#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug)]
+pub enum AddressLines {
+  // 20 address lines; addresses past 1MB wrap around, as in a real 8086.
+  Wrap20,
+  // A20 is controlled by a gate, exposing HMA (0x100000-0x10FFEF) when
+  // it's enabled.
+  A20Gate,
+}
+
+#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug)]
+pub enum CpuModel {
+  // A real 8086/8088; undocumented opcodes are decoded the way the silicon
+  // executes them.
+  I8086,
+  // Only documented opcodes are accepted, others raise an invalid opcode.
+  Strict,
+}
 
 pub struct CPU {
   pub memory: Box<dyn Memory>,
   pub io_ports: Box<dyn Memory>,
   pub register: Register,
   pub segment_selector: Option<RegisterWordType>,
+  pub repeat: Option<OpRepeatType>,
+  pub instruction_ip: u16,
+  pub intercepts: Vec<Exception>,
+  pub interrupt_controller: Option<Box<dyn InterruptController>>,
+  pub interrupt_handler: Option<Box<dyn InterruptHandler>>,
+  pub nmi_pending: bool,
+  pub interrupt_shadow: bool,
+  pub halted: bool,
+  pub address_lines: AddressLines,
+  pub a20_gate: Rc<Cell<bool>>,
   pub running: bool,
+  pub model: CpuModel,
+  pub debugger: Debugger,
 }
 
 impl CPU {
@@ -20,25 +60,63 @@ impl CPU {
       io_ports,
       register: Register::new(),
       segment_selector: None,
+      repeat: None,
+      instruction_ip: 0,
+      intercepts: vec![],
+      interrupt_controller: None,
+      interrupt_handler: None,
+      nmi_pending: false,
+      interrupt_shadow: false,
+      halted: false,
+      address_lines: AddressLines::Wrap20,
+      a20_gate: Rc::new(Cell::new(false)),
       running: true,
+      model: CpuModel::I8086,
+      debugger: Debugger::new(),
     }
   }
 
+  // Returns the A20 gate, so that I/O port handlers (8042 or port 0x92) can
+  // toggle it.
+  pub fn get_a20_gate(&self) -> Rc<Cell<bool>> {
+    self.a20_gate.clone()
+  }
+
   pub fn iter(&mut self) -> CPUIterator {
     CPUIterator::new(self)
   }
 
-  pub fn next_op(&mut self) -> Option<Op> {
-    parse_op(&mut self.iter())
+  pub fn step(&mut self) -> Result<(), Exception> {
+    self.step_with(|_, _| true)
   }
 
-  pub fn step(&mut self) -> Option<()> {
+  // Steps, first showing `inspect` the instruction as decoded, so that
+  // callers don't need to decode it again. If `inspect` returns false, the
+  // instruction doesn't run and CS:IP stays on it.
+  pub fn step_with<F>(&mut self, mut inspect: F) -> Result<(), Exception>
+  where
+    F: FnMut(&CPU, &Result<DecodedInstruction, DecodeError>) -> bool,
+  {
     if !self.running {
-      return None;
+      return Ok(());
+    }
+    // Entering an interrupt handler takes a step of its own.
+    if self.poll_interrupts() {
+      self.halted = false;
+      return Ok(());
+    }
+    if self.halted {
+      return Ok(());
+    }
+    let decoded = self.next_op();
+    if !inspect(self, &decoded) {
+      self.register.ip = self.instruction_ip;
+      return Ok(());
+    }
+    match decoded {
+      Ok(decoded) => self.exec_op(&decoded.instruction),
+      Err(_) => self.raise(Exception::InvalidOpcode),
     }
-    let op = self.next_op()?;
-    self.exec_op(&op);
-    Some(())
   }
 
   pub fn jmp(&mut self, seg: u16, addr: u16) -> () {
@@ -47,17 +125,30 @@ impl CPU {
   }
 
   pub fn hlt(&mut self) -> () {
-    self.running = false;
+    self.halted = true;
   }
 
   pub fn unhlt(&mut self) -> () {
-    self.running = true;
+    self.halted = false;
+  }
+
+  // Whether the CPU can't make progress by itself anymore: either the host
+  // has stopped it, or it has halted with nothing that could wake it up.
+  pub fn is_stopped(&self) -> bool {
+    if !self.running {
+      return true;
+    }
+    if !self.halted || self.nmi_pending {
+      return false;
+    }
+    self.get_flags() & IF == 0 || self.interrupt_controller.is_none()
   }
 
-  pub fn run(&mut self) -> () {
-    while self.running {
-      self.step();
+  pub fn run(&mut self) -> Result<(), Exception> {
+    while !self.is_stopped() {
+      self.step()?;
     }
+    Ok(())
   }
 }
 
@@ -75,10 +166,10 @@ impl<'cpu> Iterator for CPUIterator<'cpu> {
   type Item = u8;
 
   fn next(&mut self) -> Option<u8> {
-    let addr = self.cpu.register.ip as usize +
-      ((self.cpu.register.cs as usize) << 4);
+    let addr = self.cpu.get_linear_addr(
+      self.cpu.register.cs, self.cpu.register.ip);
     let value = u8::read_mem(&*self.cpu.memory, addr);
-    self.cpu.register.ip += 1;
+    self.cpu.register.ip = self.cpu.register.ip.wrapping_add(1);
     Some(value)
   }
 }
@@ -98,8 +189,8 @@ mod tests {
     let io_ports = LinearMemory::new(0);
     let mut cpu = CPU::new(Box::new(mem), Box::new(io_ports));
     assert_eq!(
-      cpu.next_op(),
-      Some(Op::Jmp(OpCallType::InterDirect(0x0000, 0xf000))),
+      cpu.next_op().map(|decoded| decoded.instruction),
+      Ok(Instruction::new(Op::Jmp(OpCallType::InterDirect(0x0000, 0xf000)))),
     );
   }
 }
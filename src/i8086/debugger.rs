@@ -0,0 +1,159 @@
+use std::cell::Cell;
+use super::cpu::CPU;
+use super::exception::Exception;
+use super::op::DecodeError;
+
+#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug)]
+pub enum Breakpoint {
+  // Matches only this exact CS:IP pair.
+  Address(u16, u16),
+  // Matches any CS:IP pair that maps to the linear address.
+  Linear(usize),
+}
+
+#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug)]
+pub enum Access {
+  Read,
+  Write,
+}
+
+#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug)]
+pub enum WatchKind {
+  Read,
+  Write,
+  Access, // either
+}
+
+impl WatchKind {
+  fn matches(&self, access: Access) -> bool {
+    match self {
+      WatchKind::Read => access == Access::Read,
+      WatchKind::Write => access == Access::Write,
+      WatchKind::Access => true,
+    }
+  }
+}
+
+// Watches linear addresses, or I/O ports, in [start, start + length).
+#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug)]
+pub struct Watchpoint {
+  pub start: usize,
+  pub length: usize,
+  pub kind: WatchKind,
+}
+
+impl Watchpoint {
+  pub fn new(start: usize, length: usize, kind: WatchKind) -> Self {
+    Watchpoint { start, length, kind }
+  }
+  fn matches(&self, address: usize, access: Access) -> bool {
+    address >= self.start && address - self.start < self.length &&
+      self.kind.matches(access)
+  }
+}
+
+#[derive(PartialEq, Clone)]
+#[derive(Debug)]
+pub enum StopReason {
+  // Stopped before executing the instruction at the breakpoint.
+  Breakpoint(Breakpoint),
+  // Stopped after the instruction that accessed the memory or port.
+  Watchpoint { address: usize, access: Access, value: u8 },
+  PortWatchpoint { port: u16, access: Access, value: u16 },
+  // Halted with nothing to wake it up, or stopped by the host.
+  Halted,
+  BudgetExhausted,
+  // Stopped before executing an instruction that can't be decoded.
+  DecodeError(DecodeError),
+}
+
+// Breakpoints and watchpoints of a CPU. Memory accesses happen through a
+// shared reference, so a watchpoint hit is kept in a cell until the
+// instruction finishes.
+#[derive(Default)]
+pub struct Debugger {
+  pub breakpoints: Vec<Breakpoint>,
+  pub watchpoints: Vec<Watchpoint>,
+  pub port_watchpoints: Vec<Watchpoint>,
+  hit: Cell<Option<StopReason>>,
+}
+
+impl Debugger {
+  pub fn new() -> Self {
+    Debugger::default()
+  }
+
+  fn record(&self, reason: StopReason) -> () {
+    let hit = self.hit.take();
+    self.hit.set(hit.or(Some(reason)));
+  }
+
+  pub fn watch_memory(&self, address: usize, access: Access, value: u8) -> () {
+    if self.watchpoints.iter().any(|watch| watch.matches(address, access)) {
+      self.record(StopReason::Watchpoint { address, access, value });
+    }
+  }
+
+  pub fn watch_port(&self, port: u16, access: Access, value: u16) -> () {
+    let address = port as usize;
+    if self.port_watchpoints.iter()
+      .any(|watch| watch.matches(address, access))
+    {
+      self.record(StopReason::PortWatchpoint { port, access, value });
+    }
+  }
+}
+
+impl CPU {
+  pub fn breakpoint_at(&self, cs: u16, ip: u16) -> Option<Breakpoint> {
+    let linear = self.get_linear_addr(cs, ip);
+    self.debugger.breakpoints.iter().cloned().find(|breakpoint| {
+      match *breakpoint {
+        Breakpoint::Address(bp_cs, bp_ip) => bp_cs == cs && bp_ip == ip,
+        Breakpoint::Linear(address) => address == linear,
+      }
+    })
+  }
+
+  // Runs until something stops the CPU, or `budget` instructions have been
+  // stepped. A breakpoint right at CS:IP doesn't stop the first step, so
+  // that it's possible to continue from a breakpoint.
+  pub fn run_until(&mut self, budget: u64) -> Result<StopReason, Exception> {
+    self.debugger.hit.take();
+    for count in 0..budget {
+      if self.is_stopped() {
+        return Ok(StopReason::Halted);
+      }
+      if !self.halted {
+        let (cs, ip) = (self.register.cs, self.register.ip);
+        match self.breakpoint_at(cs, ip) {
+          Some(breakpoint) if count > 0 =>
+            return Ok(StopReason::Breakpoint(breakpoint)),
+          _ => (),
+        }
+      }
+      let mut undecodable = None;
+      self.step_with(|_, decoded| match decoded {
+        Ok(_) => true,
+        Err(err) => {
+          undecodable = Some(err.clone());
+          false
+        },
+      })?;
+      if let Some(err) = undecodable {
+        return Ok(StopReason::DecodeError(err));
+      }
+      if let Some(reason) = self.debugger.hit.take() {
+        return Ok(reason);
+      }
+    }
+    if self.is_stopped() {
+      return Ok(StopReason::Halted);
+    }
+    Ok(StopReason::BudgetExhausted)
+  }
+}
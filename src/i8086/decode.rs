@@ -0,0 +1,164 @@
+use std::iter;
+use super::cpu::CPU;
+use super::cpu::CpuModel;
+use super::op::*;
+
+// An instruction along with where it was fetched from, and how it was
+// encoded.
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub struct DecodedInstruction {
+  pub cs: u16,
+  pub ip: u16,
+  pub bytes: Vec<u8>,
+  pub instruction: Instruction,
+}
+
+impl DecodedInstruction {
+  pub fn length(&self) -> usize {
+    self.bytes.len()
+  }
+  pub fn prefixes(&self) -> &OpPrefixes {
+    &self.instruction.prefixes
+  }
+  pub fn op(&self) -> &Op {
+    &self.instruction.op
+  }
+  // The IP of the instruction that follows; it wraps within the segment.
+  pub fn next_ip(&self) -> u16 {
+    self.ip.wrapping_add(self.bytes.len() as u16)
+  }
+}
+
+// Decodes a single instruction at the start of the buffer, as if it were
+// located at cs:ip.
+pub fn decode(
+  bytes: &[u8],
+  cs: u16,
+  ip: u16,
+  model: CpuModel,
+) -> Result<DecodedInstruction, DecodeError> {
+  let mut iter = bytes.iter().cloned();
+  let instruction = parse_op_with_model(&mut iter, model)?;
+  let length = bytes.len() - iter.len();
+  Ok(DecodedInstruction {
+    cs,
+    ip,
+    bytes: bytes[..length].to_vec(),
+    instruction,
+  })
+}
+
+// Decodes a buffer linearly, one instruction after another. After an
+// invalid instruction, decoding resumes from the next byte.
+pub struct Decoder<'a> {
+  bytes: &'a [u8],
+  offset: usize,
+  pub cs: u16,
+  pub ip: u16,
+  pub model: CpuModel,
+}
+
+impl<'a> Decoder<'a> {
+  pub fn new(bytes: &'a [u8], cs: u16, ip: u16) -> Self {
+    Decoder { bytes, offset: 0, cs, ip, model: CpuModel::I8086 }
+  }
+}
+
+impl<'a> Iterator for Decoder<'a> {
+  type Item = Result<DecodedInstruction, DecodeError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.offset >= self.bytes.len() {
+      return None;
+    }
+    let result = decode(
+      &self.bytes[self.offset..], self.cs, self.ip, self.model);
+    let length = match &result {
+      Ok(decoded) => decoded.length(),
+      Err(err) if err.kind == DecodeErrorKind::Truncated =>
+        self.bytes.len() - self.offset,
+      Err(_) => 1,
+    };
+    self.offset += length;
+    self.ip = self.ip.wrapping_add(length as u16);
+    Some(result)
+  }
+}
+
+impl CPU {
+  // Decodes the instruction at CS:IP, without moving past it.
+  pub fn peek_op(&self) -> Result<DecodedInstruction, DecodeError> {
+    let cs = self.register.cs;
+    let ip = self.register.ip;
+    // Each byte is read once, as reads may have side effects
+    let mut bytes = vec![];
+    let mut iter = iter::from_fn(|| {
+      let offset = ip.wrapping_add(bytes.len() as u16);
+      let value = self.memory.read_u8(self.get_linear_addr(cs, offset));
+      bytes.push(value);
+      Some(value)
+    });
+    let instruction = parse_op_with_model(&mut iter, self.model)?;
+    Ok(DecodedInstruction { cs, ip, bytes, instruction })
+  }
+
+  // Decodes the instruction at CS:IP, advancing IP past it.
+  pub fn next_op(&mut self) -> Result<DecodedInstruction, DecodeError> {
+    // Remember where the instruction, including its prefixes, starts.
+    self.instruction_ip = self.register.ip;
+    let decoded = self.peek_op()?;
+    self.register.ip = decoded.next_ip();
+    Ok(decoded)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::operand::*;
+  use super::super::register::*;
+
+  #[test]
+  fn test_decoder() {
+    let input: Vec<u8> = vec![
+      0x2e, 0xa1, 0x34, 0x12, // mov ax, [cs:0x1234]
+      0x60, // (invalid)
+      0xc3, // ret
+      0xb8, 0x00, // (truncated)
+    ];
+    let mut decoder = Decoder::new(&input, 0x1000, 0xfffc);
+    decoder.model = CpuModel::Strict;
+    let decoded = decoder.next().unwrap().unwrap();
+    assert_eq!(decoded, DecodedInstruction {
+      cs: 0x1000,
+      ip: 0xfffc,
+      bytes: vec![0x2e, 0xa1, 0x34, 0x12],
+      instruction: Instruction {
+        prefixes: OpPrefixes {
+          segment: Some(RegisterWordType::Cs),
+          ..OpPrefixes::default()
+        },
+        op: Op::BinaryWord {
+          op: OpBinaryOp::Mov,
+          src: Operand::Direct(0x1234),
+          dest: Operand::Register(RegisterWordType::Ax),
+        },
+      },
+    });
+    assert_eq!(decoded.length(), 4);
+    assert_eq!(decoded.next_ip(), 0x0000);
+    assert_eq!(
+      decoder.next().unwrap().map_err(|err| err.kind),
+      Err(DecodeErrorKind::InvalidOpcode),
+    );
+    let decoded = decoder.next().unwrap().unwrap();
+    assert_eq!(decoded.ip, 0x0001);
+    assert_eq!(decoded.op(), &Op::RetWithin);
+    assert_eq!(
+      decoder.next().unwrap().map_err(|err| err.kind),
+      Err(DecodeErrorKind::Truncated),
+    );
+    assert!(decoder.next().is_none());
+  }
+}
@@ -0,0 +1,523 @@
+use std::fmt;
+use super::op::*;
+use super::operand::*;
+use super::register::*;
+use super::decode::DecodedInstruction;
+
+// Formats instructions in NASM syntax, so that the output can be fed back
+// to nasm. Relative branches are resolved to absolute targets when the
+// address of the instruction is known, and written relative to `$`
+// otherwise.
+
+impl fmt::Display for RegisterWordType {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(match self {
+      RegisterWordType::Ax => "ax",
+      RegisterWordType::Cx => "cx",
+      RegisterWordType::Dx => "dx",
+      RegisterWordType::Bx => "bx",
+      RegisterWordType::Sp => "sp",
+      RegisterWordType::Bp => "bp",
+      RegisterWordType::Si => "si",
+      RegisterWordType::Di => "di",
+      RegisterWordType::Es => "es",
+      RegisterWordType::Cs => "cs",
+      RegisterWordType::Ss => "ss",
+      RegisterWordType::Ds => "ds",
+    })
+  }
+}
+
+impl fmt::Display for RegisterByteType {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(match self {
+      RegisterByteType::Al => "al",
+      RegisterByteType::Cl => "cl",
+      RegisterByteType::Dl => "dl",
+      RegisterByteType::Bl => "bl",
+      RegisterByteType::Ah => "ah",
+      RegisterByteType::Ch => "ch",
+      RegisterByteType::Dh => "dh",
+      RegisterByteType::Bh => "bh",
+    })
+  }
+}
+
+impl fmt::Display for AddressType {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(match self {
+      AddressType::BxSi => "bx+si",
+      AddressType::BxDi => "bx+di",
+      AddressType::BpSi => "bp+si",
+      AddressType::BpDi => "bp+di",
+      AddressType::Si => "si",
+      AddressType::Di => "di",
+      AddressType::Bp => "bp",
+      AddressType::Bx => "bx",
+    })
+  }
+}
+
+trait OperandSize: RegisterType + fmt::Display {
+  fn size_name() -> &'static str;
+  // Immediate bytes are sign extended in word operations.
+  fn format_imm_byte(value: u8) -> String;
+}
+
+impl OperandSize for RegisterByteType {
+  fn size_name() -> &'static str { "byte" }
+  fn format_imm_byte(value: u8) -> String {
+    format!("{:#x}", value)
+  }
+}
+
+impl OperandSize for RegisterWordType {
+  fn size_name() -> &'static str { "word" }
+  fn format_imm_byte(value: u8) -> String {
+    // Keep the short encoding when reassembled
+    format!("byte {}", format_signed(value as i8 as i16))
+  }
+}
+
+fn format_signed(value: i16) -> String {
+  if value < 0 {
+    format!("-{:#x}", -(value as i32))
+  } else {
+    format!("{:#x}", value)
+  }
+}
+
+fn is_memory<T: RegisterType>(operand: &Operand<T>) -> bool {
+  matches!(operand, Operand::Address(_, _) | Operand::Direct(_))
+}
+
+fn is_register<T: RegisterType>(operand: &Operand<T>) -> bool {
+  matches!(operand, Operand::Register(_))
+}
+
+// Formats an operand; memory operands get the segment override, and the
+// size if nothing else in the instruction tells it.
+fn format_operand<T: OperandSize>(
+  operand: &Operand<T>,
+  segment: Option<RegisterWordType>,
+  sized: bool,
+) -> String {
+  let address = match operand {
+    Operand::Register(reg) => return format!("{}", reg),
+    Operand::ImmWord(value) => return format!("{:#x}", value),
+    Operand::ImmByte(value) => return T::format_imm_byte(*value),
+    Operand::Address(addr_type, 0) => format!("{}", addr_type),
+    Operand::Address(addr_type, offset) =>
+      format!("{}{}", addr_type, format_signed_offset(*offset)),
+    Operand::Direct(offset) => format!("{:#x}", offset),
+  };
+  let segment = match segment {
+    Some(reg) => format!("{}:", reg),
+    None => String::new(),
+  };
+  if sized {
+    format!("{} [{}{}]", T::size_name(), segment, address)
+  } else {
+    format!("[{}{}]", segment, address)
+  }
+}
+
+fn format_signed_offset(offset: i16) -> String {
+  if offset < 0 {
+    format!("-{:#x}", -(offset as i32))
+  } else {
+    format!("+{:#x}", offset)
+  }
+}
+
+impl<T: RegisterType> fmt::Display for Operand<T>
+  where T: fmt::Display
+{
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Operand::Register(reg) => write!(f, "{}", reg),
+      Operand::Address(addr_type, 0) => write!(f, "[{}]", addr_type),
+      Operand::Address(addr_type, offset) =>
+        write!(f, "[{}{}]", addr_type, format_signed_offset(*offset)),
+      Operand::Direct(offset) => write!(f, "[{:#x}]", offset),
+      Operand::ImmWord(value) => write!(f, "{:#x}", value),
+      Operand::ImmByte(value) => write!(f, "{:#x}", value),
+    }
+  }
+}
+
+impl OpBinaryOp {
+  pub fn mnemonic(&self) -> &'static str {
+    match self {
+      OpBinaryOp::Add => "add",
+      OpBinaryOp::Or => "or",
+      OpBinaryOp::Adc => "adc",
+      OpBinaryOp::Sbb => "sbb",
+      OpBinaryOp::And => "and",
+      OpBinaryOp::Sub => "sub",
+      OpBinaryOp::Xor => "xor",
+      OpBinaryOp::Cmp => "cmp",
+      OpBinaryOp::Xchg => "xchg",
+      OpBinaryOp::Test => "test",
+      OpBinaryOp::Mov => "mov",
+    }
+  }
+}
+
+impl OpUnaryOp {
+  pub fn mnemonic(&self) -> &'static str {
+    match self {
+      OpUnaryOp::Push => "push",
+      OpUnaryOp::Pop => "pop",
+      OpUnaryOp::Inc => "inc",
+      OpUnaryOp::Dec => "dec",
+      OpUnaryOp::Not => "not",
+      OpUnaryOp::Neg => "neg",
+      OpUnaryOp::Mul => "mul",
+      OpUnaryOp::Imul => "imul",
+      OpUnaryOp::Div => "div",
+      OpUnaryOp::Idiv => "idiv",
+    }
+  }
+}
+
+impl OpShiftOp {
+  pub fn mnemonic(&self) -> &'static str {
+    match self {
+      OpShiftOp::Rol => "rol",
+      OpShiftOp::Ror => "ror",
+      OpShiftOp::Rcl => "rcl",
+      OpShiftOp::Rcr => "rcr",
+      OpShiftOp::Shl => "shl",
+      OpShiftOp::Shr => "shr",
+      OpShiftOp::Sal => "sal",
+      OpShiftOp::Sar => "sar",
+    }
+  }
+}
+
+impl OpCondJmpOp {
+  pub fn mnemonic(&self) -> &'static str {
+    match self {
+      OpCondJmpOp::Jo => "jo",
+      OpCondJmpOp::Jno => "jno",
+      OpCondJmpOp::Js => "js",
+      OpCondJmpOp::Jns => "jns",
+      OpCondJmpOp::Je => "je",
+      OpCondJmpOp::Jne => "jne",
+      OpCondJmpOp::Jb => "jb",
+      OpCondJmpOp::Jnb => "jnb",
+      OpCondJmpOp::Jbe => "jbe",
+      OpCondJmpOp::Ja => "ja",
+      OpCondJmpOp::Jl => "jl",
+      OpCondJmpOp::Jge => "jge",
+      OpCondJmpOp::Jle => "jle",
+      OpCondJmpOp::Jg => "jg",
+      OpCondJmpOp::Jp => "jp",
+      OpCondJmpOp::Jnp => "jnp",
+      OpCondJmpOp::Jcxz => "jcxz",
+      OpCondJmpOp::Loopne => "loopne",
+      OpCondJmpOp::Loope => "loope",
+      OpCondJmpOp::Loop => "loop",
+    }
+  }
+}
+
+impl OpNullaryOp {
+  pub fn mnemonic(&self) -> &'static str {
+    match self {
+      OpNullaryOp::Xlat => "xlatb",
+      OpNullaryOp::Lahf => "lahf",
+      OpNullaryOp::Sahf => "sahf",
+      OpNullaryOp::Pushf => "pushf",
+      OpNullaryOp::Popf => "popf",
+      OpNullaryOp::Aaa => "aaa",
+      OpNullaryOp::Daa => "daa",
+      OpNullaryOp::Aas => "aas",
+      OpNullaryOp::Das => "das",
+      OpNullaryOp::Cbw => "cbw",
+      OpNullaryOp::Cwd => "cwd",
+      OpNullaryOp::Into => "into",
+      OpNullaryOp::Iret => "iret",
+      OpNullaryOp::Clc => "clc",
+      OpNullaryOp::Cmc => "cmc",
+      OpNullaryOp::Stc => "stc",
+      OpNullaryOp::Cld => "cld",
+      OpNullaryOp::Std => "std",
+      OpNullaryOp::Cli => "cli",
+      OpNullaryOp::Sti => "sti",
+      OpNullaryOp::Hlt => "hlt",
+      OpNullaryOp::Wait => "wait",
+      OpNullaryOp::Salc => "salc",
+    }
+  }
+}
+
+fn size_suffix(size: &OpSize) -> &'static str {
+  match size {
+    OpSize::Byte => "b",
+    OpSize::Word => "w",
+  }
+}
+
+fn accumulator(size: &OpSize) -> &'static str {
+  match size {
+    OpSize::Byte => "al",
+    OpSize::Word => "ax",
+  }
+}
+
+// The target of a relative branch. `length` is the encoded length of the
+// branch, which is only used when the address isn't known; near jumps are
+// assumed to use the 3-byte form.
+fn format_target(next_ip: Option<u16>, offset: i16, length: i16) -> String {
+  match next_ip {
+    Some(ip) => format!("{:#06x}", ip.wrapping_add(offset as u16)),
+    None => {
+      let relative = offset as i32 + length as i32;
+      if relative < 0 {
+        format!("$-{:#x}", -relative)
+      } else {
+        format!("$+{:#x}", relative)
+      }
+    },
+  }
+}
+
+fn format_binary<T: OperandSize>(
+  op: &OpBinaryOp,
+  src: &Operand<T>,
+  dest: &Operand<T>,
+  segment: Option<RegisterWordType>,
+) -> String {
+  let sized = !is_register(src) && !is_register(dest);
+  format!("{} {}, {}", op.mnemonic(),
+    format_operand(dest, segment, sized),
+    format_operand(src, segment, sized))
+}
+
+fn format_unary<T: OperandSize>(
+  op: &OpUnaryOp,
+  dest: &Operand<T>,
+  segment: Option<RegisterWordType>,
+) -> String {
+  format!("{} {}", op.mnemonic(), format_operand(dest, segment, true))
+}
+
+fn format_shift<T: OperandSize>(
+  op: &OpShiftOp,
+  shift_type: &OpShiftType,
+  dest: &Operand<T>,
+  segment: Option<RegisterWordType>,
+) -> String {
+  let count = match shift_type {
+    OpShiftType::One => "1",
+    OpShiftType::Cl => "cl",
+  };
+  format!("{} {}, {}",
+    op.mnemonic(), format_operand(dest, segment, true), count)
+}
+
+fn format_call(
+  name: &str,
+  call_type: &OpCallType,
+  segment: Option<RegisterWordType>,
+  next_ip: Option<u16>,
+  length: i16,
+) -> String {
+  match call_type {
+    OpCallType::WithinDirect(offset) =>
+      format!("{} {}", name, format_target(next_ip, *offset, length)),
+    OpCallType::WithinIndirect(operand) =>
+      format!("{} {}", name, format_operand(operand, segment, true)),
+    OpCallType::InterDirect(ip, cs) =>
+      format!("{} far {:#06x}:{:#06x}", name, cs, ip),
+    OpCallType::InterIndirect(operand) =>
+      format!("{} far {}", name, format_operand(operand, segment, false)),
+  }
+}
+
+// Returns the text for the op, and whether the segment override was used
+// by one of its operands.
+fn format_op(
+  op: &Op,
+  segment: Option<RegisterWordType>,
+  next_ip: Option<u16>,
+) -> (String, bool) {
+  let text = match op {
+    Op::BinaryByte { op, src, dest } =>
+      format_binary(op, src, dest, segment),
+    Op::BinaryWord { op, src, dest } =>
+      format_binary(op, src, dest, segment),
+    Op::UnaryByte { op, dest } => format_unary(op, dest, segment),
+    Op::UnaryWord { op, dest } => format_unary(op, dest, segment),
+    Op::ShiftByte { op, shift_type, dest } =>
+      format_shift(op, shift_type, dest, segment),
+    Op::ShiftWord { op, shift_type, dest } =>
+      format_shift(op, shift_type, dest, segment),
+    Op::Nullary(op) => op.mnemonic().to_string(),
+    Op::CondJmp { op, offset } => format!("{} {}",
+      op.mnemonic(), format_target(next_ip, *offset as i16, 2)),
+    Op::InFixed(size) => format!("in {}, dx", accumulator(size)),
+    Op::InVariable(size, port) =>
+      format!("in {}, {:#x}", accumulator(size), port),
+    Op::OutFixed(size) => format!("out dx, {}", accumulator(size)),
+    Op::OutVariable(size, port) =>
+      format!("out {:#x}, {}", port, accumulator(size)),
+    Op::Lea(reg, operand) =>
+      format!("lea {}, {}", reg, format_operand(operand, segment, false)),
+    Op::Lds(reg, operand) =>
+      format!("lds {}, {}", reg, format_operand(operand, segment, false)),
+    Op::Les(reg, operand) =>
+      format!("les {}, {}", reg, format_operand(operand, segment, false)),
+    Op::Movs(size) => format!("movs{}", size_suffix(size)),
+    Op::Cmps(size) => format!("cmps{}", size_suffix(size)),
+    Op::Scas(size) => format!("scas{}", size_suffix(size)),
+    Op::Lods(size) => format!("lods{}", size_suffix(size)),
+    Op::Stos(size) => format!("stos{}", size_suffix(size)),
+    Op::Call(call_type) => format_call("call", call_type, segment, next_ip, 3),
+    Op::Jmp(call_type) => format_call("jmp", call_type, segment, next_ip, 3),
+    Op::JmpShort(offset) =>
+      format!("jmp {}", format_target(next_ip, *offset as i16, 2)),
+    Op::RetWithin => "ret".to_string(),
+    Op::RetWithinImm(value) => format!("ret {:#x}", value),
+    Op::RetInter => "retf".to_string(),
+    Op::RetInterImm(value) => format!("retf {:#x}", value),
+    Op::Aam(10) => "aam".to_string(),
+    Op::Aam(base) => format!("aam {:#x}", base),
+    Op::Aad(10) => "aad".to_string(),
+    Op::Aad(base) => format!("aad {:#x}", base),
+    Op::Int(3) => "int3".to_string(),
+    Op::Int(value) => format!("int {:#x}", value),
+    Op::Esc(code, operand) => format!("esc {:#x}, {}",
+      code, format_operand(operand, segment, false)),
+  };
+  (text, has_memory_operand(op))
+}
+
+fn has_memory_operand(op: &Op) -> bool {
+  match op {
+    Op::BinaryByte { src, dest, .. } => is_memory(src) || is_memory(dest),
+    Op::BinaryWord { src, dest, .. } => is_memory(src) || is_memory(dest),
+    Op::UnaryByte { dest, .. } => is_memory(dest),
+    Op::UnaryWord { dest, .. } => is_memory(dest),
+    Op::ShiftByte { dest, .. } => is_memory(dest),
+    Op::ShiftWord { dest, .. } => is_memory(dest),
+    Op::Lea(_, operand) | Op::Lds(_, operand) | Op::Les(_, operand) |
+    Op::Esc(_, operand) => is_memory(operand),
+    Op::Call(OpCallType::WithinIndirect(operand)) |
+    Op::Call(OpCallType::InterIndirect(operand)) |
+    Op::Jmp(OpCallType::WithinIndirect(operand)) |
+    Op::Jmp(OpCallType::InterIndirect(operand)) => is_memory(operand),
+    _ => false,
+  }
+}
+
+fn format_instruction(
+  instruction: &Instruction,
+  next_ip: Option<u16>,
+) -> String {
+  let prefixes = &instruction.prefixes;
+  let (text, used_segment) =
+    format_op(&instruction.op, prefixes.segment, next_ip);
+  let mut output = String::new();
+  if prefixes.lock {
+    output.push_str("lock ");
+  }
+  if let Some(repeat) = prefixes.repeat {
+    // CMPS and SCAS test ZF, so they're written with the conditional names
+    let compares = matches!(instruction.op, Op::Cmps(_) | Op::Scas(_));
+    output.push_str(match (repeat, compares) {
+      (OpRepeatType::Rep, false) => "rep ",
+      (OpRepeatType::Rep, true) => "repe ",
+      (OpRepeatType::Repnz, _) => "repne ",
+    });
+  }
+  if let (Some(segment), false) = (prefixes.segment, used_segment) {
+    // Written as a prefix when there's no memory operand to attach it to
+    output.push_str(&format!("{} ", segment));
+  }
+  output.push_str(&text);
+  output
+}
+
+impl fmt::Display for Op {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(&format_op(self, None, None).0)
+  }
+}
+
+impl fmt::Display for Instruction {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(&format_instruction(self, None))
+  }
+}
+
+impl fmt::Display for DecodedInstruction {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.op() {
+      Op::Esc(_, _) => {
+        // nasm has no mnemonic for ESC without a coprocessor; write the
+        // bytes as they are.
+        let bytes: Vec<String> =
+          self.bytes.iter().map(|value| format!("{:#04x}", value)).collect();
+        write!(f, "db {}", bytes.join(", "))
+      },
+      _ => f.write_str(&format_instruction(
+        &self.instruction, Some(self.next_ip()))),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::cpu::CpuModel;
+  use super::super::decode::*;
+
+  fn disassemble(bytes: &[u8]) -> String {
+    format!("{}", decode(bytes, 0, 0x100, CpuModel::I8086).unwrap())
+  }
+
+  #[test]
+  fn test_disasm() {
+    let cases: Vec<(Vec<u8>, &str)> = vec![
+      (vec![0xc7, 0x40, 0x10, 0x86, 0x80], "mov word [bx+si+0x10], 0x8086"),
+      (vec![0xea, 0x00, 0x00, 0x00, 0xf0], "jmp far 0xf000:0x0000"),
+      (vec![0x89, 0xc3], "mov bx, ax"),
+      (vec![0x8a, 0x46, 0xfe], "mov al, [bp-0x2]"),
+      (vec![0x26, 0xa1, 0x34, 0x12], "mov ax, [es:0x1234]"),
+      (vec![0x83, 0xc3, 0xff], "add bx, byte -0x1"),
+      (vec![0x80, 0x3f, 0x41], "cmp byte [bx], 0x41"),
+      (vec![0xfe, 0x07], "inc byte [bx]"),
+      (vec![0xd1, 0xe0], "shl ax, 1"),
+      (vec![0xd2, 0x2c], "shr byte [si], cl"),
+      (vec![0x74, 0xfe], "je 0x0100"),
+      (vec![0xeb, 0x10], "jmp 0x0112"),
+      (vec![0xe8, 0x00, 0x01], "call 0x0203"),
+      (vec![0xff, 0x1f], "call far [bx]"),
+      (vec![0xff, 0xe0], "jmp ax"),
+      (vec![0xf3, 0xa4], "rep movsb"),
+      (vec![0xf3, 0xa7], "repe cmpsw"),
+      (vec![0x2e, 0xac], "cs lodsb"),
+      (vec![0xe4, 0x60], "in al, 0x60"),
+      (vec![0xef], "out dx, ax"),
+      (vec![0xcd, 0x21], "int 0x21"),
+      (vec![0xcc], "int3"),
+      (vec![0xd4, 0x0a], "aam"),
+      (vec![0xca, 0x04, 0x00], "retf 0x4"),
+      (vec![0xf0, 0x87, 0x07], "lock xchg [bx], ax"),
+      (vec![0xd8, 0xc1], "db 0xd8, 0xc1"),
+    ];
+    for (input, text) in cases {
+      assert_eq!(disassemble(&input), text);
+    }
+  }
+
+  #[test]
+  fn test_disasm_op() {
+    // Without the address, branches are relative to the instruction
+    let decoded = decode(&[0x75, 0xfc], 0, 0, CpuModel::I8086).unwrap();
+    assert_eq!(format!("{}", decoded.op()), "jne $-0x2");
+    assert_eq!(format!("{}", decoded.instruction), "jne $-0x2");
+  }
+}
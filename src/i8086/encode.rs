@@ -0,0 +1,649 @@
+use std::error;
+use std::fmt;
+use super::op::*;
+use super::operand::*;
+use super::register::*;
+
+// Turns ops back into machine code. Each op is given its shortest encoding;
+// decoding the result always yields the same op again.
+
+#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug)]
+pub enum EncodeError {
+  // The operands can't be encoded together, e.g. a memory to memory move
+  // or a segment register used for arithmetic.
+  InvalidOperands,
+  // A segment prefix naming a general purpose register.
+  InvalidPrefix,
+}
+
+impl fmt::Display for EncodeError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(match self {
+      EncodeError::InvalidOperands => "invalid combination of operands",
+      EncodeError::InvalidPrefix => "invalid segment prefix",
+    })
+  }
+}
+
+impl error::Error for EncodeError {}
+
+type EncodeResult = Result<Vec<u8>, EncodeError>;
+
+trait RegisterEncodable: RegisterType + PartialEq + Sized {
+  // The W bit of the opcode.
+  fn width() -> u8;
+  // Index of a general purpose register, as stored in ModR/M.
+  fn index(&self) -> Option<u8>;
+  fn accumulator() -> Self;
+  fn push_imm(bytes: &mut Vec<u8>, operand: &Operand<Self>) -> Option<()>;
+}
+
+impl RegisterEncodable for RegisterByteType {
+  fn width() -> u8 { 0 }
+  fn index(&self) -> Option<u8> {
+    Some(match self {
+      RegisterByteType::Al => 0,
+      RegisterByteType::Cl => 1,
+      RegisterByteType::Dl => 2,
+      RegisterByteType::Bl => 3,
+      RegisterByteType::Ah => 4,
+      RegisterByteType::Ch => 5,
+      RegisterByteType::Dh => 6,
+      RegisterByteType::Bh => 7,
+    })
+  }
+  fn accumulator() -> Self { RegisterByteType::Al }
+  fn push_imm(bytes: &mut Vec<u8>, operand: &OperandByte) -> Option<()> {
+    match operand {
+      Operand::ImmByte(value) => bytes.push(*value),
+      _ => return None,
+    }
+    Some(())
+  }
+}
+
+impl RegisterEncodable for RegisterWordType {
+  fn width() -> u8 { 1 }
+  fn index(&self) -> Option<u8> {
+    Some(match self {
+      RegisterWordType::Ax => 0,
+      RegisterWordType::Cx => 1,
+      RegisterWordType::Dx => 2,
+      RegisterWordType::Bx => 3,
+      RegisterWordType::Sp => 4,
+      RegisterWordType::Bp => 5,
+      RegisterWordType::Si => 6,
+      RegisterWordType::Di => 7,
+      _ => return None,
+    })
+  }
+  fn accumulator() -> Self { RegisterWordType::Ax }
+  fn push_imm(bytes: &mut Vec<u8>, operand: &OperandWord) -> Option<()> {
+    match operand {
+      Operand::ImmWord(value) => push_u16(bytes, *value),
+      _ => return None,
+    }
+    Some(())
+  }
+}
+
+fn seg_index(reg: &RegisterWordType) -> Option<u8> {
+  Some(match reg {
+    RegisterWordType::Es => 0,
+    RegisterWordType::Cs => 1,
+    RegisterWordType::Ss => 2,
+    RegisterWordType::Ds => 3,
+    _ => return None,
+  })
+}
+
+fn push_u16(bytes: &mut Vec<u8>, value: u16) -> () {
+  bytes.push((value & 0xff) as u8);
+  bytes.push((value >> 8) as u8);
+}
+
+fn address_index(addr_type: &AddressType) -> u8 {
+  match addr_type {
+    AddressType::BxSi => 0,
+    AddressType::BxDi => 1,
+    AddressType::BpSi => 2,
+    AddressType::BpDi => 3,
+    AddressType::Si => 4,
+    AddressType::Di => 5,
+    AddressType::Bp => 6,
+    AddressType::Bx => 7,
+  }
+}
+
+fn is_register<T: RegisterEncodable>(operand: &Operand<T>) -> bool {
+  match operand {
+    Operand::Register(reg) => reg.index().is_some(),
+    _ => false,
+  }
+}
+
+fn is_memory<T: RegisterType>(operand: &Operand<T>) -> bool {
+  matches!(operand, Operand::Address(_, _) | Operand::Direct(_))
+}
+
+fn is_accumulator<T: RegisterEncodable>(operand: &Operand<T>) -> bool {
+  match operand {
+    Operand::Register(reg) => *reg == T::accumulator(),
+    _ => false,
+  }
+}
+
+fn register_index<T: RegisterEncodable>(
+  operand: &Operand<T>,
+) -> Option<u8> {
+  match operand {
+    Operand::Register(reg) => reg.index(),
+    _ => None,
+  }
+}
+
+// Appends the ModR/M byte for the operand, and its displacement.
+fn push_mod_rm<T: RegisterEncodable>(
+  bytes: &mut Vec<u8>,
+  operand: &Operand<T>,
+  reg: u8,
+) -> Option<()> {
+  let reg = (reg & 0x07) << 3;
+  match operand {
+    Operand::Register(register) => bytes.push(0xc0 | reg | register.index()?),
+    Operand::Direct(offset) => {
+      bytes.push(reg | 0x06);
+      push_u16(bytes, *offset);
+    },
+    Operand::Address(addr_type, offset) => {
+      let rm = address_index(addr_type);
+      if *offset == 0 && *addr_type != AddressType::Bp {
+        bytes.push(reg | rm);
+      } else if *offset >= -0x80 && *offset <= 0x7f {
+        bytes.push(0x40 | reg | rm);
+        bytes.push(*offset as u8);
+      } else {
+        bytes.push(0x80 | reg | rm);
+        push_u16(bytes, *offset as u16);
+      }
+    },
+    _ => return None,
+  }
+  Some(())
+}
+
+fn with_mod_rm<T: RegisterEncodable>(
+  opcode: u8,
+  operand: &Operand<T>,
+  reg: u8,
+) -> Option<Vec<u8>> {
+  let mut bytes = vec![opcode];
+  push_mod_rm(&mut bytes, operand, reg)?;
+  Some(bytes)
+}
+
+fn immed_index(op: &OpBinaryOp) -> Option<u8> {
+  Some(match op {
+    OpBinaryOp::Add => 0,
+    OpBinaryOp::Or => 1,
+    OpBinaryOp::Adc => 2,
+    OpBinaryOp::Sbb => 3,
+    OpBinaryOp::And => 4,
+    OpBinaryOp::Sub => 5,
+    OpBinaryOp::Xor => 6,
+    OpBinaryOp::Cmp => 7,
+    _ => return None,
+  })
+}
+
+fn encode_binary<T: RegisterEncodable>(
+  op: &OpBinaryOp,
+  src: &Operand<T>,
+  dest: &Operand<T>,
+) -> Option<Vec<u8>> {
+  let w = T::width();
+  let is_imm = matches!(src, Operand::ImmByte(_) | Operand::ImmWord(_));
+  if is_imm && !is_register(dest) && !is_memory(dest) {
+    return None;
+  }
+  match op {
+    OpBinaryOp::Mov => {
+      if let (true, Operand::Direct(offset)) = (is_accumulator(dest), src) {
+        // MOV AL/AX, moffs
+        let mut bytes = vec![0xa0 | w];
+        push_u16(&mut bytes, *offset);
+        return Some(bytes);
+      }
+      if let (Operand::Direct(offset), true) = (dest, is_accumulator(src)) {
+        // MOV moffs, AL/AX
+        let mut bytes = vec![0xa2 | w];
+        push_u16(&mut bytes, *offset);
+        return Some(bytes);
+      }
+      if is_imm {
+        let mut bytes = match register_index(dest) {
+          Some(index) => vec![0xb0 | (w << 3) | index],
+          None => with_mod_rm(0xc6 | w, dest, 0)?,
+        };
+        T::push_imm(&mut bytes, src)?;
+        return Some(bytes);
+      }
+      encode_reg_mod_rm(0x88, src, dest)
+    },
+    OpBinaryOp::Test => {
+      if is_imm {
+        let mut bytes = if is_accumulator(dest) {
+          vec![0xa8 | w]
+        } else {
+          with_mod_rm(0xf6 | w, dest, 0)?
+        };
+        T::push_imm(&mut bytes, src)?;
+        return Some(bytes);
+      }
+      // TEST r/m, reg is the only form
+      with_mod_rm(0x84 | w, dest, register_index(src)?)
+    },
+    OpBinaryOp::Xchg => {
+      if w == 1 && is_accumulator(src) {
+        if let Some(index) = register_index(dest) {
+          // XCHG AX, reg16
+          return Some(vec![0x90 | index]);
+        }
+      }
+      with_mod_rm(0x86 | w, dest, register_index(src)?)
+    },
+    _ => {
+      let index = immed_index(op)?;
+      match src {
+        Operand::ImmByte(value) if w == 1 => {
+          // Sign extended byte immediate
+          let mut bytes = with_mod_rm(0x83, dest, index)?;
+          bytes.push(*value);
+          Some(bytes)
+        },
+        Operand::ImmByte(_) | Operand::ImmWord(_) => {
+          let mut bytes = if is_accumulator(dest) {
+            vec![(index << 3) | 0x04 | w]
+          } else {
+            with_mod_rm(0x80 | w, dest, index)?
+          };
+          T::push_imm(&mut bytes, src)?;
+          Some(bytes)
+        },
+        _ => encode_reg_mod_rm(index << 3, src, dest),
+      }
+    },
+  }
+}
+
+// The "op r/m, reg" and "op reg, r/m" forms; the first is preferred when
+// both operands are registers.
+fn encode_reg_mod_rm<T: RegisterEncodable>(
+  base: u8,
+  src: &Operand<T>,
+  dest: &Operand<T>,
+) -> Option<Vec<u8>> {
+  let w = T::width();
+  if let Some(index) = register_index(src) {
+    return with_mod_rm(base | w, dest, index);
+  }
+  if !is_memory(src) {
+    return None;
+  }
+  with_mod_rm(base | 0x02 | w, src, register_index(dest)?)
+}
+
+fn encode_binary_word(
+  op: &OpBinaryOp,
+  src: &OperandWord,
+  dest: &OperandWord,
+) -> Option<Vec<u8>> {
+  // Segment registers can only be moved
+  match (op, src, dest) {
+    (OpBinaryOp::Mov, Operand::Register(reg), _) if seg_index(reg).is_some() =>
+      with_mod_rm(0x8c, dest, seg_index(reg)?),
+    (OpBinaryOp::Mov, _, Operand::Register(reg)) if seg_index(reg).is_some() =>
+      with_mod_rm(0x8e, src, seg_index(reg)?),
+    _ => encode_binary(op, src, dest),
+  }
+}
+
+fn grp1_index(op: &OpUnaryOp) -> Option<u8> {
+  Some(match op {
+    OpUnaryOp::Not => 2,
+    OpUnaryOp::Neg => 3,
+    OpUnaryOp::Mul => 4,
+    OpUnaryOp::Imul => 5,
+    OpUnaryOp::Div => 6,
+    OpUnaryOp::Idiv => 7,
+    _ => return None,
+  })
+}
+
+fn encode_unary_byte(op: &OpUnaryOp, dest: &OperandByte) -> Option<Vec<u8>> {
+  match op {
+    OpUnaryOp::Inc => with_mod_rm(0xfe, dest, 0),
+    OpUnaryOp::Dec => with_mod_rm(0xfe, dest, 1),
+    OpUnaryOp::Push => with_mod_rm(0xfe, dest, 6),
+    OpUnaryOp::Pop => None,
+    _ => with_mod_rm(0xf6, dest, grp1_index(op)?),
+  }
+}
+
+fn encode_unary_word(op: &OpUnaryOp, dest: &OperandWord) -> Option<Vec<u8>> {
+  if let Operand::Register(reg) = dest {
+    if let Some(index) = seg_index(reg) {
+      return match op {
+        OpUnaryOp::Push => Some(vec![0x06 | (index << 3)]),
+        OpUnaryOp::Pop => Some(vec![0x07 | (index << 3)]),
+        _ => None,
+      };
+    }
+  }
+  let index = register_index(dest);
+  match (op, index) {
+    (OpUnaryOp::Inc, Some(index)) => Some(vec![0x40 | index]),
+    (OpUnaryOp::Dec, Some(index)) => Some(vec![0x48 | index]),
+    (OpUnaryOp::Push, Some(index)) => Some(vec![0x50 | index]),
+    (OpUnaryOp::Pop, Some(index)) => Some(vec![0x58 | index]),
+    (OpUnaryOp::Inc, None) => with_mod_rm(0xff, dest, 0),
+    (OpUnaryOp::Dec, None) => with_mod_rm(0xff, dest, 1),
+    (OpUnaryOp::Push, None) => with_mod_rm(0xff, dest, 6),
+    (OpUnaryOp::Pop, None) => with_mod_rm(0x8f, dest, 0),
+    _ => with_mod_rm(0xf7, dest, grp1_index(op)?),
+  }
+}
+
+fn encode_shift<T: RegisterEncodable>(
+  op: &OpShiftOp,
+  shift_type: &OpShiftType,
+  dest: &Operand<T>,
+) -> Option<Vec<u8>> {
+  let index = match op {
+    OpShiftOp::Rol => 0,
+    OpShiftOp::Ror => 1,
+    OpShiftOp::Rcl => 2,
+    OpShiftOp::Rcr => 3,
+    OpShiftOp::Shl => 4,
+    OpShiftOp::Shr => 5,
+    OpShiftOp::Sal => 6,
+    OpShiftOp::Sar => 7,
+  };
+  let count = match shift_type {
+    OpShiftType::One => 0,
+    OpShiftType::Cl => 2,
+  };
+  with_mod_rm(0xd0 | count | T::width(), dest, index)
+}
+
+fn encode_nullary(op: &OpNullaryOp) -> u8 {
+  match op {
+    OpNullaryOp::Xlat => 0xd7,
+    OpNullaryOp::Lahf => 0x9f,
+    OpNullaryOp::Sahf => 0x9e,
+    OpNullaryOp::Pushf => 0x9c,
+    OpNullaryOp::Popf => 0x9d,
+    OpNullaryOp::Aaa => 0x37,
+    OpNullaryOp::Daa => 0x27,
+    OpNullaryOp::Aas => 0x3f,
+    OpNullaryOp::Das => 0x2f,
+    OpNullaryOp::Cbw => 0x98,
+    OpNullaryOp::Cwd => 0x99,
+    OpNullaryOp::Into => 0xce,
+    OpNullaryOp::Iret => 0xcf,
+    OpNullaryOp::Clc => 0xf8,
+    OpNullaryOp::Cmc => 0xf5,
+    OpNullaryOp::Stc => 0xf9,
+    OpNullaryOp::Cld => 0xfc,
+    OpNullaryOp::Std => 0xfd,
+    OpNullaryOp::Cli => 0xfa,
+    OpNullaryOp::Sti => 0xfb,
+    OpNullaryOp::Hlt => 0xf4,
+    OpNullaryOp::Wait => 0x9b,
+    OpNullaryOp::Salc => 0xd6,
+  }
+}
+
+fn encode_cond_jmp(op: &OpCondJmpOp) -> u8 {
+  match op {
+    OpCondJmpOp::Jo => 0x70,
+    OpCondJmpOp::Jno => 0x71,
+    OpCondJmpOp::Jb => 0x72,
+    OpCondJmpOp::Jnb => 0x73,
+    OpCondJmpOp::Je => 0x74,
+    OpCondJmpOp::Jne => 0x75,
+    OpCondJmpOp::Jbe => 0x76,
+    OpCondJmpOp::Ja => 0x77,
+    OpCondJmpOp::Js => 0x78,
+    OpCondJmpOp::Jns => 0x79,
+    OpCondJmpOp::Jp => 0x7a,
+    OpCondJmpOp::Jnp => 0x7b,
+    OpCondJmpOp::Jl => 0x7c,
+    OpCondJmpOp::Jge => 0x7d,
+    OpCondJmpOp::Jle => 0x7e,
+    OpCondJmpOp::Jg => 0x7f,
+    OpCondJmpOp::Loopne => 0xe0,
+    OpCondJmpOp::Loope => 0xe1,
+    OpCondJmpOp::Loop => 0xe2,
+    OpCondJmpOp::Jcxz => 0xe3,
+  }
+}
+
+fn size_bit(size: &OpSize) -> u8 {
+  match size {
+    OpSize::Byte => 0,
+    OpSize::Word => 1,
+  }
+}
+
+fn encode_call(
+  call_type: &OpCallType,
+  is_call: bool,
+) -> Option<Vec<u8>> {
+  match call_type {
+    OpCallType::WithinDirect(offset) => {
+      let mut bytes = vec![if is_call { 0xe8 } else { 0xe9 }];
+      push_u16(&mut bytes, *offset as u16);
+      Some(bytes)
+    },
+    OpCallType::WithinIndirect(operand) =>
+      with_mod_rm(0xff, operand, if is_call { 2 } else { 4 }),
+    OpCallType::InterDirect(ip, cs) => {
+      let mut bytes = vec![if is_call { 0x9a } else { 0xea }];
+      push_u16(&mut bytes, *ip);
+      push_u16(&mut bytes, *cs);
+      Some(bytes)
+    },
+    OpCallType::InterIndirect(operand) => {
+      if !is_memory(operand) {
+        return None;
+      }
+      with_mod_rm(0xff, operand, if is_call { 3 } else { 5 })
+    },
+  }
+}
+
+fn with_u16(opcode: u8, value: u16) -> Vec<u8> {
+  let mut bytes = vec![opcode];
+  push_u16(&mut bytes, value);
+  bytes
+}
+
+fn encode_memory_op(
+  opcode: u8,
+  reg: &RegisterWordType,
+  operand: &OperandWord,
+) -> Option<Vec<u8>> {
+  if !is_memory(operand) {
+    return None;
+  }
+  with_mod_rm(opcode, operand, reg.index()?)
+}
+
+pub fn encode(op: &Op) -> EncodeResult {
+  let bytes = match op {
+    Op::BinaryByte { op, src, dest } => encode_binary(op, src, dest),
+    Op::BinaryWord { op, src, dest } => encode_binary_word(op, src, dest),
+    Op::UnaryByte { op, dest } => encode_unary_byte(op, dest),
+    Op::UnaryWord { op, dest } => encode_unary_word(op, dest),
+    Op::ShiftByte { op, shift_type, dest } =>
+      encode_shift(op, shift_type, dest),
+    Op::ShiftWord { op, shift_type, dest } =>
+      encode_shift(op, shift_type, dest),
+    Op::Nullary(op) => Some(vec![encode_nullary(op)]),
+    Op::CondJmp { op, offset } =>
+      Some(vec![encode_cond_jmp(op), *offset as u8]),
+    Op::JmpShort(offset) => Some(vec![0xeb, *offset as u8]),
+    Op::InFixed(size) => Some(vec![0xec | size_bit(size)]),
+    Op::InVariable(size, port) => Some(vec![0xe4 | size_bit(size), *port]),
+    Op::OutFixed(size) => Some(vec![0xee | size_bit(size)]),
+    Op::OutVariable(size, port) => Some(vec![0xe6 | size_bit(size), *port]),
+    Op::Lea(reg, operand) => encode_memory_op(0x8d, reg, operand),
+    Op::Lds(reg, operand) => encode_memory_op(0xc5, reg, operand),
+    Op::Les(reg, operand) => encode_memory_op(0xc4, reg, operand),
+    Op::Movs(size) => Some(vec![0xa4 | size_bit(size)]),
+    Op::Cmps(size) => Some(vec![0xa6 | size_bit(size)]),
+    Op::Stos(size) => Some(vec![0xaa | size_bit(size)]),
+    Op::Lods(size) => Some(vec![0xac | size_bit(size)]),
+    Op::Scas(size) => Some(vec![0xae | size_bit(size)]),
+    Op::Call(call_type) => encode_call(call_type, true),
+    Op::Jmp(call_type) => encode_call(call_type, false),
+    Op::RetWithin => Some(vec![0xc3]),
+    Op::RetWithinImm(value) => Some(with_u16(0xc2, *value)),
+    Op::RetInter => Some(vec![0xcb]),
+    Op::RetInterImm(value) => Some(with_u16(0xca, *value)),
+    Op::Aam(base) => Some(vec![0xd4, *base]),
+    Op::Aad(base) => Some(vec![0xd5, *base]),
+    Op::Int(3) => Some(vec![0xcc]),
+    Op::Int(value) => Some(vec![0xcd, *value]),
+    Op::Esc(code, operand) =>
+      with_mod_rm(0xd8 | ((code >> 3) & 0x07), operand, *code),
+  };
+  bytes.ok_or(EncodeError::InvalidOperands)
+}
+
+pub fn encode_prefixes(prefixes: &OpPrefixes) -> EncodeResult {
+  let mut bytes = vec![];
+  if prefixes.lock {
+    bytes.push(0xf0);
+  }
+  match prefixes.repeat {
+    Some(OpRepeatType::Rep) => bytes.push(0xf3),
+    Some(OpRepeatType::Repnz) => bytes.push(0xf2),
+    None => (),
+  }
+  if let Some(segment) = prefixes.segment {
+    let index = seg_index(&segment).ok_or(EncodeError::InvalidPrefix)?;
+    bytes.push(0x26 | (index << 3));
+  }
+  Ok(bytes)
+}
+
+pub fn encode_instruction(instruction: &Instruction) -> EncodeResult {
+  let mut bytes = encode_prefixes(&instruction.prefixes)?;
+  bytes.extend(encode(&instruction.op)?);
+  Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::cpu::CpuModel;
+  use super::super::decode::*;
+
+  #[test]
+  fn test_encode() {
+    let cases: Vec<(Op, Vec<u8>)> = vec![
+      (Op::BinaryWord {
+        op: OpBinaryOp::Mov,
+        src: Operand::ImmWord(0x8086),
+        dest: Operand::Address(AddressType::BxSi, 0x10),
+      }, vec![0xc7, 0x40, 0x10, 0x86, 0x80]),
+      (Op::BinaryWord {
+        op: OpBinaryOp::Mov,
+        src: Operand::ImmWord(0x1234),
+        dest: Operand::Register(RegisterWordType::Cx),
+      }, vec![0xb9, 0x34, 0x12]),
+      (Op::BinaryByte {
+        op: OpBinaryOp::Add,
+        src: Operand::ImmByte(0x12),
+        dest: Operand::Register(RegisterByteType::Al),
+      }, vec![0x04, 0x12]),
+      (Op::BinaryWord {
+        op: OpBinaryOp::Mov,
+        src: Operand::Address(AddressType::Bp, 0),
+        dest: Operand::Register(RegisterWordType::Es),
+      }, vec![0x8e, 0x46, 0x00]),
+      (Op::UnaryWord {
+        op: OpUnaryOp::Push,
+        dest: Operand::Register(RegisterWordType::Ds),
+      }, vec![0x1e]),
+      (Op::Jmp(OpCallType::WithinDirect(0x100)), vec![0xe9, 0x00, 0x01]),
+      (Op::Jmp(OpCallType::WithinDirect(-2)), vec![0xe9, 0xfe, 0xff]),
+      (Op::JmpShort(-2), vec![0xeb, 0xfe]),
+      (Op::Int(3), vec![0xcc]),
+    ];
+    for (op, bytes) in cases {
+      assert_eq!(encode(&op), Ok(bytes), "{}", op);
+    }
+    // Memory to memory
+    assert_eq!(encode(&Op::BinaryByte {
+      op: OpBinaryOp::Add,
+      src: Operand::Direct(0x1234),
+      dest: Operand::Address(AddressType::Bx, 0),
+    }), Err(EncodeError::InvalidOperands));
+    // Arithmetic on segment registers
+    assert_eq!(encode(&Op::UnaryWord {
+      op: OpUnaryOp::Inc,
+      dest: Operand::Register(RegisterWordType::Es),
+    }), Err(EncodeError::InvalidOperands));
+  }
+
+  fn branch_target(decoded: &DecodedInstruction) -> Option<u16> {
+    let offset = match decoded.op() {
+      Op::Call(OpCallType::WithinDirect(offset)) |
+      Op::Jmp(OpCallType::WithinDirect(offset)) => *offset,
+      Op::JmpShort(offset) | Op::CondJmp { offset, .. } => *offset as i16,
+      _ => return None,
+    };
+    Some(decoded.next_ip().wrapping_add(offset as u16))
+  }
+
+  #[test]
+  fn test_encode_round_trip() {
+    // Every opcode with every ModR/M byte, followed by a few displacement
+    // and immediate bytes of both signs.
+    let tails: [[u8; 4]; 3] = [
+      [0x12, 0x34, 0x56, 0x78],
+      [0x80, 0xff, 0x7f, 0x00],
+      [0x00, 0x00, 0x00, 0x00],
+    ];
+    for first in 0..=0xffu16 {
+      for second in 0..=0xffu16 {
+        for tail in tails.iter() {
+          let mut input = vec![first as u8, second as u8];
+          input.extend(tail.iter());
+          let decoded = match decode(&input, 0, 0x100, CpuModel::I8086) {
+            Ok(decoded) => decoded,
+            Err(_) => continue,
+          };
+          let encoded = encode_instruction(&decoded.instruction)
+            .unwrap_or_else(|err| panic!("{} {:02x?}", err, input));
+          assert_eq!(
+            parse_op(&mut encoded.clone().into_iter()).as_ref(),
+            Ok(&decoded.instruction),
+            "{:02x?} -> {:02x?}", input, encoded,
+          );
+          // Relative branches still go to the same place
+          let redecoded = decode(&encoded, 0, 0x100, CpuModel::I8086)
+            .unwrap();
+          assert_eq!(branch_target(&redecoded), branch_target(&decoded),
+            "{:02x?} -> {:02x?}", input, encoded);
+          // Never longer than what it was decoded from
+          assert!(encoded.len() <= decoded.length(), "{:02x?}", input);
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,39 @@
+pub const INT_DE: u8 = 0x0;
+pub const INT_DB: u8 = 0x1;
+pub const INT_BP: u8 = 0x3;
+pub const INT_OF: u8 = 0x4;
+pub const INT_UD: u8 = 0x6;
+
+#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug)]
+pub enum Exception {
+  DivideError,
+  SingleStep,
+  Breakpoint,
+  Overflow,
+  InvalidOpcode,
+}
+
+impl Exception {
+  pub fn vector(&self) -> u8 {
+    match self {
+      Exception::DivideError => INT_DE,
+      Exception::SingleStep => INT_DB,
+      Exception::Breakpoint => INT_BP,
+      Exception::Overflow => INT_OF,
+      Exception::InvalidOpcode => INT_UD,
+    }
+  }
+  // Whether the return address points to the faulting instruction itself,
+  // rather than the next one. Note that 8086 returns to the next instruction
+  // after a divide error, unlike later processors.
+  pub fn is_fault(&self) -> bool {
+    match self {
+      Exception::DivideError => false,
+      Exception::SingleStep => false,
+      Exception::Breakpoint => false,
+      Exception::Overflow => false,
+      Exception::InvalidOpcode => true,
+    }
+  }
+}
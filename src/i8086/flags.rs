@@ -5,11 +5,10 @@ impl CPU {
     self.register.flags
   }
   pub fn set_flags(&mut self, value: u16) -> () {
-    self.register.flags = value;
+    self.register.flags = (value & !RESERVED_CLEAR) | RESERVED_SET;
   }
   pub fn blit_flags(&mut self, clear: u16, set: u16) -> () {
-    self.register.flags =
-      (self.register.flags & !clear) | set;
+    self.set_flags((self.register.flags & !clear) | set);
   }
 }
 
@@ -22,3 +21,6 @@ pub const TF: u16 = 0x0100;
 pub const IF: u16 = 0x0200;
 pub const DF: u16 = 0x0400;
 pub const OF: u16 = 0x0800;
+// Bits 12-15 and 1 always read as 1, bits 3 and 5 as 0.
+pub const RESERVED_SET: u16 = 0xF002;
+pub const RESERVED_CLEAR: u16 = 0x0028;
@@ -0,0 +1,582 @@
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use super::cpu::CPU;
+use super::debugger::*;
+use super::exception::Exception;
+
+// A stub for GDB's remote serial protocol, to be used with
+// `set architecture i8086` and `target remote`.
+//
+// GDB knows nothing about segments, so addresses are linear, and so is the
+// program counter: `eip` reads as CS * 16 + IP. This keeps `x/i $pc` and
+// `break *0x7c00` working the way they look in real mode.
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+
+// Instructions run between checks for an interrupt from GDB.
+const RUN_SLICE: u64 = 10000;
+
+// The end of addressable memory, including HMA.
+const MEMORY_END: usize = 0x10fff0;
+
+pub trait Connection: Read + Write {
+  // Reads are made non-blocking while the CPU runs, to look for a ^C.
+  fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+  fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+    TcpStream::set_nonblocking(self, nonblocking)
+  }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+  fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+    UnixStream::set_nonblocking(self, nonblocking)
+  }
+}
+
+// Waits for GDB on a TCP address such as "127.0.0.1:1234", and serves it
+// until it detaches.
+pub fn listen_tcp(cpu: &mut CPU, address: &str) -> io::Result<()> {
+  let (stream, _) = TcpListener::bind(address)?.accept()?;
+  stream.set_nodelay(true)?;
+  GdbStub::new(stream).serve(cpu)
+}
+
+// Same as above, on a Unix socket; `target remote | socat - UNIX:path`.
+#[cfg(unix)]
+pub fn listen_unix(cpu: &mut CPU, path: &str) -> io::Result<()> {
+  let (stream, _) = UnixListener::bind(path)?.accept()?;
+  GdbStub::new(stream).serve(cpu)
+}
+
+pub struct GdbStub<C: Connection> {
+  connection: C,
+  // Bytes read while looking for a ^C, to be handled later
+  buffer: Vec<u8>,
+  no_ack: bool,
+}
+
+enum Resume {
+  Continue,
+  Step,
+}
+
+fn checksum(data: &[u8]) -> u8 {
+  data.iter().fold(0u8, |sum, value| sum.wrapping_add(*value))
+}
+
+fn hex_u32(value: u32) -> String {
+  value.to_le_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+  usize::from_str_radix(text, 16).ok()
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+  if !text.len().is_multiple_of(2) {
+    return None;
+  }
+  (0..text.len()).step_by(2)
+    .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+    .collect()
+}
+
+// Registers in the order of GDB's i386 `g` packet: eax, ecx, edx, ebx, esp,
+// ebp, esi, edi, eip, eflags, cs, ss, ds, es, fs and gs.
+const REGISTER_COUNT: usize = 16;
+
+fn read_register(cpu: &CPU, index: usize) -> u32 {
+  let register = &cpu.register;
+  match index {
+    0 => register.ax as u32,
+    1 => register.cx as u32,
+    2 => register.dx as u32,
+    3 => register.bx as u32,
+    4 => register.sp as u32,
+    5 => register.bp as u32,
+    6 => register.si as u32,
+    7 => register.di as u32,
+    8 => cpu.get_linear_addr(register.cs, register.ip) as u32,
+    9 => cpu.get_flags() as u32,
+    10 => register.cs as u32,
+    11 => register.ss as u32,
+    12 => register.ds as u32,
+    13 => register.es as u32,
+    _ => 0,
+  }
+}
+
+fn write_register(cpu: &mut CPU, index: usize, value: u32) -> () {
+  let word = value as u16;
+  match index {
+    0 => cpu.register.ax = word,
+    1 => cpu.register.cx = word,
+    2 => cpu.register.dx = word,
+    3 => cpu.register.bx = word,
+    4 => cpu.register.sp = word,
+    5 => cpu.register.bp = word,
+    6 => cpu.register.si = word,
+    7 => cpu.register.di = word,
+    8 => {
+      let base = (cpu.register.cs as u32) << 4;
+      cpu.register.ip = value.wrapping_sub(base) as u16;
+    },
+    9 => cpu.set_flags(word),
+    10 => cpu.register.cs = word,
+    11 => cpu.register.ss = word,
+    12 => cpu.register.ds = word,
+    13 => cpu.register.es = word,
+    _ => (),
+  }
+}
+
+fn exception_signal(exception: Exception) -> u8 {
+  match exception {
+    Exception::DivideError => SIGFPE,
+    Exception::InvalidOpcode => SIGILL,
+    _ => SIGTRAP,
+  }
+}
+
+fn stop_reply(cpu: &CPU, reason: &StopReason) -> String {
+  match reason {
+    StopReason::Watchpoint { address, .. } => {
+      let kind = cpu.debugger.watchpoints.iter()
+        .find(|watch| *address >= watch.start &&
+          *address - watch.start < watch.length)
+        .map(|watch| watch.kind)
+        .unwrap_or(WatchKind::Access);
+      let name = match kind {
+        WatchKind::Write => "watch",
+        WatchKind::Read => "rwatch",
+        WatchKind::Access => "awatch",
+      };
+      format!("T{:02x}{}:{:x};", SIGTRAP, name, address)
+    },
+    StopReason::DecodeError(_) => format!("S{:02x}", SIGILL),
+    _ => format!("S{:02x}", SIGTRAP),
+  }
+}
+
+impl<C: Connection> GdbStub<C> {
+  pub fn new(connection: C) -> Self {
+    GdbStub { connection, buffer: vec![], no_ack: false }
+  }
+
+  fn read_byte(&mut self) -> io::Result<Option<u8>> {
+    if !self.buffer.is_empty() {
+      return Ok(Some(self.buffer.remove(0)));
+    }
+    let mut byte = [0];
+    match self.connection.read(&mut byte)? {
+      0 => Ok(None),
+      _ => Ok(Some(byte[0])),
+    }
+  }
+
+  // Reads the next packet, acknowledging it; None when GDB has gone.
+  fn read_packet(&mut self) -> io::Result<Option<String>> {
+    loop {
+      // Skip acks, and ^C that arrive while stopped
+      loop {
+        match self.read_byte()? {
+          None => return Ok(None),
+          Some(b'$') => break,
+          Some(_) => (),
+        }
+      }
+      let mut data = vec![];
+      loop {
+        match self.read_byte()? {
+          None => return Ok(None),
+          Some(b'#') => break,
+          Some(byte) => data.push(byte),
+        }
+      }
+      let mut sum = vec![];
+      for _ in 0..2 {
+        match self.read_byte()? {
+          None => return Ok(None),
+          Some(byte) => sum.push(byte),
+        }
+      }
+      let valid = std::str::from_utf8(&sum).ok()
+        .and_then(|sum| u8::from_str_radix(sum, 16).ok()) ==
+        Some(checksum(&data));
+      if !self.no_ack {
+        self.connection.write_all(if valid { b"+" } else { b"-" })?;
+        self.connection.flush()?;
+      }
+      if valid {
+        return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+      }
+    }
+  }
+
+  fn send(&mut self, data: &str) -> io::Result<()> {
+    loop {
+      write!(self.connection, "${}#{:02x}", data, checksum(data.as_bytes()))?;
+      self.connection.flush()?;
+      if self.no_ack {
+        return Ok(());
+      }
+      match self.read_byte()? {
+        Some(b'-') => continue,
+        Some(b'+') | None => return Ok(()),
+        Some(byte) => {
+          self.buffer.insert(0, byte);
+          return Ok(());
+        },
+      }
+    }
+  }
+
+  // Whether GDB has sent a ^C.
+  fn poll_interrupt(&mut self) -> io::Result<bool> {
+    let mut bytes = [0; 64];
+    self.connection.set_nonblocking(true)?;
+    let result = self.connection.read(&mut bytes);
+    self.connection.set_nonblocking(false)?;
+    match result {
+      // The connection is closed; stop, and let the main loop find out.
+      Ok(0) => Ok(true),
+      Ok(length) => {
+        let bytes = &bytes[..length];
+        self.buffer.extend(bytes.iter().filter(|&&byte| byte != 0x03));
+        Ok(bytes.contains(&0x03))
+      },
+      Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+      Err(err) => Err(err),
+    }
+  }
+
+  fn resume(&mut self, cpu: &mut CPU, resume: Resume) -> io::Result<String> {
+    let mut first = true;
+    loop {
+      // `run_until` only skips a breakpoint at CS:IP to get off it, which
+      // is right for the first slice alone.
+      if !first && !cpu.halted {
+        let (cs, ip) = (cpu.register.cs, cpu.register.ip);
+        if let Some(breakpoint) = cpu.breakpoint_at(cs, ip) {
+          return Ok(stop_reply(cpu, &StopReason::Breakpoint(breakpoint)));
+        }
+      }
+      first = false;
+      let budget = match resume {
+        Resume::Continue => RUN_SLICE,
+        Resume::Step => 1,
+      };
+      match cpu.run_until(budget) {
+        Ok(StopReason::BudgetExhausted) => {
+          if let Resume::Step = resume {
+            return Ok(format!("S{:02x}", SIGTRAP));
+          }
+          if self.poll_interrupt()? {
+            return Ok(format!("S{:02x}", SIGINT));
+          }
+        },
+        Ok(reason) => return Ok(stop_reply(cpu, &reason)),
+        Err(exception) =>
+          return Ok(format!("S{:02x}", exception_signal(exception))),
+      }
+    }
+  }
+
+  // Serves GDB until it detaches, kills the target or disconnects.
+  pub fn serve(&mut self, cpu: &mut CPU) -> io::Result<()> {
+    while let Some(packet) = self.read_packet()? {
+      let reply = match packet.as_bytes().first() {
+        Some(b'k') => return Ok(()),
+        Some(b'D') => {
+          self.send("OK")?;
+          return Ok(());
+        },
+        Some(b'c') | Some(b's') => {
+          if let Some(address) = parse_hex(&packet[1..]) {
+            write_register(cpu, 8, address as u32);
+          }
+          let resume = if packet.starts_with('c') {
+            Resume::Continue
+          } else {
+            Resume::Step
+          };
+          self.resume(cpu, resume)?
+        },
+        _ => self.handle(cpu, &packet),
+      };
+      self.send(&reply)?;
+      if packet == "QStartNoAckMode" {
+        self.no_ack = true;
+      }
+    }
+    Ok(())
+  }
+
+  fn handle(&mut self, cpu: &mut CPU, packet: &str) -> String {
+    let error = "E01".to_string();
+    let ok = "OK".to_string();
+    let (command, args) = packet.split_at(packet.len().min(1));
+    match command {
+      "?" => format!("S{:02x}", SIGTRAP),
+      "g" => (0..REGISTER_COUNT)
+        .map(|index| hex_u32(read_register(cpu, index)))
+        .collect(),
+      "G" => match parse_hex_bytes(args) {
+        Some(ref bytes) if bytes.len() >= REGISTER_COUNT * 4 => {
+          for (index, chunk) in bytes.chunks(4).take(REGISTER_COUNT)
+            .enumerate()
+          {
+            let value = u32::from_le_bytes([
+              chunk[0], chunk[1], chunk[2], chunk[3]]);
+            write_register(cpu, index, value);
+          }
+          ok
+        },
+        _ => error,
+      },
+      "p" => match parse_hex(args) {
+        Some(index) => hex_u32(read_register(cpu, index)),
+        None => error,
+      },
+      "P" => {
+        let parsed = args.split_once('=').and_then(|(index, value)| {
+          let bytes = parse_hex_bytes(value)?;
+          let mut value = [0; 4];
+          for (i, byte) in bytes.iter().take(4).enumerate() {
+            value[i] = *byte;
+          }
+          Some((parse_hex(index)?, u32::from_le_bytes(value)))
+        });
+        match parsed {
+          Some((index, value)) => {
+            write_register(cpu, index, value);
+            ok
+          },
+          None => error,
+        }
+      },
+      "m" => {
+        let parsed = args.split_once(',')
+          .and_then(|(addr, len)| Some((parse_hex(addr)?, parse_hex(len)?)));
+        match parsed {
+          Some((addr, len)) if addr.saturating_add(len) <= MEMORY_END =>
+            (addr..addr + len)
+              .map(|addr| format!("{:02x}", cpu.memory.read_u8(addr)))
+              .collect(),
+          _ => error,
+        }
+      },
+      "M" => {
+        let parsed = args.split_once(':').and_then(|(range, data)| {
+          let (addr, len) = range.split_once(',')?;
+          let bytes = parse_hex_bytes(data)?;
+          Some((parse_hex(addr)?, parse_hex(len)?, bytes))
+        });
+        match parsed {
+          Some((addr, len, bytes)) if bytes.len() == len &&
+            addr.saturating_add(len) <= MEMORY_END =>
+          {
+            for (i, byte) in bytes.iter().enumerate() {
+              cpu.memory.write_u8(addr + i, *byte);
+            }
+            ok
+          },
+          _ => error,
+        }
+      },
+      "Z" | "z" => {
+        let fields: Vec<&str> = args.split(',').collect();
+        let parsed = match fields.as_slice() {
+          [kind, addr, len] => parse_hex(addr)
+            .and_then(|addr| Some((*kind, addr, parse_hex(len)?))),
+          _ => None,
+        };
+        let insert = command == "Z";
+        let debugger = &mut cpu.debugger;
+        match parsed {
+          Some(("0", addr, _)) | Some(("1", addr, _)) => {
+            let breakpoint = Breakpoint::Linear(addr);
+            debugger.breakpoints.retain(|value| *value != breakpoint);
+            if insert {
+              debugger.breakpoints.push(breakpoint);
+            }
+            ok
+          },
+          Some((kind @ ("2" | "3" | "4"), addr, len)) => {
+            let kind = match kind {
+              "2" => WatchKind::Write,
+              "3" => WatchKind::Read,
+              _ => WatchKind::Access,
+            };
+            let watchpoint = Watchpoint::new(addr, len, kind);
+            debugger.watchpoints.retain(|value| *value != watchpoint);
+            if insert {
+              debugger.watchpoints.push(watchpoint);
+            }
+            ok
+          },
+          // Not supported; an empty reply tells GDB so
+          _ => String::new(),
+        }
+      },
+      "H" | "T" => ok,
+      "q" | "Q" => match packet {
+        "qAttached" => "1".to_string(),
+        "qC" => "QC1".to_string(),
+        "qfThreadInfo" => "m1".to_string(),
+        "qsThreadInfo" => "l".to_string(),
+        "QStartNoAckMode" => ok,
+        _ if packet.starts_with("qSupported") =>
+          "PacketSize=1000;QStartNoAckMode+".to_string(),
+        _ => String::new(),
+      },
+      _ => String::new(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mem::linear::LinearMemory;
+  use super::super::program::*;
+  use super::super::register::RegisterWordType::*;
+
+  // Replays GDB's side of the conversation, and records the stub's.
+  struct Script {
+    input: Vec<u8>,
+    output: Vec<u8>,
+    nonblocking: bool,
+  }
+
+  impl Read for Script {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      if self.nonblocking {
+        return Err(io::Error::from(io::ErrorKind::WouldBlock));
+      }
+      let length = buf.len().min(self.input.len());
+      buf[..length].copy_from_slice(&self.input[..length]);
+      self.input.drain(..length);
+      Ok(length)
+    }
+  }
+
+  impl Write for Script {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.output.extend(buf);
+      Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  impl Connection for Script {
+    fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+      self.nonblocking = nonblocking;
+      Ok(())
+    }
+  }
+
+  fn packet(data: &str) -> String {
+    format!("${}#{:02x}", data, checksum(data.as_bytes()))
+  }
+
+  #[test]
+  fn test_gdb() {
+    let memory = LinearMemory::new(1024 * 1024);
+    let mut cpu = CPU::new(Box::new(memory), Box::new(LinearMemory::new(0)));
+    Program::new()
+      .mov(Ax, 0x8086)
+      .mov(Bx, Ax)
+      .mov(word_ptr(0x0200u16), Bx)
+      .hlt()
+      .load(&mut cpu, 0x0010, 0x0000)
+      .unwrap();
+    cpu.jmp(0x0010, 0x0000);
+    let requests = [
+      "qSupported:multiprocess+", "QStartNoAckMode", "?", "p8", "m100,3",
+      "Z0,103,1", "c", "g", "s", "P3=3412", "Z2,200,2", "c", "z2,200,2",
+      "M300,2:cdab", "c", "k",
+    ];
+    // Acknowledge the replies until the no-ack mode
+    let mut input = String::new();
+    for (i, request) in requests.iter().enumerate() {
+      input.push_str(&packet(request));
+      if i < 2 {
+        input.push('+');
+      }
+    }
+    let mut stub = GdbStub::new(Script {
+      input: input.into_bytes(),
+      output: vec![],
+      nonblocking: false,
+    });
+    stub.serve(&mut cpu).unwrap();
+    let output = String::from_utf8(stub.connection.output).unwrap();
+    let mut expected = "+".to_string();
+    expected.push_str(&packet("PacketSize=1000;QStartNoAckMode+"));
+    expected.push('+');
+    for reply in [
+      "OK", "S05", "00010000", "b88680", "OK", "S05",
+      "868000000000000000000000000000000000000000000000000000000000000\
+        003010000\
+        02f00000100000000000000000000000000000000000000000000000",
+      "S05", "OK", "OK", "T05watch:200;", "OK", "OK", "S05",
+    ].iter() {
+      expected.push_str(&packet(reply));
+    }
+    assert_eq!(output, expected);
+    assert_eq!(cpu.register.bx, 0x1234);
+    assert_eq!(cpu.memory.read_u16(0x0200), 0x1234);
+    assert_eq!(cpu.memory.read_u16(0x0300), 0xabcd);
+    assert!(cpu.halted);
+  }
+
+  #[test]
+  fn test_gdb_breakpoint_between_slices() {
+    let memory = LinearMemory::new(1024 * 1024);
+    let mut cpu = CPU::new(Box::new(memory), Box::new(LinearMemory::new(0)));
+    // The breakpoint is reached by the first step of the second slice
+    Program::new()
+      .mov(Cx, (RUN_SLICE - 1) as i32)
+      .label("again")
+      .loop_("again")
+      .nop()
+      .hlt()
+      .load(&mut cpu, 0x0010, 0x0000)
+      .unwrap();
+    cpu.jmp(0x0010, 0x0000);
+    let mut input = packet("QStartNoAckMode") + "+";
+    for request in ["Z0,105,1", "c", "p8", "k"].iter() {
+      input.push_str(&packet(request));
+    }
+    let mut stub = GdbStub::new(Script {
+      input: input.into_bytes(),
+      output: vec![],
+      nonblocking: false,
+    });
+    stub.serve(&mut cpu).unwrap();
+    let output = String::from_utf8(stub.connection.output).unwrap();
+    let mut expected = "+".to_string();
+    for reply in ["OK", "OK", "S05", "05010000"].iter() {
+      expected.push_str(&packet(reply));
+    }
+    assert_eq!(output, expected);
+    assert!(!cpu.halted);
+  }
+}
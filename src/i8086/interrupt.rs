@@ -0,0 +1,91 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use super::cpu::CPU;
+use super::flags::*;
+
+pub const INT_NMI: u8 = 0x2;
+
+// An external device (usually an 8259 PIC) driving the INTR line.
+pub trait InterruptController {
+  // Whether INTR is asserted.
+  fn intr(&self) -> bool;
+  // The interrupt acknowledge cycle; returns the vector to be serviced.
+  fn acknowledge(&mut self) -> u8;
+}
+
+// Allows the controller to be shared with I/O port handlers.
+impl<T: InterruptController> InterruptController for Rc<RefCell<T>> {
+  fn intr(&self) -> bool {
+    self.borrow().intr()
+  }
+  fn acknowledge(&mut self) -> u8 {
+    self.borrow_mut().acknowledge()
+  }
+}
+
+// Services interrupts in the host rather than in the guest, as a BIOS or
+// DOS written in Rust would.
+pub trait InterruptHandler {
+  // Returns whether the interrupt has been handled; if not, it's
+  // dispatched through the interrupt vector table.
+  fn interrupt(&mut self, cpu: &mut CPU, vector: u8) -> bool;
+}
+
+// Allows the handler to be inspected by the host while it's installed.
+impl<T: InterruptHandler> InterruptHandler for Rc<RefCell<T>> {
+  fn interrupt(&mut self, cpu: &mut CPU, vector: u8) -> bool {
+    self.borrow_mut().interrupt(cpu, vector)
+  }
+}
+
+impl CPU {
+  pub fn set_interrupt_handler(
+    &mut self,
+    handler: Box<dyn InterruptHandler>,
+  ) -> () {
+    self.interrupt_handler = Some(handler);
+  }
+
+  pub fn set_interrupt_controller(
+    &mut self,
+    controller: Box<dyn InterruptController>,
+  ) -> () {
+    self.interrupt_controller = Some(controller);
+  }
+  pub fn nmi(&mut self) -> () {
+    // NMI is edge triggered; it's latched until the CPU gets to it.
+    self.nmi_pending = true;
+  }
+  pub fn has_pending_interrupt(&self) -> bool {
+    if self.nmi_pending {
+      return true;
+    }
+    match &self.interrupt_controller {
+      Some(controller) => self.get_flags() & IF != 0 && controller.intr(),
+      None => false,
+    }
+  }
+  // Checked between instructions. Returns true if the CPU has entered an
+  // interrupt handler.
+  pub fn poll_interrupts(&mut self) -> bool {
+    if self.interrupt_shadow {
+      // STI, MOV SS and POP SS delay interrupts by one instruction.
+      self.interrupt_shadow = false;
+      return false;
+    }
+    if self.nmi_pending {
+      self.nmi_pending = false;
+      self.interrupt(INT_NMI);
+      return true;
+    }
+    if self.get_flags() & IF == 0 {
+      return false;
+    }
+    let vector = match &mut self.interrupt_controller {
+      Some(controller) if controller.intr() => controller.acknowledge(),
+      _ => return false,
+    };
+    self.interrupt(vector);
+    true
+  }
+}
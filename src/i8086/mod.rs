@@ -1,6 +1,16 @@
 pub mod cpu;
+pub mod debugger;
+pub mod gdb;
 pub mod register;
 pub mod op;
+pub mod decode;
+pub mod disasm;
+pub mod analysis;
+pub mod encode;
+pub mod asm;
+pub mod program;
 pub mod operand;
 pub mod op_exec;
 pub mod flags;
+pub mod exception;
+pub mod interrupt;
@@ -1,13 +1,16 @@
+use std::error;
+use std::fmt;
 use super::register::*;
 use super::operand::*;
+use super::cpu::CpuModel;
 
 trait RegisterModRmParsable: Sized {
-  fn from_value(value: u8) -> Option<Self>;
+  fn from_value(value: u8) -> Result<Self, DecodeErrorKind>;
 }
 
 impl RegisterModRmParsable for RegisterWordType {
-  fn from_value(value: u8) -> Option<RegisterWordType> {
-    Some(match value {
+  fn from_value(value: u8) -> Result<RegisterWordType, DecodeErrorKind> {
+    Ok(match value {
       0 => RegisterWordType::Ax,
       1 => RegisterWordType::Cx,
       2 => RegisterWordType::Dx,
@@ -16,26 +19,26 @@ impl RegisterModRmParsable for RegisterWordType {
       5 => RegisterWordType::Bp,
       6 => RegisterWordType::Si,
       7 => RegisterWordType::Di,
-      _ => return None,
+      _ => return Err(DecodeErrorKind::InvalidOpcode),
     })
   }
 }
 
 impl RegisterWordType {
-  fn from_seg(value: u8) -> Option<RegisterWordType> {
-    Some(match value {
+  fn from_seg(value: u8) -> Result<RegisterWordType, DecodeErrorKind> {
+    Ok(match value {
       0 => RegisterWordType::Es,
       1 => RegisterWordType::Cs,
       2 => RegisterWordType::Ss,
       3 => RegisterWordType::Ds,
-      _ => return None,
+      _ => return Err(DecodeErrorKind::InvalidOpcode),
     })
   }
 }
 
 impl RegisterModRmParsable for RegisterByteType {
-  fn from_value(value: u8) -> Option<RegisterByteType> {
-    Some(match value {
+  fn from_value(value: u8) -> Result<RegisterByteType, DecodeErrorKind> {
+    Ok(match value {
       0 => RegisterByteType::Al,
       1 => RegisterByteType::Cl,
       2 => RegisterByteType::Dl,
@@ -44,14 +47,14 @@ impl RegisterModRmParsable for RegisterByteType {
       5 => RegisterByteType::Ch,
       6 => RegisterByteType::Dh,
       7 => RegisterByteType::Bh,
-      _ => return None,
+      _ => return Err(DecodeErrorKind::InvalidOpcode),
     })
   }
 }
 
 impl AddressType {
-  fn from_value(value: u8) -> Option<AddressType> {
-    Some(match value {
+  fn from_value(value: u8) -> Result<AddressType, DecodeErrorKind> {
+    Ok(match value {
       0 => AddressType::BxSi,
       1 => AddressType::BxDi,
       2 => AddressType::BpSi,
@@ -60,7 +63,7 @@ impl AddressType {
       5 => AddressType::Di,
       6 => AddressType::Bp,
       7 => AddressType::Bx,
-      _ => return None,
+      _ => return Err(DecodeErrorKind::InvalidOpcode),
     })
   }
   fn to_value(&self) -> u8 {
@@ -82,12 +85,19 @@ pub enum OpSize {
   Word,
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug)]
+pub enum OpRepeatType {
+  Rep, // Repe, Repz
+  Repnz, // Repne
+}
+
+#[derive(PartialEq, Clone)]
 #[derive(Debug)]
 pub enum OpCallType {
   WithinDirect(i16),
   WithinIndirect(OperandWord),
-  InterDirect(u16, u16),
+  InterDirect(u16, u16), // ip, cs; in encoding order
   InterIndirect(OperandWord),
 }
 
@@ -109,8 +119,8 @@ pub enum OpBinaryOp {
 }
 
 impl OpBinaryOp {
-  fn from_immed(value: u8) -> Option<OpBinaryOp> {
-    Some(match value {
+  fn from_immed(value: u8) -> Result<OpBinaryOp, DecodeErrorKind> {
+    Ok(match value {
       0 => OpBinaryOp::Add,
       1 => OpBinaryOp::Or,
       2 => OpBinaryOp::Adc,
@@ -119,7 +129,7 @@ impl OpBinaryOp {
       5 => OpBinaryOp::Sub,
       6 => OpBinaryOp::Xor,
       7 => OpBinaryOp::Cmp,
-      _ => return None,
+      _ => return Err(DecodeErrorKind::InvalidOpcode),
     })
   }
 }
@@ -140,25 +150,32 @@ pub enum OpUnaryOp {
 }
 
 impl OpUnaryOp {
-  fn from_grp1(value: u8) -> Option<OpUnaryOp> {
-    Some(match value {
-      0 => return None,
-      1 => return None,
+  fn from_grp1(value: u8) -> Result<OpUnaryOp, DecodeErrorKind> {
+    Ok(match value {
+      0 => return Err(DecodeErrorKind::InvalidOpcode),
+      1 => return Err(DecodeErrorKind::InvalidOpcode),
       2 => OpUnaryOp::Not,
       3 => OpUnaryOp::Neg,
       4 => OpUnaryOp::Mul,
       5 => OpUnaryOp::Imul,
       6 => OpUnaryOp::Div,
       7 => OpUnaryOp::Idiv,
-      _ => return None,
+      _ => return Err(DecodeErrorKind::InvalidOpcode),
     })
   }
-  fn from_grp2(value: u8) -> Option<OpUnaryOp> {
-    Some(match value {
+  fn from_grp2(
+    value: u8,
+    model: CpuModel,
+  ) -> Result<OpUnaryOp, DecodeErrorKind> {
+    Ok(match value {
       0 => OpUnaryOp::Inc,
       1 => OpUnaryOp::Dec,
       6 => OpUnaryOp::Push,
-      _ => return None,
+      7 => {
+        undocumented(model)?;
+        OpUnaryOp::Push
+      },
+      _ => return Err(DecodeErrorKind::InvalidOpcode),
     })
   }
 }
@@ -177,8 +194,8 @@ pub enum OpShiftOp {
 }
 
 impl OpShiftOp {
-  fn from_value(value: u8) -> Option<OpShiftOp> {
-    Some(match value {
+  fn from_value(value: u8) -> Result<OpShiftOp, DecodeErrorKind> {
+    Ok(match value {
       0 => OpShiftOp::Rol,
       1 => OpShiftOp::Ror,
       2 => OpShiftOp::Rcl,
@@ -187,7 +204,7 @@ impl OpShiftOp {
       5 => OpShiftOp::Shr,
       6 => OpShiftOp::Sal,
       7 => OpShiftOp::Sar,
-      _ => return None,
+      _ => return Err(DecodeErrorKind::InvalidOpcode),
     })
   }
 }
@@ -229,12 +246,8 @@ pub enum OpNullaryOp {
   Daa,
   Aas,
   Das,
-  Aam,
-  Aad,
   Cbw,
   Cwd,
-  Rep,
-  Repz,
   Into,
   Iret,
   Clc,
@@ -246,10 +259,10 @@ pub enum OpNullaryOp {
   Sti,
   Hlt,
   Wait,
-  Lock,
+  Salc,
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone)]
 #[derive(Debug)]
 pub enum Op {
   BinaryByte { op: OpBinaryOp, src: OperandByte, dest: OperandByte },
@@ -274,27 +287,113 @@ pub enum Op {
   Stos(OpSize),
   Call(OpCallType),
   Jmp(OpCallType),
+  // EB, which is relative to the end of its shorter form
+  JmpShort(i8),
   RetWithin,
   RetWithinImm(u16),
   RetInter,
   RetInterImm(u16),
+  Aam(u8),
+  Aad(u8),
   Int(u8),
   Esc(u8, OperandWord),
-  Segment(RegisterWordType),
 }
 
-fn iter_next_u16(iter: &mut dyn Iterator<Item = u8>) -> Option<u16> {
-  Some(iter.next()? as u16 +
-    ((iter.next()? as u16) << 8))
+#[derive(PartialEq, Copy, Clone, Default)]
+#[derive(Debug)]
+pub struct OpPrefixes {
+  pub segment: Option<RegisterWordType>,
+  pub repeat: Option<OpRepeatType>,
+  pub lock: bool,
+}
+
+#[derive(PartialEq, Clone)]
+#[derive(Debug)]
+pub struct Instruction {
+  pub prefixes: OpPrefixes,
+  pub op: Op,
+}
+
+impl Instruction {
+  pub fn new(op: Op) -> Instruction {
+    Instruction { prefixes: OpPrefixes::default(), op }
+  }
+}
+
+#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug)]
+pub enum DecodeErrorKind {
+  // The input ended in the middle of an instruction.
+  Truncated,
+  // The opcode, or the opcode extension in ModR/M, isn't an instruction.
+  InvalidOpcode,
+  // ModR/M selected a register where a memory operand is required.
+  RegisterOperand,
+  // More prefixes than MAX_PREFIXES, as in memory filled with them.
+  TooManyPrefixes,
+}
+
+#[derive(PartialEq, Clone)]
+#[derive(Debug)]
+pub struct DecodeError {
+  pub kind: DecodeErrorKind,
+  // Offset of the opcode in bytes, past any prefixes.
+  pub offset: usize,
+  // Every byte read for the instruction, including the offending one.
+  pub bytes: Vec<u8>,
+}
+
+impl fmt::Display for DecodeError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let message = match self.kind {
+      DecodeErrorKind::Truncated => "truncated instruction",
+      DecodeErrorKind::InvalidOpcode => "invalid opcode",
+      DecodeErrorKind::RegisterOperand => "register used as memory operand",
+      DecodeErrorKind::TooManyPrefixes => "too many prefixes",
+    };
+    write!(f, "{} at +{}:", message, self.offset)?;
+    for byte in self.bytes.iter() {
+      write!(f, " {:02X}", byte)?;
+    }
+    Ok(())
+  }
+}
+
+impl error::Error for DecodeError {}
+
+// Keeps every byte consumed while decoding, for error reporting.
+struct RecordingIterator<'a> {
+  iter: &'a mut dyn Iterator<Item = u8>,
+  bytes: Vec<u8>,
+}
+
+impl<'a> Iterator for RecordingIterator<'a> {
+  type Item = u8;
+  fn next(&mut self) -> Option<u8> {
+    let value = self.iter.next()?;
+    self.bytes.push(value);
+    Some(value)
+  }
+}
+
+fn next_u8(iter: &mut dyn Iterator<Item = u8>) -> Result<u8, DecodeErrorKind> {
+  iter.next().ok_or(DecodeErrorKind::Truncated)
+}
+
+fn iter_next_u16(
+  iter: &mut dyn Iterator<Item = u8>,
+) -> Result<u16, DecodeErrorKind> {
+  Ok(next_u8(iter)? as u16 +
+    ((next_u8(iter)? as u16) << 8))
 }
 
 fn parse_mod_rm<T: RegisterType + RegisterModRmParsable>(
   second: u8,
   iter: &mut dyn Iterator<Item = u8>,
-) -> Option<Operand<T>> {
+) -> Result<Operand<T>, DecodeErrorKind> {
   let mod_val = (second >> 6) & 0x03;
   let rm_val = second & 0x07;
-  Some(match mod_val {
+  Ok(match mod_val {
     0 => {
       let addr_type = AddressType::from_value(rm_val)?;
       if addr_type == AddressType::Bp {
@@ -304,18 +403,45 @@ fn parse_mod_rm<T: RegisterType + RegisterModRmParsable>(
       }
     },
     1 => Operand::Address(
-      AddressType::from_value(rm_val)?, iter.next()? as i8 as i16),
+      AddressType::from_value(rm_val)?, next_u8(iter)? as i8 as i16),
     2 => Operand::Address(
       AddressType::from_value(rm_val)?, iter_next_u16(iter)? as i16),
     3 => Operand::Register(T::from_value(rm_val)?),
-    _ => return None,
+    _ => return Err(DecodeErrorKind::InvalidOpcode),
   })
 }
 
+fn memory<T: RegisterType>(
+  operand: Operand<T>,
+) -> Result<Operand<T>, DecodeErrorKind> {
+  match operand {
+    Operand::Register(_) => Err(DecodeErrorKind::RegisterOperand),
+    operand => Ok(operand),
+  }
+}
+
+fn parse_mem<T: RegisterType + RegisterModRmParsable>(
+  second: u8,
+  iter: &mut dyn Iterator<Item = u8>,
+) -> Result<Operand<T>, DecodeErrorKind> {
+  memory(parse_mod_rm(second, iter)?)
+}
+
 fn parse_reg<T: RegisterType + RegisterModRmParsable>(
   value: u8,
-) -> Option<Operand<T>> {
-  Some(Operand::Register(T::from_value(value)?))
+) -> Result<Operand<T>, DecodeErrorKind> {
+  Ok(Operand::Register(T::from_value(value)?))
+}
+
+fn parse_seg(
+  value: u8,
+  model: CpuModel,
+) -> Result<RegisterWordType, DecodeErrorKind> {
+  // 8086 only looks at the lower 2 bits of the segment register field
+  if value > 3 {
+    undocumented(model)?;
+  }
+  RegisterWordType::from_seg(value & 0x03)
 }
 
 fn create_binary_op_byte(
@@ -324,12 +450,12 @@ fn create_binary_op_byte(
   op: OpBinaryOp,
   other: OperandByte,
   inversed: bool,
-) -> Option<Op> {
+) -> Result<Op, DecodeErrorKind> {
   let mod_rm = parse_mod_rm::<RegisterByteType>(second, iter)?;
   if inversed {
-    Some(Op::BinaryByte { op, src: other, dest: mod_rm })
+    Ok(Op::BinaryByte { op, src: other, dest: mod_rm })
   } else {
-    Some(Op::BinaryByte { op, src: mod_rm, dest: other })
+    Ok(Op::BinaryByte { op, src: mod_rm, dest: other })
   }
 }
 
@@ -339,12 +465,12 @@ fn create_binary_op_word(
   op: OpBinaryOp,
   other: OperandWord,
   inversed: bool,
-) -> Option<Op> {
+) -> Result<Op, DecodeErrorKind> {
   let mod_rm = parse_mod_rm::<RegisterWordType>(second, iter)?;
   if inversed {
-    Some(Op::BinaryWord { op, src: other, dest: mod_rm })
+    Ok(Op::BinaryWord { op, src: other, dest: mod_rm })
   } else {
-    Some(Op::BinaryWord { op, src: mod_rm, dest: other })
+    Ok(Op::BinaryWord { op, src: mod_rm, dest: other })
   }
 }
 
@@ -352,23 +478,23 @@ fn parse_binary_group_op(
   op: OpBinaryOp,
   first: u8,
   iter: &mut dyn Iterator<Item = u8>,
-) -> Option<Op> {
-  Some(match first & 0x07 {
+) -> Result<Op, DecodeErrorKind> {
+  Ok(match first & 0x07 {
     0 | 2 => {
-      let second = iter.next()?;
+      let second = next_u8(iter)?;
       let reg = parse_reg::<RegisterByteType>((second >> 3) & 0x07)?;
       let inversed = first & 0x02 == 0;
       create_binary_op_byte(second, iter, op, reg, inversed)?
     }
     1 | 3 => {
-      let second = iter.next()?;
+      let second = next_u8(iter)?;
       let reg = parse_reg::<RegisterWordType>((second >> 3) & 0x07)?;
       let inversed = first & 0x02 == 0;
       create_binary_op_word(second, iter, op, reg, inversed)?
     }
     4 => Op::BinaryByte {
       op: op,
-      src: Operand::ImmByte(iter.next()?),
+      src: Operand::ImmByte(next_u8(iter)?),
       dest: Operand::Register(RegisterByteType::Al),
     },
     5 => Op::BinaryWord {
@@ -376,14 +502,73 @@ fn parse_binary_group_op(
       src: Operand::ImmWord(iter_next_u16(iter)?),
       dest: Operand::Register(RegisterWordType::Ax),
     },
-    _ => return None,
+    _ => return Err(DecodeErrorKind::InvalidOpcode),
   })
 }
 
-pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
-  let first = iter.next()?;
+pub fn parse_op(
+  iter: &mut dyn Iterator<Item = u8>,
+) -> Result<Instruction, DecodeError> {
+  parse_op_with_model(iter, CpuModel::I8086)
+}
+
+// The 8086 itself takes any number of prefixes, but a decoder reading from
+// memory full of them would never return; later CPUs limit instructions to
+// 15 bytes.
+pub const MAX_PREFIXES: usize = 14;
+
+pub fn parse_op_with_model(
+  iter: &mut dyn Iterator<Item = u8>,
+  model: CpuModel,
+) -> Result<Instruction, DecodeError> {
+  let mut recorder = RecordingIterator { iter, bytes: vec![] };
+  // Any run of prefixes is folded into the instruction that follows, so
+  // that the CPU can execute them as a single unit.
+  let mut prefixes = OpPrefixes::default();
+  let mut offset = 0;
+  let result = loop {
+    if offset == MAX_PREFIXES {
+      break Err(DecodeErrorKind::TooManyPrefixes);
+    }
+    let first = match next_u8(&mut recorder) {
+      Ok(value) => value,
+      Err(kind) => break Err(kind),
+    };
+    match first {
+      0x26 => prefixes.segment = Some(RegisterWordType::Es),
+      0x2E => prefixes.segment = Some(RegisterWordType::Cs),
+      0x36 => prefixes.segment = Some(RegisterWordType::Ss),
+      0x3E => prefixes.segment = Some(RegisterWordType::Ds),
+      0xF0 => prefixes.lock = true,
+      // F1 is an undocumented alias of LOCK
+      0xF1 if model == CpuModel::I8086 => prefixes.lock = true,
+      0xF2 => prefixes.repeat = Some(OpRepeatType::Repnz),
+      0xF3 => prefixes.repeat = Some(OpRepeatType::Rep),
+      _ => break parse_opcode(first, &mut recorder, model),
+    }
+    offset += 1;
+  };
+  match result {
+    Ok(op) => Ok(Instruction { prefixes, op }),
+    Err(kind) => Err(DecodeError { kind, offset, bytes: recorder.bytes }),
+  }
+}
+
+// Undocumented opcodes are only decoded when the model allows them.
+fn undocumented(model: CpuModel) -> Result<(), DecodeErrorKind> {
+  match model {
+    CpuModel::I8086 => Ok(()),
+    CpuModel::Strict => Err(DecodeErrorKind::InvalidOpcode),
+  }
+}
+
+fn parse_opcode(
+  first: u8,
+  iter: &mut dyn Iterator<Item = u8>,
+  model: CpuModel,
+) -> Result<Op, DecodeErrorKind> {
   let first_octet = first & 0x07;
-  Some(match first & 0xf8 {
+  Ok(match first & 0xf8 {
     0x00 => {
       match first_octet {
         0..=5 => parse_binary_group_op(OpBinaryOp::Add, first, iter)?,
@@ -395,7 +580,7 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
           op: OpUnaryOp::Pop,
           dest: Operand::Register(RegisterWordType::Es),
         },
-        _ => return None,
+        _ => return Err(DecodeErrorKind::InvalidOpcode),
       }
     },
     0x08 => {
@@ -405,11 +590,15 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
           op: OpUnaryOp::Push,
           dest: Operand::Register(RegisterWordType::Cs),
         },
-        7 => Op::UnaryWord {
-          op: OpUnaryOp::Pop,
-          dest: Operand::Register(RegisterWordType::Cs),
+        7 => {
+          // POP CS; undocumented, removed in later processors
+          undocumented(model)?;
+          Op::UnaryWord {
+            op: OpUnaryOp::Pop,
+            dest: Operand::Register(RegisterWordType::Cs),
+          }
         },
-        _ => return None,
+        _ => return Err(DecodeErrorKind::InvalidOpcode),
       }
     },
     0x10 => {
@@ -423,7 +612,7 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
           op: OpUnaryOp::Pop,
           dest: Operand::Register(RegisterWordType::Ss),
         },
-        _ => return None,
+        _ => return Err(DecodeErrorKind::InvalidOpcode),
       }
     },
     0x18 => {
@@ -437,39 +626,39 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
           op: OpUnaryOp::Pop,
           dest: Operand::Register(RegisterWordType::Ds),
         },
-        _ => return None,
+        _ => return Err(DecodeErrorKind::InvalidOpcode),
       }
     },
     0x20 => {
       match first_octet {
         0..=5 => parse_binary_group_op(OpBinaryOp::And, first, iter)?,
-        6 => Op::Segment(RegisterWordType::Es),
+        6 => return Err(DecodeErrorKind::InvalidOpcode), // ES: prefix
         7 => Op::Nullary(OpNullaryOp::Daa),
-        _ => return None,
+        _ => return Err(DecodeErrorKind::InvalidOpcode),
       }
     },
     0x28 => {
       match first_octet {
         0..=5 => parse_binary_group_op(OpBinaryOp::Sub, first, iter)?,
-        6 => Op::Segment(RegisterWordType::Cs),
+        6 => return Err(DecodeErrorKind::InvalidOpcode), // CS: prefix
         7 => Op::Nullary(OpNullaryOp::Das),
-        _ => return None,
+        _ => return Err(DecodeErrorKind::InvalidOpcode),
       }
     },
     0x30 => {
       match first_octet {
         0..=5 => parse_binary_group_op(OpBinaryOp::Xor, first, iter)?,
-        6 => Op::Segment(RegisterWordType::Ss),
+        6 => return Err(DecodeErrorKind::InvalidOpcode), // SS: prefix
         7 => Op::Nullary(OpNullaryOp::Aaa),
-        _ => return None,
+        _ => return Err(DecodeErrorKind::InvalidOpcode),
       }
     },
     0x38 => {
       match first_octet {
         0..=5 => parse_binary_group_op(OpBinaryOp::Cmp, first, iter)?,
-        6 => Op::Segment(RegisterWordType::Ds),
+        6 => return Err(DecodeErrorKind::InvalidOpcode), // DS: prefix
         7 => Op::Nullary(OpNullaryOp::Aas),
-        _ => return None,
+        _ => return Err(DecodeErrorKind::InvalidOpcode),
       }
     },
     0x40 => {
@@ -496,16 +685,12 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
         dest: Operand::Register(RegisterWordType::from_value(first_octet)?),
       }
     },
-    0x60 => {
-      // not used
-      return None;
-    },
-    0x68 => {
-      // not used
-      return None;
-    },
-    0x70 => {
-      let second = iter.next()?;
+    0x60 | 0x70 => {
+      // 60..6F are undocumented aliases of 70..7F on 8086
+      if first < 0x70 {
+        undocumented(model)?;
+      }
+      let second = next_u8(iter)?;
       let jmp_type = match first_octet {
         0 => OpCondJmpOp::Jo,
         1 => OpCondJmpOp::Jno,
@@ -515,12 +700,15 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
         5 => OpCondJmpOp::Jne,
         6 => OpCondJmpOp::Jbe,
         7 => OpCondJmpOp::Ja,
-        _ => return None,
+        _ => return Err(DecodeErrorKind::InvalidOpcode),
       };
       Op::CondJmp { op: jmp_type, offset: second as i8 }
     },
-    0x78 => {
-      let second = iter.next()?;
+    0x68 | 0x78 => {
+      if first < 0x70 {
+        undocumented(model)?;
+      }
+      let second = next_u8(iter)?;
       let jmp_type = match first_octet {
         0 => OpCondJmpOp::Js,
         1 => OpCondJmpOp::Jns,
@@ -530,7 +718,7 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
         5 => OpCondJmpOp::Jge,
         6 => OpCondJmpOp::Jle,
         7 => OpCondJmpOp::Jg,
-        _ => return None,
+        _ => return Err(DecodeErrorKind::InvalidOpcode),
       };
       Op::CondJmp { op: jmp_type, offset: second as i8 }
     },
@@ -547,12 +735,12 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
       // 87 - XCHG
       match first_octet {
         0..=3 => {
-          let second = iter.next()?;
+          let second = next_u8(iter)?;
           let op = OpBinaryOp::from_immed((second >> 3) & 0x07)?;
           match first & 0x01 {
             0 => {
               let mod_rm = parse_mod_rm(second, iter)?;
-              let imm = Operand::ImmByte(iter.next()?);
+              let imm = Operand::ImmByte(next_u8(iter)?);
               Op::BinaryByte {
                 op,
                 dest: mod_rm,
@@ -563,7 +751,7 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
               let mod_rm = parse_mod_rm(second, iter)?;
               let imm = match first & 0x03 {
                 1 => Operand::ImmWord(iter_next_u16(iter)?),
-                _ => Operand::ImmByte(iter.next()?),
+                _ => Operand::ImmByte(next_u8(iter)?),
               };
               Op::BinaryWord {
                 op,
@@ -571,11 +759,11 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
                 src: imm,
               }
             },
-            _ => return None,
+            _ => return Err(DecodeErrorKind::InvalidOpcode),
           }
         },
         4 => {
-          let second = iter.next()?;
+          let second = next_u8(iter)?;
           Op::BinaryByte {
             op: OpBinaryOp::Test,
             src: parse_reg((second >> 3) & 0x07)?,
@@ -583,7 +771,7 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
           }
         },
         5 => {
-          let second = iter.next()?;
+          let second = next_u8(iter)?;
           Op::BinaryWord {
             op: OpBinaryOp::Test,
             src: parse_reg((second >> 3) & 0x07)?,
@@ -591,7 +779,7 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
           }
         },
         6 => {
-          let second = iter.next()?;
+          let second = next_u8(iter)?;
           Op::BinaryByte {
             op: OpBinaryOp::Xchg,
             src: parse_reg((second >> 3) & 0x07)?,
@@ -599,14 +787,14 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
           }
         },
         7 => {
-          let second = iter.next()?;
+          let second = next_u8(iter)?;
           Op::BinaryWord {
             op: OpBinaryOp::Xchg,
             src: parse_reg((second >> 3) & 0x07)?,
             dest: parse_mod_rm(second, iter)?,
           }
         },
-        _ => return None,
+        _ => return Err(DecodeErrorKind::InvalidOpcode),
       }
     },
     0x88 => {
@@ -625,8 +813,8 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
         },
         4 => {
           // MOV r/m16, segreg
-          let second = iter.next()?;
-          let reg = RegisterWordType::from_seg((second >> 3) & 0x07)?;
+          let second = next_u8(iter)?;
+          let reg = parse_seg((second >> 3) & 0x07, model)?;
           Op::BinaryWord {
             op: OpBinaryOp::Mov,
             src: Operand::Register(reg),
@@ -634,16 +822,16 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
           }
         },
         5 => {
-          // LEA reg16, r/m16
-          let second = iter.next()?;
-          let mod_rm = parse_mod_rm(second, iter)?;
+          // LEA reg16, m16
+          let second = next_u8(iter)?;
+          let mod_rm = parse_mem(second, iter)?;
           let reg = RegisterWordType::from_value((second >> 3) & 0x07)?;
           Op::Lea(reg, mod_rm)
         },
         6 => {
           // MOV segreg, r/m16
-          let second = iter.next()?;
-          let reg = RegisterWordType::from_seg((second >> 3) & 0x07)?;
+          let second = next_u8(iter)?;
+          let reg = parse_seg((second >> 3) & 0x07, model)?;
           Op::BinaryWord {
             op: OpBinaryOp::Mov,
             src: parse_mod_rm(second, iter)?,
@@ -652,17 +840,17 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
         },
         7 => {
           // Pop r/m16 (second 000)
-          let second = iter.next()?;
+          let second = next_u8(iter)?;
           let mod_rm = parse_mod_rm(second, iter)?;
           match (second >> 3) & 0x07 {
             0 => Op::UnaryWord {
               op: OpUnaryOp::Pop,
               dest: mod_rm,
             },
-            _ => return None,
+            _ => return Err(DecodeErrorKind::InvalidOpcode),
           }
         },
-        _ => return None,
+        _ => return Err(DecodeErrorKind::InvalidOpcode),
       }
     },
     0x90 => {
@@ -682,7 +870,7 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
         5 => Op::Nullary(OpNullaryOp::Popf),
         6 => Op::Nullary(OpNullaryOp::Sahf),
         7 => Op::Nullary(OpNullaryOp::Lahf),
-        _ => return None,
+        _ => return Err(DecodeErrorKind::InvalidOpcode),
       }
     },
     0xA0 => {
@@ -712,9 +900,9 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
         },
         4 => Op::Movs(OpSize::Byte),
         5 => Op::Movs(OpSize::Word),
-        6 => Op::Movs(OpSize::Byte),
-        7 => Op::Movs(OpSize::Word),
-        _ => return None,
+        6 => Op::Cmps(OpSize::Byte),
+        7 => Op::Cmps(OpSize::Word),
+        _ => return Err(DecodeErrorKind::InvalidOpcode),
       }
     },
     0xA8 => {
@@ -725,7 +913,7 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
       match first_octet {
         0 => Op::BinaryByte {
           op: OpBinaryOp::Test,
-          src: Operand::ImmByte(iter.next()?),
+          src: Operand::ImmByte(next_u8(iter)?),
           dest: Operand::Register(RegisterByteType::Al),
         },
         1 => Op::BinaryWord {
@@ -739,14 +927,14 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
         5 => Op::Lods(OpSize::Word),
         6 => Op::Scas(OpSize::Byte),
         7 => Op::Scas(OpSize::Word),
-        _ => return None,
+        _ => return Err(DecodeErrorKind::InvalidOpcode),
       }
     },
     0xB0 => {
       // MOV
       Op::BinaryByte {
         op: OpBinaryOp::Mov,
-        src: Operand::ImmByte(iter.next()?),
+        src: Operand::ImmByte(next_u8(iter)?),
         dest: parse_reg(first_octet)?,
       }
     },
@@ -759,44 +947,49 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
       }
     },
     0xC0 => {
-      // C0 - 
-      // C1 - 
+      // C0..C1 - RET (undocumented aliases of C2..C3)
       // C2..C3 - RET
       // C4 - LES
       // C5 - LDS
       // C6 - MOV
       // C7 - MOV
       match first_octet {
-        0 => return None,
-        1 => return None,
+        0 => {
+          undocumented(model)?;
+          Op::RetWithinImm(iter_next_u16(iter)?)
+        },
+        1 => {
+          undocumented(model)?;
+          Op::RetWithin
+        },
         2 => Op::RetWithinImm(iter_next_u16(iter)?),
         3 => Op::RetWithin,
         4 => {
-          let second = iter.next()?;
-          let mod_rm = parse_mod_rm(second, iter)?;
+          let second = next_u8(iter)?;
+          let mod_rm = parse_mem(second, iter)?;
           let reg = RegisterWordType::from_value((second >> 3) & 0x07)?;
           Op::Les(reg, mod_rm)
         }
         5 => {
-          let second = iter.next()?;
-          let mod_rm = parse_mod_rm(second, iter)?;
+          let second = next_u8(iter)?;
+          let mod_rm = parse_mem(second, iter)?;
           let reg = RegisterWordType::from_value((second >> 3) & 0x07)?;
           Op::Lds(reg, mod_rm)
         }
         6 => {
-          let second = iter.next()?;
+          let second = next_u8(iter)?;
           let mod_rm = parse_mod_rm(second, iter)?;
           match (second >> 3) & 0x07 {
             0 => Op::BinaryByte {
               op: OpBinaryOp::Mov,
-              src: Operand::ImmByte(iter.next()?),
+              src: Operand::ImmByte(next_u8(iter)?),
               dest: mod_rm,
             },
-            _ => return None,
+            _ => return Err(DecodeErrorKind::InvalidOpcode),
           }
         },
         7 => {
-          let second = iter.next()?;
+          let second = next_u8(iter)?;
           let mod_rm = parse_mod_rm(second, iter)?;
           match (second >> 3) & 0x07 {
             0 => Op::BinaryWord {
@@ -804,15 +997,14 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
               src: Operand::ImmWord(iter_next_u16(iter)?),
               dest: mod_rm,
             },
-            _ => return None,
+            _ => return Err(DecodeErrorKind::InvalidOpcode),
           }
         },
-        _ => return None,
+        _ => return Err(DecodeErrorKind::InvalidOpcode),
       }
     },
     0xC8 => {
-      // C8 - 
-      // C9 - 
+      // C8..C9 - RET (undocumented aliases of CA..CB)
       // CA - RET
       // CB - RET
       // CC - INT
@@ -820,15 +1012,21 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
       // CE - INTO
       // CF - IRET
       match first_octet {
-        0 => return None,
-        1 => return None,
+        0 => {
+          undocumented(model)?;
+          Op::RetInterImm(iter_next_u16(iter)?)
+        },
+        1 => {
+          undocumented(model)?;
+          Op::RetInter
+        },
         2 => Op::RetInterImm(iter_next_u16(iter)?),
         3 => Op::RetInter,
         4 => Op::Int(3),
-        5 => Op::Int(iter.next()?),
+        5 => Op::Int(next_u8(iter)?),
         6 => Op::Nullary(OpNullaryOp::Into),
         7 => Op::Nullary(OpNullaryOp::Iret),
-        _ => return None,
+        _ => return Err(DecodeErrorKind::InvalidOpcode),
       }
     },
     0xD0 => {
@@ -839,16 +1037,16 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
       // D3 - op R/M16, CL
       // D4 - AAM
       // D5 - AAD
-      // D6 - 
+      // D6 - SALC (undocumented)
       // D7 - XLAT
       match first_octet {
         0 | 2 => {
-          let second = iter.next()?;
+          let second = next_u8(iter)?;
           let mod_rm = parse_mod_rm(second, iter)?;
           let shift_type = match (first >> 1) & 0x01 {
             0 => OpShiftType::One,
             1 => OpShiftType::Cl,
-            _ => return None,
+            _ => return Err(DecodeErrorKind::InvalidOpcode),
           };
           let shift_op = OpShiftOp::from_value((second >> 3) & 0x07)?;
           Op::ShiftByte {
@@ -858,12 +1056,12 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
           }
         }
         1 | 3 => {
-          let second = iter.next()?;
+          let second = next_u8(iter)?;
           let mod_rm = parse_mod_rm(second, iter)?;
           let shift_type = match (first >> 1) & 0x01 {
             0 => OpShiftType::One,
             1 => OpShiftType::Cl,
-            _ => return None,
+            _ => return Err(DecodeErrorKind::InvalidOpcode),
           };
           let shift_op = OpShiftOp::from_value((second >> 3) & 0x07)?;
           Op::ShiftWord {
@@ -872,30 +1070,19 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
             dest: mod_rm,
           }
         }
-        4 => {
-          let second = iter.next()?;
-          if second == 0x0A {
-            Op::Nullary(OpNullaryOp::Aam)
-          } else {
-            return None;
-          }
-        }
-        5 => {
-          let second = iter.next()?;
-          if second == 0x0A {
-            Op::Nullary(OpNullaryOp::Aad)
-          } else {
-            return None;
-          }
-        }
-        6 => return None,
+        4 => Op::Aam(next_u8(iter)?),
+        5 => Op::Aad(next_u8(iter)?),
+        6 => {
+          undocumented(model)?;
+          Op::Nullary(OpNullaryOp::Salc)
+        },
         7 => Op::Nullary(OpNullaryOp::Xlat),
-        _ => return None,
+        _ => return Err(DecodeErrorKind::InvalidOpcode),
       }
     },
     0xD8 => {
       // ESC
-      let second = iter.next()?;
+      let second = next_u8(iter)?;
       let rm = parse_mod_rm(second, iter)?;
       let esc_id = ((first & 0x7) << 3) + ((second >> 3) & 0x7);
       Op::Esc(esc_id, rm)
@@ -909,21 +1096,21 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
       // E6..E7 - OUT
       match first_octet {
         0..=3 => {
-          let second = iter.next()?;
+          let second = next_u8(iter)?;
           let jmp_type = match first_octet {
             0 => OpCondJmpOp::Loopne,
             1 => OpCondJmpOp::Loope,
             2 => OpCondJmpOp::Loop,
             3 => OpCondJmpOp::Jcxz,
-            _ => return None,
+            _ => return Err(DecodeErrorKind::InvalidOpcode),
           };
           Op::CondJmp { op: jmp_type, offset: second as i8 }
         },
-        4 => Op::InVariable(OpSize::Byte, iter.next()?),
-        5 => Op::InVariable(OpSize::Word, iter.next()?),
-        6 => Op::OutVariable(OpSize::Byte, iter.next()?),
-        7 => Op::OutVariable(OpSize::Word, iter.next()?),
-        _ => return None,
+        4 => Op::InVariable(OpSize::Byte, next_u8(iter)?),
+        5 => Op::InVariable(OpSize::Word, next_u8(iter)?),
+        6 => Op::OutVariable(OpSize::Byte, next_u8(iter)?),
+        7 => Op::OutVariable(OpSize::Word, next_u8(iter)?),
+        _ => return Err(DecodeErrorKind::InvalidOpcode),
       }
     },
     0xE8 => {
@@ -938,12 +1125,12 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
         1 => Op::Jmp(OpCallType::WithinDirect(iter_next_u16(iter)? as i16)),
         2 => Op::Jmp(OpCallType::InterDirect(
           iter_next_u16(iter)?, iter_next_u16(iter)?)),
-        3 => Op::Jmp(OpCallType::WithinDirect(iter.next()? as i8 as i16)),
+        3 => Op::JmpShort(next_u8(iter)? as i8),
         4 => Op::InFixed(OpSize::Byte),
         5 => Op::InFixed(OpSize::Word),
         6 => Op::OutFixed(OpSize::Byte),
         7 => Op::OutFixed(OpSize::Word),
-        _ => return None,
+        _ => return Err(DecodeErrorKind::InvalidOpcode),
       }
     },
     0xF0 => {
@@ -953,25 +1140,26 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
       // F3 - REP
       // F4 - HLT
       // F5 - CMC
-      // TEST, -, NOT, NEG, MUL, IMUL, DIV, IDIV
+      // TEST, TEST (undocumented), NOT, NEG, MUL, IMUL, DIV, IDIV
       // F6 - op R/M8
       // F7 - op R/M16
       match first_octet {
-        0 => Op::Nullary(OpNullaryOp::Lock),
-        1 => return None,
-        2 => Op::Nullary(OpNullaryOp::Repz),
-        3 => Op::Nullary(OpNullaryOp::Rep),
+        // LOCK, LOCK (undocumented), REPNE, REP prefixes
+        0..=3 => return Err(DecodeErrorKind::InvalidOpcode),
         4 => Op::Nullary(OpNullaryOp::Hlt),
         5 => Op::Nullary(OpNullaryOp::Cmc),
         6 => {
-          let second = iter.next()?;
+          let second = next_u8(iter)?;
           let mod_rm = parse_mod_rm(second, iter)?;
           let type_octet = (second >> 3) & 0x07;
+          if type_octet == 1 {
+            undocumented(model)?;
+          }
           match type_octet {
-            0 => {
+            0 | 1 => {
               Op::BinaryByte {
                 op: OpBinaryOp::Test,
-                src: OperandByte::ImmByte(iter.next()?),
+                src: OperandByte::ImmByte(next_u8(iter)?),
                 dest: mod_rm,
               }
             },
@@ -985,11 +1173,14 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
           }
         },
         7 => {
-          let second = iter.next()?;
+          let second = next_u8(iter)?;
           let mod_rm = parse_mod_rm(second, iter)?;
           let type_octet = (second >> 3) & 0x07;
+          if type_octet == 1 {
+            undocumented(model)?;
+          }
           match type_octet {
-            0 => {
+            0 | 1 => {
               Op::BinaryWord {
                 op: OpBinaryOp::Test,
                 src: OperandWord::ImmWord(iter_next_u16(iter)?),
@@ -1005,7 +1196,7 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
             },
           }
         },
-        _ => return None,
+        _ => return Err(DecodeErrorKind::InvalidOpcode),
       }
     },
     0xF8 => {
@@ -1015,9 +1206,9 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
       // FB - STI
       // FC - CLD
       // FD - STD
-      // INC, DEC, -, -, -, -, -, -
+      // INC, DEC, -, -, -, -, Push, Push (undocumented)
       // FE - op R/M8
-      // INC, DEC, CALL, CALL, JMP, JMP, Push, -
+      // INC, DEC, CALL, CALL, JMP, JMP, Push, Push (undocumented)
       // FF - op MEM16
       match first_octet {
         0 => Op::Nullary(OpNullaryOp::Clc),
@@ -1027,26 +1218,26 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
         4 => Op::Nullary(OpNullaryOp::Cld),
         5 => Op::Nullary(OpNullaryOp::Std),
         6 => {
-          let second = iter.next()?;
+          let second = next_u8(iter)?;
           let mod_rm = parse_mod_rm(second, iter)?;
           let type_octet = (second >> 3) & 0x07;
-          let op = OpUnaryOp::from_grp2(type_octet)?;
+          let op = OpUnaryOp::from_grp2(type_octet, model)?;
           Op::UnaryByte {
             op: op,
             dest: mod_rm,
           }
         },
         7 => {
-          let second = iter.next()?;
+          let second = next_u8(iter)?;
           let mod_rm = parse_mod_rm(second, iter)?;
           let type_octet = (second >> 3) & 0x07;
           match type_octet {
             2 => Op::Call(OpCallType::WithinIndirect(mod_rm)),
-            3 => Op::Call(OpCallType::InterIndirect(mod_rm)),
+            3 => Op::Call(OpCallType::InterIndirect(memory(mod_rm)?)),
             4 => Op::Jmp(OpCallType::WithinIndirect(mod_rm)),
-            5 => Op::Jmp(OpCallType::InterIndirect(mod_rm)),
+            5 => Op::Jmp(OpCallType::InterIndirect(memory(mod_rm)?)),
             _ => {
-              let op = OpUnaryOp::from_grp2(type_octet)?;
+              let op = OpUnaryOp::from_grp2(type_octet, model)?;
               Op::UnaryWord {
                 op: op,
                 dest: mod_rm,
@@ -1054,10 +1245,10 @@ pub fn parse_op(iter: &mut dyn Iterator<Item = u8>) -> Option<Op> {
             },
           }
         },
-        _ => return None,
+        _ => return Err(DecodeErrorKind::InvalidOpcode),
       }
     },
-    _ => return None,
+    _ => return Err(DecodeErrorKind::InvalidOpcode),
   })
 }
 
@@ -1067,109 +1258,252 @@ fn test_parse_add() {
     let input: Vec<u8> = vec![0x00, 0xC0];
     assert_eq!(
       parse_op(&mut input.into_iter()),
-      Some(Op::BinaryByte {
+      Ok(Instruction::new(Op::BinaryByte {
         op: OpBinaryOp::Add,
         src: Operand::Register(RegisterByteType::Al),
         dest: Operand::Register(RegisterByteType::Al),
-      }),
+      })),
     );
   }
   {
     let input: Vec<u8> = vec![0x8b, 0xE2];
     assert_eq!(
       parse_op(&mut input.into_iter()),
-      Some(Op::BinaryWord {
+      Ok(Instruction::new(Op::BinaryWord {
         op: OpBinaryOp::Mov,
         src: Operand::Register(RegisterWordType::Dx),
         dest: Operand::Register(RegisterWordType::Sp),
-      }),
+      })),
     );
   }
   {
     let input: Vec<u8> = vec![0x89, 0xE2];
     assert_eq!(
       parse_op(&mut input.into_iter()),
-      Some(Op::BinaryWord {
+      Ok(Instruction::new(Op::BinaryWord {
         op: OpBinaryOp::Mov,
         src: Operand::Register(RegisterWordType::Sp),
         dest: Operand::Register(RegisterWordType::Dx),
-      }),
+      })),
     );
   }
   {
     let input: Vec<u8> = vec![0x89, 0xc3];
     assert_eq!(
       parse_op(&mut input.into_iter()),
-      Some(Op::BinaryWord {
+      Ok(Instruction::new(Op::BinaryWord {
         op: OpBinaryOp::Mov,
         src: Operand::Register(RegisterWordType::Ax),
         dest: Operand::Register(RegisterWordType::Bx),
-      }),
+      })),
     );
   }
   {
     let input: Vec<u8> = vec![0x88, 0xc3];
     assert_eq!(
       parse_op(&mut input.into_iter()),
-      Some(Op::BinaryByte {
+      Ok(Instruction::new(Op::BinaryByte {
         op: OpBinaryOp::Mov,
         src: Operand::Register(RegisterByteType::Al),
         dest: Operand::Register(RegisterByteType::Bl),
-      }),
+      })),
     );
   }
   {
     let input: Vec<u8> = vec![0x06];
     assert_eq!(
       parse_op(&mut input.into_iter()),
-      Some(Op::UnaryWord {
+      Ok(Instruction::new(Op::UnaryWord {
         op: OpUnaryOp::Push,
         dest: Operand::Register(RegisterWordType::Es),
-      }),
+      })),
     );
   }
   {
     let input: Vec<u8> = vec![0x01, 0xd4];
     assert_eq!(
       parse_op(&mut input.into_iter()),
-      Some(Op::BinaryWord {
+      Ok(Instruction::new(Op::BinaryWord {
         op: OpBinaryOp::Add,
         src: Operand::Register(RegisterWordType::Dx),
         dest: Operand::Register(RegisterWordType::Sp),
-      }),
+      })),
     );
   }
   {
     let input: Vec<u8> = vec![0x31, 0x80, 0xab, 0xcd];
     assert_eq!(
       parse_op(&mut input.into_iter()),
-      Some(Op::BinaryWord {
+      Ok(Instruction::new(Op::BinaryWord {
         op: OpBinaryOp::Xor,
         src: Operand::Register(RegisterWordType::Ax),
         dest: Operand::Address(AddressType::BxSi, 0xcdab as u16 as i16),
-      }),
+      })),
     );
   }
   {
     let input: Vec<u8> = vec![0x80, 0x80, 0xab, 0xcd, 0x25];
     assert_eq!(
       parse_op(&mut input.into_iter()),
-      Some(Op::BinaryByte {
+      Ok(Instruction::new(Op::BinaryByte {
         op: OpBinaryOp::Add,
         src: Operand::ImmByte(0x25),
         dest: Operand::Address(AddressType::BxSi, 0xcdab as u16 as i16),
-      }),
+      })),
     );
   }
   {
     let input: Vec<u8> = vec![0x81, 0xc3, 0x00, 0xf0];
     assert_eq!(
       parse_op(&mut input.into_iter()),
-      Some(Op::BinaryWord {
+      Ok(Instruction::new(Op::BinaryWord {
         op: OpBinaryOp::Add,
         src: Operand::ImmWord(0xf000),
         dest: Operand::Register(RegisterWordType::Bx),
+      })),
+    );
+  }
+}
+
+#[test]
+fn test_parse_prefix() {
+  {
+    let input: Vec<u8> = vec![0x26, 0xf3, 0xa4];
+    assert_eq!(
+      parse_op(&mut input.into_iter()),
+      Ok(Instruction {
+        prefixes: OpPrefixes {
+        segment: Some(RegisterWordType::Es),
+        repeat: Some(OpRepeatType::Rep),
+        lock: false,
+        },
+        op: Op::Movs(OpSize::Byte),
       }),
     );
   }
+  {
+    let input: Vec<u8> = vec![0xf0, 0x2e, 0x36, 0xf2, 0xae];
+    assert_eq!(
+      parse_op(&mut input.into_iter()),
+      Ok(Instruction {
+        prefixes: OpPrefixes {
+        segment: Some(RegisterWordType::Ss),
+        repeat: Some(OpRepeatType::Repnz),
+        lock: true,
+        },
+        op: Op::Scas(OpSize::Byte),
+      }),
+    );
+  }
+  {
+    // A prefix without an instruction isn't an instruction.
+    let input: Vec<u8> = vec![0x26, 0xf3];
+    assert_eq!(
+      parse_op(&mut input.into_iter()),
+      Err(DecodeError {
+        kind: DecodeErrorKind::Truncated,
+        offset: 2,
+        bytes: vec![0x26, 0xf3],
+      }),
+    );
+  }
+  {
+    // Prefixes without end are cut short.
+    let mut input = std::iter::repeat(0x26);
+    assert_eq!(
+      parse_op(&mut input),
+      Err(DecodeError {
+        kind: DecodeErrorKind::TooManyPrefixes,
+        offset: MAX_PREFIXES,
+        bytes: vec![0x26; MAX_PREFIXES],
+      }),
+    );
+    let mut input = vec![0x26; MAX_PREFIXES - 1];
+    input.push(0x90);
+    assert!(parse_op(&mut input.into_iter()).is_ok());
+  }
+}
+
+#[test]
+fn test_parse_undocumented() {
+  let cases: Vec<(Vec<u8>, Op)> = vec![
+    (vec![0x0f], Op::UnaryWord {
+      op: OpUnaryOp::Pop,
+      dest: Operand::Register(RegisterWordType::Cs),
+    }),
+    (vec![0x64, 0x10], Op::CondJmp { op: OpCondJmpOp::Je, offset: 0x10 }),
+    (vec![0x6f, 0xfe], Op::CondJmp { op: OpCondJmpOp::Jg, offset: -2 }),
+    (vec![0xc0, 0x04, 0x00], Op::RetWithinImm(4)),
+    (vec![0xc1], Op::RetWithin),
+    (vec![0xc8, 0x04, 0x00], Op::RetInterImm(4)),
+    (vec![0xc9], Op::RetInter),
+    (vec![0xd6], Op::Nullary(OpNullaryOp::Salc)),
+    (vec![0xf6, 0xc8, 0x12], Op::BinaryByte {
+      op: OpBinaryOp::Test,
+      src: Operand::ImmByte(0x12),
+      dest: Operand::Register(RegisterByteType::Al),
+    }),
+    (vec![0xfe, 0xf8], Op::UnaryByte {
+      op: OpUnaryOp::Push,
+      dest: Operand::Register(RegisterByteType::Al),
+    }),
+    (vec![0xff, 0xf8], Op::UnaryWord {
+      op: OpUnaryOp::Push,
+      dest: Operand::Register(RegisterWordType::Ax),
+    }),
+    (vec![0x8e, 0xe8], Op::BinaryWord {
+      op: OpBinaryOp::Mov,
+      src: Operand::Register(RegisterWordType::Ax),
+      dest: Operand::Register(RegisterWordType::Cs),
+    }),
+  ];
+  for (input, op) in cases {
+    assert_eq!(
+      parse_op_with_model(&mut input.clone().into_iter(), CpuModel::Strict)
+        .map_err(|err| err.kind),
+      Err(DecodeErrorKind::InvalidOpcode),
+    );
+    assert_eq!(
+      parse_op(&mut input.into_iter()),
+      Ok(Instruction::new(op)),
+    );
+  }
+  {
+    // F1 is a LOCK prefix
+    let input: Vec<u8> = vec![0xf1, 0x90];
+    assert_eq!(
+      parse_op(&mut input.into_iter()).map(|instr| instr.prefixes.lock),
+      Ok(true),
+    );
+  }
+}
+
+#[test]
+fn test_parse_error() {
+  let cases: Vec<(Vec<u8>, DecodeErrorKind, usize, Vec<u8>)> = vec![
+    // mov ax, imm16 without its last byte
+    (vec![0xb8, 0x34], DecodeErrorKind::Truncated, 0, vec![0xb8, 0x34]),
+    // cs: add [bx+si+disp16], al without the displacement
+    (vec![0x2e, 0x00, 0x80, 0x12], DecodeErrorKind::Truncated, 1,
+      vec![0x2e, 0x00, 0x80, 0x12]),
+    // mov [bx], imm8 with a reg field other than 0
+    (vec![0xc6, 0x0f, 0x12], DecodeErrorKind::InvalidOpcode, 0,
+      vec![0xc6, 0x0f]),
+    // lea ax, bx
+    (vec![0x8d, 0xc3], DecodeErrorKind::RegisterOperand, 0, vec![0x8d, 0xc3]),
+    // jmp far bx
+    (vec![0xf3, 0xff, 0xeb], DecodeErrorKind::RegisterOperand, 1,
+      vec![0xf3, 0xff, 0xeb]),
+  ];
+  for (input, kind, offset, bytes) in cases {
+    assert_eq!(
+      parse_op(&mut input.into_iter()),
+      Err(DecodeError { kind, offset, bytes }),
+    );
+  }
+  let input: Vec<u8> = vec![0x8d, 0xc3];
+  assert_eq!(
+    format!("{}", parse_op(&mut input.into_iter()).unwrap_err()),
+    "register used as memory operand at +0: 8D C3",
+  );
 }
@@ -3,16 +3,11 @@ use super::operand::*;
 use super::op::*;
 use super::register::*;
 use super::flags::*;
+use super::exception::*;
+use super::debugger::Access;
 use crate::mem::*;
 
 type Flags = (u16, u16);
-const INT_DE: u8 = 0x0;
-const INT_OF: u8 = 0x4;
-const INT_BR: u8 = 0x5;
-const INT_UD: u8 = 0x6;
-const INT_DF: u8 = 0x8;
-const INT_GP: u8 = 0xd;
-const INT_MF: u8 = 0x10;
 
 trait OperandOpValue: Sized + Copy {
   fn zero() -> Self;
@@ -35,8 +30,8 @@ trait OperandOpValue: Sized + Copy {
   fn not(src: Self) -> Self;
   fn mul(cpu: &mut CPU, value: Self) -> ();
   fn imul(cpu: &mut CPU, value: Self) -> ();
-  fn div(cpu: &mut CPU, value: Self) -> Option<()>;
-  fn idiv(cpu: &mut CPU, value: Self) -> Option<()>;
+  fn div(cpu: &mut CPU, value: Self) -> Result<(), Exception>;
+  fn idiv(cpu: &mut CPU, value: Self) -> Result<(), Exception>;
   fn shl(src: Self, count: u8) -> Self;
   fn sar(src: Self, count: u8) -> Self;
   fn shr(src: Self, count: u8) -> Self;
@@ -45,16 +40,28 @@ trait OperandOpValue: Sized + Copy {
   fn get_flags(value: Self) -> Flags;
 }
 
+fn get_mul_flags(carry: bool, high_sign: bool, high_low_byte: u8) -> Flags {
+  // Only CF and OF are defined. The rest follows 8086: ZF is the inverse of
+  // CF, and SF / PF come from the upper half of the result.
+  let (_, pzs_set) = <u8 as OperandOpValue>::get_flags(high_low_byte);
+  (CF | OF | ZF | SF | PF | AF,
+    if carry { CF | OF } else { ZF } |
+    if high_sign { SF } else { 0 } |
+    (pzs_set & PF)
+  )
+}
+
 impl OperandOpValue for u8 {
   fn zero() -> u8 { 0 }
   fn one() -> u8 { 1 }
   fn get_stack_size() -> u16 { 2 }
   fn get_bits() -> u8 { 8 }
   fn add(src: u8, dest: u8, carry: bool) -> (u8, Flags) {
-    let result = src.wrapping_add(dest.wrapping_add(if carry { 1 } else { 0 }));
-    let cf = !(result > src && result > dest);
-    let af = (!(src ^ dest)) & (src ^ result) & 0x8 != 0;
-    let of = (!(src ^ dest)) & (src ^ result) & 0x80 != 0;
+    let wide = (src as u16) + (dest as u16) + (carry as u16);
+    let result = wide as u8;
+    let cf = wide > 0xFF;
+    let af = (src ^ dest ^ result) & 0x10 != 0;
+    let of = (src ^ result) & (dest ^ result) & 0x80 != 0;
     let (prev_clear, prev_set) = OperandOpValue::get_flags(result);
     (result, (
       prev_clear | CF | AF | OF,
@@ -65,14 +72,14 @@ impl OperandOpValue for u8 {
     ))
   }
   fn sub(src: u8, dest: u8, carry: bool) -> (u8, Flags) {
-    let carry_src = (src as i8).wrapping_add(if carry { 1 } else { 0 });
-    let new_src = carry_src.wrapping_neg() as u8;
-    let result = dest.wrapping_add(new_src);
-    let cf = (carry_src as u8) > dest;
-    let af = (!(carry_src as u8 ^ dest)) &
-      (carry_src as u8 ^ result) & 0x8 != 0;
-    let of = (!(carry_src as u8 ^ dest)) &
-      (carry_src as u8 ^ result) & 0x80 != 0;
+    // dest - src - carry
+    let wide = (dest as u16)
+      .wrapping_sub(src as u16)
+      .wrapping_sub(carry as u16);
+    let result = wide as u8;
+    let cf = (src as u16) + (carry as u16) > (dest as u16);
+    let af = (src ^ dest ^ result) & 0x10 != 0;
+    let of = (src ^ dest) & (dest ^ result) & 0x80 != 0;
     let (prev_clear, prev_set) = OperandOpValue::get_flags(result);
     (result, (
       prev_clear | CF | AF | OF,
@@ -98,43 +105,52 @@ impl OperandOpValue for u8 {
     let other = u8::read_reg(&cpu.register, &RegisterByteType::Al);
     let result = (other as u16) * (value as u16);
     u16::write_reg(&mut cpu.register, &RegisterWordType::Ax, result);
-    cpu.blit_flags(OF | CF, if result & 0xFF00 == 0 { OF | CF } else { 0 });
+    let high = (result >> 8) as u8;
+    let (flag_clear, flag_set) =
+      get_mul_flags(high != 0, high & 0x80 != 0, high);
+    cpu.blit_flags(flag_clear, flag_set);
   }
   fn imul(cpu: &mut CPU, value: u8) -> () {
     let other = u8::read_reg(&cpu.register, &RegisterByteType::Al);
-    let result = ((other as i8 as i16) * (value as i8 as i16)) as u16;
-    u16::write_reg(&mut cpu.register, &RegisterWordType::Ax, result);
-    cpu.blit_flags(OF | CF, if result & 0xFF00 == 0 { OF | CF } else { 0 });
+    let result = (other as i8 as i16) * (value as i8 as i16);
+    u16::write_reg(&mut cpu.register, &RegisterWordType::Ax, result as u16);
+    let high = ((result as u16) >> 8) as u8;
+    // Set if AH isn't a sign extension of AL
+    let carry = result != (result as i8 as i16);
+    let (flag_clear, flag_set) =
+      get_mul_flags(carry, high & 0x80 != 0, high);
+    cpu.blit_flags(flag_clear, flag_set);
   }
-  fn div(cpu: &mut CPU, value: u8) -> Option<()> {
+  fn div(cpu: &mut CPU, value: u8) -> Result<(), Exception> {
     if value == 0 {
-      cpu.interrupt(INT_DE);
-      return None;
+      return Err(Exception::DivideError);
     }
     let dividend = u16::read_reg(&cpu.register, &RegisterWordType::Ax);
     let quotient = dividend / (value as u16);
     if quotient > 0xFF {
-      return None;
+      return Err(Exception::DivideError);
     }
     let remainder = dividend % (value as u16);
     u8::write_reg(&mut cpu.register, &RegisterByteType::Al, quotient as u8);
     u8::write_reg(&mut cpu.register, &RegisterByteType::Ah, remainder as u8);
-    Some(())
+    Ok(())
   }
-  fn idiv(cpu: &mut CPU, value: u8) -> Option<()> {
+  fn idiv(cpu: &mut CPU, value: u8) -> Result<(), Exception> {
     if value == 0 {
-      cpu.interrupt(INT_DE);
-      return None;
+      return Err(Exception::DivideError);
     }
-    let dividend = u16::read_reg(&cpu.register, &RegisterWordType::Ax);
-    let quotient = (dividend as i16 / (value as i16)) as u16;
-    if quotient > 0x7F || quotient < 0x80 {
-      return None;
+    let dividend =
+      u16::read_reg(&cpu.register, &RegisterWordType::Ax) as i16 as i32;
+    let divisor = value as i8 as i32;
+    let quotient = dividend / divisor;
+    // 8086 can't return -0x80 as a quotient, unlike later processors.
+    if !(-0x7F..=0x7F).contains(&quotient) {
+      return Err(Exception::DivideError);
     }
-    let remainder = ((dividend as i16) % (value as i16)) as u16;
+    let remainder = dividend % divisor;
     u8::write_reg(&mut cpu.register, &RegisterByteType::Al, quotient as u8);
     u8::write_reg(&mut cpu.register, &RegisterByteType::Ah, remainder as u8);
-    Some(())
+    Ok(())
   }
   fn shl(src: u8, count: u8) -> u8 {
     src << count
@@ -172,10 +188,11 @@ impl OperandOpValue for u16 {
   fn get_stack_size() -> u16 { 2 }
   fn get_bits() -> u8 { 16 }
   fn add(src: u16, dest: u16, carry: bool) -> (u16, Flags) {
-    let result = src.wrapping_add(dest.wrapping_add(if carry { 1 } else { 0 }));
-    let cf = !(result > src && result > dest);
-    let af = (!(src ^ dest)) & (src ^ result) & 0x8 != 0;
-    let of = (!(src ^ dest)) & (src ^ result) & 0x8000 != 0;
+    let wide = (src as u32) + (dest as u32) + (carry as u32);
+    let result = wide as u16;
+    let cf = wide > 0xFFFF;
+    let af = (src ^ dest ^ result) & 0x10 != 0;
+    let of = (src ^ result) & (dest ^ result) & 0x8000 != 0;
     let (prev_clear, prev_set) = OperandOpValue::get_flags(result);
     (result, (
       prev_clear | CF | AF | OF,
@@ -186,14 +203,14 @@ impl OperandOpValue for u16 {
     ))
   }
   fn sub(src: u16, dest: u16, carry: bool) -> (u16, Flags) {
-    let carry_src = (src as i16).wrapping_add(if carry { 1 } else { 0 });
-    let new_src = carry_src.wrapping_neg() as u16;
-    let result = dest.wrapping_add(new_src);
-    let cf = (carry_src as u16) > dest;
-    let af = (!(carry_src as u16 ^ dest)) &
-      (carry_src as u16 ^ result) & 0x8 != 0;
-    let of = (!(carry_src as u16 ^ dest)) &
-      (carry_src as u16 ^ result) & 0x8000 != 0;
+    // dest - src - carry
+    let wide = (dest as u32)
+      .wrapping_sub(src as u32)
+      .wrapping_sub(carry as u32);
+    let result = wide as u16;
+    let cf = (src as u32) + (carry as u32) > (dest as u32);
+    let af = (src ^ dest ^ result) & 0x10 != 0;
+    let of = (src ^ dest) & (dest ^ result) & 0x8000 != 0;
     let (prev_clear, prev_set) = OperandOpValue::get_flags(result);
     (result, (
       prev_clear | CF | AF | OF,
@@ -222,50 +239,59 @@ impl OperandOpValue for u16 {
       (result & 0xFFFF) as u16);
     u16::write_reg(&mut cpu.register, &RegisterWordType::Dx,
       ((result >> 16) & 0xFFFF) as u16);
-    cpu.blit_flags(OF | CF, if result & 0xFFFF0000 == 0 { OF | CF } else { 0 });
+    let high = (result >> 16) as u16;
+    let (flag_clear, flag_set) =
+      get_mul_flags(high != 0, high & 0x8000 != 0, high as u8);
+    cpu.blit_flags(flag_clear, flag_set);
   }
   fn imul(cpu: &mut CPU, value: u16) -> () {
     let other = u16::read_reg(&cpu.register, &RegisterWordType::Ax);
-    let result = ((other as i8 as i32) * (value as i8 as i32)) as u32;
+    let result = (other as i16 as i32) * (value as i16 as i32);
     u16::write_reg(&mut cpu.register, &RegisterWordType::Ax,
       (result & 0xFFFF) as u16);
     u16::write_reg(&mut cpu.register, &RegisterWordType::Dx,
       ((result >> 16) & 0xFFFF) as u16);
-    cpu.blit_flags(OF | CF, if result & 0xFFFF0000 == 0 { OF | CF } else { 0 });
+    let high = ((result as u32) >> 16) as u16;
+    // Set if DX isn't a sign extension of AX
+    let carry = result != (result as i16 as i32);
+    let (flag_clear, flag_set) =
+      get_mul_flags(carry, high & 0x8000 != 0, high as u8);
+    cpu.blit_flags(flag_clear, flag_set);
   }
-  fn div(cpu: &mut CPU, value: u16) -> Option<()> {
+  fn div(cpu: &mut CPU, value: u16) -> Result<(), Exception> {
     if value == 0 {
-      cpu.interrupt(INT_DE);
-      return None;
+      return Err(Exception::DivideError);
     }
     let dividend =
       u16::read_reg(&cpu.register, &RegisterWordType::Ax) as u32 |
       ((u16::read_reg(&cpu.register, &RegisterWordType::Dx) as u32) << 16);
     let quotient = dividend / (value as u32);
     if quotient > 0xFFFF {
-      return None;
+      return Err(Exception::DivideError);
     }
     let remainder = dividend % (value as u32);
     u16::write_reg(&mut cpu.register, &RegisterWordType::Ax, quotient as u16);
     u16::write_reg(&mut cpu.register, &RegisterWordType::Dx, remainder as u16);
-    Some(())
+    Ok(())
   }
-  fn idiv(cpu: &mut CPU, value: u16) -> Option<()> {
+  fn idiv(cpu: &mut CPU, value: u16) -> Result<(), Exception> {
     if value == 0 {
-      cpu.interrupt(INT_DE);
-      return None;
+      return Err(Exception::DivideError);
     }
-    let dividend =
+    let dividend = (
       u16::read_reg(&cpu.register, &RegisterWordType::Ax) as u32 |
-      ((u16::read_reg(&cpu.register, &RegisterWordType::Dx) as u32) << 16);
-    let quotient = (dividend as i32 / (value as i32)) as u32;
-    if quotient > 0x7FFF || quotient < 0x8000 {
-      return None;
+      ((u16::read_reg(&cpu.register, &RegisterWordType::Dx) as u32) << 16)
+    ) as i32 as i64;
+    let divisor = value as i16 as i64;
+    let quotient = dividend / divisor;
+    // 8086 can't return -0x8000 as a quotient, unlike later processors.
+    if !(-0x7FFF..=0x7FFF).contains(&quotient) {
+      return Err(Exception::DivideError);
     }
-    let remainder = ((dividend as i32) % (value as i32)) as u32;
+    let remainder = dividend % divisor;
     u16::write_reg(&mut cpu.register, &RegisterWordType::Ax, quotient as u16);
     u16::write_reg(&mut cpu.register, &RegisterWordType::Dx, remainder as u16);
-    Some(())
+    Ok(())
   }
   fn shl(src: u16, count: u8) -> u16 {
     src << count
@@ -303,8 +329,7 @@ fn push_val<T, R>(
 ) -> ()
   where T: OperandValue<R> + OperandOpValue, R: RegisterType 
 {
-  // TODO Is this really good idea?
-  cpu.register.sp -= T::get_stack_size();
+  cpu.register.sp = cpu.register.sp.wrapping_sub(T::get_stack_size());
   cpu.set_operand_with_seg::<T, R>(
     &Operand::Direct(cpu.register.sp),
     &Some(RegisterWordType::Ss),
@@ -316,11 +341,10 @@ fn pop_val<T, R>(
 ) -> T
   where T: OperandValue<R> + OperandOpValue, R: RegisterType 
 {
-  // TODO Is this really good idea?
   let result = cpu.get_operand_with_seg::<T, R>(
     &Operand::Direct(cpu.register.sp),
     &Some(RegisterWordType::Ss));
-  cpu.register.sp += T::get_stack_size();
+  cpu.register.sp = cpu.register.sp.wrapping_add(T::get_stack_size());
   return result;
 }
 
@@ -351,7 +375,7 @@ fn exec_binary<T, R>(
     OpBinaryOp::And => {
       let result = OperandOpValue::and(src_val, dest_val);
       let (flag_clear, flag_set) = OperandOpValue::get_flags(result);
-      cpu.blit_flags(flag_clear | OF | CF, flag_set);
+      cpu.blit_flags(flag_clear | OF | CF | AF, flag_set);
       cpu.set_operand(dest, result);
     },
     OpBinaryOp::Cmp => {
@@ -365,7 +389,7 @@ fn exec_binary<T, R>(
     OpBinaryOp::Or => {
       let result = OperandOpValue::or(src_val, dest_val);
       let (flag_clear, flag_set) = OperandOpValue::get_flags(result);
-      cpu.blit_flags(flag_clear | OF | CF, flag_set);
+      cpu.blit_flags(flag_clear | OF | CF | AF, flag_set);
       cpu.set_operand(dest, result);
     },
     OpBinaryOp::Sbb => {
@@ -384,7 +408,7 @@ fn exec_binary<T, R>(
     OpBinaryOp::Test => {
       let result = OperandOpValue::and(src_val, dest_val);
       let (flag_clear, flag_set) = OperandOpValue::get_flags(result);
-      cpu.blit_flags(flag_clear | OF | CF, flag_set);
+      cpu.blit_flags(flag_clear | OF | CF | AF, flag_set);
     },
     OpBinaryOp::Xchg => {
       cpu.set_operand(dest, src_val);
@@ -393,7 +417,7 @@ fn exec_binary<T, R>(
     OpBinaryOp::Xor => {
       let result = OperandOpValue::xor(src_val, dest_val);
       let (flag_clear, flag_set) = OperandOpValue::get_flags(result);
-      cpu.blit_flags(flag_clear | OF | CF, flag_set);
+      cpu.blit_flags(flag_clear | OF | CF | AF, flag_set);
       cpu.set_operand(dest, result);
     },
   }
@@ -403,7 +427,7 @@ fn exec_unary<T, R>(
   cpu: &mut CPU,
   op: &OpUnaryOp,
   dest: &Operand<R>,
-) -> () 
+) -> Result<(), Exception>
   where T: OperandValue<R> + OperandOpValue, R: RegisterType
 {
   let dest_val: T = cpu.get_operand(dest);
@@ -416,15 +440,17 @@ fn exec_unary<T, R>(
       cpu.set_operand(dest, new_val);
     },
     OpUnaryOp::Inc => {
+      // CF is left untouched
       let (result, (flag_clear, flag_set)) =
         OperandOpValue::add(OperandOpValue::zero(), dest_val, true);
-      cpu.blit_flags(flag_clear, flag_set);
+      cpu.blit_flags(flag_clear & !CF, flag_set & !CF);
       cpu.set_operand(dest, result);
     },
     OpUnaryOp::Dec => {
+      // CF is left untouched
       let (result, (flag_clear, flag_set)) =
         OperandOpValue::sub(OperandOpValue::zero(), dest_val, true);
-      cpu.blit_flags(flag_clear, flag_set);
+      cpu.blit_flags(flag_clear & !CF, flag_set & !CF);
       cpu.set_operand(dest, result);
     },
     OpUnaryOp::Not => {
@@ -443,12 +469,13 @@ fn exec_unary<T, R>(
       OperandOpValue::imul(cpu, dest_val);
     },
     OpUnaryOp::Div => {
-      OperandOpValue::div(cpu, dest_val);
+      OperandOpValue::div(cpu, dest_val)?;
     },
     OpUnaryOp::Idiv => {
-      OperandOpValue::idiv(cpu, dest_val);
+      OperandOpValue::idiv(cpu, dest_val)?;
     },
   }
+  Ok(())
 }
 
 fn exec_shift<T, R>(
@@ -460,97 +487,102 @@ fn exec_shift<T, R>(
   where T: OperandValue<R> + OperandOpValue, R: RegisterType
 {
   let dest_val: T = cpu.get_operand(dest);
+  // 8086 doesn't mask the count; and a zero count leaves flags untouched.
   let count = match shift_type {
     OpShiftType::Cl => u8::read_reg(&cpu.register, &RegisterByteType::Cl),
     OpShiftType::One => 1,
   };
-  let (result, (flag_clear, flag_set)) = match op {
-    OpShiftOp::Rol => {
-      let bits = T::get_bits();
-      let wrapped_count = count % bits;
-      // a = a << 1 | a >> 7;
-      let result = T::or(
-        T::shl(dest_val, wrapped_count),
-        T::shr(dest_val, bits - wrapped_count));
-      let cf = T::lsb(result);
-      let of = count == 1 && T::msb(result) != cf;
-      (result, (CF | OF, if cf { CF } else { 0 } | if of { OF } else { 0 }))
-    },
-    OpShiftOp::Ror => {
-      let bits = T::get_bits();
-      let wrapped_count = count % bits;
-      // a = a >> 1 | a << 7;
-      let result = T::or(
-        T::shr(dest_val, wrapped_count),
-        T::shl(dest_val, bits - wrapped_count));
-      let cf = T::msb(result);
-      let of = count == 1 && cf != T::msb(T::shl(result, 1));
-      (result, (CF | OF, if cf { CF } else { 0 } | if of { OF } else { 0 }))
-    },
-    OpShiftOp::Rcl => {
-      let bits = T::get_bits() + 1;
-      let wrapped_count = count % (bits + 1);
-      let prev_cf = cpu.get_flags() % CF != 0;
-      // a = a << 1 | a >> 8 | cf;
-      // a = a << 2 | a >> 7 | cf << 1;
-      let result = T::or(
-        T::shl(if prev_cf { T::one() } else { T::zero() }, wrapped_count - 1),
-        T::or(
-          T::shl(dest_val, wrapped_count),
-          T::shr(dest_val, bits + 1 - wrapped_count)),
-        );
-      let cf = T::msb(T::shl(dest_val, wrapped_count - 1));
-      let of = count == 1 && T::msb(result) != cf;
-      (result, (CF | OF, if cf { CF } else { 0 } | if of { OF } else { 0 }))
-    },
-    OpShiftOp::Rcr => {
-      let bits = T::get_bits() + 1;
-      let wrapped_count = count % (bits + 1);
-      let prev_cf = cpu.get_flags() % CF != 0;
-      let of = count == 1 && T::msb(dest_val) != prev_cf;
-      // a = a >> 1 | a << 8 | cf << 7;
-      // a = a >> 2 | a << 7 | cf << 6;
-      let result = T::or(
-        T::shl(if prev_cf { T::one() } else { T::zero() }, bits - wrapped_count),
-        T::or(
-          T::shr(dest_val, wrapped_count),
-          T::shl(dest_val, bits + 1 - wrapped_count)),
-        );
-      let cf = T::lsb(T::shr(dest_val, wrapped_count - 1));
-      (result, (CF | OF, if cf { CF } else { 0 } | if of { OF } else { 0 }))
-    },
-    OpShiftOp::Shl | OpShiftOp::Sal => {
-      let cf = T::msb(T::shl(dest_val, count - 1));
-      let result = T::shl(dest_val, count);
-      let of = count == 1 && T::msb(result) != cf;
-      (result, (CF | OF, if cf { CF } else { 0 } | if of { OF } else { 0 }))
-    },
-    OpShiftOp::Shr => {
-      let cf = T::lsb(T::shr(dest_val, count - 1));
-      let result = T::shr(dest_val, count);
-      let of = count == 1 && T::msb(dest_val);
-      (result, (CF | OF, if cf { CF } else { 0 } | if of { OF } else { 0 }))
+  if count == 0 {
+    return;
+  }
+  let high_bit = T::shl(T::one(), T::get_bits() - 1);
+  let bit = |value: bool| if value { T::one() } else { T::zero() };
+  let mut result = dest_val;
+  let mut cf = cpu.get_flags() & CF != 0;
+  let mut of = false;
+  // Shift one bit at a time; OF ends up being the one from the last step.
+  for _ in 0..count {
+    match op {
+      OpShiftOp::Rol => {
+        cf = T::msb(result);
+        result = T::or(T::shl(result, 1), bit(cf));
+        of = T::msb(result) != cf;
+      },
+      OpShiftOp::Ror => {
+        cf = T::lsb(result);
+        result = T::or(T::shr(result, 1), if cf { high_bit } else { T::zero() });
+        of = T::msb(result) != T::msb(T::shl(result, 1));
+      },
+      OpShiftOp::Rcl => {
+        let prev_cf = cf;
+        cf = T::msb(result);
+        result = T::or(T::shl(result, 1), bit(prev_cf));
+        of = T::msb(result) != cf;
+      },
+      OpShiftOp::Rcr => {
+        let prev_cf = cf;
+        cf = T::lsb(result);
+        result = T::or(
+          T::shr(result, 1), if prev_cf { high_bit } else { T::zero() });
+        of = T::msb(result) != T::msb(T::shl(result, 1));
+      },
+      OpShiftOp::Shl | OpShiftOp::Sal => {
+        cf = T::msb(result);
+        result = T::shl(result, 1);
+        of = T::msb(result) != cf;
+      },
+      OpShiftOp::Shr => {
+        cf = T::lsb(result);
+        of = T::msb(result);
+        result = T::shr(result, 1);
+      },
+      OpShiftOp::Sar => {
+        cf = T::lsb(result);
+        of = false;
+        result = T::sar(result, 1);
+      },
+    }
+  }
+  let (flag_clear, flag_set) = match op {
+    OpShiftOp::Rol | OpShiftOp::Ror | OpShiftOp::Rcl | OpShiftOp::Rcr => {
+      // Rotates only touch CF and OF
+      (CF | OF, 0)
     },
-    OpShiftOp::Sar => {
-      let cf = T::lsb(T::sar(dest_val, count - 1));
-      let result = T::sar(dest_val, count);
-      (result, (CF, if cf { CF } else { 0 }))
+    _ => {
+      // AF is undefined; 8086 sets it from bit 4 of a left shift result,
+      // and clears it for right shifts.
+      let af = match op {
+        OpShiftOp::Shl | OpShiftOp::Sal =>
+          T::lsb(T::shr(result, 4)),
+        _ => false,
+      };
+      let (pzs_clear, pzs_set) = T::get_flags(result);
+      (CF | OF | AF | pzs_clear, pzs_set | if af { AF } else { 0 })
     },
   };
   cpu.set_operand(dest, result);
-  cpu.blit_flags(flag_clear, flag_set);
+  cpu.blit_flags(
+    flag_clear,
+    flag_set | if cf { CF } else { 0 } | if of { OF } else { 0 });
 }
 
-fn exec_nullary(cpu: &mut CPU, op: &OpNullaryOp) -> () {
+fn exec_nullary(cpu: &mut CPU, op: &OpNullaryOp) -> Result<(), Exception> {
   match op {
-    OpNullaryOp::Xlat => {},
+    OpNullaryOp::Xlat => {
+      // AL = [DS:BX + AL], DS is overridable
+      let al = u8::read_reg(&cpu.register, &RegisterByteType::Al);
+      let offset = cpu.register.bx.wrapping_add(al as u16);
+      let value: u8 = cpu.get_operand(&OperandByte::Direct(offset));
+      u8::write_reg(&mut cpu.register, &RegisterByteType::Al, value);
+    },
     OpNullaryOp::Lahf => {
       let flags = (cpu.get_flags() & 0xff) as u8;
       u8::write_reg(&mut cpu.register, &RegisterByteType::Ah, flags);
     },
     OpNullaryOp::Sahf => {
-      let flags = u8::read_reg(&mut cpu.register, &RegisterByteType::Ah);
-      cpu.blit_flags(0xff, flags as u16);
+      // Only SF, ZF, AF, PF, CF are loaded
+      let flags = u8::read_reg(&cpu.register, &RegisterByteType::Ah);
+      cpu.blit_flags(SF | ZF | AF | PF | CF, flags as u16);
     },
     OpNullaryOp::Pushf => {
       push_val::<u16, RegisterWordType>(cpu, cpu.get_flags());
@@ -560,108 +592,98 @@ fn exec_nullary(cpu: &mut CPU, op: &OpNullaryOp) -> () {
       cpu.set_flags(result);
     },
     OpNullaryOp::Aaa => {
-      // ASCII adjust AL after addition
+      // ASCII adjust AL after addition. 8086 adjusts AL and AH separately,
+      // and the undefined flags come from adding 6 to AL.
       let flags = cpu.get_flags();
-      let mut al = cpu.register.ax & 0xff;
-      let mut ah = (cpu.register.ax >> 8) & 0xff;
-      if (al & 0xf) > 9 || (flags & AF) != 0 {
-        al = al + 6;
-        ah = ah + 1;
+      let al = u8::read_reg(&cpu.register, &RegisterByteType::Al);
+      let ah = u8::read_reg(&cpu.register, &RegisterByteType::Ah);
+      let adjust = (al & 0xf) > 9 || (flags & AF) != 0;
+      let (result, (flag_clear, flag_set)) =
+        OperandOpValue::add(if adjust { 6u8 } else { 0 }, al, false);
+      cpu.blit_flags(flag_clear, flag_set & !(AF | CF));
+      if adjust {
         cpu.blit_flags(AF | CF, AF | CF);
-      } else {
-        cpu.blit_flags(AF | CF, 0);
+        u8::write_reg(&mut cpu.register, &RegisterByteType::Ah,
+          ah.wrapping_add(1));
       }
-      al = al & 0xf;
-      cpu.register.ax = al | (ah << 8);
+      u8::write_reg(&mut cpu.register, &RegisterByteType::Al, result & 0xf);
     },
     OpNullaryOp::Daa => {
       // Decimal adjust AL after addition
       let flags = cpu.get_flags();
+      let old_af = flags & AF != 0;
       let old_cf = flags & CF != 0;
-      let mut al = cpu.register.ax & 0xff;
-      if (al & 0xf) > 9 || (flags & AF) != 0 {
-        let old_al = al;
-        al = al.wrapping_add(6) & 0xff;
-        let cf = !(al > old_al && al > 6);
-        cpu.blit_flags(AF | CF, AF | (if old_cf || cf { CF } else { 0 }));
-      } else {
-        cpu.blit_flags(AF, 0);
+      let old_al = u8::read_reg(&cpu.register, &RegisterByteType::Al);
+      let mut al = old_al;
+      let af = (old_al & 0xf) > 9 || old_af;
+      if af {
+        al = al.wrapping_add(0x06);
       }
-      if al > 0x99 || old_cf {
-        al = (al + 0x60) & 0xff;
-        cpu.blit_flags(CF, CF);
-      } else {
-        cpu.blit_flags(CF, 0);
+      // 8086 compares against 0x9F instead when AF is set
+      let cf = old_al > (if old_af { 0x9f } else { 0x99 }) || old_cf;
+      if cf {
+        al = al.wrapping_add(0x60);
       }
-      cpu.register.ax = al & (cpu.register.ax & 0xff00);
+      let of = !old_al & al & 0x80 != 0;
+      let (flag_clear, flag_set) = OperandOpValue::get_flags(al);
+      cpu.blit_flags(flag_clear | AF | CF | OF, flag_set |
+        if af { AF } else { 0 } |
+        if cf { CF } else { 0 } |
+        if of { OF } else { 0 });
+      u8::write_reg(&mut cpu.register, &RegisterByteType::Al, al);
     },
     OpNullaryOp::Aas => {
-      // ASCII adjust AL after subtraction
+      // ASCII adjust AL after subtraction. Like AAA, the undefined flags
+      // come from subtracting 6 from AL.
       let flags = cpu.get_flags();
-      let mut al = cpu.register.ax & 0xff;
-      let mut ah = (cpu.register.ax >> 8) & 0xff;
-      if (al & 0xf) > 9 || (flags & AF) != 0 {
-        al = al - 6;
-        ah = ah - 1;
+      let al = u8::read_reg(&cpu.register, &RegisterByteType::Al);
+      let ah = u8::read_reg(&cpu.register, &RegisterByteType::Ah);
+      let adjust = (al & 0xf) > 9 || (flags & AF) != 0;
+      let (result, (flag_clear, flag_set)) =
+        OperandOpValue::sub(if adjust { 6u8 } else { 0 }, al, false);
+      cpu.blit_flags(flag_clear, flag_set & !(AF | CF));
+      if adjust {
         cpu.blit_flags(AF | CF, AF | CF);
-      } else {
-        cpu.blit_flags(AF | CF, 0);
+        u8::write_reg(&mut cpu.register, &RegisterByteType::Ah,
+          ah.wrapping_sub(1));
       }
-      al = al & 0xf;
-      cpu.register.ax = al | (ah << 8);
+      u8::write_reg(&mut cpu.register, &RegisterByteType::Al, result & 0xf);
     },
     OpNullaryOp::Das => {
       // Decimal adjust AL after subtraction
       let flags = cpu.get_flags();
+      let old_af = flags & AF != 0;
       let old_cf = flags & CF != 0;
-      let mut al = cpu.register.ax & 0xff;
-      if (al & 0xf) > 9 || (flags & AF) != 0 {
-        let old_al = al;
-        al = al.wrapping_sub(6) & 0xff;
-        let cf = !(al <= old_al);
-        cpu.blit_flags(AF | CF, AF | (if old_cf || cf { CF } else { 0 }));
-      } else {
-        cpu.blit_flags(AF, 0);
+      let old_al = u8::read_reg(&cpu.register, &RegisterByteType::Al);
+      let mut al = old_al;
+      let af = (old_al & 0xf) > 9 || old_af;
+      if af {
+        al = al.wrapping_sub(0x06);
       }
-      if al > 0x99 || old_cf {
-        al = (al - 0x60) & 0xff;
-        cpu.blit_flags(CF, CF);
-      } else {
-        cpu.blit_flags(CF, 0);
+      // 8086 compares against 0x9F instead when AF is set
+      let cf = old_al > (if old_af { 0x9f } else { 0x99 }) || old_cf;
+      if cf {
+        al = al.wrapping_sub(0x60);
       }
-      cpu.register.ax = al & (cpu.register.ax & 0xff00);
-    },
-    OpNullaryOp::Aam => {
-      // ASCII adjust AL after multiply 
-      let mut al = cpu.register.ax & 0xff;
-      let mut ah = (cpu.register.ax >> 8) & 0xff;
-      ah = al / 0x0a;
-      al = al % 0x0a;
-      let (flags_clear, flags_set) = u16::get_flags(al);
-      cpu.register.ax = al | (ah << 8);
-      cpu.blit_flags(flags_clear, flags_set);
-    },
-    OpNullaryOp::Aad => {
-      // ASCII adjust AL after divide
-      let mut al = cpu.register.ax & 0xff;
-      let mut ah = (cpu.register.ax >> 8) & 0xff;
-      al = (al + (ah * 0x0a)) & 0xff;
-      ah = 0;
-      let (flags_clear, flags_set) = u16::get_flags(al);
-      cpu.register.ax = al | (ah << 8);
-      cpu.blit_flags(flags_clear, flags_set);
+      let of = old_al & !al & 0x80 != 0;
+      let (flag_clear, flag_set) = OperandOpValue::get_flags(al);
+      cpu.blit_flags(flag_clear | AF | CF | OF, flag_set |
+        if af { AF } else { 0 } |
+        if cf { CF } else { 0 } |
+        if of { OF } else { 0 });
+      u8::write_reg(&mut cpu.register, &RegisterByteType::Al, al);
     },
     OpNullaryOp::Cbw => {
       cpu.register.ax = (cpu.register.ax & 0xff) as u8 as i8 as i16 as u16;
     },
     OpNullaryOp::Cwd => {
-      cpu.register.dx = 
-        if (cpu.register.ax & 0xf000) != 0 { 0xffff } else { 0 };
+      cpu.register.dx =
+        if (cpu.register.ax & 0x8000) != 0 { 0xffff } else { 0 };
     },
-    OpNullaryOp::Rep => {},
-    OpNullaryOp::Repz => {},
     OpNullaryOp::Into => {
-      cpu.interrupt(4);
+      if cpu.get_flags() & OF != 0 {
+        return Err(Exception::Overflow);
+      }
     },
     OpNullaryOp::Iret => {
       // Pop IP
@@ -697,15 +719,51 @@ fn exec_nullary(cpu: &mut CPU, op: &OpNullaryOp) -> () {
       cpu.blit_flags(IF, IF);
     },
     OpNullaryOp::Hlt => {
-      cpu.running = false;
+      // Wait for an interrupt
+      cpu.halted = true;
     },
     OpNullaryOp::Wait => {
       // This is noop for now
     },
-    OpNullaryOp::Lock => {
-      // This is noop
+    OpNullaryOp::Salc => {
+      // Set AL from carry; flags are left untouched
+      let al = if cpu.get_flags() & CF != 0 { 0xff } else { 0 };
+      u8::write_reg(&mut cpu.register, &RegisterByteType::Al, al);
     },
   }
+  Ok(())
+}
+
+fn exec_in(cpu: &mut CPU, size: &OpSize, port: u16) -> () {
+  let value = match size {
+    OpSize::Byte => {
+      let value = u8::read_mem(&*cpu.io_ports, port as usize);
+      u8::write_reg(&mut cpu.register, &RegisterByteType::Al, value);
+      value as u16
+    },
+    OpSize::Word => {
+      let value = u16::read_mem(&*cpu.io_ports, port as usize);
+      u16::write_reg(&mut cpu.register, &RegisterWordType::Ax, value);
+      value
+    },
+  };
+  cpu.debugger.watch_port(port, Access::Read, value);
+}
+
+fn exec_out(cpu: &mut CPU, size: &OpSize, port: u16) -> () {
+  let value = match size {
+    OpSize::Byte => {
+      let value = u8::read_reg(&cpu.register, &RegisterByteType::Al);
+      u8::write_mem(&mut *cpu.io_ports, port as usize, value);
+      value as u16
+    },
+    OpSize::Word => {
+      let value = u16::read_reg(&cpu.register, &RegisterWordType::Ax);
+      u16::write_mem(&mut *cpu.io_ports, port as usize, value);
+      value
+    },
+  };
+  cpu.debugger.watch_port(port, Access::Write, value);
 }
 
 fn exec_cond_jmp(cpu: &mut CPU, op: &OpCondJmpOp, offset: i8) -> () {
@@ -732,7 +790,8 @@ fn exec_cond_jmp(cpu: &mut CPU, op: &OpCondJmpOp, offset: i8) -> () {
     OpCondJmpOp::Jcxz =>
       u16::read_reg(&cpu.register, &RegisterWordType::Cx) == 0,
     OpCondJmpOp::Loop | OpCondJmpOp::Loope | OpCondJmpOp::Loopne => {
-      let count = u16::read_reg(&cpu.register, &RegisterWordType::Cx) - 1;
+      let count = u16::read_reg(&cpu.register, &RegisterWordType::Cx)
+        .wrapping_sub(1);
       u16::write_reg(&mut cpu.register, &RegisterWordType::Cx, count);
       match op {
         OpCondJmpOp::Loop => count != 0,
@@ -748,23 +807,205 @@ fn exec_cond_jmp(cpu: &mut CPU, op: &OpCondJmpOp, offset: i8) -> () {
   }
 }
 
+fn exec_load_far_pointer(
+  cpu: &mut CPU,
+  reg: &RegisterWordType,
+  operand: &OperandWord,
+  seg: &RegisterWordType,
+) -> Result<(), Exception> {
+  // Only memory reference is allowed
+  let next_operand = match operand {
+    Operand::Address(addr_type, offset) =>
+      Operand::Address(*addr_type, offset.wrapping_add(2)),
+    Operand::Direct(addr) => Operand::Direct(addr.wrapping_add(2)),
+    _ => return Err(Exception::InvalidOpcode),
+  };
+  let offset_val: u16 = cpu.get_operand(operand);
+  let seg_val: u16 = cpu.get_operand(&next_operand);
+  u16::write_reg(&mut cpu.register, reg, offset_val);
+  u16::write_reg(&mut cpu.register, seg, seg_val);
+  Ok(())
+}
+
+fn inhibits_interrupts(op: &Op) -> bool {
+  matches!(op,
+    Op::Nullary(OpNullaryOp::Sti) |
+    Op::BinaryWord {
+      op: OpBinaryOp::Mov,
+      dest: Operand::Register(RegisterWordType::Ss),
+      ..
+    } |
+    Op::UnaryWord {
+      op: OpUnaryOp::Pop,
+      dest: Operand::Register(RegisterWordType::Ss),
+    })
+}
+
+fn advance_string_index(cpu: &CPU, index: u16, size: u16) -> u16 {
+  if cpu.get_flags() & DF != 0 {
+    index.wrapping_sub(size)
+  } else {
+    index.wrapping_add(size)
+  }
+}
+
+fn exec_movs<T, R>(cpu: &mut CPU) -> ()
+  where T: OperandValue<R> + OperandOpValue, R: RegisterType
+{
+  // DS:SI (overridable) -> ES:DI
+  let size = (T::get_bits() / 8) as u16;
+  let value: T = cpu.get_operand(&Operand::Direct(cpu.register.si));
+  cpu.set_operand_with_seg(
+    &Operand::Direct(cpu.register.di),
+    &Some(RegisterWordType::Es),
+    value);
+  cpu.register.si = advance_string_index(cpu, cpu.register.si, size);
+  cpu.register.di = advance_string_index(cpu, cpu.register.di, size);
+}
+
+fn exec_cmps<T, R>(cpu: &mut CPU) -> ()
+  where T: OperandValue<R> + OperandOpValue, R: RegisterType
+{
+  // Compare DS:SI (overridable) with ES:DI, that is, DS:SI - ES:DI
+  let size = (T::get_bits() / 8) as u16;
+  let src_val: T = cpu.get_operand(&Operand::Direct(cpu.register.si));
+  let dest_val: T = cpu.get_operand_with_seg(
+    &Operand::Direct(cpu.register.di),
+    &Some(RegisterWordType::Es));
+  let (_, (flag_clear, flag_set)) =
+    OperandOpValue::sub(dest_val, src_val, false);
+  cpu.blit_flags(flag_clear, flag_set);
+  cpu.register.si = advance_string_index(cpu, cpu.register.si, size);
+  cpu.register.di = advance_string_index(cpu, cpu.register.di, size);
+}
+
+fn exec_scas<T, R>(cpu: &mut CPU, acc: &Operand<R>) -> ()
+  where T: OperandValue<R> + OperandOpValue, R: RegisterType
+{
+  // Compare AL/AX with ES:DI, that is, AL/AX - ES:DI
+  let size = (T::get_bits() / 8) as u16;
+  let acc_val: T = cpu.get_operand(acc);
+  let dest_val: T = cpu.get_operand_with_seg(
+    &Operand::Direct(cpu.register.di),
+    &Some(RegisterWordType::Es));
+  let (_, (flag_clear, flag_set)) =
+    OperandOpValue::sub(dest_val, acc_val, false);
+  cpu.blit_flags(flag_clear, flag_set);
+  cpu.register.di = advance_string_index(cpu, cpu.register.di, size);
+}
+
+fn exec_lods<T, R>(cpu: &mut CPU, acc: &Operand<R>) -> ()
+  where T: OperandValue<R> + OperandOpValue, R: RegisterType
+{
+  // DS:SI (overridable) -> AL/AX
+  let size = (T::get_bits() / 8) as u16;
+  let value: T = cpu.get_operand(&Operand::Direct(cpu.register.si));
+  cpu.set_operand(acc, value);
+  cpu.register.si = advance_string_index(cpu, cpu.register.si, size);
+}
+
+fn exec_stos<T, R>(cpu: &mut CPU, acc: &Operand<R>) -> ()
+  where T: OperandValue<R> + OperandOpValue, R: RegisterType
+{
+  // AL/AX -> ES:DI
+  let size = (T::get_bits() / 8) as u16;
+  let value: T = cpu.get_operand(acc);
+  cpu.set_operand_with_seg(
+    &Operand::Direct(cpu.register.di),
+    &Some(RegisterWordType::Es),
+    value);
+  cpu.register.di = advance_string_index(cpu, cpu.register.di, size);
+}
+
+fn exec_string<F>(cpu: &mut CPU, compares: bool, mut step: F) -> ()
+  where F: FnMut(&mut CPU) -> ()
+{
+  let repeat = match cpu.repeat {
+    Some(v) => v,
+    None => {
+      step(cpu);
+      return;
+    },
+  };
+  // REP prefixes run the operation CX times. CMPS and SCAS additionally
+  // stop when ZF doesn't match the prefix (REPE / REPNE).
+  if cpu.register.cx == 0 {
+    return;
+  }
+  step(cpu);
+  cpu.register.cx = cpu.register.cx.wrapping_sub(1);
+  let zf = cpu.get_flags() & ZF != 0;
+  let finished = cpu.register.cx == 0 || (compares && match repeat {
+    OpRepeatType::Rep => !zf,
+    OpRepeatType::Repnz => zf,
+  });
+  if !finished {
+    // Each iteration is a separate step; restart from the first prefix byte
+    // so that anything handled between steps resumes the whole instruction.
+    cpu.register.ip = cpu.instruction_ip;
+  }
+}
+
 impl CPU {
   pub fn interrupt(&mut self, value: u8) -> () {
+    // The host gets the first go; the handler is taken out while it runs,
+    // so that it can have the CPU.
+    if let Some(mut handler) = self.interrupt_handler.take() {
+      let handled = handler.interrupt(self, value);
+      if self.interrupt_handler.is_none() {
+        self.interrupt_handler = Some(handler);
+      }
+      if handled {
+        return;
+      }
+    }
     // Push flags
-    // Clear IF, TF, AF
+    // Clear IF, TF
     // Push cs, ip
     push_val::<u16, RegisterWordType>(self, self.get_flags());
-    self.blit_flags(IF | TF | AF, 0);
+    self.blit_flags(IF | TF, 0);
     push_val(self, self.register.cs);
     push_val(self, self.register.ip);
     // Jump to IVT. IVT = ip 2 bytes, cs 2 bytes
-    let target_addr = (value as usize) * 4;
-    let new_ip = u16::read_mem(&*self.memory, target_addr);
-    let new_cs = u16::read_mem(&*self.memory, target_addr + 2);
+    let target_addr = (value as u16) * 4;
+    let new_ip = u16::read_seg_mem(self, 0, target_addr);
+    let new_cs = u16::read_seg_mem(self, 0, target_addr + 2);
     self.register.ip = new_ip;
     self.register.cs = new_cs;
   }
-  pub fn exec_op(&mut self, op: &Op) -> () {
+  pub fn raise(&mut self, exception: Exception) -> Result<(), Exception> {
+    if exception.is_fault() {
+      self.register.ip = self.instruction_ip;
+    }
+    if self.intercepts.contains(&exception) {
+      // Let the host handle it; the CPU is left as if it were going to
+      // enter the handler.
+      return Err(exception);
+    }
+    self.interrupt(exception.vector());
+    Ok(())
+  }
+  pub fn exec_op(&mut self, instruction: &Instruction) -> Result<(), Exception> {
+    // Prefixes only last for the instruction they're attached to. LOCK is
+    // noop since there is no other bus master.
+    self.segment_selector = instruction.prefixes.segment;
+    self.repeat = instruction.prefixes.repeat;
+    // TF is sampled before the instruction runs; so POPF / IRET setting TF
+    // traps after the next instruction, and the one clearing it still traps.
+    let trap = self.get_flags() & TF != 0;
+    let result = self.exec_bare_op(&instruction.op);
+    self.segment_selector = None;
+    self.repeat = None;
+    // The next instruction runs before any interrupt is recognized, so that
+    // SS:SP can be loaded together or STI can be followed by RET / HLT.
+    self.interrupt_shadow = inhibits_interrupts(&instruction.op);
+    match result {
+      Ok(()) if trap => self.raise(Exception::SingleStep),
+      Ok(()) => Ok(()),
+      Err(exception) => self.raise(exception),
+    }
+  }
+  fn exec_bare_op(&mut self, op: &Op) -> Result<(), Exception> {
     match op {
       Op::BinaryByte { op, src, dest } => {
         exec_binary::<u8, RegisterByteType>(self, op, src, dest);
@@ -773,10 +1014,10 @@ impl CPU {
         exec_binary::<u16, RegisterWordType>(self, op, src, dest);
       },
       Op::UnaryByte { op, dest } => {
-        exec_unary::<u8, RegisterByteType>(self, op, dest);
+        exec_unary::<u8, RegisterByteType>(self, op, dest)?;
       },
       Op::UnaryWord { op, dest } => {
-        exec_unary::<u16, RegisterWordType>(self, op, dest);
+        exec_unary::<u16, RegisterWordType>(self, op, dest)?;
       },
       Op::ShiftByte { op, shift_type, dest } => {
         exec_shift::<u8, RegisterByteType>(self, op, shift_type, dest);
@@ -785,71 +1026,21 @@ impl CPU {
         exec_shift::<u16, RegisterWordType>(self, op, shift_type, dest);
       },
       Op::Nullary(op) => {
-        exec_nullary(self, op);
+        exec_nullary(self, op)?;
       },
       Op::CondJmp { op, offset } => {
         exec_cond_jmp(self, op, *offset);
       },
       Op::InFixed(size) => {
-        let offset = u16::read_reg(&self.register, &RegisterWordType::Dx)
-          as usize;
-        match size {
-          OpSize::Byte => {
-            u8::write_reg(
-              &mut self.register, 
-              &RegisterByteType::Al,
-              u8::read_mem(&*self.io_ports, offset));
-          },
-          OpSize::Word => {
-            u16::write_reg(
-              &mut self.register, 
-              &RegisterWordType::Ax,
-              u16::read_mem(&*self.io_ports, offset));
-          },
-        }
-      },
-      Op::InVariable(size, value) => {
-        match size {
-          OpSize::Byte => {
-            u8::write_reg(
-              &mut self.register, 
-              &RegisterByteType::Al,
-              u8::read_mem(&*self.io_ports, *value as usize));
-          },
-          OpSize::Word => {
-            u16::write_reg(
-              &mut self.register, 
-              &RegisterWordType::Ax,
-              u16::read_mem(&*self.io_ports, *value as usize));
-          },
-        }
+        let port = u16::read_reg(&self.register, &RegisterWordType::Dx);
+        exec_in(self, size, port);
       },
+      Op::InVariable(size, value) => exec_in(self, size, *value as u16),
       Op::OutFixed(size) => {
-        let offset = u16::read_reg(&self.register, &RegisterWordType::Dx)
-          as usize;
-        match size {
-          OpSize::Byte => {
-            u8::write_mem(&mut *self.io_ports, offset,
-              u8::read_reg(&self.register, &RegisterByteType::Al));
-          },
-          OpSize::Word => {
-            u16::write_mem(&mut *self.io_ports, offset,
-              u16::read_reg(&self.register, &RegisterWordType::Ax));
-          },
-        }
-      },
-      Op::OutVariable(size, value) => {
-        match size {
-          OpSize::Byte => {
-            u8::write_mem(&mut *self.io_ports, *value as usize,
-              u8::read_reg(&self.register, &RegisterByteType::Al));
-          },
-          OpSize::Word => {
-            u16::write_mem(&mut *self.io_ports, *value as usize,
-              u16::read_reg(&self.register, &RegisterWordType::Ax));
-          },
-        }
+        let port = u16::read_reg(&self.register, &RegisterWordType::Dx);
+        exec_out(self, size, port);
       },
+      Op::OutVariable(size, value) => exec_out(self, size, *value as u16),
       Op::Lea(reg, operand) => {
         // Only memory reference is allowed
         match operand {
@@ -860,42 +1051,45 @@ impl CPU {
           Operand::Direct(addr) => {
             u16::write_reg(&mut self.register, reg, *addr);
           },
-          _ => {
-            // Raise UD exception
-          },
+          _ => return Err(Exception::InvalidOpcode),
         }
       },
       Op::Lds(reg, operand) => {
-        // Only memory reference is allowed
-        match operand {
-          Operand::Address(_, _) | Operand::Direct(_) => {
-            let value: u16 =
-              self.get_operand_with_seg(operand, &Some(RegisterWordType::Ds));
-            u16::write_reg(&mut self.register, reg, value);
-          },
-          _ => {
-            // Raise UD exception
-          },
-        }
+        exec_load_far_pointer(self, reg, operand, &RegisterWordType::Ds)?;
       },
       Op::Les(reg, operand) => {
-        // Only memory reference is allowed
-        match operand {
-          Operand::Address(_, _) | Operand::Direct(_) => {
-            let value: u16 =
-              self.get_operand_with_seg(operand, &Some(RegisterWordType::Es));
-            u16::write_reg(&mut self.register, reg, value);
-          },
-          _ => {
-            // Raise UD exception
-          },
-        }
+        exec_load_far_pointer(self, reg, operand, &RegisterWordType::Es)?;
+      },
+      Op::Movs(size) => match size {
+        OpSize::Byte => exec_string(self, false,
+          exec_movs::<u8, RegisterByteType>),
+        OpSize::Word => exec_string(self, false,
+          exec_movs::<u16, RegisterWordType>),
+      },
+      Op::Cmps(size) => match size {
+        OpSize::Byte => exec_string(self, true,
+          exec_cmps::<u8, RegisterByteType>),
+        OpSize::Word => exec_string(self, true,
+          exec_cmps::<u16, RegisterWordType>),
+      },
+      Op::Scas(size) => match size {
+        OpSize::Byte => exec_string(self, true, |cpu| exec_scas::<u8, _>(
+          cpu, &Operand::Register(RegisterByteType::Al))),
+        OpSize::Word => exec_string(self, true, |cpu| exec_scas::<u16, _>(
+          cpu, &Operand::Register(RegisterWordType::Ax))),
+      },
+      Op::Lods(size) => match size {
+        OpSize::Byte => exec_string(self, false, |cpu| exec_lods::<u8, _>(
+          cpu, &Operand::Register(RegisterByteType::Al))),
+        OpSize::Word => exec_string(self, false, |cpu| exec_lods::<u16, _>(
+          cpu, &Operand::Register(RegisterWordType::Ax))),
+      },
+      Op::Stos(size) => match size {
+        OpSize::Byte => exec_string(self, false, |cpu| exec_stos::<u8, _>(
+          cpu, &Operand::Register(RegisterByteType::Al))),
+        OpSize::Word => exec_string(self, false, |cpu| exec_stos::<u16, _>(
+          cpu, &Operand::Register(RegisterWordType::Ax))),
       },
-      Op::Movs(size) => {},
-      Op::Cmps(size) => {},
-      Op::Scas(size) => {},
-      Op::Lods(size) => {},
-      Op::Stos(size) => {},
       Op::Call(call_type) => {
         match call_type {
           OpCallType::WithinDirect(addr) => {
@@ -911,7 +1105,7 @@ impl CPU {
             let value: u16 = self.get_operand(operand);
             self.register.ip = value;
           },
-          OpCallType::InterDirect(cs, ip) => {
+          OpCallType::InterDirect(ip, cs) => {
             let old_cs = self.register.cs;
             push_val(self, old_cs);
             let old_ip = self.register.ip;
@@ -946,7 +1140,7 @@ impl CPU {
                   &Operand::Direct(addr.wrapping_add(2)));
                 self.register.cs = cs;
               },
-              _ => {},
+              _ => return Err(Exception::InvalidOpcode),
             }
           },
         }
@@ -961,8 +1155,8 @@ impl CPU {
             let ip: u16 = self.get_operand(operand);
             self.register.ip = ip;
           },
-          OpCallType::InterDirect(cs, ip) => {
-            self.register.ip = *cs;
+          OpCallType::InterDirect(ip, cs) => {
+            self.register.cs = *cs;
             self.register.ip = *ip;
           },
           OpCallType::InterIndirect(operand) => {
@@ -984,11 +1178,15 @@ impl CPU {
                   &Operand::Direct(addr.wrapping_add(2)));
                 self.register.cs = cs;
               },
-              _ => {},
+              _ => return Err(Exception::InvalidOpcode),
             }
           },
         }
       },
+      Op::JmpShort(offset) => {
+        let ip = self.register.ip;
+        self.register.ip = ip.wrapping_add(*offset as i16 as u16);
+      },
       Op::RetWithin => {
         let ip = pop_val(self);
         self.register.ip = ip;
@@ -996,7 +1194,7 @@ impl CPU {
       Op::RetWithinImm(value) => {
         let ip = pop_val(self);
         self.register.ip = ip;
-        self.register.sp += value;
+        self.register.sp = self.register.sp.wrapping_add(*value);
       },
       Op::RetInter => {
         let ip = pop_val(self);
@@ -1009,17 +1207,248 @@ impl CPU {
         self.register.ip = ip;
         let cs = pop_val(self);
         self.register.cs = cs;
-        self.register.sp += value;
+        self.register.sp = self.register.sp.wrapping_add(*value);
+      },
+      Op::Aam(base) => {
+        // ASCII adjust AX after multiply; AH = AL / base, AL = AL % base
+        if *base == 0 {
+          return Err(Exception::DivideError);
+        }
+        let al = u8::read_reg(&self.register, &RegisterByteType::Al);
+        u8::write_reg(&mut self.register, &RegisterByteType::Ah, al / base);
+        u8::write_reg(&mut self.register, &RegisterByteType::Al, al % base);
+        let (flag_clear, flag_set) = OperandOpValue::get_flags(al % base);
+        self.blit_flags(flag_clear | CF | OF | AF, flag_set);
+      },
+      Op::Aad(base) => {
+        // ASCII adjust AX before division; AL = AH * base + AL, AH = 0.
+        // Flags come from the final addition.
+        let al = u8::read_reg(&self.register, &RegisterByteType::Al);
+        let ah = u8::read_reg(&self.register, &RegisterByteType::Ah);
+        let (result, (flag_clear, flag_set)) =
+          OperandOpValue::add(ah.wrapping_mul(*base), al, false);
+        self.register.ax = result as u16;
+        self.blit_flags(flag_clear, flag_set);
+      },
+      Op::Int(3) => {
+        return Err(Exception::Breakpoint);
       },
       Op::Int(value) => {
         self.interrupt(*value);
       },
       Op::Esc(code, operand) => {},
-      Op::Segment(seg) => {
-        self.segment_selector = Some(*seg);
-        return;
-      },
     }
-    self.segment_selector = None;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::mem::linear::LinearMemory;
+  use super::super::cpu::CPU;
+  use super::super::op::*;
+  use super::super::operand::*;
+  use super::super::register::*;
+  use super::super::flags::*;
+
+  const STATUS: u16 = CF | PF | AF | ZF | SF | OF;
+
+  fn new_cpu(ax: u16, bx: u16, cx: u16, flags: u16) -> CPU {
+    let mem = LinearMemory::new(0x100);
+    let io_ports = LinearMemory::new(0);
+    let mut cpu = CPU::new(Box::new(mem), Box::new(io_ports));
+    cpu.register.ax = ax;
+    cpu.register.bx = bx;
+    cpu.register.cx = cx;
+    cpu.set_flags(flags);
+    cpu
+  }
+
+  fn run(cpu: &mut CPU, op: Op) -> (u16, u16, u16) {
+    cpu.exec_op(&Instruction::new(op)).unwrap();
+    (cpu.register.ax, cpu.register.bx, cpu.get_flags() & STATUS)
+  }
+
+  #[test]
+  fn test_binary_flags() {
+    // (op, al, bl, flags in, al out, flags out)
+    let byte_cases = [
+      (OpBinaryOp::Add, 0x7f, 0x01, 0, 0x80, OF | SF | AF),
+      (OpBinaryOp::Add, 0xff, 0x01, 0, 0x00, CF | ZF | AF | PF),
+      (OpBinaryOp::Add, 0x80, 0x80, 0, 0x00, CF | OF | ZF | PF),
+      (OpBinaryOp::Adc, 0x7f, 0x00, CF, 0x80, OF | SF | AF),
+      (OpBinaryOp::Adc, 0xff, 0xff, CF, 0xff, CF | AF | SF | PF),
+      (OpBinaryOp::Sub, 0x00, 0x01, 0, 0xff, CF | AF | SF | PF),
+      (OpBinaryOp::Sub, 0x80, 0x01, 0, 0x7f, OF | AF),
+      (OpBinaryOp::Sbb, 0x00, 0x00, CF, 0xff, CF | AF | SF | PF),
+      (OpBinaryOp::Sbb, 0x10, 0x0f, CF, 0x00, AF | ZF | PF),
+      (OpBinaryOp::Cmp, 0x05, 0x05, 0, 0x05, ZF | PF),
+      (OpBinaryOp::Cmp, 0x01, 0x02, 0, 0x01, CF | AF | SF | PF),
+      (OpBinaryOp::And, 0xf0, 0x0f, CF | OF | AF | SF, 0x00, ZF | PF),
+      (OpBinaryOp::Or, 0x80, 0x01, CF | OF | AF, 0x81, SF | PF),
+      (OpBinaryOp::Xor, 0xff, 0xff, CF | OF | AF, 0x00, ZF | PF),
+      (OpBinaryOp::Test, 0x80, 0x80, CF | OF | AF, 0x80, SF),
+    ];
+    for (op, al, bl, flags, result, result_flags) in byte_cases.iter() {
+      let mut cpu = new_cpu(*al, *bl, 0, *flags);
+      let (ax, _, flags_out) = run(&mut cpu, Op::BinaryByte {
+        op: *op,
+        src: Operand::Register(RegisterByteType::Bl),
+        dest: Operand::Register(RegisterByteType::Al),
+      });
+      assert_eq!((ax, flags_out), (*result, *result_flags), "{:?}", op);
+    }
+    // (op, ax, bx, flags in, ax out, flags out)
+    let word_cases = [
+      (OpBinaryOp::Add, 0x7fff, 0x0001, 0, 0x8000, OF | SF | AF | PF),
+      (OpBinaryOp::Adc, 0xffff, 0x0000, CF, 0x0000, CF | ZF | AF | PF),
+      (OpBinaryOp::Sub, 0x0000, 0x0001, 0, 0xffff, CF | AF | SF | PF),
+      (OpBinaryOp::Sbb, 0x8000, 0x0000, CF, 0x7fff, OF | AF | PF),
+      (OpBinaryOp::Cmp, 0x1234, 0x1234, 0, 0x1234, ZF | PF),
+      (OpBinaryOp::And, 0xff00, 0x8001, CF | OF | AF, 0x8000, SF | PF),
+      (OpBinaryOp::Or, 0x0100, 0x0001, 0, 0x0101, 0),
+      (OpBinaryOp::Xor, 0x8000, 0x8000, OF, 0x0000, ZF | PF),
+      (OpBinaryOp::Test, 0x0003, 0x0001, CF, 0x0003, 0),
+    ];
+    for (op, ax, bx, flags, result, result_flags) in word_cases.iter() {
+      let mut cpu = new_cpu(*ax, *bx, 0, *flags);
+      let (ax, _, flags_out) = run(&mut cpu, Op::BinaryWord {
+        op: *op,
+        src: Operand::Register(RegisterWordType::Bx),
+        dest: Operand::Register(RegisterWordType::Ax),
+      });
+      assert_eq!((ax, flags_out), (*result, *result_flags), "{:?}", op);
+    }
+  }
+
+  #[test]
+  fn test_unary_flags() {
+    // (op, ax, bl, flags in, ax out, bl out, flags out)
+    let byte_cases = [
+      (OpUnaryOp::Inc, 0, 0x7f, CF, 0, 0x80, OF | SF | AF | CF),
+      (OpUnaryOp::Inc, 0, 0xff, 0, 0, 0x00, ZF | AF | PF),
+      (OpUnaryOp::Dec, 0, 0x80, CF, 0, 0x7f, OF | AF | CF),
+      (OpUnaryOp::Dec, 0, 0x01, 0, 0, 0x00, ZF | PF),
+      (OpUnaryOp::Not, 0, 0x0f, CF | ZF, 0, 0xf0, CF | ZF),
+      (OpUnaryOp::Neg, 0, 0x01, 0, 0, 0xff, CF | AF | SF | PF),
+      (OpUnaryOp::Neg, 0, 0x00, CF, 0, 0x00, ZF | PF),
+      (OpUnaryOp::Neg, 0, 0x80, 0, 0, 0x80, CF | OF | SF),
+      (OpUnaryOp::Mul, 0x10, 0x10, 0, 0x0100, 0x10, CF | OF),
+      (OpUnaryOp::Mul, 0x02, 0x03, CF | OF, 0x0006, 0x03, ZF | PF),
+      (OpUnaryOp::Mul, 0xff, 0xff, 0, 0xfe01, 0xff, CF | OF | SF),
+      (OpUnaryOp::Imul, 0xff, 0x02, 0, 0xfffe, 0x02, ZF | SF | PF),
+      (OpUnaryOp::Imul, 0x40, 0x02, 0, 0x0080, 0x02, CF | OF | PF),
+      (OpUnaryOp::Div, 0x0007, 0x02, CF, 0x0103, 0x02, CF),
+      (OpUnaryOp::Idiv, 0xfff9, 0x02, 0, 0xfffd, 0x02, 0),
+    ];
+    for (op, ax, bl, flags, ax_out, bl_out, result_flags) in
+      byte_cases.iter()
+    {
+      let mut cpu = new_cpu(*ax, *bl, 0, *flags);
+      let (ax, bx, flags_out) = run(&mut cpu, Op::UnaryByte {
+        op: *op,
+        dest: Operand::Register(RegisterByteType::Bl),
+      });
+      assert_eq!(
+        (ax, bx, flags_out), (*ax_out, *bl_out, *result_flags), "{:?}", op);
+    }
+    // (op, ax, bx, flags in, bx out, flags out)
+    let word_cases = [
+      (OpUnaryOp::Inc, 0, 0x7fff, 0, 0x8000, OF | SF | AF | PF),
+      (OpUnaryOp::Dec, 0, 0x0000, CF, 0xffff, CF | SF | AF | PF),
+      (OpUnaryOp::Not, 0, 0x00ff, 0, 0xff00, 0),
+      (OpUnaryOp::Neg, 0, 0x8000, 0, 0x8000, CF | OF | SF | PF),
+    ];
+    for (op, ax, bx, flags, bx_out, result_flags) in word_cases.iter() {
+      let mut cpu = new_cpu(*ax, *bx, 0, *flags);
+      let (_, bx, flags_out) = run(&mut cpu, Op::UnaryWord {
+        op: *op,
+        dest: Operand::Register(RegisterWordType::Bx),
+      });
+      assert_eq!((bx, flags_out), (*bx_out, *result_flags), "{:?}", op);
+    }
+  }
+
+  #[test]
+  fn test_shift_flags() {
+    // (op, count, al, flags in, al out, flags out); count 1 uses the
+    // implicit form, others are loaded to CL.
+    let byte_cases = [
+      (OpShiftOp::Shl, 1, 0x81, 0, 0x02, CF | OF),
+      (OpShiftOp::Shl, 1, 0x40, 0, 0x80, OF | SF),
+      (OpShiftOp::Shl, 1, 0x08, 0, 0x10, AF),
+      (OpShiftOp::Shl, 0, 0x81, CF | ZF, 0x81, CF | ZF),
+      (OpShiftOp::Shl, 9, 0xff, 0, 0x00, ZF | PF),
+      (OpShiftOp::Sal, 3, 0x01, 0, 0x08, 0),
+      (OpShiftOp::Shr, 1, 0x81, 0, 0x40, CF | OF),
+      (OpShiftOp::Shr, 4, 0x18, AF, 0x01, CF),
+      (OpShiftOp::Sar, 1, 0x81, OF, 0xc0, CF | SF | PF),
+      (OpShiftOp::Rol, 1, 0x81, ZF, 0x03, ZF | CF | OF),
+      (OpShiftOp::Rol, 4, 0x12, 0, 0x21, CF | OF),
+      (OpShiftOp::Ror, 1, 0x01, 0, 0x80, CF | OF),
+      (OpShiftOp::Ror, 1, 0x02, CF | SF, 0x01, SF),
+      (OpShiftOp::Rcl, 1, 0x80, 0, 0x00, CF | OF),
+      (OpShiftOp::Rcl, 9, 0x55, 0, 0x55, 0),
+      (OpShiftOp::Rcr, 1, 0x01, CF, 0x80, CF | OF),
+      (OpShiftOp::Rcr, 1, 0x02, PF, 0x01, PF),
+    ];
+    for (op, count, al, flags, result, result_flags) in byte_cases.iter() {
+      let mut cpu = new_cpu(*al, 0, *count, *flags);
+      let (ax, _, flags_out) = run(&mut cpu, Op::ShiftByte {
+        op: *op,
+        shift_type: if *count == 1 { OpShiftType::One } else { OpShiftType::Cl },
+        dest: Operand::Register(RegisterByteType::Al),
+      });
+      assert_eq!(
+        (ax & 0xff, flags_out), (*result, *result_flags), "{:?}", op);
+    }
+    // (op, count, ax, flags in, ax out, flags out)
+    let word_cases = [
+      (OpShiftOp::Shl, 1, 0x8000, 0, 0x0000, CF | OF | ZF | PF),
+      (OpShiftOp::Shr, 15, 0x8000, 0, 0x0001, 0),
+      (OpShiftOp::Sar, 15, 0x8000, 0, 0xffff, SF | PF),
+      (OpShiftOp::Rol, 8, 0x1234, 0, 0x3412, 0),
+      (OpShiftOp::Ror, 1, 0x0001, 0, 0x8000, CF | OF),
+      (OpShiftOp::Rcl, 17, 0x1234, CF, 0x1234, CF | OF),
+      (OpShiftOp::Rcr, 1, 0x0000, CF, 0x8000, OF),
+    ];
+    for (op, count, ax, flags, result, result_flags) in word_cases.iter() {
+      let mut cpu = new_cpu(*ax, 0, *count, *flags);
+      let (ax, _, flags_out) = run(&mut cpu, Op::ShiftWord {
+        op: *op,
+        shift_type: if *count == 1 { OpShiftType::One } else { OpShiftType::Cl },
+        dest: Operand::Register(RegisterWordType::Ax),
+      });
+      assert_eq!((ax, flags_out), (*result, *result_flags), "{:?}", op);
+    }
+  }
+
+  #[test]
+  fn test_bcd_flags() {
+    // (op, ax, flags in, ax out, flags out)
+    let cases = vec![
+      (Op::Nullary(OpNullaryOp::Daa), 0x009a, 0, 0x0000, AF | CF | ZF | PF),
+      (Op::Nullary(OpNullaryOp::Daa), 0x0079, 0, 0x0079, 0),
+      (Op::Nullary(OpNullaryOp::Das), 0x0000, CF, 0x00a0, CF | SF | PF),
+      (Op::Nullary(OpNullaryOp::Aaa), 0x000a, 0, 0x0100, AF | CF),
+      (Op::Nullary(OpNullaryOp::Aas), 0x0200, AF, 0x010a, AF | CF | SF | PF),
+      (Op::Aam(10), 0x003f, CF | OF, 0x0603, PF),
+      (Op::Aam(16), 0x0000, 0, 0x0000, ZF | PF),
+      (Op::Aad(10), 0x0603, 0, 0x003f, PF),
+    ];
+    for (op, ax, flags, result, result_flags) in cases {
+      let name = format!("{:?}", op);
+      let mut cpu = new_cpu(ax, 0, 0, flags);
+      let (ax, _, flags_out) = run(&mut cpu, op);
+      assert_eq!((ax, flags_out), (result, result_flags), "{}", name);
+    }
+  }
+
+  #[test]
+  fn test_reserved_flags() {
+    let mut cpu = new_cpu(0, 0, 0, 0);
+    assert_eq!(cpu.get_flags(), 0xf002);
+    cpu.set_flags(0xffff);
+    assert_eq!(cpu.get_flags(), 0xffd7);
   }
 }
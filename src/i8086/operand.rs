@@ -1,6 +1,8 @@
 use std::ops::*;
 use super::cpu::CPU;
+use super::cpu::AddressLines;
 use super::register::*;
+use super::debugger::Access;
 use crate::mem::*;
 
 #[derive(PartialEq, Copy, Clone)]
@@ -16,7 +18,7 @@ pub enum AddressType {
   Bx,
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone)]
 #[derive(Debug)]
 pub enum Operand<T: RegisterType> {
   Register(T),
@@ -26,16 +28,37 @@ pub enum Operand<T: RegisterType> {
   ImmByte(u8),
 }
 
+impl AddressType {
+  pub fn get_default_segment(&self) -> RegisterWordType {
+    match self {
+      AddressType::BpSi | AddressType::BpDi | AddressType::Bp =>
+        RegisterWordType::Ss,
+      _ => RegisterWordType::Ds,
+    }
+  }
+}
+
+impl<T: RegisterType> Operand<T> {
+  // The segment used when no segment prefix is given; BP based addressing
+  // uses the stack segment.
+  pub fn get_default_segment(&self) -> RegisterWordType {
+    match self {
+      Operand::Address(addr_type, _) => addr_type.get_default_segment(),
+      _ => RegisterWordType::Ds,
+    }
+  }
+}
+
 pub type OperandWord = Operand<RegisterWordType>;
 pub type OperandByte = Operand<RegisterByteType>;
 
 impl CPU {
   pub fn get_offset(&self, addr_type: &AddressType, offset: i16) -> u16 {
     let base_offset = match addr_type {
-      AddressType::BxSi => self.register.bx + self.register.si,
-      AddressType::BxDi => self.register.bx + self.register.di,
-      AddressType::BpSi => self.register.bp + self.register.si,
-      AddressType::BpDi => self.register.bp + self.register.di,
+      AddressType::BxSi => self.register.bx.wrapping_add(self.register.si),
+      AddressType::BxDi => self.register.bx.wrapping_add(self.register.di),
+      AddressType::BpSi => self.register.bp.wrapping_add(self.register.si),
+      AddressType::BpDi => self.register.bp.wrapping_add(self.register.di),
       AddressType::Si => self.register.si,
       AddressType::Di => self.register.di,
       AddressType::Bp => self.register.bp,
@@ -43,17 +66,65 @@ impl CPU {
     };
     base_offset.wrapping_add(offset as u16)
   }
-  pub fn get_segment_addr(&self, segment: &Option<RegisterWordType>) -> usize {
-    (match segment {
-      Some(reg) => u16::read_reg(&self.register, reg),
-      None => {
-        let selector = self.segment_selector;
-        match selector {
-          Some(reg) => u16::read_reg(&self.register, &reg),
-          None => self.register.ds,
-        }
+  pub fn get_linear_addr(&self, seg: u16, offset: u16) -> usize {
+    // Offsets wrap in the segment, and the address wraps at 1MB unless A20
+    // is enabled.
+    let address = ((seg as usize) << 4) + (offset as usize);
+    match self.address_lines {
+      AddressLines::A20Gate if self.a20_gate.get() => address,
+      _ => address & 0xFFFFF,
+    }
+  }
+  // Bytes at seg:offset, with the offset wrapping in the segment.
+  pub fn read_bytes(&self, seg: u16, offset: u16, length: usize) -> Vec<u8> {
+    (0..length)
+      .map(|i| {
+        let address = self.get_linear_addr(seg, offset.wrapping_add(i as u16));
+        self.memory.read_u8(address)
+      })
+      .collect()
+  }
+  pub fn write_bytes(&mut self, seg: u16, offset: u16, bytes: &[u8]) -> () {
+    for (i, value) in bytes.iter().enumerate() {
+      let address = self.get_linear_addr(seg, offset.wrapping_add(i as u16));
+      self.memory.write_u8(address, *value);
+    }
+  }
+  pub fn get_operand_segment<R: RegisterType>(
+    &self,
+    operand: &Operand<R>,
+    segment: &Option<RegisterWordType>,
+  ) -> RegisterWordType {
+    match segment {
+      // Explicit segments (ES for string destinations, SS for the stack)
+      // can't be overridden.
+      Some(reg) => *reg,
+      None => match self.segment_selector {
+        Some(reg) => reg,
+        None => operand.get_default_segment(),
       },
-    } as usize) << 4
+    }
+  }
+  pub fn get_operand_seg_offset<R: RegisterType>(
+    &self,
+    operand: &Operand<R>,
+    segment: &Option<RegisterWordType>,
+  ) -> Option<(u16, u16)> {
+    let offset = match operand {
+      Operand::Address(addr, offset) => self.get_offset(addr, *offset),
+      Operand::Direct(offset) => *offset,
+      _ => return None,
+    };
+    let seg = self.get_operand_segment(operand, segment);
+    Some((u16::read_reg(&self.register, &seg), offset))
+  }
+  pub fn get_operand_addr<R: RegisterType>(
+    &self,
+    operand: &Operand<R>,
+    segment: &Option<RegisterWordType>,
+  ) -> Option<usize> {
+    let (seg, offset) = self.get_operand_seg_offset(operand, segment)?;
+    Some(self.get_linear_addr(seg, offset))
   }
   pub fn get_operand_with_seg<T, R>(
     &self,
@@ -64,14 +135,11 @@ impl CPU {
   {
     match operand {
       Operand::Register(reg) => T::read_reg(&self.register, reg),
-      Operand::Address(addr, offset) => T::read_mem(
-        &*self.memory,
-        (self.get_offset(addr, *offset) as usize) +
-        self.get_segment_addr(segment)),
-      Operand::Direct(offset) => T::read_mem(
-        &*self.memory,
-        (*offset as usize) +
-        self.get_segment_addr(segment)),
+      Operand::Address(_, _) | Operand::Direct(_) => {
+        let (seg, offset) =
+          self.get_operand_seg_offset(operand, segment).unwrap();
+        T::read_seg_mem(self, seg, offset)
+      },
       Operand::ImmWord(value) => T::from_u16(*value),
       Operand::ImmByte(value) => T::from_u8(*value),
     }
@@ -86,15 +154,11 @@ impl CPU {
   {
     match operand {
       Operand::Register(reg) => T::write_reg(&mut self.register, reg, value),
-      Operand::Address(addr, offset) => {
-        let address = (self.get_offset(addr, *offset) as usize) +
-          self.get_segment_addr(segment);
-        T::write_mem(&mut *self.memory, address, value);
-      }
-      Operand::Direct(offset) => {
-        let address = (*offset as usize) + self.get_segment_addr(segment);
-        T::write_mem(&mut *self.memory, address, value);
-      }
+      Operand::Address(_, _) | Operand::Direct(_) => {
+        let (seg, offset) =
+          self.get_operand_seg_offset(operand, segment).unwrap();
+        T::write_seg_mem(self, seg, offset, value);
+      },
       _ => (),
     }
   }
@@ -120,14 +184,37 @@ impl CPU {
 pub trait OperandValue<R>: MemoryValue + RegisterValue<R> + Add<Output=Self> + Sized {
   fn from_u8(value: u8) -> Self;
   fn from_u16(value: u16) -> Self;
+  fn read_seg_mem(cpu: &CPU, seg: u16, offset: u16) -> Self;
+  fn write_seg_mem(cpu: &mut CPU, seg: u16, offset: u16, value: Self) -> ();
 }
 
 impl OperandValue<RegisterByteType> for u8 {
   fn from_u8(value: u8) -> u8 { value }
   fn from_u16(value: u16) -> u8 { value as u8 }
+  fn read_seg_mem(cpu: &CPU, seg: u16, offset: u16) -> u8 {
+    let address = cpu.get_linear_addr(seg, offset);
+    let value = u8::read_mem(&*cpu.memory, address);
+    cpu.debugger.watch_memory(address, Access::Read, value);
+    value
+  }
+  fn write_seg_mem(cpu: &mut CPU, seg: u16, offset: u16, value: u8) -> () {
+    let address = cpu.get_linear_addr(seg, offset);
+    u8::write_mem(&mut *cpu.memory, address, value);
+    cpu.debugger.watch_memory(address, Access::Write, value);
+  }
 }
 
 impl OperandValue<RegisterWordType> for u16 {
   fn from_u8(value: u8) -> u16 { value as i8 as i16 as u16 }
   fn from_u16(value: u16) -> u16 { value }
+  fn read_seg_mem(cpu: &CPU, seg: u16, offset: u16) -> u16 {
+    // A word at offset 0xFFFF takes its high byte from offset 0.
+    let low = u8::read_seg_mem(cpu, seg, offset);
+    let high = u8::read_seg_mem(cpu, seg, offset.wrapping_add(1));
+    (low as u16) | ((high as u16) << 8)
+  }
+  fn write_seg_mem(cpu: &mut CPU, seg: u16, offset: u16, value: u16) -> () {
+    u8::write_seg_mem(cpu, seg, offset, (value & 0xff) as u8);
+    u8::write_seg_mem(cpu, seg, offset.wrapping_add(1), (value >> 8) as u8);
+  }
 }
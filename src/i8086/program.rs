@@ -0,0 +1,760 @@
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use super::asm::Assembly;
+use super::cpu::CPU;
+use super::encode::*;
+use super::op::*;
+use super::operand::*;
+use super::register::*;
+
+// A typed builder for 8086 programs, mostly for tests:
+//
+//   Program::new()
+//     .mov(Cx, 3)
+//     .label("l")
+//     .add(Ax, word_ptr((AddressType::Bx, 2)))
+//     .loop_("l")
+//     .hlt()
+//     .load(&mut cpu, 0x1000, 0x0100)
+//
+// Every instruction referring to a label has a fixed size, so labels are
+// resolved with one pass to lay out the program and another to emit it.
+
+#[derive(PartialEq, Clone)]
+#[derive(Debug)]
+pub enum ProgramError {
+  UndefinedLabel(String),
+  DuplicateLabel(String),
+  OutOfRange(String),
+  InvalidOperands,
+  Encode(EncodeError),
+}
+
+impl fmt::Display for ProgramError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ProgramError::UndefinedLabel(name) =>
+        write!(f, "undefined label '{}'", name),
+      ProgramError::DuplicateLabel(name) =>
+        write!(f, "label '{}' redefined", name),
+      ProgramError::OutOfRange(name) =>
+        write!(f, "jump to '{}' is out of range", name),
+      ProgramError::InvalidOperands =>
+        f.write_str("invalid combination of operands"),
+      ProgramError::Encode(err) => err.fmt(f),
+    }
+  }
+}
+
+impl error::Error for ProgramError {}
+
+impl From<EncodeError> for ProgramError {
+  fn from(err: EncodeError) -> Self {
+    ProgramError::Encode(err)
+  }
+}
+
+// An operand; its size comes from registers and pointers, and immediates
+// take the size of the other operand.
+#[derive(PartialEq, Clone)]
+#[derive(Debug)]
+pub enum Arg {
+  Byte(OperandByte),
+  Word(OperandWord),
+  Imm(i32),
+  Offset(String),
+  Label(OpSize, String),
+}
+
+impl From<RegisterByteType> for Arg {
+  fn from(reg: RegisterByteType) -> Self {
+    Arg::Byte(Operand::Register(reg))
+  }
+}
+
+impl From<RegisterWordType> for Arg {
+  fn from(reg: RegisterWordType) -> Self {
+    Arg::Word(Operand::Register(reg))
+  }
+}
+
+impl From<OperandByte> for Arg {
+  fn from(operand: OperandByte) -> Self {
+    Arg::Byte(operand)
+  }
+}
+
+impl From<OperandWord> for Arg {
+  fn from(operand: OperandWord) -> Self {
+    Arg::Word(operand)
+  }
+}
+
+impl From<i32> for Arg {
+  fn from(value: i32) -> Self {
+    Arg::Imm(value)
+  }
+}
+
+#[derive(PartialEq, Clone)]
+#[derive(Debug)]
+pub enum Pointer {
+  Address(AddressType, i16),
+  Direct(u16),
+  Label(String),
+}
+
+impl From<AddressType> for Pointer {
+  fn from(addr: AddressType) -> Self {
+    Pointer::Address(addr, 0)
+  }
+}
+
+impl From<(AddressType, i16)> for Pointer {
+  fn from((addr, disp): (AddressType, i16)) -> Self {
+    Pointer::Address(addr, disp)
+  }
+}
+
+impl From<u16> for Pointer {
+  fn from(addr: u16) -> Self {
+    Pointer::Direct(addr)
+  }
+}
+
+impl<'a> From<&'a str> for Pointer {
+  fn from(label: &'a str) -> Self {
+    Pointer::Label(label.to_string())
+  }
+}
+
+fn pointer<T: RegisterType>(pointer: Pointer) -> Result<Operand<T>, String> {
+  match pointer {
+    Pointer::Address(addr, disp) => Ok(Operand::Address(addr, disp)),
+    Pointer::Direct(addr) => Ok(Operand::Direct(addr)),
+    Pointer::Label(label) => Err(label),
+  }
+}
+
+// `byte [..]`
+pub fn byte_ptr<P: Into<Pointer>>(addr: P) -> Arg {
+  match pointer(addr.into()) {
+    Ok(operand) => Arg::Byte(operand),
+    Err(label) => Arg::Label(OpSize::Byte, label),
+  }
+}
+
+// `word [..]`
+pub fn word_ptr<P: Into<Pointer>>(addr: P) -> Arg {
+  match pointer(addr.into()) {
+    Ok(operand) => Arg::Word(operand),
+    Err(label) => Arg::Label(OpSize::Word, label),
+  }
+}
+
+// The address of a label, as an immediate.
+pub fn offset(label: &str) -> Arg {
+  Arg::Offset(label.to_string())
+}
+
+struct Layout<'a> {
+  labels: &'a HashMap<String, u16>,
+  here: u16,
+  final_pass: bool,
+}
+
+impl<'a> Layout<'a> {
+  fn label(&self, name: &str) -> Result<u16, ProgramError> {
+    match self.labels.get(name) {
+      Some(value) => Ok(*value),
+      // Sizes never depend on labels, so anything will do for now.
+      None if !self.final_pass => Ok(0),
+      None => Err(ProgramError::UndefinedLabel(name.to_string())),
+    }
+  }
+}
+
+type Emit = Box<dyn Fn(&Layout) -> Result<Vec<u8>, ProgramError>>;
+
+enum Item {
+  Label(String),
+  Code(Emit),
+}
+
+pub struct Program {
+  items: Vec<Item>,
+  prefixes: OpPrefixes,
+}
+
+fn arg_size(arg: &Arg) -> Option<OpSize> {
+  match arg {
+    Arg::Byte(_) | Arg::Label(OpSize::Byte, _) => Some(OpSize::Byte),
+    Arg::Word(_) | Arg::Label(OpSize::Word, _) => Some(OpSize::Word),
+    _ => None,
+  }
+}
+
+fn is_register(arg: &Arg) -> bool {
+  matches!(arg, Arg::Byte(Operand::Register(_)) |
+    Arg::Word(Operand::Register(_)))
+}
+
+fn fits_i8(value: i32) -> bool {
+  (-0x80..=0x7f).contains(&(value as u16 as i16))
+}
+
+fn byte_operand(
+  arg: &Arg,
+  layout: &Layout,
+) -> Result<OperandByte, ProgramError> {
+  match arg {
+    Arg::Byte(operand) => Ok(operand.clone()),
+    Arg::Imm(value) if (-0x80..=0xff).contains(value) =>
+      Ok(Operand::ImmByte(*value as u8)),
+    Arg::Label(OpSize::Byte, name) => Ok(Operand::Direct(layout.label(name)?)),
+    _ => Err(ProgramError::InvalidOperands),
+  }
+}
+
+fn word_operand(
+  arg: &Arg,
+  layout: &Layout,
+  imm_byte: bool,
+) -> Result<OperandWord, ProgramError> {
+  match arg {
+    Arg::Word(operand) => Ok(operand.clone()),
+    Arg::Imm(value) if imm_byte && fits_i8(*value) =>
+      Ok(Operand::ImmByte(*value as u8)),
+    Arg::Imm(value) if (-0x8000..=0xffff).contains(value) =>
+      Ok(Operand::ImmWord(*value as u16)),
+    Arg::Offset(name) => Ok(Operand::ImmWord(layout.label(name)?)),
+    Arg::Label(OpSize::Word, name) => Ok(Operand::Direct(layout.label(name)?)),
+    _ => Err(ProgramError::InvalidOperands),
+  }
+}
+
+macro_rules! binary_ops {
+  ($($name:ident => $op:ident,)*) => {
+    $(
+      pub fn $name<D: Into<Arg>, S: Into<Arg>>(self, dest: D, src: S) -> Self {
+        self.binary(OpBinaryOp::$op, dest.into(), src.into())
+      }
+    )*
+  };
+}
+
+macro_rules! unary_ops {
+  ($($name:ident => $op:ident,)*) => {
+    $(
+      pub fn $name<D: Into<Arg>>(self, dest: D) -> Self {
+        self.unary(OpUnaryOp::$op, dest.into())
+      }
+    )*
+  };
+}
+
+macro_rules! shift_ops {
+  ($($name:ident => $op:ident,)*) => {
+    $(
+      pub fn $name<D, C>(self, dest: D, count: C) -> Self
+        where D: Into<Arg>, C: Into<Arg>
+      {
+        self.shift(OpShiftOp::$op, dest.into(), count.into())
+      }
+    )*
+  };
+}
+
+macro_rules! nullary_ops {
+  ($($name:ident => $op:expr,)*) => {
+    $(
+      pub fn $name(self) -> Self {
+        self.op($op)
+      }
+    )*
+  };
+}
+
+macro_rules! cond_jmp_ops {
+  ($($name:ident => $op:ident,)*) => {
+    $(
+      pub fn $name(self, label: &str) -> Self {
+        self.cond_jmp(OpCondJmpOp::$op, label)
+      }
+    )*
+  };
+}
+
+#[allow(clippy::should_implement_trait)]
+impl Program {
+  pub fn new() -> Self {
+    Program { items: vec![], prefixes: OpPrefixes::default() }
+  }
+
+  pub fn label(mut self, name: &str) -> Self {
+    self.items.push(Item::Label(name.to_string()));
+    self
+  }
+
+  // Prefixes apply to the next instruction.
+  pub fn segment(mut self, segment: RegisterWordType) -> Self {
+    self.prefixes.segment = Some(segment);
+    self
+  }
+
+  pub fn rep(mut self) -> Self {
+    self.prefixes.repeat = Some(OpRepeatType::Rep);
+    self
+  }
+
+  pub fn repnz(mut self) -> Self {
+    self.prefixes.repeat = Some(OpRepeatType::Repnz);
+    self
+  }
+
+  pub fn lock(mut self) -> Self {
+    self.prefixes.lock = true;
+    self
+  }
+
+  pub fn db(self, bytes: &[u8]) -> Self {
+    let bytes = bytes.to_vec();
+    self.code(Box::new(move |_| Ok(bytes.clone())))
+  }
+
+  pub fn dw(self, words: &[u16]) -> Self {
+    let bytes = words.iter()
+      .flat_map(|word| vec![(word & 0xff) as u8, (word >> 8) as u8])
+      .collect::<Vec<_>>();
+    self.code(Box::new(move |_| Ok(bytes.clone())))
+  }
+
+  pub fn op(self, op: Op) -> Self {
+    self.build(move |_| Ok(op.clone()))
+  }
+
+  fn code(mut self, emit: Emit) -> Self {
+    self.items.push(Item::Code(emit));
+    self
+  }
+
+  // Adds an instruction built once the labels are known.
+  fn build<F>(mut self, op: F) -> Self
+    where F: Fn(&Layout) -> Result<Op, ProgramError> + 'static
+  {
+    let prefixes = std::mem::take(&mut self.prefixes);
+    self.code(Box::new(move |layout| {
+      Ok(encode_instruction(&Instruction { prefixes, op: op(layout)? })?)
+    }))
+  }
+
+  // Adds a relative branch of a fixed length, given the offset to the
+  // label.
+  fn branch<F>(mut self, label: &str, length: u16, bytes: F) -> Self
+    where F: Fn(i16) -> Result<Vec<u8>, ProgramError> + 'static
+  {
+    let prefixes = std::mem::take(&mut self.prefixes);
+    let label = label.to_string();
+    self.code(Box::new(move |layout| {
+      let mut output = encode_prefixes(&prefixes)?;
+      let next = layout.here
+        .wrapping_add(output.len() as u16)
+        .wrapping_add(length);
+      let offset = layout.label(&label)?.wrapping_sub(next) as i16;
+      output.extend(bytes(offset).or_else(|err| match err {
+        ProgramError::OutOfRange(_) if !layout.final_pass => bytes(0),
+        ProgramError::OutOfRange(_) =>
+          Err(ProgramError::OutOfRange(label.clone())),
+        err => Err(err),
+      })?);
+      Ok(output)
+    }))
+  }
+
+  fn binary(self, op: OpBinaryOp, dest: Arg, src: Arg) -> Self {
+    // The register always goes to the reg field
+    let (dest, src) = match op {
+      OpBinaryOp::Xchg | OpBinaryOp::Test
+        if is_register(&src) && !is_register(&dest) => (src, dest),
+      _ => (dest, src),
+    };
+    let arithmetic = !matches!(op,
+      OpBinaryOp::Mov | OpBinaryOp::Test | OpBinaryOp::Xchg);
+    self.build(move |layout| {
+      match (arg_size(&dest), arg_size(&src)) {
+        (Some(a), Some(b)) if a != b => Err(ProgramError::InvalidOperands),
+        (Some(OpSize::Byte), _) | (None, Some(OpSize::Byte)) =>
+          Ok(Op::BinaryByte {
+            op,
+            src: byte_operand(&src, layout)?,
+            dest: byte_operand(&dest, layout)?,
+          }),
+        (Some(OpSize::Word), _) | (None, Some(OpSize::Word)) =>
+          Ok(Op::BinaryWord {
+            op,
+            src: word_operand(&src, layout, arithmetic)?,
+            dest: word_operand(&dest, layout, false)?,
+          }),
+        (None, None) => Err(ProgramError::InvalidOperands),
+      }
+    })
+  }
+
+  fn unary(self, op: OpUnaryOp, dest: Arg) -> Self {
+    self.build(move |layout| match arg_size(&dest) {
+      Some(OpSize::Byte) =>
+        Ok(Op::UnaryByte { op, dest: byte_operand(&dest, layout)? }),
+      Some(OpSize::Word) =>
+        Ok(Op::UnaryWord { op, dest: word_operand(&dest, layout, false)? }),
+      None => Err(ProgramError::InvalidOperands),
+    })
+  }
+
+  fn shift(self, op: OpShiftOp, dest: Arg, count: Arg) -> Self {
+    self.build(move |layout| {
+      let shift_type = match count {
+        Arg::Imm(1) => OpShiftType::One,
+        Arg::Byte(Operand::Register(RegisterByteType::Cl)) => OpShiftType::Cl,
+        _ => return Err(ProgramError::InvalidOperands),
+      };
+      match arg_size(&dest) {
+        Some(OpSize::Byte) => Ok(Op::ShiftByte {
+          op, shift_type, dest: byte_operand(&dest, layout)?,
+        }),
+        Some(OpSize::Word) => Ok(Op::ShiftWord {
+          op, shift_type, dest: word_operand(&dest, layout, false)?,
+        }),
+        None => Err(ProgramError::InvalidOperands),
+      }
+    })
+  }
+
+  fn cond_jmp(self, op: OpCondJmpOp, label: &str) -> Self {
+    self.branch(label, 2, move |offset| {
+      if !fits_i8(offset as i32) {
+        return Err(ProgramError::OutOfRange(String::new()));
+      }
+      Ok(encode(&Op::CondJmp { op, offset: offset as i8 })?)
+    })
+  }
+
+  binary_ops! {
+    mov => Mov,
+    add => Add,
+    or => Or,
+    adc => Adc,
+    sbb => Sbb,
+    and => And,
+    sub => Sub,
+    xor => Xor,
+    cmp => Cmp,
+    test => Test,
+    xchg => Xchg,
+  }
+
+  unary_ops! {
+    push => Push,
+    pop => Pop,
+    inc => Inc,
+    dec => Dec,
+    not => Not,
+    neg => Neg,
+    mul => Mul,
+    imul => Imul,
+    div => Div,
+    idiv => Idiv,
+  }
+
+  shift_ops! {
+    rol => Rol,
+    ror => Ror,
+    rcl => Rcl,
+    rcr => Rcr,
+    shl => Shl,
+    shr => Shr,
+    sal => Shl,
+    sar => Sar,
+  }
+
+  nullary_ops! {
+    nop => Op::BinaryWord {
+      op: OpBinaryOp::Xchg,
+      src: Operand::Register(RegisterWordType::Ax),
+      dest: Operand::Register(RegisterWordType::Ax),
+    },
+    xlat => Op::Nullary(OpNullaryOp::Xlat),
+    lahf => Op::Nullary(OpNullaryOp::Lahf),
+    sahf => Op::Nullary(OpNullaryOp::Sahf),
+    pushf => Op::Nullary(OpNullaryOp::Pushf),
+    popf => Op::Nullary(OpNullaryOp::Popf),
+    aaa => Op::Nullary(OpNullaryOp::Aaa),
+    daa => Op::Nullary(OpNullaryOp::Daa),
+    aas => Op::Nullary(OpNullaryOp::Aas),
+    das => Op::Nullary(OpNullaryOp::Das),
+    cbw => Op::Nullary(OpNullaryOp::Cbw),
+    cwd => Op::Nullary(OpNullaryOp::Cwd),
+    into => Op::Nullary(OpNullaryOp::Into),
+    iret => Op::Nullary(OpNullaryOp::Iret),
+    clc => Op::Nullary(OpNullaryOp::Clc),
+    cmc => Op::Nullary(OpNullaryOp::Cmc),
+    stc => Op::Nullary(OpNullaryOp::Stc),
+    cld => Op::Nullary(OpNullaryOp::Cld),
+    std => Op::Nullary(OpNullaryOp::Std),
+    cli => Op::Nullary(OpNullaryOp::Cli),
+    sti => Op::Nullary(OpNullaryOp::Sti),
+    hlt => Op::Nullary(OpNullaryOp::Hlt),
+    wait => Op::Nullary(OpNullaryOp::Wait),
+    salc => Op::Nullary(OpNullaryOp::Salc),
+    movsb => Op::Movs(OpSize::Byte),
+    movsw => Op::Movs(OpSize::Word),
+    cmpsb => Op::Cmps(OpSize::Byte),
+    cmpsw => Op::Cmps(OpSize::Word),
+    scasb => Op::Scas(OpSize::Byte),
+    scasw => Op::Scas(OpSize::Word),
+    lodsb => Op::Lods(OpSize::Byte),
+    lodsw => Op::Lods(OpSize::Word),
+    stosb => Op::Stos(OpSize::Byte),
+    stosw => Op::Stos(OpSize::Word),
+    ret => Op::RetWithin,
+    retf => Op::RetInter,
+  }
+
+  cond_jmp_ops! {
+    jo => Jo,
+    jno => Jno,
+    jb => Jb,
+    jc => Jb,
+    jnb => Jnb,
+    jnc => Jnb,
+    je => Je,
+    jz => Je,
+    jne => Jne,
+    jnz => Jne,
+    jbe => Jbe,
+    ja => Ja,
+    js => Js,
+    jns => Jns,
+    jp => Jp,
+    jnp => Jnp,
+    jl => Jl,
+    jge => Jge,
+    jle => Jle,
+    jg => Jg,
+    jcxz => Jcxz,
+    loop_ => Loop,
+    loope => Loope,
+    loopne => Loopne,
+  }
+
+  // Always the near form, as the label may be anywhere.
+  pub fn jmp(self, label: &str) -> Self {
+    self.branch(label, 3, |offset| {
+      Ok(encode(&Op::Jmp(OpCallType::WithinDirect(offset)))?)
+    })
+  }
+
+  pub fn jmp_short(self, label: &str) -> Self {
+    self.branch(label, 2, |offset| {
+      if !fits_i8(offset as i32) {
+        return Err(ProgramError::OutOfRange(String::new()));
+      }
+      Ok(encode(&Op::JmpShort(offset as i8))?)
+    })
+  }
+
+  pub fn call(self, label: &str) -> Self {
+    self.branch(label, 3, |offset| {
+      Ok(encode(&Op::Call(OpCallType::WithinDirect(offset)))?)
+    })
+  }
+
+  pub fn jmp_far(self, segment: u16, offset: u16) -> Self {
+    self.op(Op::Jmp(OpCallType::InterDirect(offset, segment)))
+  }
+
+  pub fn call_far(self, segment: u16, offset: u16) -> Self {
+    self.op(Op::Call(OpCallType::InterDirect(offset, segment)))
+  }
+
+  pub fn ret_imm(self, value: u16) -> Self {
+    self.op(Op::RetWithinImm(value))
+  }
+
+  pub fn retf_imm(self, value: u16) -> Self {
+    self.op(Op::RetInterImm(value))
+  }
+
+  pub fn int(self, value: u8) -> Self {
+    self.op(Op::Int(value))
+  }
+
+  pub fn in_<A: Into<Arg>, P: Into<Arg>>(self, acc: A, port: P) -> Self {
+    self.io(true, acc.into(), port.into())
+  }
+
+  pub fn out<P: Into<Arg>, A: Into<Arg>>(self, port: P, acc: A) -> Self {
+    self.io(false, acc.into(), port.into())
+  }
+
+  fn io(self, input: bool, acc: Arg, port: Arg) -> Self {
+    self.build(move |_| {
+      let size = match acc {
+        Arg::Byte(Operand::Register(RegisterByteType::Al)) => OpSize::Byte,
+        Arg::Word(Operand::Register(RegisterWordType::Ax)) => OpSize::Word,
+        _ => return Err(ProgramError::InvalidOperands),
+      };
+      match (&port, input) {
+        (Arg::Word(Operand::Register(RegisterWordType::Dx)), true) =>
+          Ok(Op::InFixed(size)),
+        (Arg::Word(Operand::Register(RegisterWordType::Dx)), false) =>
+          Ok(Op::OutFixed(size)),
+        (Arg::Imm(port), true) if (0..=0xff).contains(port) =>
+          Ok(Op::InVariable(size, *port as u8)),
+        (Arg::Imm(port), false) if (0..=0xff).contains(port) =>
+          Ok(Op::OutVariable(size, *port as u8)),
+        _ => Err(ProgramError::InvalidOperands),
+      }
+    })
+  }
+
+  pub fn lea<P: Into<Pointer>>(self, reg: RegisterWordType, addr: P) -> Self {
+    let addr = word_ptr(addr);
+    self.build(move |layout| Ok(Op::Lea(reg, word_operand(&addr, layout,
+      false)?)))
+  }
+
+  pub fn lds<P: Into<Pointer>>(self, reg: RegisterWordType, addr: P) -> Self {
+    let addr = word_ptr(addr);
+    self.build(move |layout| Ok(Op::Lds(reg, word_operand(&addr, layout,
+      false)?)))
+  }
+
+  pub fn les<P: Into<Pointer>>(self, reg: RegisterWordType, addr: P) -> Self {
+    let addr = word_ptr(addr);
+    self.build(move |layout| Ok(Op::Les(reg, word_operand(&addr, layout,
+      false)?)))
+  }
+
+  // Lays out the program at the origin, and emits it.
+  pub fn assemble(&self, origin: u16) -> Result<Assembly, ProgramError> {
+    let mut labels = HashMap::new();
+    let mut bytes = vec![];
+    for &final_pass in [false, true].iter() {
+      bytes = vec![];
+      for item in self.items.iter() {
+        let here = origin.wrapping_add(bytes.len() as u16);
+        match item {
+          Item::Label(name) if !final_pass => {
+            if labels.insert(name.clone(), here).is_some() {
+              return Err(ProgramError::DuplicateLabel(name.clone()));
+            }
+          },
+          Item::Label(_) => (),
+          Item::Code(emit) => {
+            let layout = Layout { labels: &labels, here, final_pass };
+            bytes.extend(emit(&layout)?);
+          },
+        }
+      }
+    }
+    Ok(Assembly {
+      origin,
+      bytes,
+      symbols: labels.into_iter()
+        .map(|(name, value)| (name, value as i64))
+        .collect(),
+    })
+  }
+
+  // Assembles the program for segment:offset, and writes it to memory there.
+  pub fn load(
+    &self,
+    cpu: &mut CPU,
+    segment: u16,
+    offset: u16,
+  ) -> Result<Assembly, ProgramError> {
+    let assembly = self.assemble(offset)?;
+    cpu.write_bytes(segment, offset, &assembly.bytes);
+    Ok(assembly)
+  }
+}
+
+impl Default for Program {
+  fn default() -> Self {
+    Program::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::asm::assemble;
+  use super::super::register::RegisterByteType::*;
+  use super::super::register::RegisterWordType::*;
+
+  #[test]
+  fn test_program() {
+    let program = Program::new()
+      .mov(Si, offset("data"))
+      .mov(Cx, 3)
+      .xor(Ax, Ax)
+      .label("sum")
+      .add(Ax, word_ptr(AddressType::Si))
+      .add(Si, 2)
+      .loop_("sum")
+      .mov(word_ptr("result"), Ax)
+      .segment(Es)
+      .mov(byte_ptr((AddressType::BxSi, -2)), Al)
+      .shl(Ax, 1)
+      .test(byte_ptr(0x1234u16), 0x80)
+      .jmp("end")
+      .label("data")
+      .dw(&[1, 2, 3])
+      .label("result")
+      .dw(&[0])
+      .label("end")
+      .hlt();
+    let expected = assemble("
+      org 0x100
+        mov si, data
+        mov cx, 3
+        xor ax, ax
+      sum:
+        add ax, [si]
+        add si, 2
+        loop sum
+        mov [result], ax
+        mov [es:bx+si-2], al
+        shl ax, 1
+        test byte [0x1234], 0x80
+        jmp near end
+      data: dw 1, 2, 3
+      result: dw 0
+      end:
+        hlt
+    ").unwrap();
+    let assembly = program.assemble(0x100).unwrap();
+    assert_eq!(assembly.bytes, expected.bytes);
+    assert_eq!(assembly.symbols, expected.symbols);
+  }
+
+  #[test]
+  fn test_program_error() {
+    let cases = vec![
+      (Program::new().jmp("nowhere"),
+        ProgramError::UndefinedLabel("nowhere".to_string())),
+      (Program::new().label("a").label("a"),
+        ProgramError::DuplicateLabel("a".to_string())),
+      (Program::new().jz("far").db(&[0; 200]).label("far"),
+        ProgramError::OutOfRange("far".to_string())),
+      (Program::new().mov(Al, Bx), ProgramError::InvalidOperands),
+      (Program::new().mov(word_ptr(AddressType::Bx), 0x10000),
+        ProgramError::InvalidOperands),
+      (Program::new().inc(0), ProgramError::InvalidOperands),
+    ];
+    for (program, err) in cases {
+      assert_eq!(program.assemble(0).unwrap_err(), err);
+    }
+  }
+}
@@ -47,7 +47,7 @@ impl Register {
       ss: 0,
       ds: 0,
       es: 0,
-      flags: 0,
+      flags: 0xF002,
     }
   }
 }
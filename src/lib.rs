@@ -1,2 +1,3 @@
 pub mod mem;
 pub mod i8086;
+pub mod dos;
@@ -1,19 +1,46 @@
-mod mem;
-mod i8086;
+mod monitor;
+mod runner;
 
-use mem::linear::LinearMemory;
-use i8086::cpu::CPU;
+use std::env;
+use std::io;
+use std::process;
+use rust_8086::mem::linear::LinearMemory;
+use rust_8086::mem::paged::PagedMemory;
+use rust_8086::i8086::cpu::CPU;
+use monitor::Monitor;
+
+// Where programs are loaded, like the free segment DEBUG picks.
+const SEGMENT: u16 = 0x0100;
 
 fn create_cpu() -> CPU {
-  // 1MB
+  // 1MB, with no devices on the I/O ports
   let memory = LinearMemory::new(1024 * 1024);
-  let io_map = LinearMemory::new(0);
-  CPU::new(Box::new(memory), Box::new(io_map))
+  let io_map = PagedMemory::new();
+  let mut cpu = CPU::new(Box::new(memory), Box::new(io_map));
+  cpu.register.cs = SEGMENT;
+  cpu.register.ds = SEGMENT;
+  cpu.register.es = SEGMENT;
+  cpu.register.ss = SEGMENT;
+  cpu.register.ip = 0x100;
+  cpu.register.sp = 0xfffe;
+  cpu
 }
 
 fn main() {
-    let mut cpu = create_cpu();
-    cpu.memory.write(0, 0xf000);
-    cpu.step();
-    println!("Hello, world!");
+  let args: Vec<String> = env::args().skip(1).collect();
+  if args.first().map(|arg| arg.as_str()) == Some("run") {
+    process::exit(runner::main(&args[1..]));
+  }
+  let stdin = io::stdin();
+  let mut monitor = Monitor::new(create_cpu(), stdin.lock(), io::stdout());
+  if let Some(file_name) = args.first() {
+    if let Err(err) = monitor.load(file_name) {
+      eprintln!("{}: {}", file_name, err);
+      process::exit(1);
+    }
+  }
+  if let Err(err) = monitor.run() {
+    eprintln!("{}", err);
+    process::exit(1);
+  }
 }
@@ -0,0 +1,774 @@
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+use rust_8086::i8086::cpu::CPU;
+use rust_8086::i8086::debugger::*;
+use rust_8086::i8086::decode::decode;
+use rust_8086::i8086::exception::Exception;
+use rust_8086::i8086::flags::*;
+use rust_8086::i8086::op::*;
+use rust_8086::i8086::register::Register;
+
+// An interactive monitor modelled on MS-DOS DEBUG. Numbers are hexadecimal,
+// and addresses are written as `offset` or `segment:offset`, where the
+// segment can also be a segment register.
+
+const PROMPT: &str = "-";
+
+const HELP: &str = "\
+d [range]             dump memory
+e address [list]      enter bytes
+g [=address] [addrs]  go, stopping at the given breakpoints
+i port                input a byte from the port
+l [address]           load the named file, setting BX:CX to its size
+n filename            name a file for l and w
+o port byte           output a byte to the port
+p [=address] [count]  proceed, stepping over calls, interrupts and loops
+q                     quit
+r [register]          show, or change, registers
+t [=address] [count]  trace
+u [range]             unassemble
+w [address]           write BX:CX bytes to the named file
+";
+
+// How flags are shown by `r`, as in DEBUG: the mnemonic when set, then when
+// clear.
+const FLAG_NAMES: [(u16, &str, &str); 8] = [
+  (OF, "OV", "NV"),
+  (DF, "DN", "UP"),
+  (IF, "EI", "DI"),
+  (SF, "NG", "PL"),
+  (ZF, "ZR", "NZ"),
+  (AF, "AC", "NA"),
+  (PF, "PE", "PO"),
+  (CF, "CY", "NC"),
+];
+
+// The column where a command line couldn't be parsed.
+struct ParseError(usize);
+
+type ParseResult<T> = Result<T, ParseError>;
+
+// Commands fail to parse, or to write to the console.
+type CommandResult = ParseResult<io::Result<()>>;
+
+// A start address, and the length if given.
+type Range = ((u16, u16), Option<usize>);
+
+struct Parser<'a> {
+  line: &'a str,
+  pos: usize,
+}
+
+impl<'a> Parser<'a> {
+  fn new(line: &'a str, pos: usize) -> Self {
+    Parser { line, pos }
+  }
+
+  fn rest(&self) -> &'a str {
+    &self.line[self.pos..]
+  }
+
+  fn skip_space(&mut self) -> () {
+    let rest = self.rest();
+    let trimmed = rest.trim_start_matches(|c: char| {
+      c.is_ascii_whitespace() || c == ','
+    });
+    self.pos += rest.len() - trimmed.len();
+  }
+
+  fn error<T>(&self) -> ParseResult<T> {
+    Err(ParseError(self.pos))
+  }
+
+  fn at_end(&mut self) -> bool {
+    self.skip_space();
+    self.rest().is_empty()
+  }
+
+  fn expect_end(&mut self) -> ParseResult<()> {
+    if self.at_end() { Ok(()) } else { self.error() }
+  }
+
+  // Consumes `token` (case insensitive) if it's next.
+  fn eat(&mut self, token: &str) -> bool {
+    self.skip_space();
+    match self.rest().get(..token.len()) {
+      Some(text) if text.eq_ignore_ascii_case(token) => {
+        self.pos += token.len();
+        true
+      },
+      _ => false,
+    }
+  }
+
+  fn word(&mut self) -> &'a str {
+    self.skip_space();
+    let rest = self.rest();
+    let length = rest.find(|c: char| c.is_ascii_whitespace())
+      .unwrap_or(rest.len());
+    self.pos += length;
+    &rest[..length]
+  }
+
+  fn number(&mut self) -> ParseResult<u16> {
+    self.skip_space();
+    let rest = self.rest();
+    let length = rest.find(|c: char| !c.is_ascii_hexdigit())
+      .unwrap_or(rest.len());
+    match u16::from_str_radix(&rest[..length], 16) {
+      Ok(value) => {
+        self.pos += length;
+        Ok(value)
+      },
+      Err(_) => self.error(),
+    }
+  }
+
+  fn byte(&mut self) -> ParseResult<u8> {
+    let start = self.pos;
+    let value = self.number()?;
+    if value > 0xff {
+      return Err(ParseError(start));
+    }
+    Ok(value as u8)
+  }
+
+  fn address(&mut self, cpu: &CPU, segment: u16) -> ParseResult<(u16, u16)> {
+    self.skip_space();
+    let registers = [
+      ("cs", cpu.register.cs),
+      ("ds", cpu.register.ds),
+      ("es", cpu.register.es),
+      ("ss", cpu.register.ss),
+    ];
+    for (name, value) in registers.iter() {
+      let start = self.pos;
+      if self.eat(name) {
+        if self.eat(":") {
+          return Ok((*value, self.number()?));
+        }
+        self.pos = start;
+      }
+    }
+    let value = self.number()?;
+    if self.eat(":") {
+      return Ok((value, self.number()?));
+    }
+    Ok((segment, value))
+  }
+
+  // `address`, `address L length` or `address end`; None when there's
+  // nothing to parse.
+  fn range(
+    &mut self,
+    cpu: &CPU,
+    segment: u16,
+  ) -> ParseResult<Option<Range>> {
+    if self.at_end() {
+      return Ok(None);
+    }
+    let (seg, offset) = self.address(cpu, segment)?;
+    if self.eat("l") {
+      let length = self.number()? as usize;
+      return Ok(Some(((seg, offset), Some(length))));
+    }
+    if self.at_end() {
+      return Ok(Some(((seg, offset), None)));
+    }
+    let start = self.pos;
+    let end = self.number()?;
+    if end < offset {
+      return Err(ParseError(start));
+    }
+    Ok(Some(((seg, offset), Some((end - offset) as usize + 1))))
+  }
+
+  // Bytes and quoted strings, as taken by `e`.
+  fn list(&mut self) -> ParseResult<Vec<u8>> {
+    let mut bytes = vec![];
+    while !self.at_end() {
+      let quote = match self.rest().chars().next() {
+        Some(c @ '\'') | Some(c @ '"') => c,
+        _ => {
+          bytes.push(self.byte()?);
+          continue;
+        },
+      };
+      let text = &self.rest()[1..];
+      match text.find(quote) {
+        Some(end) => {
+          bytes.extend(text[..end].bytes());
+          self.pos += end + 2;
+        },
+        None => return self.error(),
+      }
+    }
+    Ok(bytes)
+  }
+
+  // `=address`, which sets CS:IP before running.
+  fn start(&mut self, cpu: &CPU) -> ParseResult<Option<(u16, u16)>> {
+    if self.eat("=") {
+      Ok(Some(self.address(cpu, cpu.register.cs)?))
+    } else {
+      Ok(None)
+    }
+  }
+}
+
+fn register_mut<'a>(
+  register: &'a mut Register,
+  name: &str,
+) -> Option<&'a mut u16> {
+  Some(match name {
+    "ax" => &mut register.ax,
+    "bx" => &mut register.bx,
+    "cx" => &mut register.cx,
+    "dx" => &mut register.dx,
+    "sp" => &mut register.sp,
+    "bp" => &mut register.bp,
+    "si" => &mut register.si,
+    "di" => &mut register.di,
+    "ds" => &mut register.ds,
+    "es" => &mut register.es,
+    "ss" => &mut register.ss,
+    "cs" => &mut register.cs,
+    "ip" | "pc" => &mut register.ip,
+    _ => return None,
+  })
+}
+
+pub struct Monitor<R: BufRead, W: Write> {
+  pub cpu: CPU,
+  input: R,
+  output: W,
+  // Where `d` and `u` continue from
+  dump: (u16, u16),
+  unassemble: (u16, u16),
+  file_name: Option<String>,
+}
+
+impl<R: BufRead, W: Write> Monitor<R, W> {
+  pub fn new(mut cpu: CPU, input: R, output: W) -> Self {
+    // INT 3 returns to the monitor, as under DEBUG
+    if !cpu.intercepts.contains(&Exception::Breakpoint) {
+      cpu.intercepts.push(Exception::Breakpoint);
+    }
+    let dump = (cpu.register.ds, 0x100);
+    let unassemble = (cpu.register.cs, cpu.register.ip);
+    Monitor { cpu, input, output, dump, unassemble, file_name: None }
+  }
+
+  // Names the file and loads it at CS:0100, like `debug file`.
+  pub fn load(&mut self, file_name: &str) -> io::Result<()> {
+    self.file_name = Some(file_name.to_string());
+    self.load_file(self.cpu.register.cs, 0x100)
+  }
+
+  fn read_line(&mut self) -> io::Result<Option<String>> {
+    self.output.flush()?;
+    let mut line = String::new();
+    match self.input.read_line(&mut line)? {
+      0 => Ok(None),
+      _ => Ok(Some(line.trim_end_matches(&['\r', '\n'][..]).to_string())),
+    }
+  }
+
+  // Reads commands until `q`, or the end of input.
+  pub fn run(&mut self) -> io::Result<()> {
+    loop {
+      write!(self.output, "{}", PROMPT)?;
+      let line = match self.read_line()? {
+        Some(line) => line,
+        None => return Ok(()),
+      };
+      match self.command(&line) {
+        Ok(true) => (),
+        Ok(false) => return Ok(()),
+        Err(ParseError(pos)) => {
+          let column = PROMPT.len() + pos;
+          writeln!(self.output, "{:>width$} Error", "^", width = column + 1)?;
+        },
+      }
+    }
+  }
+
+  // Runs a command line; false when it asks to quit.
+  fn command(&mut self, line: &str) -> ParseResult<bool> {
+    let mut parser = Parser::new(line, 0);
+    if parser.at_end() {
+      return Ok(true);
+    }
+    let command = parser.rest().chars().next().unwrap().to_ascii_lowercase();
+    parser.pos += command.len_utf8();
+    let result = match command {
+      'd' => self.dump(&mut parser),
+      'e' => self.enter(&mut parser),
+      'g' => self.go(&mut parser),
+      'i' => self.input_port(&mut parser),
+      'l' => self.load_command(&mut parser),
+      'n' => {
+        let name = parser.word();
+        self.file_name = Some(name.to_string());
+        parser.expect_end().map(|_| Ok(()))
+      },
+      'o' => self.output_port(&mut parser),
+      'p' => self.trace(&mut parser, true),
+      'q' => return parser.expect_end().map(|_| false),
+      'r' => self.register(&mut parser),
+      't' => self.trace(&mut parser, false),
+      'u' => self.unassemble(&mut parser),
+      'w' => self.write_command(&mut parser),
+      '?' => Ok(write!(self.output, "{}", HELP)),
+      _ => Err(ParseError(0)),
+    };
+    // I/O errors on the console aren't worth stopping for
+    result?.ok();
+    Ok(true)
+  }
+
+  fn read_u8(&self, seg: u16, offset: u16) -> u8 {
+    self.cpu.memory.read_u8(self.cpu.get_linear_addr(seg, offset))
+  }
+
+  fn dump(&mut self, parser: &mut Parser) -> CommandResult {
+    let ((seg, start), length) = match parser.range(&self.cpu, self.dump.0)? {
+      Some(range) => range,
+      None => (self.dump, None),
+    };
+    parser.expect_end()?;
+    let length = length.unwrap_or(0x80);
+    self.dump = (seg, start.wrapping_add(length as u16));
+    Ok(self.write_dump(seg, start, length))
+  }
+
+  fn write_dump(
+    &mut self,
+    seg: u16,
+    start: u16,
+    length: usize,
+  ) -> io::Result<()> {
+    // Lines are aligned to 16 bytes, as in DEBUG.
+    let end = start as usize + length;
+    let mut line = (start & !0xf) as usize;
+    while line < end {
+      let mut hex = String::new();
+      let mut text = String::new();
+      for i in 0..16 {
+        let offset = line + i;
+        let separator = if i == 8 { '-' } else { ' ' };
+        if offset < start as usize || offset >= end {
+          hex.push_str("   ");
+          text.push(' ');
+          continue;
+        }
+        let value = self.read_u8(seg, offset as u16);
+        hex.push_str(&format!("{}{:02X}", separator, value));
+        text.push(match value {
+          0x20..=0x7e => value as char,
+          _ => '.',
+        });
+      }
+      writeln!(self.output, "{:04X}:{:04X} {}   {}",
+        seg, line as u16, hex, text.trim_end())?;
+      line += 16;
+    }
+    Ok(())
+  }
+
+  fn enter(&mut self, parser: &mut Parser) -> CommandResult {
+    let (seg, offset) = parser.address(&self.cpu, self.cpu.register.ds)?;
+    let bytes = parser.list()?;
+    if !bytes.is_empty() {
+      self.cpu.write_bytes(seg, offset, &bytes);
+      return Ok(Ok(()));
+    }
+    // Without a list, show the byte and read replacements; an empty line
+    // leaves memory as it is.
+    let value = self.read_u8(seg, offset);
+    let result =
+      write!(self.output, "{:04X}:{:04X}  {:02X}.", seg, offset, value)
+      .and_then(|_| self.read_line());
+    let line = match result {
+      Ok(Some(line)) => line,
+      Ok(None) => return Ok(Ok(())),
+      Err(err) => return Ok(Err(err)),
+    };
+    let bytes = Parser::new(&line, 0).list()?;
+    self.cpu.write_bytes(seg, offset, &bytes);
+    Ok(Ok(()))
+  }
+
+  fn unassemble(&mut self, parser: &mut Parser) -> CommandResult {
+    let cs = self.unassemble.0;
+    let ((seg, start), length) = match parser.range(&self.cpu, cs)? {
+      Some(range) => range,
+      None => (self.unassemble, None),
+    };
+    parser.expect_end()?;
+    let length = length.unwrap_or(0x20);
+    let mut offset = start;
+    while (offset.wrapping_sub(start) as usize) < length {
+      let (text, size) = self.disassemble(seg, offset);
+      if let Err(err) = writeln!(self.output, "{}", text) {
+        return Ok(Err(err));
+      }
+      offset = offset.wrapping_add(size as u16);
+      if offset < start {
+        break;
+      }
+    }
+    self.unassemble = (seg, offset);
+    Ok(Ok(()))
+  }
+
+  // A line of `u`, and the length of the instruction.
+  fn disassemble(&self, seg: u16, offset: u16) -> (String, usize) {
+    let bytes = self.cpu.read_bytes(seg, offset, 8);
+    let (bytes, text) = match decode(&bytes, seg, offset, self.cpu.model) {
+      Ok(decoded) => (decoded.bytes.clone(), decoded.to_string()),
+      Err(_) => (vec![bytes[0]], format!("db {:#04x}", bytes[0])),
+    };
+    let hex: String = bytes.iter().map(|value| format!("{:02X}", value))
+      .collect();
+    (format!("{:04X}:{:04X} {:<14}{}", seg, offset, hex, text), bytes.len())
+  }
+
+  fn flags_text(&self) -> String {
+    let flags = self.cpu.get_flags();
+    let names: Vec<&str> = FLAG_NAMES.iter()
+      .map(|(flag, set, clear)| if flags & flag != 0 { *set } else { *clear })
+      .collect();
+    names.join(" ")
+  }
+
+  fn write_registers(&mut self) -> io::Result<()> {
+    let register = &self.cpu.register;
+    writeln!(self.output,
+      "AX={:04X}  BX={:04X}  CX={:04X}  DX={:04X}  \
+      SP={:04X}  BP={:04X}  SI={:04X}  DI={:04X}",
+      register.ax, register.bx, register.cx, register.dx,
+      register.sp, register.bp, register.si, register.di)?;
+    writeln!(self.output,
+      "DS={:04X}  ES={:04X}  SS={:04X}  CS={:04X}  IP={:04X}   {}",
+      register.ds, register.es, register.ss, register.cs, register.ip,
+      self.flags_text())?;
+    let (cs, ip) = (self.cpu.register.cs, self.cpu.register.ip);
+    let (text, size) = self.disassemble(cs, ip);
+    writeln!(self.output, "{}", text)?;
+    self.unassemble = (cs, ip.wrapping_add(size as u16));
+    Ok(())
+  }
+
+  fn register(&mut self, parser: &mut Parser) -> CommandResult {
+    if parser.at_end() {
+      return Ok(self.write_registers());
+    }
+    let start = parser.pos;
+    let name = parser.word().to_ascii_lowercase();
+    if name == "f" {
+      parser.expect_end()?;
+      return self.edit_flags();
+    }
+    if register_mut(&mut self.cpu.register, &name).is_none() {
+      return Err(ParseError(start));
+    }
+    // `r ax 1234` sets it right away; `r ax` asks for the value
+    let value = if parser.at_end() {
+      let current = *register_mut(&mut self.cpu.register, &name).unwrap();
+      let result = write!(self.output, "{} {:04X}\n:",
+        name.to_ascii_uppercase(), current)
+        .and_then(|_| self.read_line());
+      let line = match result {
+        Ok(Some(line)) => line,
+        Ok(None) => return Ok(Ok(())),
+        Err(err) => return Ok(Err(err)),
+      };
+      let mut input = Parser::new(&line, 0);
+      if input.at_end() {
+        return Ok(Ok(()));
+      }
+      let value = input.number()?;
+      input.expect_end()?;
+      value
+    } else {
+      let value = parser.number()?;
+      parser.expect_end()?;
+      value
+    };
+    *register_mut(&mut self.cpu.register, &name).unwrap() = value;
+    Ok(Ok(()))
+  }
+
+  fn edit_flags(&mut self) -> CommandResult {
+    let result = write!(self.output, "{}  -", self.flags_text())
+      .and_then(|_| self.read_line());
+    let line = match result {
+      Ok(Some(line)) => line,
+      Ok(None) => return Ok(Ok(())),
+      Err(err) => return Ok(Err(err)),
+    };
+    let mut parser = Parser::new(&line, 0);
+    let (mut clear, mut set) = (0, 0);
+    while !parser.at_end() {
+      let start = parser.pos;
+      let name = parser.word();
+      let found = FLAG_NAMES.iter().find(|(_, on, off)| {
+        on.eq_ignore_ascii_case(name) || off.eq_ignore_ascii_case(name)
+      });
+      match found {
+        Some((flag, on, _)) if on.eq_ignore_ascii_case(name) => set |= flag,
+        Some((flag, _, _)) => clear |= flag,
+        None => return Err(ParseError(start + self.flags_text().len() + 2)),
+      }
+    }
+    self.cpu.blit_flags(clear, set);
+    Ok(Ok(()))
+  }
+
+  fn report(
+    &mut self,
+    result: Result<StopReason, Exception>,
+  ) -> io::Result<()> {
+    match result {
+      Ok(StopReason::Halted) => writeln!(self.output, "CPU halted")?,
+      Ok(StopReason::DecodeError(_)) =>
+        writeln!(self.output, "Invalid opcode")?,
+      Ok(StopReason::Watchpoint { address, access, value }) =>
+        writeln!(self.output, "{:?} of {:02X} at {:05X}",
+          access, value, address)?,
+      Ok(StopReason::PortWatchpoint { port, access, value }) =>
+        writeln!(self.output, "{:?} of {:04X} at port {:04X}",
+          access, value, port)?,
+      Ok(_) => (),
+      Err(Exception::Breakpoint) => (),
+      Err(Exception::DivideError) =>
+        writeln!(self.output, "Divide overflow")?,
+      Err(exception) =>
+        writeln!(self.output, "Unhandled exception: {:?}", exception)?,
+    }
+    self.write_registers()
+  }
+
+  // Whether `p` should run past the instruction at CS:IP, rather than trace
+  // into it.
+  fn steps_over(&self) -> Option<u16> {
+    let decoded = self.cpu.peek_op().ok()?;
+    let over = match decoded.op() {
+      Op::Call(_) | Op::Int(_) | Op::Nullary(OpNullaryOp::Into) => true,
+      Op::CondJmp { op: OpCondJmpOp::Loop, .. } |
+      Op::CondJmp { op: OpCondJmpOp::Loope, .. } |
+      Op::CondJmp { op: OpCondJmpOp::Loopne, .. } => true,
+      _ => decoded.prefixes().repeat.is_some(),
+    };
+    if over { Some(decoded.next_ip()) } else { None }
+  }
+
+  fn trace(
+    &mut self,
+    parser: &mut Parser,
+    proceed: bool,
+  ) -> CommandResult {
+    let start = parser.start(&self.cpu)?;
+    let count = if parser.at_end() { 1 } else { parser.number()? };
+    parser.expect_end()?;
+    if let Some((cs, ip)) = start {
+      self.cpu.jmp(cs, ip);
+    }
+    for _ in 0..count {
+      let cs = self.cpu.register.cs;
+      let result = match self.steps_over().filter(|_| proceed) {
+        Some(ip) => self.run_to(&[Breakpoint::Address(cs, ip)]),
+        None => self.cpu.step().map(|_| {
+          if self.cpu.is_stopped() {
+            StopReason::Halted
+          } else {
+            StopReason::BudgetExhausted
+          }
+        }),
+      };
+      let stopped = !matches!(result,
+        Ok(StopReason::BudgetExhausted) | Ok(StopReason::Breakpoint(_)));
+      if let Err(err) = self.report(result) {
+        return Ok(Err(err));
+      }
+      if stopped {
+        break;
+      }
+    }
+    Ok(Ok(()))
+  }
+
+  // Runs with temporary breakpoints, besides the ones already set.
+  fn run_to(
+    &mut self,
+    breakpoints: &[Breakpoint],
+  ) -> Result<StopReason, Exception> {
+    let saved = self.cpu.debugger.breakpoints.clone();
+    self.cpu.debugger.breakpoints.extend_from_slice(breakpoints);
+    let result = self.cpu.run_until(u64::MAX);
+    self.cpu.debugger.breakpoints = saved;
+    result
+  }
+
+  fn go(&mut self, parser: &mut Parser) -> CommandResult {
+    let start = parser.start(&self.cpu)?;
+    let cs = start.map(|(cs, _)| cs).unwrap_or(self.cpu.register.cs);
+    let mut breakpoints = vec![];
+    while !parser.at_end() {
+      let (seg, offset) = parser.address(&self.cpu, cs)?;
+      breakpoints.push(Breakpoint::Address(seg, offset));
+    }
+    if let Some((cs, ip)) = start {
+      self.cpu.jmp(cs, ip);
+    }
+    let result = self.run_to(&breakpoints);
+    Ok(self.report(result))
+  }
+
+  fn input_port(&mut self, parser: &mut Parser) -> CommandResult {
+    let port = parser.number()?;
+    parser.expect_end()?;
+    let value = self.cpu.io_ports.read_u8(port as usize);
+    Ok(writeln!(self.output, "{:02X}", value))
+  }
+
+  fn output_port(&mut self, parser: &mut Parser) -> CommandResult {
+    let port = parser.number()?;
+    let value = parser.byte()?;
+    parser.expect_end()?;
+    self.cpu.io_ports.write_u8(port as usize, value);
+    Ok(Ok(()))
+  }
+
+  fn file_address(&mut self, parser: &mut Parser) -> ParseResult<(u16, u16)> {
+    let address = if parser.at_end() {
+      (self.cpu.register.cs, 0x100)
+    } else {
+      parser.address(&self.cpu, self.cpu.register.cs)?
+    };
+    parser.expect_end()?;
+    Ok(address)
+  }
+
+  fn load_file(&mut self, seg: u16, offset: u16) -> io::Result<()> {
+    let name = self.file_name.clone().ok_or_else(|| {
+      io::Error::new(io::ErrorKind::InvalidInput, "No file name")
+    })?;
+    let bytes = fs::read(name)?;
+    // Past the segment, continue into the following ones, as `w` does
+    let start = self.cpu.get_linear_addr(seg, offset);
+    for (i, value) in bytes.iter().enumerate() {
+      self.cpu.memory.write_u8((start + i) & 0xfffff, *value);
+    }
+    self.cpu.register.bx = (bytes.len() >> 16) as u16;
+    self.cpu.register.cx = bytes.len() as u16;
+    Ok(())
+  }
+
+  fn load_command(&mut self, parser: &mut Parser) -> CommandResult {
+    let (seg, offset) = self.file_address(parser)?;
+    if let Err(err) = self.load_file(seg, offset) {
+      return Ok(writeln!(self.output, "{}", err));
+    }
+    Ok(Ok(()))
+  }
+
+  fn write_command(&mut self, parser: &mut Parser) -> CommandResult {
+    let (seg, offset) = self.file_address(parser)?;
+    let length = ((self.cpu.register.bx as usize) << 16) |
+      self.cpu.register.cx as usize;
+    // Past the segment, continue into the following ones
+    let start = self.cpu.get_linear_addr(seg, offset);
+    let bytes: Vec<u8> = (start..start + length)
+      .map(|address| self.cpu.memory.read_u8(address & 0xfffff))
+      .collect();
+    let result = match &self.file_name {
+      Some(name) => fs::write(name, &bytes),
+      None => Err(io::Error::new(io::ErrorKind::InvalidInput, "No file name")),
+    };
+    Ok(match result {
+      Ok(()) => writeln!(self.output, "Writing {:X} bytes", length),
+      Err(err) => writeln!(self.output, "{}", err),
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Cursor;
+  use rust_8086::mem::linear::LinearMemory;
+  use super::*;
+
+  fn run(script: &str) -> (CPU, String) {
+    let memory = LinearMemory::new(0x110000 / 4);
+    let mut cpu = CPU::new(Box::new(memory), Box::new(LinearMemory::new(0)));
+    cpu.jmp(0x0100, 0x0100);
+    cpu.register.ds = 0x0100;
+    let mut monitor = Monitor::new(cpu, Cursor::new(script), vec![]);
+    monitor.run().unwrap();
+    (monitor.cpu, String::from_utf8(monitor.output).unwrap())
+  }
+
+  #[test]
+  fn test_monitor() {
+    let (cpu, output) = run("\
+      e 100 b8 34 12 e8 01 00 f4 43 c3 'Hi'\n\
+      d 100 l b\n\
+      u 100 l 6\n\
+      r cx\n\
+      10\n\
+      t\n\
+      p\n\
+      x\n\
+      g\n");
+    let expected = "\
+      -\
+      -0100:0100  B8 34 12 E8 01 00 F4 43-C3 48 69                  \
+      .4.....C.Hi\n\
+      -0100:0100 B83412        mov ax, 0x1234\n\
+      0100:0103 E80100        call 0x0107\n\
+      -CX 0000\n\
+      :-AX=1234  BX=0000  CX=0010  DX=0000  \
+      SP=0000  BP=0000  SI=0000  DI=0000\n\
+      DS=0100  ES=0000  SS=0000  CS=0100  IP=0103   \
+      NV UP DI PL NZ NA PO NC\n\
+      0100:0103 E80100        call 0x0107\n\
+      -AX=1234  BX=0001  CX=0010  DX=0000  \
+      SP=0000  BP=0000  SI=0000  DI=0000\n\
+      DS=0100  ES=0000  SS=0000  CS=0100  IP=0106   \
+      NV UP DI PL NZ NA PO NC\n\
+      0100:0106 F4            hlt\n\
+      - ^ Error\n\
+      -CPU halted\n\
+      AX=1234  BX=0001  CX=0010  DX=0000  \
+      SP=0000  BP=0000  SI=0000  DI=0000\n\
+      DS=0100  ES=0000  SS=0000  CS=0100  IP=0107   \
+      NV UP DI PL NZ NA PO NC\n\
+      0100:0107 43            inc bx\n\
+      -";
+    assert_eq!(output, expected);
+    assert!(cpu.halted);
+  }
+
+  #[test]
+  fn test_load_write_past_segment() {
+    let dir = std::env::temp_dir();
+    let id = std::process::id();
+    let input = dir.join(format!("rust-8086-monitor-{}.bin", id));
+    let output = dir.join(format!("rust-8086-monitor-{}.out", id));
+    let bytes: Vec<u8> = (0..0x10010).map(|i| (i % 251) as u8).collect();
+    fs::write(&input, &bytes).unwrap();
+    let (cpu, _) = run(&format!("n {}\nl\nn {}\nw\n",
+      input.display(), output.display()));
+    // Into the next segment rather than around to CS:0000
+    assert_eq!(cpu.memory.read_u8(0x1000), 0);
+    assert_eq!(cpu.memory.read_u8(0x11100), bytes[0x10000]);
+    assert_eq!((cpu.register.bx, cpu.register.cx), (1, 0x10));
+    assert_eq!(fs::read(&output).unwrap(), bytes);
+    fs::remove_file(&input).unwrap();
+    fs::remove_file(&output).unwrap();
+  }
+}
@@ -0,0 +1,212 @@
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::io::Write;
+use std::rc::Rc;
+use rust_8086::dos::int21::Dos;
+use rust_8086::dos::loader::*;
+use rust_8086::dos::process;
+use rust_8086::dos::process::Terminate;
+use rust_8086::dos::process::Termination;
+use rust_8086::dos::psp::PspBuilder;
+use rust_8086::mem::linear::LinearMemory;
+use rust_8086::mem::paged::*;
+use rust_8086::i8086::cpu::CPU;
+use rust_8086::i8086::gdb;
+
+// `run [options] program [args]`: runs a DOS program, .COM or .EXE, to
+// completion, and exits with its exit code.
+
+const USAGE: &str = "\
+usage: rust-8086 run [options] program [args...]
+
+options:
+  --limit N     stop after N instructions
+  --trace       write each instruction to stderr before it runs
+  --memory KB   conventional memory size, 64 to 1024 (default 640)
+  --dos DIR     emulate INT 21h, with DIR as drive C:
+  --gdb ADDR    wait for GDB on ADDR, host:port or the path of a Unix
+                socket, and run the program under it";
+
+// Where the environment and then the PSP go; everything below is left for
+// the interrupt vectors and the BIOS data area.
+const LOAD_SEGMENT: u16 = 0x0100;
+
+struct Options {
+  limit: Option<u64>,
+  trace: bool,
+  memory: usize,
+  dos: Option<String>,
+  gdb: Option<String>,
+  program: String,
+  args: Vec<String>,
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+  let mut options = Options {
+    limit: None,
+    trace: false,
+    memory: 640,
+    dos: None,
+    gdb: None,
+    program: String::new(),
+    args: vec![],
+  };
+  let mut iter = args.iter();
+  while let Some(arg) = iter.next() {
+    let mut value = |name: &str| {
+      iter.next().ok_or_else(|| format!("{} needs a value", name))
+    };
+    match arg.as_str() {
+      "--limit" => {
+        let text = value(arg)?;
+        let limit = text.parse()
+          .map_err(|_| format!("invalid limit '{}'", text))?;
+        options.limit = Some(limit);
+      },
+      "--trace" => options.trace = true,
+      "--memory" => {
+        let text = value(arg)?;
+        options.memory = match text.parse() {
+          Ok(size) if (64..=1024).contains(&size) => size,
+          _ => return Err(format!("invalid memory size '{}'", text)),
+        };
+      },
+      "--dos" => options.dos = Some(value(arg)?.clone()),
+      "--gdb" => options.gdb = Some(value(arg)?.clone()),
+      "-h" | "--help" => return Err(USAGE.to_string()),
+      _ if arg.starts_with("--") =>
+        return Err(format!("unknown option '{}'", arg)),
+      _ => {
+        options.program = arg.clone();
+        options.args = iter.cloned().collect();
+        return Ok(options);
+      },
+    }
+  }
+  Err(USAGE.to_string())
+}
+
+fn create_cpu(kilobytes: usize) -> CPU {
+  // Past the end of memory, reads give 0 and writes go nowhere.
+  let words = kilobytes * 1024 / 4;
+  let mut memory = PagedMemory::new();
+  memory.insert_page(PagedMemorySegment::new(
+    0, words, Box::new(RefCell::new(LinearMemory::new(words)))));
+  CPU::new(Box::new(memory), Box::new(PagedMemory::new()))
+}
+
+// The program is named as if it were in the root of drive C, and gets the
+// host's environment, leaving out what DOS couldn't represent; what doesn't
+// fit in an environment block is left out when it's built.
+fn create_psp_builder(options: &Options) -> PspBuilder {
+  let name = Path::new(&options.program).file_name()
+    .map(|name| name.to_string_lossy().to_ascii_uppercase())
+    .unwrap_or_default();
+  let mut builder = PspBuilder::new(&format!("C:\\{}", name))
+    .args(&options.args);
+  let printable = |text: &str| {
+    text.bytes().all(|value| (0x20..0x7f).contains(&value))
+  };
+  for (name, value) in env::vars() {
+    if printable(&name) && printable(&value) {
+      builder = builder.env(&name, &value);
+    }
+  }
+  builder
+}
+
+// A path is taken for a Unix socket, anything else for a TCP address.
+fn listen(cpu: &mut CPU, address: &str) -> io::Result<()> {
+  #[cfg(unix)]
+  if address.contains('/') {
+    return gdb::listen_unix(cpu, address);
+  }
+  gdb::listen_tcp(cpu, address)
+}
+
+// Serves GDB with `handler` taking the program's interrupts, until it
+// detaches; the exit code is the program's if it ended.
+fn debug<H: Terminate + 'static>(
+  cpu: &mut CPU,
+  handler: Rc<RefCell<H>>,
+  address: &str,
+) -> i32 {
+  cpu.set_interrupt_handler(Box::new(handler.clone()));
+  if let Err(err) = listen(cpu, address) {
+    eprintln!("{}: {}", address, err);
+    return 1;
+  }
+  let exit_code = handler.borrow().exit_code();
+  exit_code.map_or(0, |code| code as i32)
+}
+
+// Returns the exit code for the shell.
+pub fn main(args: &[String]) -> i32 {
+  let options = match parse_options(args) {
+    Ok(options) => options,
+    Err(message) => {
+      eprintln!("{}", message);
+      return 2;
+    },
+  };
+  let image = match fs::read(&options.program) {
+    Ok(image) => image,
+    Err(err) => {
+      eprintln!("{}: {}", options.program, err);
+      return 1;
+    },
+  };
+  let mut cpu = create_cpu(options.memory);
+  let memory_top = (options.memory * 64).min(0xffff) as u16;
+  let psp_builder = create_psp_builder(&options);
+  let result =
+    load(&mut cpu, &image, LOAD_SEGMENT, memory_top, &psp_builder);
+  let psp = match result {
+    Ok(psp) => psp,
+    Err(err) => {
+      eprintln!("{}: {}", options.program, err);
+      return 1;
+    },
+  };
+  let dos = options.dos.as_ref().map(|root| {
+    let mut dos = Dos::new(root.into());
+    dos.dta = (psp, 0x80);
+    dos.memory_top = memory_top;
+    Rc::new(RefCell::new(dos))
+  });
+  if let Some(address) = &options.gdb {
+    return match dos {
+      Some(dos) => debug(&mut cpu, dos, address),
+      None => {
+        let handler = Rc::new(RefCell::new(Termination::default()));
+        debug(&mut cpu, handler, address)
+      },
+    };
+  }
+  let stderr = io::stderr();
+  let mut trace = stderr.lock();
+  let trace = if options.trace {
+    Some(&mut trace as &mut dyn Write)
+  } else {
+    None
+  };
+  let result = match dos {
+    Some(dos) => process::run_with(&mut cpu, dos, options.limit, trace),
+    None => process::run(&mut cpu, options.limit, trace),
+  };
+  match result {
+    Ok(exit) => {
+      if !matches!(exit, process::Exit::Terminated(_)) {
+        eprintln!("{}: {}", options.program, exit);
+      }
+      exit.code()
+    },
+    Err(err) => {
+      eprintln!("{}", err);
+      1
+    },
+  }
+}
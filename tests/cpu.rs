@@ -5,7 +5,20 @@ use std::rc::Rc;
 use std::thread;
 use std::time;
 
+use rust_8086::dos::int21::Dos;
+use rust_8086::dos::loader::*;
+use rust_8086::dos::process;
+use rust_8086::dos::psp::PspBuilder;
+use rust_8086::i8086::asm::assemble;
 use rust_8086::i8086::cpu::CPU;
+use rust_8086::i8086::cpu::AddressLines;
+use rust_8086::i8086::cpu::CpuModel;
+use rust_8086::i8086::debugger::*;
+use rust_8086::i8086::exception::Exception;
+use rust_8086::i8086::program::*;
+use rust_8086::i8086::register::RegisterByteType::*;
+use rust_8086::i8086::register::RegisterWordType::*;
+use rust_8086::i8086::interrupt::InterruptController;
 use rust_8086::mem::linear::LinearMemory;
 use rust_8086::mem::paged::*;
 use rust_8086::mem::callback::CallbackMemory;
@@ -32,11 +45,11 @@ fn op_mov_imm() {
     cpu.memory.write_u8(i, *value);
   }
   cpu.jmp(0, 0);
-  cpu.step();
+  cpu.step().unwrap();
   assert_eq!(cpu.register.ax, 0x8086);
-  cpu.step();
+  cpu.step().unwrap();
   assert_eq!(cpu.register.bx, 0x0086);
-  cpu.step();
+  cpu.step().unwrap();
   assert_eq!(cpu.register.cx, 0x0080);
 }
 
@@ -59,15 +72,15 @@ fn op_mov_mem() {
     cpu.memory.write_u8(i, *value);
   }
   cpu.jmp(0, 0);
-  cpu.step();
+  cpu.step().unwrap();
   assert_eq!(cpu.memory.read_u16(0x5353), 0xabcd);
-  cpu.step();
+  cpu.step().unwrap();
   assert_eq!(cpu.memory.read_u16(0x2000), 0x8086);
-  cpu.step();
+  cpu.step().unwrap();
   assert_eq!(cpu.register.bx, 0x8086);
-  cpu.step();
+  cpu.step().unwrap();
   assert_eq!(cpu.memory.read_u16(0x8086), 0x5353);
-  cpu.step();
+  cpu.step().unwrap();
   assert_eq!(cpu.register.bx, 0x5353);
 }
 
@@ -121,19 +134,27 @@ fn op_tests() {
       PagedMemorySegment::new(1, 1, Box::new(RefCell::new(io_handler))));
   }
   let mut cpu = create_cpu(Box::new(io_map));
+  cpu.intercepts = vec![
+    Exception::DivideError,
+    Exception::Overflow,
+    Exception::InvalidOpcode,
+  ];
   let test_data = include_bytes!("tests.com");
   for (i, value) in test_data.into_iter().enumerate() {
     cpu.memory.write_u8(i + 0x1100, *value);
   }
   cpu.jmp(0x100, 0x100);
-  while cpu.running {
+  while !cpu.halted {
     let op = match cpu.next_op() {
-      Some(v) => v,
-      None => break,
+      Ok(decoded) => decoded,
+      Err(err) => panic!("{} at {:#?}", err, &cpu.register),
     };
-    cpu.exec_op(&op);
+    if let Err(exception) = cpu.exec_op(&op.instruction) {
+      panic!("{:?} at {:04X}:{:04X} {}\n{:#?}",
+        exception, op.cs, op.ip, &op, &cpu.register);
+    }
     if *debugging.borrow() {
-      println!("{:#?} {:#?}", &op, &cpu.register);
+      println!("{:04X}:{:04X} {}\n{:#?}", op.cs, op.ip, &op, &cpu.register);
       thread::sleep(time::Duration::from_millis(100));
     }
   }
@@ -144,3 +165,780 @@ fn op_tests() {
     None => (),
   };
 }
+
+#[test]
+fn op_string() {
+  let mut cpu = create_cpu(Box::new(PagedMemory::new()));
+  let input: Vec<u8> = vec![
+    // cld
+    0xfc,
+    // mov si, 0x2000
+    0xbe, 0x00, 0x20,
+    // mov di, 0x3000
+    0xbf, 0x00, 0x30,
+    // mov cx, 3
+    0xb9, 0x03, 0x00,
+    // rep movsw
+    0xf3, 0xa5,
+    // mov di, 0x3000
+    0xbf, 0x00, 0x30,
+    // mov al, 0x56
+    0xb0, 0x56,
+    // mov cx, 6
+    0xb9, 0x06, 0x00,
+    // repne scasb
+    0xf2, 0xae,
+    // mov si, 0x2000
+    0xbe, 0x00, 0x20,
+    // mov di, 0x3000
+    0xbf, 0x00, 0x30,
+    // mov cx, 6
+    0xb9, 0x06, 0x00,
+    // repe cmpsb
+    0xf3, 0xa6,
+    // std
+    0xfd,
+    // mov di, 0x4002
+    0xbf, 0x02, 0x40,
+    // mov ax, 0xabcd
+    0xb8, 0xcd, 0xab,
+    // stosw
+    0xab,
+    // mov si, 0x4002
+    0xbe, 0x02, 0x40,
+    // xor ax, ax
+    0x31, 0xc0,
+    // lodsb
+    0xac,
+  ];
+  for (i, value) in input.iter().enumerate() {
+    cpu.memory.write_u8(i, *value);
+  }
+  for (i, value) in [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc].iter().enumerate() {
+    cpu.memory.write_u8(0x2000 + i, *value);
+  }
+  cpu.jmp(0, 0);
+  // Each iteration of a repeated instruction is a separate step
+  for _ in 0..7 {
+    cpu.step().unwrap();
+  }
+  assert_eq!(cpu.register.cx, 0);
+  assert_eq!(cpu.register.si, 0x2006);
+  assert_eq!(cpu.register.di, 0x3006);
+  assert_eq!(cpu.memory.read_u16(0x3000), 0x3412);
+  assert_eq!(cpu.memory.read_u16(0x3004), 0xbc9a);
+  for _ in 0..6 {
+    cpu.step().unwrap();
+  }
+  // Stops right after the matching byte
+  assert_eq!(cpu.register.di, 0x3003);
+  assert_eq!(cpu.register.cx, 3);
+  assert_eq!(cpu.register.flags & 0x0040, 0x0040);
+  cpu.memory.write_u8(0x3004, 0x00);
+  for _ in 0..8 {
+    cpu.step().unwrap();
+  }
+  // Stops right after the mismatching byte
+  assert_eq!(cpu.register.si, 0x2005);
+  assert_eq!(cpu.register.cx, 1);
+  assert_eq!(cpu.register.flags & 0x0040, 0);
+  for _ in 0..4 {
+    cpu.step().unwrap();
+  }
+  assert_eq!(cpu.memory.read_u16(0x4002), 0xabcd);
+  assert_eq!(cpu.register.di, 0x4000);
+  for _ in 0..3 {
+    cpu.step().unwrap();
+  }
+  assert_eq!(cpu.register.ax, 0x00cd);
+  assert_eq!(cpu.register.si, 0x4001);
+}
+
+#[test]
+fn op_prefix() {
+  let mut cpu = create_cpu(Box::new(PagedMemory::new()));
+  let input: Vec<u8> = vec![
+    // mov ax, 0x0100
+    0xb8, 0x00, 0x01,
+    // mov es, ax
+    0x8e, 0xc0,
+    // mov cx, 2
+    0xb9, 0x02, 0x00,
+    // es mov word [0x0010], 0x8086
+    0x26, 0xc7, 0x06, 0x10, 0x00, 0x86, 0x80,
+    // mov word [0x0010], 0x5353
+    0xc7, 0x06, 0x10, 0x00, 0x53, 0x53,
+    // rep es lodsw
+    0xf3, 0x26, 0xad,
+  ];
+  for (i, value) in input.iter().enumerate() {
+    cpu.memory.write_u8(i, *value);
+  }
+  cpu.jmp(0, 0);
+  for _ in 0..4 {
+    cpu.step().unwrap();
+  }
+  // Segment prefix is a part of the instruction
+  assert_eq!(cpu.register.ip, 0x000f);
+  assert_eq!(cpu.memory.read_u16(0x1010), 0x8086);
+  cpu.step().unwrap();
+  // ... and doesn't leak into the next one
+  assert_eq!(cpu.memory.read_u16(0x0010), 0x5353);
+  cpu.register.si = 0x0010;
+  cpu.step().unwrap();
+  // Unfinished repetition restarts from the first prefix byte
+  assert_eq!(cpu.register.ip, 0x0015);
+  assert_eq!(cpu.register.ax, 0x8086);
+  assert_eq!(cpu.register.cx, 1);
+  cpu.step().unwrap();
+  assert_eq!(cpu.register.ip, 0x0018);
+  assert_eq!(cpu.register.cx, 0);
+}
+
+#[test]
+fn op_exception() {
+  let mut cpu = create_cpu(Box::new(PagedMemory::new()));
+  let input: Vec<u8> = vec![
+    // mov sp, 0x1000
+    0xbc, 0x00, 0x10,
+    // mov ax, 0x1234
+    0xb8, 0x34, 0x12,
+    // xor bl, bl
+    0x30, 0xdb,
+    // div bl
+    0xf6, 0xf3,
+    // nop
+    0x90,
+  ];
+  for (i, value) in input.iter().enumerate() {
+    cpu.memory.write_u8(0x1000 + i, *value);
+  }
+  // Divide error handler at 0x0200:0x0000
+  cpu.memory.write_u16(0x0000, 0x0000);
+  cpu.memory.write_u16(0x0002, 0x0200);
+  // Invalid opcode handler at 0x0300:0x0000
+  cpu.memory.write_u16(0x0018, 0x0000);
+  cpu.memory.write_u16(0x001a, 0x0300);
+  cpu.jmp(0x0100, 0);
+  for _ in 0..4 {
+    cpu.step().unwrap();
+  }
+  // 8086 returns to the instruction after DIV
+  assert_eq!(cpu.register.cs, 0x0200);
+  assert_eq!(cpu.register.ip, 0x0000);
+  assert_eq!(cpu.register.sp, 0x0ffa);
+  assert_eq!(cpu.memory.read_u16(0x0ffa), 0x000a);
+  assert_eq!(cpu.register.ax, 0x1234);
+  // Invalid opcode points to the instruction itself
+  // lea ax, ax
+  cpu.memory.write_u8(0x2000, 0x8d);
+  cpu.memory.write_u8(0x2001, 0xc0);
+  cpu.step().unwrap();
+  assert_eq!(cpu.register.cs, 0x0300);
+  assert_eq!(cpu.memory.read_u16(0x0ff4), 0x0000);
+  assert_eq!(cpu.memory.read_u16(0x0ff6), 0x0200);
+  // Intercepted exceptions are returned to the host instead
+  cpu.intercepts = vec![Exception::InvalidOpcode];
+  // into (OF clear)
+  cpu.memory.write_u8(0x3000, 0xce);
+  // (0x60 is only valid on a real 8086)
+  cpu.model = CpuModel::Strict;
+  cpu.memory.write_u8(0x3001, 0x60);
+  cpu.step().unwrap();
+  assert_eq!(cpu.step(), Err(Exception::InvalidOpcode));
+  assert_eq!(cpu.register.cs, 0x0300);
+  assert_eq!(cpu.register.ip, 0x0001);
+  assert_eq!(cpu.register.sp, 0x0ff4);
+}
+
+struct TestPic {
+  line: bool,
+  vector: u8,
+}
+
+impl InterruptController for TestPic {
+  fn intr(&self) -> bool {
+    self.line
+  }
+  fn acknowledge(&mut self) -> u8 {
+    self.line = false;
+    self.vector
+  }
+}
+
+#[test]
+fn op_interrupt() {
+  let mut cpu = create_cpu(Box::new(PagedMemory::new()));
+  let pic = Rc::new(RefCell::new(TestPic { line: false, vector: 0x08 }));
+  cpu.set_interrupt_controller(Box::new(pic.clone()));
+  let input: Vec<u8> = vec![
+    // mov sp, 0x1000
+    0xbc, 0x00, 0x10,
+    // cli
+    0xfa,
+    // nop
+    0x90,
+    // sti
+    0xfb,
+    // nop
+    0x90,
+    // nop
+    0x90,
+  ];
+  for (i, value) in input.iter().enumerate() {
+    cpu.memory.write_u8(0x1000 + i, *value);
+  }
+  // IRQ 0 handler at 0x0200:0x0000
+  cpu.memory.write_u16(0x0020, 0x0000);
+  cpu.memory.write_u16(0x0022, 0x0200);
+  // NMI handler at 0x0300:0x0000
+  cpu.memory.write_u16(0x0008, 0x0000);
+  cpu.memory.write_u16(0x000a, 0x0300);
+  cpu.jmp(0x0100, 0);
+  cpu.step().unwrap();
+  cpu.step().unwrap();
+  pic.borrow_mut().line = true;
+  // Masked by IF
+  cpu.step().unwrap();
+  assert_eq!(cpu.register.ip, 0x0005);
+  cpu.step().unwrap();
+  // Delayed by STI
+  cpu.step().unwrap();
+  assert_eq!(cpu.register.ip, 0x0007);
+  cpu.step().unwrap();
+  assert_eq!(cpu.register.cs, 0x0200);
+  assert_eq!(cpu.register.ip, 0x0000);
+  assert_eq!(cpu.memory.read_u16(0x0ffa), 0x0007);
+  assert_eq!(cpu.memory.read_u16(0x0ffc), 0x0100);
+  assert_eq!(cpu.register.flags & 0x0200, 0);
+  assert!(!pic.borrow().line);
+  // NMI ignores IF
+  cpu.nmi();
+  cpu.step().unwrap();
+  assert_eq!(cpu.register.cs, 0x0300);
+  assert_eq!(cpu.memory.read_u16(0x0ff4), 0x0000);
+  assert_eq!(cpu.memory.read_u16(0x0ff6), 0x0200);
+}
+
+#[test]
+fn op_trap() {
+  let mut cpu = create_cpu(Box::new(PagedMemory::new()));
+  let input: Vec<u8> = vec![
+    // mov sp, 0x1000
+    0xbc, 0x00, 0x10,
+    // pushf
+    0x9c,
+    // pop ax
+    0x58,
+    // or ax, 0x0100
+    0x0d, 0x00, 0x01,
+    // push ax
+    0x50,
+    // popf
+    0x9d,
+    // inc bx
+    0x43,
+    // int3
+    0xcc,
+    // inc bx
+    0x43,
+  ];
+  for (i, value) in input.iter().enumerate() {
+    cpu.memory.write_u8(0x1000 + i, *value);
+  }
+  // Single step handler at 0x0200:0x0000; iret
+  cpu.memory.write_u16(0x0004, 0x0000);
+  cpu.memory.write_u16(0x0006, 0x0200);
+  cpu.memory.write_u8(0x2000, 0xcf);
+  cpu.jmp(0x0100, 0);
+  for _ in 0..6 {
+    cpu.step().unwrap();
+  }
+  // POPF setting TF doesn't trap by itself
+  assert_eq!(cpu.register.cs, 0x0100);
+  assert_eq!(cpu.register.ip, 0x000a);
+  cpu.step().unwrap();
+  // ... but the next instruction does
+  assert_eq!(cpu.register.bx, 1);
+  assert_eq!(cpu.register.cs, 0x0200);
+  assert_eq!(cpu.memory.read_u16(0x0ffa), 0x000b);
+  assert_eq!(cpu.register.flags & 0x0100, 0);
+  cpu.step().unwrap();
+  assert_eq!(cpu.register.cs, 0x0100);
+  assert_eq!(cpu.register.flags & 0x0100, 0x0100);
+  // Debug exceptions can be handled by the host
+  cpu.intercepts = vec![Exception::SingleStep, Exception::Breakpoint];
+  assert_eq!(cpu.step(), Err(Exception::Breakpoint));
+  assert_eq!(cpu.register.ip, 0x000c);
+  assert_eq!(cpu.step(), Err(Exception::SingleStep));
+  assert_eq!(cpu.register.bx, 2);
+  assert_eq!(cpu.register.ip, 0x000d);
+}
+
+#[test]
+fn op_hlt() {
+  let mut cpu = create_cpu(Box::new(PagedMemory::new()));
+  let input: Vec<u8> = vec![
+    // mov sp, 0x1000
+    0xbc, 0x00, 0x10,
+    // sti
+    0xfb,
+    // hlt
+    0xf4,
+    // cli
+    0xfa,
+    // hlt
+    0xf4,
+  ];
+  for (i, value) in input.iter().enumerate() {
+    cpu.memory.write_u8(0x1000 + i, *value);
+  }
+  // IRQ 0 handler at 0x0200:0x0000; iret
+  cpu.memory.write_u16(0x0020, 0x0000);
+  cpu.memory.write_u16(0x0022, 0x0200);
+  cpu.memory.write_u8(0x2000, 0xcf);
+  let pic = Rc::new(RefCell::new(TestPic { line: false, vector: 0x08 }));
+  cpu.set_interrupt_controller(Box::new(pic.clone()));
+  cpu.jmp(0x0100, 0);
+  for _ in 0..3 {
+    cpu.step().unwrap();
+  }
+  assert!(cpu.halted);
+  // Waiting for an interrupt isn't termination
+  assert!(!cpu.is_stopped());
+  cpu.step().unwrap();
+  assert_eq!(cpu.register.ip, 0x0005);
+  pic.borrow_mut().line = true;
+  cpu.step().unwrap();
+  assert!(!cpu.halted);
+  assert_eq!(cpu.register.cs, 0x0200);
+  // Returns to the instruction after HLT
+  cpu.step().unwrap();
+  assert_eq!(cpu.register.cs, 0x0100);
+  assert_eq!(cpu.register.ip, 0x0005);
+  cpu.run().unwrap();
+  assert!(cpu.halted);
+  assert!(cpu.is_stopped());
+  assert_eq!(cpu.register.ip, 0x0007);
+}
+
+#[test]
+fn op_segment_default() {
+  let mut cpu = create_cpu(Box::new(PagedMemory::new()));
+  let input: Vec<u8> = vec![
+    // mov ax, 0x0200
+    0xb8, 0x00, 0x02,
+    // mov ss, ax
+    0x8e, 0xd0,
+    // mov bp, 0x0010
+    0xbd, 0x10, 0x00,
+    // mov word [bp-2], 0x8086
+    0xc7, 0x46, 0xfe, 0x86, 0x80,
+    // mov ax, [bp+si-2]
+    0x8b, 0x42, 0xfe,
+    // ds mov bx, [bp-2]
+    0x3e, 0x8b, 0x5e, 0xfe,
+    // mov word [0x0100], 0x1234
+    0xc7, 0x06, 0x00, 0x01, 0x34, 0x12,
+    // mov word [0x0102], 0x0300
+    0xc7, 0x06, 0x02, 0x01, 0x00, 0x03,
+    // les di, [0x0100]
+    0xc4, 0x3e, 0x00, 0x01,
+    // mov cx, 1
+    0xb9, 0x01, 0x00,
+    // ss rep movsb (destination ignores the override)
+    0xf3, 0x36, 0xa4,
+  ];
+  for (i, value) in input.iter().enumerate() {
+    cpu.memory.write_u8(0x1000 + i, *value);
+  }
+  cpu.register.ds = 0x0100;
+  cpu.jmp(0x0100, 0);
+  for _ in 0..4 {
+    cpu.step().unwrap();
+  }
+  // [bp-n] uses SS
+  assert_eq!(cpu.memory.read_u16(0x200e), 0x8086);
+  cpu.step().unwrap();
+  assert_eq!(cpu.register.ax, 0x8086);
+  cpu.memory.write_u16(0x100e, 0x5353);
+  cpu.step().unwrap();
+  assert_eq!(cpu.register.bx, 0x5353);
+  for _ in 0..3 {
+    cpu.step().unwrap();
+  }
+  assert_eq!(cpu.register.di, 0x1234);
+  assert_eq!(cpu.register.es, 0x0300);
+  cpu.register.si = 0x000e;
+  cpu.step().unwrap();
+  cpu.step().unwrap();
+  assert_eq!(cpu.memory.read_u8(0x4234), 0x86);
+}
+
+#[test]
+fn op_xlat_segment() {
+  let mut cpu = create_cpu(Box::new(PagedMemory::new()));
+  let input: Vec<u8> = vec![
+    // mov bx, 0x0010
+    0xbb, 0x10, 0x00,
+    // mov al, 2
+    0xb0, 0x02,
+    // xlatb
+    0xd7,
+    // mov al, 2
+    0xb0, 0x02,
+    // es xlatb
+    0x26, 0xd7,
+    // mov al, 1
+    0xb0, 0x01,
+    // cs xlatb
+    0x2e, 0xd7,
+  ];
+  for (i, value) in input.iter().enumerate() {
+    cpu.memory.write_u8(0x1000 + i, *value);
+  }
+  cpu.register.ds = 0x0200;
+  cpu.register.es = 0x0300;
+  cpu.memory.write_u8(0x2012, 0x44);
+  cpu.memory.write_u8(0x3012, 0x55);
+  cpu.memory.write_u8(0x1011, 0x66);
+  cpu.jmp(0x0100, 0);
+  for _ in 0..3 {
+    cpu.step().unwrap();
+  }
+  // [DS:BX+AL] by default
+  assert_eq!(cpu.register.ax & 0xff, 0x44);
+  cpu.step().unwrap();
+  cpu.step().unwrap();
+  assert_eq!(cpu.register.ax & 0xff, 0x55);
+  cpu.step().unwrap();
+  cpu.step().unwrap();
+  assert_eq!(cpu.register.ax & 0xff, 0x66);
+}
+
+#[test]
+fn op_wraparound() {
+  let mut cpu = create_cpu(Box::new(PagedMemory::new()));
+  // mov ax, 0x8086 placed across IP=0xFFFF
+  cpu.memory.write_u8(0x10ffe, 0xb8);
+  cpu.memory.write_u8(0x10fff, 0x86);
+  cpu.memory.write_u8(0x01000, 0x80);
+  let input: Vec<u8> = vec![
+    // push ax
+    0x50,
+    // pop bx
+    0x5b,
+    // mov word [0xffff], 0x5353
+    0xc7, 0x06, 0xff, 0xff, 0x53, 0x53,
+    // mov cx, [bx+si]
+    0x8b, 0x08,
+    // loop (-2)
+    0xe2, 0xfe,
+  ];
+  for (i, value) in input.iter().enumerate() {
+    cpu.memory.write_u8(0x1001 + i, *value);
+  }
+  cpu.register.ss = 0x0200;
+  cpu.register.sp = 0x0000;
+  cpu.register.ds = 0x0300;
+  cpu.jmp(0x0100, 0xfffe);
+  cpu.step().unwrap();
+  assert_eq!(cpu.register.ax, 0x8086);
+  assert_eq!(cpu.register.ip, 0x0001);
+  // Stack wraps at SP=0
+  cpu.step().unwrap();
+  assert_eq!(cpu.register.sp, 0xfffe);
+  assert_eq!(cpu.memory.read_u16(0x11ffe), 0x8086);
+  cpu.step().unwrap();
+  assert_eq!(cpu.register.sp, 0x0000);
+  assert_eq!(cpu.register.bx, 0x8086);
+  // Words at offset 0xFFFF wrap in the segment
+  cpu.step().unwrap();
+  assert_eq!(cpu.memory.read_u8(0x12fff), 0x53);
+  assert_eq!(cpu.memory.read_u8(0x03000), 0x53);
+  assert_eq!(cpu.memory.read_u8(0x13000), 0x00);
+  // Effective address wraps too
+  cpu.register.si = 0x7f79;
+  cpu.step().unwrap();
+  assert_eq!(cpu.register.cx, 0x5353);
+  cpu.register.cx = 0;
+  cpu.step().unwrap();
+  assert_eq!(cpu.register.cx, 0xffff);
+  assert_eq!(cpu.register.ip, 0x000b);
+  // Linear address wraps at 1MB
+  cpu.register.ds = 0xffff;
+  cpu.register.bx = 0x0010;
+  cpu.register.si = 0x0000;
+  cpu.memory.write_u16(0x00000, 0xabcd);
+  cpu.jmp(0x0100, 0x0009);
+  cpu.step().unwrap();
+  assert_eq!(cpu.register.cx, 0xabcd);
+}
+
+#[test]
+fn op_a20() {
+  let mut cpu = create_cpu(Box::new(PagedMemory::new()));
+  cpu.address_lines = AddressLines::A20Gate;
+  let mut io_map = PagedMemory::new();
+  {
+    // Port 0x92 (system control port A); bit 1 is the A20 gate.
+    let gate_read = cpu.get_a20_gate();
+    let gate_write = cpu.get_a20_gate();
+    let io_handler = CallbackMemory::new(
+      Box::new(move |_| if gate_read.get() { 0x00020000 } else { 0 }),
+      Box::new(move |_, value| gate_write.set(value & 0x00020000 != 0)),
+    );
+    io_map.insert_page(
+      PagedMemorySegment::new(0x24, 1, Box::new(RefCell::new(io_handler))));
+  }
+  cpu.io_ports = Box::new(io_map);
+  let input: Vec<u8> = vec![
+    // mov ax, [0x0010]
+    0xa1, 0x10, 0x00,
+    // in al, 0x92
+    0xe4, 0x92,
+    // or al, 0x02
+    0x0c, 0x02,
+    // out 0x92, al
+    0xe6, 0x92,
+    // mov ax, [0x0010]
+    0xa1, 0x10, 0x00,
+  ];
+  for (i, value) in input.iter().enumerate() {
+    cpu.memory.write_u8(0x1000 + i, *value);
+  }
+  cpu.memory.write_u16(0x000000, 0x1234);
+  cpu.memory.write_u16(0x100000, 0x5678);
+  cpu.register.ds = 0xffff;
+  cpu.jmp(0x0100, 0);
+  cpu.step().unwrap();
+  // Wraps around while A20 is disabled
+  assert_eq!(cpu.register.ax, 0x1234);
+  for _ in 0..4 {
+    cpu.step().unwrap();
+  }
+  assert!(cpu.a20_gate.get());
+  assert_eq!(cpu.register.ax, 0x5678);
+}
+
+#[test]
+fn op_undocumented() {
+  let mut cpu = create_cpu(Box::new(LinearMemory::new(0)));
+  let input: Vec<u8> = vec![
+    0xb8, 0x00, 0x20, // mov ax, 0x2000
+    0x8e, 0xe0, // mov fs, ax (decodes as mov es, ax)
+    0xf9, // stc
+    0xd6, // salc
+    0x62, 0x02, // jb +2 (alias of 0x72)
+    0xf4, // hlt
+    0xf4, // hlt
+    0xf6, 0xc8, 0x80, // test al, 0x80 (alias of f6 /0)
+    0xe8, 0x02, 0x00, // call +2
+    0xf4, // hlt
+    0xf4, // hlt
+    0xc1, // ret (alias of c3)
+  ];
+  for (i, value) in input.iter().enumerate() {
+    cpu.memory.write_u8(0x1000 + i, *value);
+  }
+  cpu.register.sp = 0x0800;
+  cpu.jmp(0x0100, 0);
+  for _ in 0..5 {
+    cpu.step().unwrap();
+  }
+  assert_eq!(cpu.register.es, 0x2000);
+  assert_eq!(cpu.register.ax, 0x20ff);
+  assert_eq!(cpu.register.ip, 0x000b);
+  cpu.step().unwrap();
+  assert_eq!(cpu.register.flags & 0x0080, 0x0080);
+  cpu.step().unwrap();
+  cpu.step().unwrap();
+  assert_eq!(cpu.register.ip, 0x0011);
+  // The strict model rejects them instead
+  cpu.model = CpuModel::Strict;
+  cpu.intercepts = vec![Exception::InvalidOpcode];
+  cpu.memory.write_u8(0x1011, 0xd6);
+  assert_eq!(cpu.step(), Err(Exception::InvalidOpcode));
+  assert_eq!(cpu.register.ip, 0x0011);
+}
+
+#[test]
+fn op_assembled() {
+  let mut cpu = create_cpu(Box::new(LinearMemory::new(0)));
+  let assembly = assemble("
+    org 0x100
+      mov si, data
+      xor ax, ax
+      mov cx, (data.end - data) / 2
+    .sum:
+      add ax, [si]
+      inc si
+      inc si
+      loop .sum
+      mov [result], ax
+      hlt
+    data:
+      dw 1, 2, 3, 0x1000
+    .end:
+    result: dw 0
+  ").unwrap();
+  for (i, value) in assembly.bytes.iter().enumerate() {
+    cpu.memory.write_u8(0x100 + i, *value);
+  }
+  cpu.jmp(0, 0x100);
+  while !cpu.halted {
+    cpu.step().unwrap();
+  }
+  assert_eq!(cpu.register.ax, 0x1006);
+  let result = assembly.symbols["result"] as usize;
+  assert_eq!(cpu.memory.read_u16(result), 0x1006);
+}
+
+#[test]
+fn op_program() {
+  let mut cpu = create_cpu(Box::new(LinearMemory::new(0)));
+  let assembly = Program::new()
+    .mov(Ax, 0x8086)
+    .mov(Cx, 4)
+    .label("l")
+    .shl(Ax, 1)
+    .loop_("l")
+    .call("store")
+    .hlt()
+    .label("store")
+    .mov(word_ptr("result"), Ax)
+    .ret()
+    .label("result")
+    .dw(&[0])
+    .load(&mut cpu, 0x1000, 0x0100)
+    .unwrap();
+  cpu.register.ds = 0x1000;
+  cpu.register.sp = 0x0800;
+  cpu.jmp(0x1000, 0x0100);
+  while !cpu.halted {
+    cpu.step().unwrap();
+  }
+  assert_eq!(cpu.register.ax, 0x0860);
+  let result = assembly.symbols["result"] as usize;
+  assert_eq!(cpu.memory.read_u16(0x10000 + result), 0x0860);
+}
+
+#[test]
+fn op_debugger() {
+  let mut cpu = create_cpu(Box::new(LinearMemory::new(0x100)));
+  Program::new()
+    .mov(Cx, 3)
+    .label("loop")
+    .inc(word_ptr(0x0200u16))
+    .loop_("loop")
+    .label("after")
+    .out(0x42, Al)
+    .mov(Ax, word_ptr(0x0300u16))
+    .db(&[0x0f])
+    .load(&mut cpu, 0, 0x0100)
+    .unwrap();
+  cpu.jmp(0, 0x0100);
+  cpu.debugger.breakpoints.push(Breakpoint::Address(0, 0x0103));
+  // The first stop is before the loop body; continuing stops there again.
+  assert_eq!(cpu.run_until(100), Ok(StopReason::Breakpoint(
+    Breakpoint::Address(0, 0x0103))));
+  assert_eq!(cpu.register.cx, 3);
+  assert_eq!(cpu.run_until(100), Ok(StopReason::Breakpoint(
+    Breakpoint::Address(0, 0x0103))));
+  assert_eq!(cpu.register.cx, 2);
+  cpu.debugger.breakpoints.clear();
+  assert_eq!(cpu.run_until(2), Ok(StopReason::BudgetExhausted));
+  cpu.debugger.breakpoints.push(Breakpoint::Linear(0x0109));
+  assert_eq!(cpu.run_until(100), Ok(StopReason::Breakpoint(
+    Breakpoint::Linear(0x0109))));
+  assert_eq!(cpu.memory.read_u16(0x0200), 3);
+  cpu.debugger.port_watchpoints.push(
+    Watchpoint::new(0x40, 4, WatchKind::Write));
+  cpu.debugger.watchpoints.push(
+    Watchpoint::new(0x0301, 1, WatchKind::Read));
+  assert_eq!(cpu.run_until(100), Ok(StopReason::PortWatchpoint {
+    port: 0x42,
+    access: Access::Write,
+    value: 0x00,
+  }));
+  cpu.memory.write_u16(0x0300, 0xabcd);
+  assert_eq!(cpu.run_until(100), Ok(StopReason::Watchpoint {
+    address: 0x0301,
+    access: Access::Read,
+    value: 0xab,
+  }));
+  assert_eq!(cpu.register.ax, 0xabcd);
+  cpu.model = CpuModel::Strict;
+  match cpu.run_until(100) {
+    Ok(StopReason::DecodeError(err)) => assert_eq!(err.bytes, vec![0x0f]),
+    reason => panic!("unexpected {:?}", reason),
+  }
+  assert_eq!(cpu.register.ip, 0x010e);
+  cpu.memory.write_u8(0x010e, 0xf4);
+  assert_eq!(cpu.run_until(100), Ok(StopReason::Halted));
+}
+
+#[test]
+fn dos_com() {
+  let run = |program: Program, args: &[&str]| {
+    let mut cpu = create_cpu(Box::new(LinearMemory::new(0)));
+    let image = program.assemble(0x100).unwrap().bytes;
+    let builder = PspBuilder::new("C:\\TEST.COM").args(args);
+    load_com(&mut cpu, &image, 0x1000, 0xa000, &builder).unwrap();
+    assert_eq!(cpu.register.sp, 0xfffe);
+    process::run(&mut cpu, Some(1000), None).unwrap()
+  };
+  // The exit code is the first character plus the length of the tail
+  let program = Program::new()
+    .mov(Bl, byte_ptr(0x80u16))
+    .mov(Al, byte_ptr(0x82u16))
+    .add(Al, Bl)
+    .mov(Ah, 0x4c)
+    .int(0x21);
+  assert_eq!(run(program, &["ab", "c"]), process::Exit::Terminated(0x66));
+  // RET goes to the INT 20h at PSP:0000
+  assert_eq!(run(Program::new().ret(), &[]), process::Exit::Terminated(0));
+  let program = Program::new()
+    .xor(Cl, Cl)
+    .div(Cl);
+  assert_eq!(run(program, &[]),
+    process::Exit::Exception(Exception::DivideError));
+  let program = Program::new()
+    .mov(Ah, 0x30)
+    .int(0x21);
+  assert_eq!(run(program, &[]),
+    process::Exit::UnhandledInterrupt { vector: 0x21, ax: 0x3000 });
+  let program = Program::new()
+    .label("loop")
+    .jmp_short("loop");
+  assert_eq!(run(program, &[]), process::Exit::LimitReached);
+}
+
+#[test]
+fn dos_int21() {
+  let run = |program: Program| {
+    let mut cpu = create_cpu(Box::new(LinearMemory::new(0)));
+    let image = program.assemble(0x100).unwrap().bytes;
+    let builder = PspBuilder::new("C:\\TEST.COM");
+    load_com(&mut cpu, &image, 0x1000, 0xa000, &builder).unwrap();
+    let dos = Rc::new(RefCell::new(Dos::new(std::env::temp_dir())));
+    let exit = process::run_with(&mut cpu, dos.clone(), Some(1000), None);
+    assert!(cpu.interrupt_handler.is_none());
+    let exit_code = dos.borrow().exit_code;
+    (exit.unwrap(), exit_code)
+  };
+  // The exit code is the major version DOS reports
+  let program = Program::new()
+    .mov(Ah, 0x30)
+    .int(0x21)
+    .mov(Ah, 0x4c)
+    .int(0x21);
+  assert_eq!(run(program), (process::Exit::Terminated(5), Some(5)));
+  assert_eq!(run(Program::new().ret()),
+    (process::Exit::Terminated(0), Some(0)));
+  let program = Program::new()
+    .mov(Ah, 0x0e)
+    .int(0x10);
+  assert_eq!(run(program),
+    (process::Exit::UnhandledInterrupt { vector: 0x10, ax: 0x0e00 }, None));
+}